@@ -92,6 +92,22 @@ class PluginHost(object):
         first_view = buffer_info[0]["views"][0]
         self.plugin.new_buffer(self.views[first_view])
 
+    def will_save(self, peer, view_id, path):
+        """Request sent before a buffer is saved to `path`."""
+        view = self.views[view_id]
+        self.plugin.will_save(view, path)
+        return 0
+
+    def completion(self, peer, view_id, offset):
+        """Request for a list of completions at `offset`."""
+        view = self.views[view_id]
+        return self.plugin.completion(view, offset)
+
+    def hover(self, peer, view_id, offset):
+        """Request for information about the text at `offset`."""
+        view = self.views[view_id]
+        return self.plugin.hover(view, offset)
+
     def did_save(self, peer, view_id, path):
         """Notification that a buffer was saved."""
         view = self.views[view_id]
@@ -112,6 +128,22 @@ class PluginHost(object):
         view.lines.apply_update(peer, **params)
         return self.plugin.update(view, **params)
 
+    def syntax_changed(self, peer, view_id, syntax):
+        """Notification that a buffer's syntax changed."""
+        pass
+
+    def selection_changed(self, peer, view_id, selections):
+        """Notification that a view's selections changed."""
+        pass
+
+    def focus_changed(self, peer, view_id, focused):
+        """Notification that a view gained or lost focus."""
+        pass
+
+    def viewport_changed(self, peer, view_id, first_line, last_line):
+        """Notification that the lines visible in a view changed."""
+        pass
+
     def ping(self, peer, **params):
         pass
 
@@ -119,7 +151,7 @@ class PluginHost(object):
         peer.done = True
         self.plugin.shutdown()
 
-    def custom_command(self, peer, method, params):
+    def custom_command(self, peer, method, params, view_id=None):
         '''Custom command provided by the plugin.'''
         if params.get('view'):
             view = self.views.get(params['view'])
@@ -36,6 +36,16 @@ class Plugin(object):
         self.print_err("initialize: {}".format(view.view_id))
         pass
 
+    def will_save(self, view, path):
+        self.print_err("will_save: {}".format(view.view_id))
+        pass
+
+    def completion(self, view, offset):
+        return []
+
+    def hover(self, view, offset):
+        return None
+
     def did_save(self, view, old_path):
         self.print_err("did_save: {}".format(view.view_id))
         pass
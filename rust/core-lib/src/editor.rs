@@ -17,7 +17,7 @@ use std::cmp::{min, max};
 use std::fs::File;
 use std::path::{Path, PathBuf};
 use std::io::Write;
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::time::SystemTime;
 
 use serde_json::Value;
@@ -38,8 +38,8 @@ use selection::{Affinity, Selection, SelRegion};
 use tabs::{self, BufferIdentifier, ViewIdentifier, DocumentCtx};
 use rpc::{self, GestureType};
 use syntax::SyntaxDefinition;
-use plugins::rpc::{PluginUpdate, PluginEdit, ScopeSpan, PluginBufferInfo,
-ClientPluginInfo, TextUnit, GetDataResponse};
+use plugins::rpc::{PluginUpdate, PluginEdit, ScopeSpan, SpanUpdate, PluginBufferInfo,
+ClientPluginInfo, TextUnit, GetDataResponse, Diagnostic, FoldRange, UpdateStatus};
 use plugins::{PluginPid, Command};
 use layers::Scopes;
 use config::{BufferConfig, Table};
@@ -59,6 +59,9 @@ const MAX_UNDOS: usize = 20;
 // Maximum returned result from plugin get_data RPC.
 const MAX_SIZE_LIMIT: usize = 1024 * 1024;
 
+// Maximum size of a buffer whose text is sent to plugins on init.
+const MAX_INITIAL_TEXT: usize = 64 * 1024;
+
 enum CharacterEncoding {
     Utf8,
     Utf8WithBom
@@ -101,6 +104,20 @@ pub struct Editor {
     scroll_to: Option<usize>,
 
     styles: Scopes,
+    /// The most recently published diagnostics, by plugin.
+    diagnostics: BTreeMap<PluginPid, Vec<Diagnostic>>,
+    /// The sequence number of the last span update applied, by plugin.
+    span_seqs: BTreeMap<PluginPid, u64>,
+    /// The sequence number of the last diagnostics applied, by plugin.
+    diagnostic_seqs: BTreeMap<PluginPid, u64>,
+    /// The spans received so far of a span update split across several
+    /// messages, with the update's sequence number, by plugin.
+    pending_spans: BTreeMap<PluginPid, (Option<u64>, Vec<ScopeSpan>)>,
+    /// The diagnostics received so far of a set split across several
+    /// messages, with the set's sequence number, by plugin.
+    pending_diagnostics: BTreeMap<PluginPid, (Option<u64>, Vec<Diagnostic>)>,
+    /// The most recently sent fold ranges, by plugin.
+    folding_ranges: BTreeMap<PluginPid, Vec<FoldRange>>,
     doc_ctx: DocumentCtx,
     config: BufferConfig,
     revs_in_flight: usize,
@@ -186,6 +203,12 @@ impl Editor {
             this_edit_type: EditType::Other,
             scroll_to: Some(0),
             styles: Scopes::default(),
+            diagnostics: BTreeMap::new(),
+            span_seqs: BTreeMap::new(),
+            diagnostic_seqs: BTreeMap::new(),
+            pending_spans: BTreeMap::new(),
+            pending_diagnostics: BTreeMap::new(),
+            folding_ranges: BTreeMap::new(),
             doc_ctx: doc_ctx,
             config: config,
             revs_in_flight: 0,
@@ -320,10 +343,15 @@ impl Editor {
         let nb_lines = self.text.measure::<LinesMetric>() + 1;
         let views = vec![self.view.view_id];
         let config = self.config.to_table();
-        PluginBufferInfo::new(self.buffer_id, &views,
-                              self.engine.get_head_rev_id().token(), self.text.len(),
-                              nb_lines, self.path.clone(), self.syntax.clone(),
-                              config)
+        let info = PluginBufferInfo::new(self.buffer_id, &views,
+                                         self.engine.get_head_rev_id().token(),
+                                         self.text.len(), nb_lines, self.path.clone(),
+                                         self.syntax.clone(), config);
+        if self.text.len() <= MAX_INITIAL_TEXT {
+            info.with_text(String::from(&self.text))
+        } else {
+            info
+        }
     }
 
     /// Send initial config state to the client.
@@ -1185,6 +1213,17 @@ impl Editor {
         self.apply_plugin_edit(edit, None)
     }
 
+    /// Applies a plugin's edit, if it was made against the head revision.
+    /// Otherwise the edit is discarded, and the head revision is returned.
+    pub fn plugin_edit_checked(&mut self, edit: PluginEdit) -> Result<(), RevToken> {
+        let head_rev = self.engine.get_head_rev_id().token();
+        if edit.rev != head_rev {
+            return Err(head_rev);
+        }
+        self.plugin_edit_async(edit);
+        Ok(())
+    }
+
     pub fn plugin_n_lines(&self) -> usize {
         self.text.measure::<LinesMetric>() + 1
     }
@@ -1196,9 +1235,20 @@ impl Editor {
         self.styles.add_scopes(plugin, scopes, &self.doc_ctx);
     }
 
-    pub fn plugin_update_spans(&mut self, plugin: PluginPid, start: usize, len: usize,
-                               spans: Vec<ScopeSpan>, rev: RevToken) {
+    /// Replaces `plugin`'s spans as described by `update`, unless its `seq`
+    /// shows that a later update from the plugin has already been applied.
+    /// If `more` is set, the update continues in a later message, and the
+    /// spans are held until it arrives.
+    pub fn plugin_update_spans(&mut self, plugin: PluginPid, update: SpanUpdate) {
         let _t = trace_block("Editor::update_spans", &["core"]);
+        let SpanUpdate { start, len, spans, rev, seq, more } = update;
+        let spans = match reassemble(&mut self.pending_spans, plugin, seq, spans, more) {
+            Some(spans) => spans,
+            None => return,
+        };
+        if is_overtaken(&mut self.span_seqs, plugin, seq) {
+            return;
+        }
         // TODO: more protection against invalid input
         let mut start = start;
         let mut end_offset = start + len;
@@ -1258,12 +1308,16 @@ impl Editor {
     pub fn plugin_get_selections(&self, view_id: ViewIdentifier) -> Value {
         //TODO: multiview support
         assert_eq!(view_id, self.view.view_id);
-        let sels: Vec<(usize, usize)> = self.view.sel_regions()
+        json!({"selections": self.plugin_selections()})
+    }
+
+    /// Returns the view's selections as (start, end) pairs, in the form
+    /// they are sent to plugins.
+    pub fn plugin_selections(&self) -> Vec<(usize, usize)> {
+        self.view.sel_regions()
             .iter()
             .map(|s| { (s.start, s.end) })
-            .collect();
-
-        json!({"selections": sels})
+            .collect()
     }
 
     // Note: currently we route up through Editor to DocumentCtx, but perhaps the plugin
@@ -1272,6 +1326,48 @@ impl Editor {
         self.doc_ctx.alert(msg);
     }
 
+    /// Replaces the diagnostics published by `plugin`, unless `seq` shows
+    /// that later diagnostics from the plugin have already been applied. If
+    /// `more` is set, the set continues in a later message, and the
+    /// diagnostics are held until it arrives.
+    pub fn plugin_diagnostics(&mut self, plugin: PluginPid, diagnostics: Vec<Diagnostic>,
+                              seq: Option<u64>, more: bool) {
+        let _t = trace_block("Editor::plugin_diagnostics", &["core"]);
+        let diagnostics = match reassemble(&mut self.pending_diagnostics, plugin, seq,
+                                           diagnostics, more) {
+            Some(diagnostics) => diagnostics,
+            None => return,
+        };
+        if is_overtaken(&mut self.diagnostic_seqs, plugin, seq) {
+            return;
+        }
+        if diagnostics.is_empty() {
+            self.diagnostics.remove(&plugin);
+        } else {
+            self.diagnostics.insert(plugin, diagnostics);
+        }
+    }
+
+    /// Returns the diagnostics published by all plugins, ordered by plugin.
+    pub fn get_diagnostics(&self) -> Vec<&Diagnostic> {
+        self.diagnostics.values().flat_map(|diags| diags.iter()).collect()
+    }
+
+    /// Replaces the fold ranges sent by `plugin`.
+    pub fn plugin_folding_ranges(&mut self, plugin: PluginPid, ranges: Vec<FoldRange>) {
+        let _t = trace_block("Editor::plugin_folding_ranges", &["core"]);
+        if ranges.is_empty() {
+            self.folding_ranges.remove(&plugin);
+        } else {
+            self.folding_ranges.insert(plugin, ranges);
+        }
+    }
+
+    /// Returns the fold ranges sent by all plugins, ordered by plugin.
+    pub fn get_folding_ranges(&self) -> Vec<&FoldRange> {
+        self.folding_ranges.values().flat_map(|ranges| ranges.iter()).collect()
+    }
+
     /// Notifies the client of the currently available plugins.
     pub fn available_plugins(&self, view_id: ViewIdentifier,
                              plugins: &[ClientPluginInfo]) {
@@ -1293,6 +1389,11 @@ impl Editor {
         self.doc_ctx.update_cmds(view_id, plugin, cmds);
     }
 
+    /// Passes the status a plugin returned from an update on to the client.
+    pub fn plugin_status(&self, plugin: &str, status: &UpdateStatus) {
+        self.doc_ctx.plugin_status(self.view.view_id, plugin, status);
+    }
+
     /// Notifies client that the named plugin has stopped.
     ///
     /// `code` is reserved for future use.
@@ -1302,6 +1403,12 @@ impl Editor {
         let _t = trace_block("Editor::plugin_stopped", &["core"]);
         {
             self.styles.remove_layer(plugin_id);
+            self.diagnostics.remove(&plugin_id);
+            self.span_seqs.remove(&plugin_id);
+            self.diagnostic_seqs.remove(&plugin_id);
+            self.pending_spans.remove(&plugin_id);
+            self.pending_diagnostics.remove(&plugin_id);
+            self.folding_ranges.remove(&plugin_id);
             self.view.set_dirty(&self.text);
             self.render();
         }
@@ -1311,6 +1418,44 @@ impl Editor {
     }
 }
 
+/// Adds `items`, part `seq` of a payload from `plugin` which may be split
+/// across several messages, to the parts received before, returning the
+/// whole payload once its last part, without `more` set, arrives.
+///
+/// Only one payload from each plugin is reassembled at a time; if another
+/// is split before the last finishes, the last is dropped. Payloads which
+/// aren't split may arrive in between, as from a plugin's worker threads,
+/// and leave it be.
+fn reassemble<T>(pending: &mut BTreeMap<PluginPid, (Option<u64>, Vec<T>)>, plugin: PluginPid,
+                 seq: Option<u64>, mut items: Vec<T>, more: bool) -> Option<Vec<T>> {
+    if pending.get(&plugin).is_some_and(|&(pending_seq, _)| pending_seq == seq) {
+        let (_, mut parts) = pending.remove(&plugin).unwrap();
+        parts.append(&mut items);
+        items = parts;
+    }
+    if more {
+        pending.insert(plugin, (seq, items));
+        return None;
+    }
+    Some(items)
+}
+
+/// Returns `true` if a payload from `plugin` numbered `seq` was overtaken by
+/// a later one, which has already been applied. Otherwise, records `seq` as
+/// the last applied. Payloads without a `seq` are never overtaken.
+fn is_overtaken(seqs: &mut BTreeMap<PluginPid, u64>, plugin: PluginPid, seq: Option<u64>)
+                -> bool {
+    let seq = match seq {
+        Some(seq) => seq,
+        None => return false,
+    };
+    if seqs.get(&plugin).is_some_and(|&last| seq <= last) {
+        return true;
+    }
+    seqs.insert(plugin, seq);
+    false
+}
+
 fn n_spaces(n: usize) -> &'static str {
     let spaces = "                                ";
     assert!(n <= spaces.len());
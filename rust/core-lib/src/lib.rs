@@ -75,7 +75,7 @@ pub mod internal {
 
 pub use plugins::rpc as plugin_rpc;
 pub use plugins::PluginPid;
-pub use tabs::ViewIdentifier;
+pub use tabs::{BufferIdentifier, ViewIdentifier};
 pub use syntax::SyntaxDefinition;
 pub use config::{BufferItems as BufferConfig, Table as ConfigTable};
 
@@ -33,7 +33,7 @@ use config::Table;
 
 use super::{PluginCatalog, PluginRef, start_plugin_process, PluginPid};
 use super::rpc::{PluginNotification, PluginRequest, PluginCommand,
-PluginUpdate, UpdateResponse, PluginBufferInfo, ClientPluginInfo};
+PluginUpdate, UpdateResponse, PluginBufferInfo, ClientPluginInfo, STALE_REVISION_ERROR};
 use super::manifest::{PluginActivation, Command};
 
 pub type PluginName = String;
@@ -95,6 +95,7 @@ impl PluginManager {
                     .unwrap().increment_revs_in_flight();
 
                 let view_id = view_id.to_owned();
+                let name = name.to_owned();
                 let buffers = self.buffers.clone().to_weak();
                 let mut plugin_ref = plugin.clone();
 
@@ -109,6 +110,10 @@ impl PluginManager {
                             buffers.lock().editor_for_view_mut(view_id).unwrap()
                                 .apply_plugin_edit(edit, Some(undo_group));
                         }
+                        Ok(Ok(UpdateResponse::Status(status))) => {
+                            buffers.lock().editor_for_view(view_id).unwrap()
+                                .plugin_status(&name, &status);
+                        }
                         Ok(Ok(UpdateResponse::Ack(_))) => (),
                         Ok(Err(err)) => eprintln!("plugin response json err: {:?}", err),
                         Err(err) => {
@@ -195,7 +200,11 @@ impl PluginManager {
 
         match plugin_ref {
             Some(plug) => {
-                let inner = json!({"method": method, "params": params});
+                let inner = json!({
+                    "view_id": view_id,
+                    "method": method,
+                    "params": params,
+                });
                 plug.rpc_notification("custom_command", &inner);
             }
             None => {
@@ -520,7 +529,9 @@ impl PluginManagerRef {
                 self.stop_plugin(view_id, &plugin_name);
             }
 
-        //TODO: send syntax_changed notification before starting new plugins
+        // plugins which keep running learn of the change before new ones start
+        self.lock().notify_plugins(view_id, false, "syntax_changed",
+                                   &json!({"view_id": view_id, "syntax": init_info.syntax}));
 
         let to_run = start_keys.iter()
             .filter(|k| !self.lock().plugin_is_running(view_id, k))
@@ -530,6 +541,27 @@ impl PluginManagerRef {
         self.start_plugins(view_id, &init_info, &to_run);
     }
 
+    /// Notifies plugins that the selections in a view have changed.
+    pub fn document_selection_changed(&self, view_id: ViewIdentifier,
+                                      selections: &[(usize, usize)]) {
+        self.lock().notify_plugins(view_id, false, "selection_changed",
+                                   &json!({"view_id": view_id, "selections": selections}));
+    }
+
+    /// Notifies plugins that a view has gained or lost focus.
+    pub fn document_focus_changed(&self, view_id: ViewIdentifier, focused: bool) {
+        self.lock().notify_plugins(view_id, false, "focus_changed",
+                                   &json!({"view_id": view_id, "focused": focused}));
+    }
+
+    /// Notifies plugins that the lines visible in a view have changed.
+    pub fn document_viewport_changed(&self, view_id: ViewIdentifier,
+                                     first_line: usize, last_line: usize) {
+        self.lock().notify_plugins(view_id, false, "viewport_changed",
+                                   &json!({"view_id": view_id, "first_line": first_line,
+                                           "last_line": last_line}));
+    }
+
     /// Notifies plugins of a user config change
     pub fn document_config_changed(&self, view_id: ViewIdentifier,
                                    changes: &Table) {
@@ -556,7 +588,10 @@ impl PluginManagerRef {
         self.lock().update_plugins(view_id, update, undo_group)
     }
 
-    /// Sends a custom notification to a running plugin
+    /// Sends a custom notification to a running plugin.
+    ///
+    /// The plugin receives this as a `custom_command` notification, with
+    /// the `view_id` it applies to and the command's `method` and `params`.
     pub fn dispatch_command(&self, view_id: ViewIdentifier, receiver: &str,
                              method: &str, params: &Value) {
         self.lock().dispatch_command(view_id, receiver, method, params);
@@ -636,12 +671,26 @@ impl Handler for PluginManagerRef {
         match cmd {
             AddScopes { scopes } => buffers.editor_for_view_mut(view_id)
                 .map(|ed| ed.plugin_add_scopes(plugin_id, scopes)),
-            UpdateSpans { start, len, spans, rev } => buffers.editor_for_view_mut(view_id)
-                .map(|ed| ed.plugin_update_spans(plugin_id, start, len, spans, rev)),
+            UpdateSpans(update) => buffers.editor_for_view_mut(view_id)
+                .map(|ed| ed.plugin_update_spans(plugin_id, update)),
             Edit { edit } => buffers.editor_for_view_mut(view_id)
                 .map(|ed| ed.plugin_edit_async(edit)),
             Alert { msg } => buffers.editor_for_view(view_id)
                 .map(|ed| ed.plugin_alert(&msg)),
+            PluginInfo { info } => {
+                eprintln!("plugin {:?} is {} {}, features: {:?}",
+                          plugin_id, info.name, info.version, info.features);
+                None
+            }
+            Diagnostics { diagnostics, seq, more } => buffers.editor_for_view_mut(view_id)
+                .map(|ed| ed.plugin_diagnostics(plugin_id, diagnostics, seq, more)),
+            FoldingRanges { ranges } => buffers.editor_for_view_mut(view_id)
+                .map(|ed| ed.plugin_folding_ranges(plugin_id, ranges)),
+            Panic { msg } => {
+                eprintln!("plugin {:?} panicked: {}", plugin_id, msg);
+                buffers.editor_for_view(view_id)
+                    .map(|ed| ed.plugin_alert(&format!("A plugin crashed: {}", msg)))
+            }
         };
     }
 
@@ -650,7 +699,7 @@ impl Handler for PluginManagerRef {
         let _t = trace_block("PluginManager::handle_request", &["core"]);
         let PluginCommand { view_id, cmd, .. } = rpc;
         let inner = self.lock();
-        let buffers = inner.buffers.lock();
+        let mut buffers = inner.buffers.lock();
 
         let resp = match cmd {
             LineCount => buffers.editor_for_view(view_id)
@@ -659,6 +708,15 @@ impl Handler for PluginManagerRef {
                 .map(|ed| json!(ed.plugin_get_data(start, unit, max_size, rev))),
             GetSelections => buffers.editor_for_view(view_id)
                 .map(|ed| json!(ed.plugin_get_selections(view_id))),
+            Edit { ref edit } => match buffers.editor_for_view_mut(view_id) {
+                Some(ed) => return ed.plugin_edit_checked(edit.clone())
+                    .map(|_| json!(0))
+                    .map_err(|head_rev| RemoteError::custom(
+                        STALE_REVISION_ERROR,
+                        "Edit made against stale revision",
+                        json!({"rev": edit.rev, "head_rev": head_rev}))),
+                None => None,
+            },
             };
         resp.ok_or(RemoteError::custom(404,
                                        "Missing editor",
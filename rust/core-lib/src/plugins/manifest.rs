@@ -20,6 +20,7 @@ use serde_json::{self, Value};
 use serde::Serialize;
 
 use syntax::SyntaxDefinition;
+use xi_rpc::Framing;
 
 /// Describes attributes and capabilities of a plugin.
 ///
@@ -39,6 +40,10 @@ pub struct PluginDescription {
     pub activations: Vec<PluginActivation>,
     #[serde(default)]
     pub commands: Vec<Command>,
+    /// How RPCs to and from the plugin are framed. Plugins which ask for
+    /// `message_pack` are started with `--msgpack`.
+    #[serde(default)]
+    pub framing: Framing,
 }
 
 /// `PluginActivation`s represent events that trigger running a plugin.
@@ -255,4 +260,16 @@ mod tests {
         assert_eq!(command.rpc_cmd.params_ref()["non_arg"], "plugin supplied value");
         assert_eq!(command.args[1].options.clone().unwrap()[1].value, json!(10));
     }
+
+    #[test]
+    fn test_serde_framing() {
+        let json = r#"{"name": "p", "version": "0.1", "exec_path": "/bin/p"}"#;
+        let desc: PluginDescription = serde_json::from_str(json).unwrap();
+        assert_eq!(desc.framing, Framing::Json);
+
+        let json = r#"{"name": "p", "version": "0.1", "exec_path": "/bin/p",
+            "framing": "message_pack"}"#;
+        let desc: PluginDescription = serde_json::from_str(json).unwrap();
+        assert_eq!(desc.framing, Framing::MessagePack);
+    }
 }
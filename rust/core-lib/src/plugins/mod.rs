@@ -27,7 +27,7 @@ use std::io::{self, BufReader};
 
 use serde_json::{self, Value};
 
-use xi_rpc::{self, RpcPeer, RpcLoop, Callback as RpcCallback};
+use xi_rpc::{self, Framing, RpcPeer, RpcLoop, Callback as RpcCallback};
 use tabs::ViewIdentifier;
 
 pub use self::manager::{PluginManagerRef, WeakPluginManagerRef};
@@ -120,11 +120,8 @@ impl PluginRef {
             Ok(mut inner) => {
                 //FIXME: don't block here?
                 inner.peer.send_rpc_notification("shutdown", &json!({}));
-                // TODO: get rust plugin lib to respect shutdown msg
-                if inner.description.name == "syntect" {
-                    let _ = inner.process.kill();
-                }
-                eprintln!("waiting on process {}", inner.process.id());
+                eprintln!("waiting on plugin {} (process {})",
+                          inner.description.name, inner.process.id());
                 let exit_status = inner.process.wait();
                 eprintln!("process ended {:?}", exit_status);
             }
@@ -194,7 +191,11 @@ pub fn start_plugin_process<C>(manager_ref: &PluginManagerRef,
 
     thread::spawn(move || {
         eprintln!("starting plugin at path {:?}", &plugin_desc.exec_path);
-        let child = ProcCommand::new(&plugin_desc.exec_path)
+        let mut command = ProcCommand::new(&plugin_desc.exec_path);
+        if plugin_desc.framing == Framing::MessagePack {
+            command.arg("--msgpack");
+        }
+        let child = command
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .spawn();
@@ -203,7 +204,7 @@ pub fn start_plugin_process<C>(manager_ref: &PluginManagerRef,
             Ok(mut child) => {
                 let child_stdin = child.stdin.take().unwrap();
                 let child_stdout = child.stdout.take().unwrap();
-                let mut looper = RpcLoop::new(child_stdin);
+                let mut looper = RpcLoop::with_framing(child_stdin, plugin_desc.framing);
                 let peer: RpcPeer = Box::new(looper.get_raw_peer());
                 peer.send_rpc_notification("ping", &Value::Array(Vec::new()));
                 let plugin = Plugin {
@@ -49,6 +49,10 @@ pub struct PluginBufferInfo {
     pub path: Option<String>,
     pub syntax: SyntaxDefinition,
     pub config: Table,
+    /// The buffer's text; only sent if the buffer is small.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
 }
 
 //TODO: very likely this should be merged with PluginDescription
@@ -85,11 +89,30 @@ pub struct PluginUpdate {
 pub enum UpdateResponse {
     /// An edit to the buffer.
     Edit(PluginEdit),
+    /// Feedback about the plugin's handling of the update, for display.
+    Status(UpdateStatus),
     /// An acknowledgement with no action. A response cannot be Null,
     /// so we send a uint.
     Ack(u64),
 }
 
+/// Lightweight feedback returned by a plugin in response to an update,
+/// which core passes on to the client.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateStatus {
+    /// A short message, such as "indexing".
+    #[serde(default)]
+    pub status: Option<String>,
+    /// How far along the plugin's work is, from 0.0 to 1.0.
+    #[serde(default)]
+    pub progress: Option<f64>,
+    /// The names of commands the plugin offers in response to the update,
+    /// such as "fix all".
+    #[serde(default)]
+    pub actions: Vec<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EmptyStruct {}
 
@@ -100,6 +123,34 @@ pub struct EmptyStruct {}
 pub enum HostRequest {
     Update(PluginUpdate),
     CollectTrace(EmptyStruct),
+    /// Checks that the plugin is responsive. Unlike the `ping` notification,
+    /// this expects a response describing the plugin's state.
+    Ping(EmptyStruct),
+    /// A plugin-defined command. Plugins reply with an error to commands
+    /// they do not recognize.
+    CustomCommand { view_id: ViewIdentifier, method: String, params: Value },
+    /// Asks the plugin to clean up and exit, like the `shutdown`
+    /// notification, but with a response once cleanup is done; the
+    /// response describes what the plugin did, and its format is up to the
+    /// plugin.
+    Shutdown(EmptyStruct),
+    /// Sent before the view's buffer is written to `path`, so that the
+    /// plugin can make last edits, such as formatting. An error response
+    /// asks for the save to be cancelled, in which case no `did_save`
+    /// follows; otherwise `did_save` is sent once the file is written.
+    WillSave { view_id: ViewIdentifier, path: PathBuf },
+    /// Asks for completions at `offset`, typically the caret. The response
+    /// is a list of `CompletionItem`s, which is empty if the plugin has
+    /// nothing to suggest.
+    Completion { view_id: ViewIdentifier, offset: usize },
+    /// Asks for information about the text at `offset`, such as the type of
+    /// the expression under the mouse. The response is a `Hover`, or null
+    /// if the plugin has nothing to show.
+    Hover { view_id: ViewIdentifier, offset: usize },
+    /// Asks for a snapshot of the plugin's internal state, for debugging.
+    /// The response's format is up to the plugin, but should describe
+    /// documents by their sizes rather than include their text.
+    DebugState(EmptyStruct),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -114,7 +165,34 @@ pub enum HostNotification {
     NewBuffer { buffer_info: Vec<PluginBufferInfo> },
     DidClose { view_id: ViewIdentifier },
     Shutdown(EmptyStruct),
-    TracingConfig {enabled: bool},
+    /// Enables or disables tracing. When enabling, `categories`, if not
+    /// empty, limits tracing to samples in those categories, and
+    /// `max_samples` overrides the default limit on samples kept.
+    TracingConfig {
+        enabled: bool,
+        #[serde(default)]
+        categories: Vec<String>,
+        #[serde(default)]
+        max_samples: Option<usize>,
+    },
+    /// Requests that the plugin abandon the idle work scheduled with `token`.
+    Cancel { view_id: ViewIdentifier, token: usize },
+    /// A plugin-defined command, forwarded from a client's `plugin_rpc`
+    /// (see `PluginManagerRef::dispatch_command`).
+    CustomCommand { view_id: ViewIdentifier, method: String, params: Value },
+    /// The syntax of the view's buffer has changed, for instance because it
+    /// was saved with a new file extension.
+    SyntaxChanged { view_id: ViewIdentifier, syntax: SyntaxDefinition },
+    /// The view's selections have changed. Each selection is a pair of
+    /// byte offsets, the inactive edge followed by the active edge (the
+    /// caret), in the same form as the response to `get_selections`.
+    SelectionChanged { view_id: ViewIdentifier, selections: Vec<(usize, usize)> },
+    /// The view has gained or lost focus. Views are unfocused until core
+    /// says otherwise.
+    FocusChanged { view_id: ViewIdentifier, focused: bool },
+    /// The lines visible in the view have changed. Both lines are
+    /// inclusive.
+    ViewportChanged { view_id: ViewIdentifier, first_line: usize, last_line: usize },
 }
 
 
@@ -137,13 +215,119 @@ pub struct PluginEdit {
     pub author: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ScopeSpan {
     pub start: usize,
     pub end: usize,
     pub scope_id: u32,
 }
 
+/// Replaces a plugin's spans in `start..start + len`, computed against
+/// revision `rev`. `seq` numbers the plugin's span updates in the order it
+/// sent them; an update whose `seq` is not greater than that of the last
+/// applied was overtaken by it, and is dropped. Updates without a `seq` are
+/// always applied.
+///
+/// A large update may be split across several messages, each with the same
+/// `start`, `len`, `rev` and `seq`, and a share of the spans, in order. Every
+/// message but the last has `more` set; core holds on to their spans, and
+/// applies them all when the last message arrives.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpanUpdate {
+    pub start: usize,
+    pub len: usize,
+    pub spans: Vec<ScopeSpan>,
+    pub rev: u64,
+    #[serde(default)]
+    pub seq: Option<u64>,
+    #[serde(default)]
+    pub more: bool,
+}
+
+/// The severity of a `Diagnostic`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+    Hint,
+}
+
+/// A problem found in a document, such as a lint warning.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    /// The start and end offsets of the affected region.
+    pub range: (usize, usize),
+    pub severity: Severity,
+    pub message: String,
+    /// An identifier for the kind of problem, if the plugin has one.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+}
+
+/// The kind of region a `FoldRange` covers.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FoldKind {
+    /// A block of code, such as a function body.
+    Region,
+    Comment,
+    /// A run of imports or includes.
+    Imports,
+}
+
+/// A region of a document which can be folded away, from the end of
+/// `start_line`, which stays visible, up to the end of `end_line`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FoldRange {
+    pub start_line: usize,
+    pub end_line: usize,
+    pub kind: FoldKind,
+}
+
+/// The kind of thing a `CompletionItem` inserts, which clients may use to
+/// pick an icon.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CompletionKind {
+    Text,
+    Keyword,
+    Function,
+    Variable,
+    Type,
+    Module,
+    Snippet,
+}
+
+/// A suggestion returned in response to a `completion` request.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CompletionItem {
+    /// The text shown in the list of completions.
+    pub label: String,
+    /// The text inserted if this item is chosen.
+    pub insert_text: String,
+    pub kind: CompletionKind,
+    /// Extra information, such as a function's signature.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Information about the text at an offset, returned in response to a
+/// `hover` request.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Hover {
+    /// The text to show, such as a type signature.
+    pub contents: String,
+    /// The start and end offsets of the text the information is about, if
+    /// the client should highlight it.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<(usize, usize)>,
+}
+
 /// The object returned by the `get_data` RPC.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GetDataResponse {
@@ -173,8 +357,16 @@ pub enum PluginRequest {
     GetData { start: usize, unit: TextUnit, max_size: usize, rev: u64 },
     LineCount,
     GetSelections,
+    /// Like the `edit` notification, but the edit is only applied if it was
+    /// made against the current revision. Otherwise, an error with code
+    /// `STALE_REVISION_ERROR` is returned.
+    Edit { edit: PluginEdit },
 }
 
+/// The error code returned when a plugin's edit request is rejected
+/// because the buffer has changed since the edit's revision.
+pub const STALE_REVISION_ERROR: i64 = 409;
+
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "snake_case")]
@@ -182,9 +374,34 @@ pub enum PluginRequest {
 /// RPC commands sent from plugins.
 pub enum PluginNotification {
     AddScopes { scopes: Vec<Vec<String>> },
-    UpdateSpans { start: usize, len: usize, spans: Vec<ScopeSpan>, rev: u64 },
+    UpdateSpans(SpanUpdate),
     Edit { edit: PluginEdit },
     Alert { msg: String },
+    /// Describes the plugin; sent once the plugin has been initialized.
+    PluginInfo { info: PluginInfo },
+    /// Replaces the diagnostics previously published by the plugin.
+    ///
+    /// As with `UpdateSpans`, `seq` numbers the sets of diagnostics, and
+    /// a set overtaken by a later one is dropped. A large set may be split
+    /// across several messages, all but the last with `more` set; the
+    /// diagnostics are replaced once the last arrives.
+    Diagnostics { diagnostics: Vec<Diagnostic>,
+                  #[serde(default)] seq: Option<u64>,
+                  #[serde(default)] more: bool },
+    /// Replaces the fold ranges previously sent by the plugin.
+    FoldingRanges { ranges: Vec<FoldRange> },
+    /// Sent on a best-effort basis when the plugin panics, just before it
+    /// exits.
+    Panic { msg: String },
+}
+
+/// A plugin's self-description.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PluginInfo {
+    pub name: String,
+    pub version: String,
+    /// The features the plugin makes use of, such as "spans" or "edits".
+    pub features: Vec<String>,
 }
 
 /// Common wrapper for plugin-originating RPCs.
@@ -238,7 +455,13 @@ impl PluginBufferInfo {
         let path = path.map(|p| p.to_str().unwrap().to_owned());
         let views = views.to_owned();
         PluginBufferInfo { buffer_id, views, rev, buf_size,
-        nb_lines, path, syntax, config }
+        nb_lines, path, syntax, config, text: None }
+    }
+
+    /// Includes the buffer's text, so that plugins need not fetch it.
+    pub fn with_text(mut self, text: String) -> Self {
+        self.text = Some(text);
+        self
     }
 }
 
@@ -327,6 +550,19 @@ mod tests {
         assert_eq!(val.rev, 1);
         assert_eq!(val.path, Some("some_path".to_owned()));
         assert_eq!(val.syntax, SyntaxDefinition::Toml);
+        assert_eq!(val.text, None);
+
+        let json = r#"
+            {"buffer_id": 42,
+             "views": ["view-id-4"],
+             "rev": 1,
+             "buf_size": 5,
+             "nb_lines": 2,
+             "syntax": "toml",
+             "config": {},
+             "text": "a = 1"}"#;
+        let val: PluginBufferInfo = serde_json::from_str(json).unwrap();
+        assert_eq!(val.text, Some("a = 1".to_owned()));
     }
 
     #[test]
@@ -340,4 +576,285 @@ mod tests {
             _ => panic!("{:?}", de.cmd),
         }
     }
+
+    #[test]
+    fn test_de_plugin_info() {
+        let json = r#"{"method": "plugin_info", "params": {"view_id": "view-id-1", "plugin_id": 42,
+            "info": {"name": "test", "version": "0.1.0", "features": ["spans"]}}}"#;
+        let de: PluginCommand<PluginNotification> = serde_json::from_str(json).unwrap();
+        match de.cmd {
+            PluginNotification::PluginInfo { ref info } => {
+                assert_eq!(info.name, "test");
+                assert_eq!(info.features, vec!["spans".to_owned()]);
+            }
+            _ => panic!("{:?}", de.cmd),
+        }
+    }
+
+    #[test]
+    fn test_de_panic() {
+        let json = r#"{"method": "panic", "params": {"view_id": "view-id-1", "plugin_id": 42,
+            "msg": "oh no"}}"#;
+        let de: PluginCommand<PluginNotification> = serde_json::from_str(json).unwrap();
+        match de.cmd {
+            PluginNotification::Panic { ref msg } if msg == "oh no" => (),
+            _ => panic!("{:?}", de.cmd),
+        }
+    }
+
+    #[test]
+    fn test_de_custom_command() {
+        let json = r#"{"method": "custom_command", "params": {"view_id": "view-id-1",
+            "method": "format", "params": {"indent": 2}}}"#;
+        let de: HostNotification = serde_json::from_str(json).unwrap();
+        match de {
+            HostNotification::CustomCommand { ref method, ref params, .. } => {
+                assert_eq!(method, "format");
+                assert_eq!(params, &json!({"indent": 2}));
+            }
+            _ => panic!("{:?}", de),
+        }
+    }
+
+    #[test]
+    fn test_de_will_save() {
+        let json = r#"{"id": 3, "method": "will_save", "params": {"view_id": "view-id-1",
+            "path": "/tmp/a.rs"}}"#;
+        let de: HostRequest = serde_json::from_str(json).unwrap();
+        match de {
+            HostRequest::WillSave { path, .. } => assert_eq!(path, PathBuf::from("/tmp/a.rs")),
+            _ => panic!("{:?}", de),
+        }
+    }
+
+    #[test]
+    fn test_de_completion() {
+        let json = r#"{"id": 4, "method": "completion", "params": {"view_id": "view-id-1",
+            "offset": 12}}"#;
+        let de: HostRequest = serde_json::from_str(json).unwrap();
+        match de {
+            HostRequest::Completion { offset, .. } => assert_eq!(offset, 12),
+            _ => panic!("{:?}", de),
+        }
+
+        let json = r#"{"label": "len", "insert_text": "len()", "kind": "function"}"#;
+        let item: CompletionItem = serde_json::from_str(json).unwrap();
+        assert_eq!(item.kind, CompletionKind::Function);
+        assert_eq!(item.detail, None);
+        assert_eq!(serde_json::to_value(&item).unwrap(), json!({
+            "label": "len", "insert_text": "len()", "kind": "function"}));
+    }
+
+    #[test]
+    fn test_de_hover() {
+        let json = r#"{"id": 5, "method": "hover", "params": {"view_id": "view-id-1",
+            "offset": 3}}"#;
+        let de: HostRequest = serde_json::from_str(json).unwrap();
+        match de {
+            HostRequest::Hover { offset, .. } => assert_eq!(offset, 3),
+            _ => panic!("{:?}", de),
+        }
+
+        let hover: Hover = serde_json::from_str(r#"{"contents": "fn main()"}"#).unwrap();
+        assert_eq!(hover, Hover { contents: "fn main()".into(), range: None });
+        let hover = Hover { range: Some((0, 4)), ..hover };
+        assert_eq!(serde_json::to_value(&hover).unwrap(),
+                   json!({"contents": "fn main()", "range": [0, 4]}));
+    }
+
+    #[test]
+    fn test_de_tracing_config() {
+        let json = r#"{"method": "tracing_config", "params": {"enabled": true}}"#;
+        let de: HostNotification = serde_json::from_str(json).unwrap();
+        match de {
+            HostNotification::TracingConfig { enabled, categories, max_samples } => {
+                assert!(enabled);
+                assert!(categories.is_empty());
+                assert_eq!(max_samples, None);
+            }
+            _ => panic!("{:?}", de),
+        }
+
+        let json = r#"{"method": "tracing_config", "params": {"enabled": true,
+            "categories": ["rpc"], "max_samples": 100}}"#;
+        let de: HostNotification = serde_json::from_str(json).unwrap();
+        match de {
+            HostNotification::TracingConfig { categories, max_samples, .. } => {
+                assert_eq!(categories, vec!["rpc".to_owned()]);
+                assert_eq!(max_samples, Some(100));
+            }
+            _ => panic!("{:?}", de),
+        }
+    }
+
+    #[test]
+    fn test_de_syntax_changed() {
+        let json = r#"{"method": "syntax_changed", "params": {"view_id": "view-id-1",
+            "syntax": "rust"}}"#;
+        let de: HostNotification = serde_json::from_str(json).unwrap();
+        match de {
+            HostNotification::SyntaxChanged { syntax, .. } => {
+                assert_eq!(syntax, SyntaxDefinition::Rust);
+            }
+            _ => panic!("{:?}", de),
+        }
+    }
+
+    #[test]
+    fn test_de_selection_changed() {
+        let json = r#"{"method": "selection_changed", "params": {"view_id": "view-id-1",
+            "selections": [[4, 4], [10, 7]]}}"#;
+        let de: HostNotification = serde_json::from_str(json).unwrap();
+        match de {
+            HostNotification::SelectionChanged { ref selections, .. } => {
+                assert_eq!(selections, &[(4, 4), (10, 7)]);
+            }
+            _ => panic!("{:?}", de),
+        }
+    }
+
+    #[test]
+    fn test_de_focus_changed() {
+        let json = r#"{"method": "focus_changed", "params": {"view_id": "view-id-1",
+            "focused": true}}"#;
+        let de: HostNotification = serde_json::from_str(json).unwrap();
+        match de {
+            HostNotification::FocusChanged { focused, .. } => assert!(focused),
+            _ => panic!("{:?}", de),
+        }
+    }
+
+    #[test]
+    fn test_de_viewport_changed() {
+        let json = r#"{"method": "viewport_changed", "params": {"view_id": "view-id-1",
+            "first_line": 40, "last_line": 79}}"#;
+        let de: HostNotification = serde_json::from_str(json).unwrap();
+        match de {
+            HostNotification::ViewportChanged { first_line, last_line, .. } => {
+                assert_eq!((first_line, last_line), (40, 79));
+            }
+            _ => panic!("{:?}", de),
+        }
+    }
+
+    #[test]
+    fn test_de_update_spans() {
+        let json = r#"{"method": "update_spans", "params": {"view_id": "view-id-1",
+            "plugin_id": 42, "start": 0, "len": 4, "rev": 3, "seq": 7,
+            "spans": [{"start": 0, "end": 2, "scope_id": 1}]}}"#;
+        let de: PluginCommand<PluginNotification> = serde_json::from_str(json).unwrap();
+        match de.cmd {
+            PluginNotification::UpdateSpans(update) => {
+                assert_eq!((update.rev, update.seq, update.more), (3, Some(7), false));
+            }
+            _ => panic!("{:?}", de.cmd),
+        }
+    }
+
+    #[test]
+    fn test_de_chunked_payloads() {
+        let json = r#"{"method": "update_spans", "params": {"view_id": "view-id-1",
+            "plugin_id": 42, "start": 0, "len": 4, "rev": 3, "seq": 7, "more": true,
+            "spans": [{"start": 0, "end": 2, "scope_id": 1}]}}"#;
+        let de: PluginCommand<PluginNotification> = serde_json::from_str(json).unwrap();
+        match de.cmd {
+            PluginNotification::UpdateSpans(update) => assert!(update.more),
+            _ => panic!("{:?}", de.cmd),
+        }
+        let json = r#"{"method": "diagnostics", "params": {"view_id": "view-id-1", "plugin_id": 42,
+            "diagnostics": [], "more": true}}"#;
+        let de: PluginCommand<PluginNotification> = serde_json::from_str(json).unwrap();
+        match de.cmd {
+            PluginNotification::Diagnostics { more, seq, .. } => {
+                assert!(more);
+                assert_eq!(seq, None);
+            }
+            _ => panic!("{:?}", de.cmd),
+        }
+    }
+
+    #[test]
+    fn test_de_update_response() {
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        let delta = Delta::simple_edit(Interval::new_closed_open(0, 0), "a".into(), 0);
+        let edit = PluginEdit { rev: 1, delta, priority: 0, after_cursor: false,
+                                author: "me".into() };
+        match serde_json::from_value::<UpdateResponse>(json!(edit)).unwrap() {
+            UpdateResponse::Edit(edit) => assert_eq!(edit.author, "me"),
+            other => panic!("{:?}", other),
+        }
+        let status = json!({"status": "indexing", "progress": 0.5});
+        match serde_json::from_value::<UpdateResponse>(status).unwrap() {
+            UpdateResponse::Status(status) => {
+                assert_eq!(status.status.as_ref().map(String::as_str), Some("indexing"));
+                assert_eq!(status.progress, Some(0.5));
+                assert!(status.actions.is_empty());
+            }
+            other => panic!("{:?}", other),
+        }
+        match serde_json::from_value::<UpdateResponse>(json!(0)).unwrap() {
+            UpdateResponse::Ack(0) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_de_diagnostics() {
+        let json = r#"{"method": "diagnostics", "params": {"view_id": "view-id-1", "plugin_id": 42,
+            "diagnostics": [{"range": [2, 5], "severity": "warning", "message": "unused"},
+                            {"range": [7, 9], "severity": "error", "message": "bad", "code": "E1"}]}}"#;
+        let de: PluginCommand<PluginNotification> = serde_json::from_str(json).unwrap();
+        match de.cmd {
+            PluginNotification::Diagnostics { ref diagnostics, more, .. } => {
+                assert!(!more);
+                assert_eq!(diagnostics.len(), 2);
+                assert_eq!(diagnostics[0].range, (2, 5));
+                assert_eq!(diagnostics[0].severity, Severity::Warning);
+                assert_eq!(diagnostics[0].code, None);
+                assert_eq!(diagnostics[1].code, Some("E1".to_owned()));
+            }
+            _ => panic!("{:?}", de.cmd),
+        }
+    }
+
+    #[test]
+    fn test_de_debug_state() {
+        let json = r#"{"method": "debug_state", "params": {}}"#;
+        let de: HostRequest = serde_json::from_str(json).unwrap();
+        match de {
+            HostRequest::DebugState(..) => (),
+            _ => panic!("{:?}", de),
+        }
+    }
+
+    #[test]
+    fn test_de_folding_ranges() {
+        let json = r#"{"method": "folding_ranges", "params": {"view_id": "view-id-1",
+            "plugin_id": 42, "ranges": [{"start_line": 2, "end_line": 9, "kind": "region"},
+                                        {"start_line": 0, "end_line": 1, "kind": "imports"}]}}"#;
+        let de: PluginCommand<PluginNotification> = serde_json::from_str(json).unwrap();
+        match de.cmd {
+            PluginNotification::FoldingRanges { ref ranges } => {
+                assert_eq!(ranges, &[
+                    FoldRange { start_line: 2, end_line: 9, kind: FoldKind::Region },
+                    FoldRange { start_line: 0, end_line: 1, kind: FoldKind::Imports },
+                ]);
+            }
+            _ => panic!("{:?}", de.cmd),
+        }
+    }
+
+    #[test]
+    fn test_de_plugin_edit_request() {
+        let json = r#"{"method": "edit", "params": {"view_id": "view-id-1", "plugin_id": 42,
+            "edit": {"rev": 3, "delta": {"base_len": 2, "els": [{"copy": [0,2]}, {"insert": "!"}]},
+            "priority": 1, "after_cursor": false, "author": "me"}}}"#;
+        let de: PluginCommand<PluginRequest> = serde_json::from_str(json).unwrap();
+        match de.cmd {
+            PluginRequest::Edit { ref edit } if edit.rev == 3 => (),
+            _ => panic!("{:?}", de.cmd),
+        }
+    }
 }
@@ -45,7 +45,7 @@ use MainPeer;
 use syntax::SyntaxDefinition;
 use config::{ConfigManager, ConfigDomain, Table};
 use plugins::{self, PluginManagerRef, Command};
-use plugins::rpc::{PluginUpdate, ClientPluginInfo};
+use plugins::rpc::{PluginUpdate, ClientPluginInfo, UpdateStatus};
 
 #[cfg(feature="ledger")]
 use apps_ledger_services_public::{Ledger_Proxy};
@@ -398,9 +398,17 @@ impl Documents {
                 self.do_save(rpc_ctx.get_peer(), view_id, file_path),
             CloseView { view_id } => self.do_close_view(view_id),
             Edit(rpc::EditCommand { view_id, cmd }) => {
-                self.buffers.lock().editor_for_view_mut(view_id)
-                    .map(|ed| ed.handle_notification(view_id, cmd));
+                let changed_sels = self.buffers.lock().editor_for_view_mut(view_id)
+                    .and_then(|ed| {
+                        let old_sels = ed.plugin_selections();
+                        ed.handle_notification(view_id, cmd);
+                        let sels = ed.plugin_selections();
+                        if sels != old_sels { Some(sels) } else { None }
+                    });
+                if let Some(sels) = changed_sels {
+                    self.plugins.document_selection_changed(view_id, &sels);
                 }
+            }
             Plugin(cmd) => self.do_plugin_cmd(cmd),
             ModifyUserConfig { domain, changes } =>
                 self.do_modify_user_config(rpc_ctx.get_peer(), domain, changes),
@@ -964,6 +972,19 @@ impl DocumentCtx {
                                                 "plugins": plugins }));
     }
 
+    /// Notify the client of a plugin's status, as it returned from an update.
+    pub fn plugin_status(&self, view_id: ViewIdentifier, plugin: &str,
+                         status: &UpdateStatus) {
+        self.rpc_peer.send_rpc_notification("plugin_status",
+                                            &json!({
+                                                "view_id": view_id,
+                                                "plugin": plugin,
+                                                "status": status.status,
+                                                "progress": status.progress,
+                                                "actions": status.actions,
+                                            }));
+    }
+
     pub fn update_cmds(&self, view_id: ViewIdentifier,
                        plugin: &str, cmds: &[Command]) {
         self.rpc_peer.send_rpc_notification("update_cmds",
@@ -1216,6 +1237,45 @@ mod tests {
         assert_eq!(container_ref.lock().editors.len(), 0);
     }
 
+    #[test]
+    fn test_plugin_diagnostics() {
+        use plugins::PluginPid;
+        use plugins::rpc::{Diagnostic, Severity};
+
+        let config = ConfigManager::default().default_buffer_config();
+        let mut editor = Editor::with_text(mock_doc_ctx("test_plugin_diagnostics"), config,
+                                           BufferIdentifier(1), ViewIdentifier(1),
+                                           "hello world".to_owned());
+        let plugin: PluginPid = serde_json::from_value(json!(1)).unwrap();
+        let diag = |message: &str| Diagnostic {
+            range: (0, 5),
+            severity: Severity::Warning,
+            message: message.to_owned(),
+            code: None,
+        };
+        let messages = |editor: &Editor| editor.get_diagnostics().iter()
+            .map(|d| d.message.clone())
+            .collect::<Vec<_>>();
+
+        // a set sent from a worker thread arrives between the parts of a
+        // split set, and is applied on its own
+        editor.plugin_diagnostics(plugin, vec![diag("a")], Some(2), true);
+        editor.plugin_diagnostics(plugin, vec![diag("worker")], Some(1), false);
+        assert_eq!(messages(&editor), vec!["worker"]);
+        editor.plugin_diagnostics(plugin, vec![diag("b")], Some(2), false);
+        assert_eq!(messages(&editor), vec!["a", "b"]);
+
+        // a set overtaken by a later one is dropped
+        editor.plugin_diagnostics(plugin, vec![diag("late")], Some(1), false);
+        assert_eq!(messages(&editor), vec!["a", "b"]);
+
+        // as is a split set which never finishes, once another is split
+        editor.plugin_diagnostics(plugin, vec![diag("c")], Some(3), true);
+        editor.plugin_diagnostics(plugin, vec![diag("d")], Some(4), true);
+        editor.plugin_diagnostics(plugin, vec![diag("e")], Some(4), false);
+        assert_eq!(messages(&editor), vec!["d", "e"]);
+    }
+
     #[test]
     fn test_id_serde() {
         // check to see that struct with single string member serializes as string
@@ -0,0 +1,105 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compares how often `update` is called for a burst of edits, with and
+//! without `wants_batched_updates`. The counts are printed to stderr; run
+//! with `cargo bench -- --nocapture` to see them.
+
+#![feature(test)]
+
+extern crate serde_json;
+extern crate test;
+extern crate xi_core_lib as xi_core;
+extern crate xi_plugin_lib;
+extern crate xi_rope;
+extern crate xi_rpc;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde_json::Value;
+use test::Bencher;
+use xi_core::ConfigTable;
+use xi_plugin_lib::state_cache::{Plugin, PluginCtx, UpdateContext, UPDATE_BACKLOG_KEY};
+use xi_plugin_lib::testing::{MockCore, PluginBufferInfoBuilder};
+use xi_rope::delta::Delta;
+use xi_rope::interval::Interval;
+use xi_rope::rope::RopeDelta;
+use xi_rpc::RemoteError;
+
+/// The number of edits in each burst.
+const BURST_LEN: usize = 100;
+
+/// Counts the calls to `update`.
+struct CountingPlugin {
+    batched: bool,
+    updates: Arc<AtomicUsize>,
+}
+
+impl Plugin for CountingPlugin {
+    type State = ();
+
+    fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+
+    fn update(&mut self, _ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+              -> Result<Option<Value>, RemoteError> {
+        self.updates.fetch_add(1, Ordering::Relaxed);
+        Ok(None)
+    }
+
+    fn wants_batched_updates(&self) -> bool { self.batched }
+}
+
+/// Typing a character and deleting it again, over and over, so that the
+/// document is the same after each burst.
+fn edit_burst(text_len: usize) -> Vec<RopeDelta> {
+    (0..BURST_LEN).map(|i| if i % 2 == 0 {
+        Delta::simple_edit(Interval::new_closed_open(0, 0), "a".into(), text_len)
+    } else {
+        Delta::simple_edit(Interval::new_closed_open(0, 1), "".into(), text_len + 1)
+    }).collect()
+}
+
+fn run_burst(b: &mut Bencher, batched: bool) {
+    let text = "fn main() {\n    println!(\"hello\");\n}\n".repeat(100);
+    // without this, updates are collapsed anyway once the plugin falls behind
+    let mut config = ConfigTable::new();
+    config.insert(UPDATE_BACKLOG_KEY.into(), 0.into());
+    let info = PluginBufferInfoBuilder::new().text(&text).config(config).build();
+    let updates = Arc::new(AtomicUsize::new(0));
+    let mut core = MockCore::start(CountingPlugin { batched, updates: updates.clone() });
+    core.new_view_with_info(info);
+
+    let mut bursts = 0;
+    updates.store(0, Ordering::Relaxed);
+    b.iter(|| {
+        core.update_burst(edit_burst(text.len()));
+        // batched updates are passed on once idle
+        core.wait_idle();
+        bursts += 1;
+    });
+    eprintln!("batched: {}, {:.1} update calls per burst of {}", batched,
+              updates.load(Ordering::Relaxed) as f64 / bursts as f64, BURST_LEN);
+    core.finish();
+}
+
+#[bench]
+fn bench_unbatched_updates(b: &mut Bencher) {
+    run_burst(b, false);
+}
+
+#[bench]
+fn bench_batched_updates(b: &mut Bencher) {
+    run_burst(b, true);
+}
@@ -0,0 +1,141 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compares the allocations made in highlighting a document after each
+//! edit, collecting spans in a fresh `Vec` for `update_spans` or in a
+//! reused `SpanBuilder` for `flush_spans`. The counts are printed to
+//! stderr; run with `cargo bench -- --nocapture` to see them.
+
+#![feature(test)]
+
+extern crate serde_json;
+extern crate test;
+extern crate xi_core_lib as xi_core;
+extern crate xi_plugin_lib;
+extern crate xi_rope;
+extern crate xi_rpc;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde_json::Value;
+use test::Bencher;
+use xi_core::plugin_rpc::ScopeSpan;
+use xi_plugin_lib::state_cache::{Plugin, PluginCtx, SpanBuilder, UpdateContext};
+use xi_plugin_lib::testing::MockCore;
+use xi_rope::delta::Delta;
+use xi_rope::interval::Interval;
+use xi_rope::rope::RopeDelta;
+use xi_rpc::RemoteError;
+
+/// Counts the allocations made on each thread.
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(Cell::get)
+}
+
+/// Highlights every word after each update, as a syntax highlighter would,
+/// counting the allocations made in building and sending the spans.
+struct HighlightPlugin {
+    builder: Option<SpanBuilder>,
+    allocations: Arc<AtomicUsize>,
+}
+
+impl Plugin for HighlightPlugin {
+    type State = ();
+
+    fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+
+    fn update(&mut self, mut ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+              -> Result<Option<Value>, RemoteError> {
+        let len = ctx.get_buf_size();
+        let text = ctx.get_region(0, len).unwrap().to_owned();
+        let words = text.split(' ').scan(0, |offset, word| {
+            let start = *offset;
+            *offset += word.len() + 1;
+            Some((start, start + word.len()))
+        });
+
+        let before = allocations();
+        match self.builder {
+            Some(ref mut builder) => {
+                for (start, end) in words {
+                    builder.add(start, end, 1);
+                }
+                ctx.flush_spans(0, len, builder);
+            }
+            None => {
+                let spans = words.map(|(start, end)| ScopeSpan { start, end, scope_id: 1 })
+                    .collect::<Vec<_>>();
+                ctx.update_spans(0, len, &spans);
+            }
+        }
+        self.allocations.fetch_add(allocations() - before, Ordering::Relaxed);
+        Ok(None)
+    }
+}
+
+fn run_highlight(b: &mut Bencher, builder: Option<SpanBuilder>) {
+    let text = "fn main() { let x = some_function(argument); } ".repeat(200);
+    let allocations = Arc::new(AtomicUsize::new(0));
+    let mut core = MockCore::start(HighlightPlugin { builder, allocations: allocations.clone() });
+    core.new_view(&text);
+
+    let mut updates = 0;
+    b.iter(|| {
+        // retyping the first character, so that the document is unchanged
+        let delta = Delta::simple_edit(Interval::new_closed_open(0, 1), "f".into(), text.len());
+        core.update(delta).unwrap();
+        updates += 1;
+    });
+    let with_builder = core.finish().builder.is_some();
+    eprintln!("span builder: {}, {:.1} allocations per update", with_builder,
+              allocations.load(Ordering::Relaxed) as f64 / updates as f64);
+}
+
+#[bench]
+fn bench_fresh_spans(b: &mut Bencher) {
+    run_highlight(b, None);
+}
+
+#[bench]
+fn bench_span_builder(b: &mut Bencher) {
+    run_highlight(b, Some(SpanBuilder::new()));
+}
@@ -12,9 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-//! The simplest cache. This should eventually offer line-oriented access
-//! to the remote document, and can be used as a building block for more
-//! complicated caching schemes.
+//! Caches of the remote document.
+//!
+//! A plugin only sees the document through the updates core sends it, and
+//! the text it fetches with `get_data`. The `Cache` trait describes what
+//! the plugin library needs from a cache of that text; `ChunkCache`, the
+//! default, holds a single contiguous chunk of the document, and
+//! `MetadataCache` holds none of it, fetching text each time it is read.
 
 use memchr::memchr;
 
@@ -25,10 +29,40 @@ use xi_core::plugin_rpc::{TextUnit, GetDataResponse};
 use plugin_base::{Error, DataSource};
 
 #[cfg(not(test))]
-const CHUNK_SIZE: usize = 1024 * 1024;
+pub(crate) const CHUNK_SIZE: usize = 1024 * 1024;
 
 #[cfg(test)]
-const CHUNK_SIZE: usize = 16;
+pub(crate) const CHUNK_SIZE: usize = 16;
+
+/// The line ending style of a document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    /// `\n`
+    Lf,
+    /// `\r\n`
+    CrLf,
+    /// Both styles are in use. Text written to such a document uses `Lf`.
+    Mixed,
+}
+
+impl LineEnding {
+    /// The line ending to use for new lines.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Lf | LineEnding::Mixed => "\n",
+        }
+    }
+
+    /// Returns `text` with all of its line endings converted to this style.
+    pub fn normalize(self, text: &str) -> String {
+        let lf = text.replace("\r\n", "\n");
+        match self {
+            LineEnding::CrLf => lf.replace('\n', "\r\n"),
+            LineEnding::Lf | LineEnding::Mixed => lf,
+        }
+    }
+}
 
 /// A simple cache, holding a single contiguous chunk of the document.
 #[derive(Debug, Clone, Default)]
@@ -49,6 +83,9 @@ pub struct ChunkCache {
     pub buf_size: usize,
     pub num_lines: usize,
     pub rev: u64,
+    /// The most bytes of text the chunk may hold, or `None` if it is
+    /// unbounded. See `set_max_bytes`.
+    pub max_bytes: Option<usize>,
 }
 
 impl ChunkCache {
@@ -60,7 +97,7 @@ impl ChunkCache {
     /// Returns an error if `line_num` is greater than the total number of lines
     /// in the document, or if there is a problem communicating with `source`.
     pub fn get_line<DS>(&mut self, source: &DS, line_num: usize) -> Result<&str, Error>
-        where DS: DataSource
+        where DS: DataSource + ?Sized
     {
         if line_num > self.num_lines { return Err(Error::BadRequest) }
 
@@ -78,9 +115,9 @@ impl ChunkCache {
 
         // Now we make sure we also contain the end of the line, fetching more
         // of the document as necessary.
-        loop {
+        let end_off = loop {
             if let Some(end_off) = self.cached_offset_of_line(line_num + 1) {
-                return Ok(&self.contents[start_off..end_off - self.offset])
+                break end_off - self.offset
             }
             // if we have a chunk and we're fetching more, discard unnecessary
             // portion of our chunk.
@@ -93,7 +130,46 @@ impl ChunkCache {
             let resp = source.get_data(chunk_end, TextUnit::Utf8,
                                        CHUNK_SIZE, self.rev)?;
             self.append_chunk(resp);
+        };
+        let evicted = self.evict(start_off, end_off);
+        Ok(&self.contents[start_off - evicted..end_off - evicted])
+    }
+
+    /// Returns the text in the byte range `start..end`, fetching data from
+    /// `source` if it is not already in the chunk. Fetched data replaces
+    /// the current chunk.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::OutOfRange` if `end` is past the end of the document,
+    /// an error if `start..end` is not a valid range of character boundaries,
+    /// or if there is a problem communicating with `source`.
+    pub fn get_region<DS>(&mut self, source: &DS, start: usize, end: usize)
+        -> Result<&str, Error>
+        where DS: DataSource + ?Sized
+    {
+        if end > self.buf_size {
+            return Err(Error::OutOfRange { end, buf_size: self.buf_size })
+        }
+        if start > end { return Err(Error::BadRequest) }
+        if start == end { return Ok("") }
+
+        if start < self.offset || start > self.offset + self.contents.len() {
+            let resp = source.get_data(start, TextUnit::Utf8, CHUNK_SIZE, self.rev)?;
+            self.reset_chunk(resp);
         }
+
+        while self.offset + self.contents.len() < end {
+            let chunk_end = self.offset + self.contents.len();
+            let resp = source.get_data(chunk_end, TextUnit::Utf8,
+                                       CHUNK_SIZE, self.rev)?;
+            if resp.chunk.is_empty() { return Err(Error::WrongReturnType) }
+            self.append_chunk(resp);
+        }
+
+        self.evict(start - self.offset, end - self.offset);
+        self.contents.get(start - self.offset..end - self.offset)
+            .ok_or(Error::BadRequest)
     }
 
     /// Returns the offset of the line at `line_num`, zero-indexed, fetching
@@ -104,7 +180,7 @@ impl ChunkCache {
     /// Returns an error if `line_num` is greater than the total number of lines
     /// in the document, or if there is a problem communicating with `source`.
     pub fn offset_of_line<DS>(&mut self, source: &DS, line_num: usize) -> Result<usize, Error>
-        where DS: DataSource
+        where DS: DataSource + ?Sized
     {
         if line_num > self.num_lines { return Err(Error::BadRequest) }
         match self.cached_offset_of_line(line_num) {
@@ -117,6 +193,31 @@ impl ChunkCache {
         }
     }
 
+    /// Returns the (zero-based) line containing `offset`, fetching data
+    /// from `source` if needed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `offset` is past the end of the document, or if
+    /// there is a problem communicating with `source`.
+    pub fn line_of_offset<DS>(&mut self, source: &DS, offset: usize) -> Result<usize, Error>
+        where DS: DataSource + ?Sized
+    {
+        if offset > self.buf_size { return Err(Error::BadRequest) }
+        if self.contents.is_empty()
+            || offset < self.offset
+            || offset > self.offset + self.contents.len() {
+                let resp = source.get_data(offset, TextUnit::Utf8, CHUNK_SIZE, self.rev)?;
+                self.reset_chunk(resp);
+        }
+        let rel_offset = offset - self.offset;
+        let rel_line_num = match self.line_offsets.binary_search(&rel_offset) {
+            Ok(ix) => ix + 1,
+            Err(ix) => ix,
+        };
+        Ok(self.first_line + rel_line_num)
+    }
+
     /// Returns the offset of the provided `line_num` if it can be determined
     /// without fetching data.
     fn cached_offset_of_line(&self, line_num: usize) -> Option<usize> {
@@ -171,6 +272,112 @@ impl ChunkCache {
         self.first_line_offset = new_line_off;
     }
 
+    /// Replaces the chunk with `text`, which must be the whole document.
+    pub fn set_contents(&mut self, text: String) {
+        self.contents = text;
+        self.offset = 0;
+        self.first_line = 0;
+        self.first_line_offset = 0;
+        self.recalculate_line_offsets();
+        self.evict(0, 0);
+    }
+
+    /// Sets the most bytes of text the chunk may hold, evicting text if it
+    /// already holds more. `None` leaves the chunk unbounded.
+    ///
+    /// When the chunk grows past this size, the text fetched longest ago,
+    /// at the start of the chunk, is evicted first, then text at its end.
+    /// Text which was just requested is always kept, so a single line or
+    /// region larger than the budget can still be read. The document's size
+    /// and line count are unaffected, and evicted text is fetched again if
+    /// it is needed.
+    pub fn set_max_bytes(&mut self, max_bytes: Option<usize>) {
+        self.max_bytes = max_bytes;
+        self.evict(0, 0);
+    }
+
+    /// If the chunk is over budget, evicts text outside of `keep_start..keep_end`,
+    /// which is indexed relative to `self.contents`. Returns the number of
+    /// bytes evicted from the start of the chunk.
+    fn evict(&mut self, keep_start: usize, keep_end: usize) -> usize {
+        let max_bytes = match self.max_bytes {
+            Some(max_bytes) if self.contents.len() > max_bytes => max_bytes,
+            _ => return 0,
+        };
+        let mut front = (self.contents.len() - max_bytes).min(keep_start);
+        while !self.contents.is_char_boundary(front) {
+            front -= 1;
+        }
+        if front > 0 {
+            self.clear_up_to(front);
+        }
+        let mut end = max_bytes.max(keep_end - front);
+        if end < self.contents.len() {
+            while !self.contents.is_char_boundary(end) {
+                end += 1;
+            }
+            self.contents.truncate(end);
+            self.line_offsets.retain(|&line_start| line_start <= end);
+        }
+        front
+    }
+
+    /// Checks the cache against `source`, returning `false` if the size or
+    /// line count of the document, or the text in the chunk, differ from
+    /// those in `source` at the cached revision.
+    pub fn verify<DS>(&self, source: &DS) -> Result<bool, Error>
+        where DS: DataSource + ?Sized
+    {
+        if !verify_size(source, self.buf_size, self.num_lines, self.rev)? {
+            return Ok(false)
+        }
+
+        let mut checked = 0;
+        while checked < self.contents.len() {
+            let resp = source.get_data(self.offset + checked, TextUnit::Utf8,
+                                       self.contents.len() - checked, self.rev)?;
+            if checked == 0 && (resp.first_line, resp.first_line_offset)
+                != (self.first_line, self.first_line_offset) {
+                return Ok(false)
+            }
+            let expected = &self.contents.as_bytes()[checked..];
+            let len = resp.chunk.len().min(expected.len());
+            if len == 0 || resp.chunk.as_bytes()[..len] != expected[..len] {
+                return Ok(false)
+            }
+            checked += len;
+        }
+        Ok(true)
+    }
+
+    /// Returns `true` if the chunk holds the whole document, so that no
+    /// data needs to be fetched.
+    pub fn is_fully_cached(&self) -> bool {
+        self.offset == 0 && self.contents.len() == self.buf_size
+    }
+
+    /// Infers the document's line ending style from the cached chunk, or
+    /// returns `None` if the chunk contains no line breaks.
+    pub fn line_ending(&self) -> Option<LineEnding> {
+        let (mut lf, mut crlf) = (false, false);
+        for &line_start in &self.line_offsets {
+            let newline = line_start - 1;
+            if newline > 0 && self.contents.as_bytes()[newline - 1] == b'\r' {
+                crlf = true;
+            // a break at the start of the chunk may be preceded by a '\r'
+            // outside of it
+            } else if newline > 0 || self.offset == 0 {
+                lf = true;
+            }
+        }
+        match (lf, crlf) {
+            (true, true) => Some(LineEnding::Mixed),
+            (false, true) => Some(LineEnding::CrLf),
+            (true, false) => Some(LineEnding::Lf),
+            (false, false) => None,
+        }
+    }
+
     /// Discard any existing cache, starting again with the new data.
     fn reset_chunk(&mut self, data: GetDataResponse) {
         self.contents = data.chunk;
@@ -210,6 +417,7 @@ impl ChunkCache {
         } else {
             // only reached if delta exists
             self.update_chunk(delta.unwrap());
+            self.evict(0, 0);
         }
         self.buf_size = new_len;
         self.num_lines =  num_lines;
@@ -349,6 +557,325 @@ impl ChunkCache {
     }
 }
 
+/// A cache of the remote document's text, which the plugin library reads
+/// through, and keeps in step with the updates core sends.
+///
+/// Implementations must uphold these invariants:
+///
+/// - `buf_size`, `num_lines` and `rev` are those passed to the last call to
+///   `update`, whatever text is or isn't held.
+/// - Text returned by `get_line` and `get_region`, or by `cached_region`,
+///   is that of the document at `rev`. Text held across an `update` must be
+///   patched with the update's delta, or discarded; when there is no delta,
+///   it must be discarded.
+/// - Offsets are in bytes, and the ranges returned are on character
+///   boundaries. Requests past the end of the document are errors, as
+///   documented on each method, rather than being clipped.
+/// - `clear` discards any text, but leaves the metadata alone.
+pub trait Cache {
+    /// The size of the document, in bytes.
+    fn buf_size(&self) -> usize;
+
+    /// The number of lines in the document.
+    fn num_lines(&self) -> usize;
+
+    /// The revision of the document the cache reflects.
+    fn rev(&self) -> u64;
+
+    /// Brings the cache up to date with an update to revision `rev`, which
+    /// leaves the document `new_len` bytes long, with `num_lines` lines.
+    /// `delta` is `None` if core didn't send one.
+    fn update(&mut self, new_len: usize, num_lines: usize, rev: u64, delta: Option<&RopeDelta>);
+
+    /// Discards any cached text.
+    fn clear(&mut self);
+
+    /// Offers the cache `text`, the whole document at the current revision,
+    /// as sent by core on init. Caches which don't hold text ignore it.
+    #[allow(unused_variables)]
+    fn set_contents(&mut self, text: String) {}
+
+    /// Returns the text of the line at `line_num`, zero-indexed, including
+    /// its line ending, fetching data from `source` if needed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `line_num` is greater than the total number of lines
+    /// in the document, or if there is a problem communicating with `source`.
+    fn get_line(&mut self, source: &dyn DataSource, line_num: usize) -> Result<&str, Error>;
+
+    /// Returns the text in the byte range `start..end`, fetching data from
+    /// `source` if needed.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::OutOfRange` if `end` is past the end of the document,
+    /// an error if `start..end` is not a valid range of character boundaries,
+    /// or if there is a problem communicating with `source`.
+    fn get_region(&mut self, source: &dyn DataSource, start: usize, end: usize)
+        -> Result<&str, Error>;
+
+    /// Returns the offset of the line at `line_num`, zero-indexed, fetching
+    /// data from `source` if needed.
+    fn offset_of_line(&mut self, source: &dyn DataSource, line_num: usize) -> Result<usize, Error>;
+
+    /// Returns the (zero-based) line containing `offset`, fetching data
+    /// from `source` if needed.
+    fn line_of_offset(&mut self, source: &dyn DataSource, offset: usize) -> Result<usize, Error>;
+
+    /// Returns the text in `start..end` if it is held, without fetching it.
+    #[allow(unused_variables)]
+    fn cached_region(&self, start: usize, end: usize) -> Option<&str> { None }
+
+    /// The number of bytes of document text held.
+    fn cached_bytes(&self) -> usize { 0 }
+
+    /// Returns `true` if the whole document is held, so that no data needs
+    /// to be fetched.
+    fn is_fully_cached(&self) -> bool { false }
+
+    /// Infers the document's line ending style from the text held, if any.
+    fn line_ending(&self) -> Option<LineEnding> { None }
+
+    /// Sets the most bytes of text the cache should hold, or `None` if it
+    /// is unbounded. Caches which hold no more than the text just read
+    /// ignore it.
+    #[allow(unused_variables)]
+    fn set_max_bytes(&mut self, max_bytes: Option<usize>) {}
+
+    /// Checks the cache against `source`, returning `false` if the size or
+    /// line count of the document, or any text held, differ from those in
+    /// `source` at the cached revision.
+    fn verify(&self, source: &dyn DataSource) -> Result<bool, Error> {
+        verify_size(source, self.buf_size(), self.num_lines(), self.rev())
+    }
+
+    /// Converts a byte offset into a line number and a column, measured in
+    /// UTF-16 code units, as used by the language server protocol.
+    ///
+    /// Offsets past the end of the document are treated as the end of the
+    /// document, and offsets inside a character are rounded down to the
+    /// start of that character.
+    fn offset_to_line_col(&mut self, source: &dyn DataSource, offset: usize)
+        -> Result<(usize, usize), Error>
+    {
+        let offset = offset.min(self.buf_size());
+        let line_num = self.line_of_offset(source, offset)?;
+        let line_start = self.offset_of_line(source, line_num)?;
+        let line = self.get_line(source, line_num)?;
+        let mut rel_offset = (offset - line_start).min(line.len());
+        while !line.is_char_boundary(rel_offset) {
+            rel_offset -= 1;
+        }
+        Ok((line_num, line[..rel_offset].encode_utf16().count()))
+    }
+
+    /// Converts a line number and a column, measured in UTF-16 code units,
+    /// into a byte offset. This is the inverse of `offset_to_line_col`.
+    ///
+    /// Columns past the end of the line are treated as the end of the line
+    /// (before any line ending) and lines past the end of the document as the
+    /// end of the document. A column inside a surrogate pair is rounded down.
+    fn line_col_to_offset(&mut self, source: &dyn DataSource, line_num: usize, col: usize)
+        -> Result<usize, Error>
+    {
+        if line_num >= self.num_lines() { return Ok(self.buf_size()) }
+        let line_start = self.offset_of_line(source, line_num)?;
+        let line = strip_line_ending(self.get_line(source, line_num)?);
+        let mut utf16_col = 0;
+        for (ix, c) in line.char_indices() {
+            utf16_col += c.len_utf16();
+            if utf16_col > col {
+                return Ok(line_start + ix)
+            }
+        }
+        Ok(line_start + line.len())
+    }
+}
+
+impl Default for Box<dyn Cache> {
+    fn default() -> Self {
+        Box::new(ChunkCache::default())
+    }
+}
+
+impl Cache for ChunkCache {
+    fn buf_size(&self) -> usize { self.buf_size }
+
+    fn num_lines(&self) -> usize { self.num_lines }
+
+    fn rev(&self) -> u64 { self.rev }
+
+    fn update(&mut self, new_len: usize, num_lines: usize, rev: u64, delta: Option<&RopeDelta>) {
+        self.apply_update(new_len, num_lines, rev, delta);
+    }
+
+    fn clear(&mut self) {
+        ChunkCache::clear(self);
+    }
+
+    fn set_contents(&mut self, text: String) {
+        ChunkCache::set_contents(self, text);
+    }
+
+    fn get_line(&mut self, source: &dyn DataSource, line_num: usize) -> Result<&str, Error> {
+        ChunkCache::get_line(self, source, line_num)
+    }
+
+    fn get_region(&mut self, source: &dyn DataSource, start: usize, end: usize)
+        -> Result<&str, Error>
+    {
+        ChunkCache::get_region(self, source, start, end)
+    }
+
+    fn offset_of_line(&mut self, source: &dyn DataSource, line_num: usize) -> Result<usize, Error> {
+        ChunkCache::offset_of_line(self, source, line_num)
+    }
+
+    fn line_of_offset(&mut self, source: &dyn DataSource, offset: usize) -> Result<usize, Error> {
+        ChunkCache::line_of_offset(self, source, offset)
+    }
+
+    fn cached_region(&self, start: usize, end: usize) -> Option<&str> {
+        if start < self.offset || start > end {
+            return None
+        }
+        self.contents.get(start - self.offset..end - self.offset)
+    }
+
+    fn cached_bytes(&self) -> usize {
+        self.contents.len()
+    }
+
+    fn is_fully_cached(&self) -> bool {
+        ChunkCache::is_fully_cached(self)
+    }
+
+    fn line_ending(&self) -> Option<LineEnding> {
+        ChunkCache::line_ending(self)
+    }
+
+    fn set_max_bytes(&mut self, max_bytes: Option<usize>) {
+        ChunkCache::set_max_bytes(self, max_bytes);
+    }
+
+    fn verify(&self, source: &dyn DataSource) -> Result<bool, Error> {
+        ChunkCache::verify(self, source)
+    }
+}
+
+/// A cache which holds only the document's size, line count and revision.
+/// Text is fetched from core each time it is read, and kept only until the
+/// next read; this suits plugins which rarely read the document, and would
+/// rather not hold a copy of it.
+#[derive(Debug, Clone, Default)]
+pub struct MetadataCache {
+    buf_size: usize,
+    num_lines: usize,
+    rev: u64,
+    /// The text last read.
+    scratch: String,
+}
+
+impl MetadataCache {
+    /// Fetches text from `start` into the scratch buffer, until it holds at
+    /// least `len` bytes, or `stop` returns `true`.
+    fn fetch<F>(&mut self, source: &dyn DataSource, start: usize, unit: TextUnit, len: usize,
+                stop: F) -> Result<usize, Error>
+        where F: Fn(&str) -> bool
+    {
+        self.scratch.clear();
+        let resp = source.get_data(start, unit, CHUNK_SIZE, self.rev)?;
+        let offset = resp.offset;
+        self.scratch.push_str(&resp.chunk);
+        while self.scratch.len() < len && !stop(&self.scratch)
+            && offset + self.scratch.len() < self.buf_size {
+            let resp = source.get_data(offset + self.scratch.len(), TextUnit::Utf8,
+                                       CHUNK_SIZE, self.rev)?;
+            if resp.chunk.is_empty() { return Err(Error::WrongReturnType) }
+            self.scratch.push_str(&resp.chunk);
+        }
+        Ok(offset)
+    }
+}
+
+impl Cache for MetadataCache {
+    fn buf_size(&self) -> usize { self.buf_size }
+
+    fn num_lines(&self) -> usize { self.num_lines }
+
+    fn rev(&self) -> u64 { self.rev }
+
+    fn update(&mut self, new_len: usize, num_lines: usize, rev: u64, _delta: Option<&RopeDelta>) {
+        self.clear();
+        self.buf_size = new_len;
+        self.num_lines = num_lines;
+        self.rev = rev;
+    }
+
+    fn clear(&mut self) {
+        self.scratch = String::new();
+    }
+
+    fn cached_bytes(&self) -> usize {
+        self.scratch.len()
+    }
+
+    fn get_line(&mut self, source: &dyn DataSource, line_num: usize) -> Result<&str, Error> {
+        if line_num > self.num_lines { return Err(Error::BadRequest) }
+        self.fetch(source, line_num, TextUnit::Line, usize::MAX, |text| text.contains('\n'))?;
+        let end = self.scratch.find('\n').map(|ix| ix + 1).unwrap_or(self.scratch.len());
+        self.scratch.truncate(end);
+        Ok(&self.scratch)
+    }
+
+    fn get_region(&mut self, source: &dyn DataSource, start: usize, end: usize)
+        -> Result<&str, Error>
+    {
+        if end > self.buf_size {
+            return Err(Error::OutOfRange { end, buf_size: self.buf_size })
+        }
+        if start > end { return Err(Error::BadRequest) }
+        if start == end { return Ok("") }
+        self.fetch(source, start, TextUnit::Utf8, end - start, |_| false)?;
+        if !self.scratch.is_char_boundary(end - start) { return Err(Error::BadRequest) }
+        self.scratch.truncate(end - start);
+        Ok(&self.scratch)
+    }
+
+    fn offset_of_line(&mut self, source: &dyn DataSource, line_num: usize) -> Result<usize, Error> {
+        if line_num > self.num_lines { return Err(Error::BadRequest) }
+        Ok(source.get_data(line_num, TextUnit::Line, 1, self.rev)?.offset)
+    }
+
+    fn line_of_offset(&mut self, source: &dyn DataSource, offset: usize) -> Result<usize, Error> {
+        if offset > self.buf_size { return Err(Error::BadRequest) }
+        Ok(source.get_data(offset, TextUnit::Utf8, 1, self.rev)?.first_line)
+    }
+}
+
+/// Checks the size and line count of the document against `source`, at
+/// revision `rev`.
+fn verify_size<DS>(source: &DS, buf_size: usize, num_lines: usize, rev: u64)
+    -> Result<bool, Error>
+    where DS: DataSource + ?Sized
+{
+    // the document's last character ends it, on the last line
+    let resp = match buf_size {
+        0 => source.get_data(0, TextUnit::Line, 1, rev)?,
+        _ => source.get_data(buf_size - 1, TextUnit::Utf8, 1, rev)?,
+    };
+    let last_line = resp.first_line + resp.chunk.matches('\n').count();
+    Ok(resp.offset + resp.chunk.len() == buf_size && last_line + 1 == num_lines)
+}
+
+/// Returns `line` without its trailing line ending, if it has one.
+fn strip_line_ending(line: &str) -> &str {
+    line.strip_suffix("\r\n")
+        .or_else(|| line.strip_suffix('\n'))
+        .unwrap_or(line)
+}
+
 /// Calculates the offsets of newlines in `text`,
 /// inserting the results into `storage`. The offsets are the offset
 /// of the start of the line, not the line break character.
@@ -441,6 +968,55 @@ mod tests {
         assert!(c.get_line(&remote_document, 4).is_err());
     }
 
+    #[test]
+    fn get_region() {
+        let remote_document = MockDataSource("this\nhas\nfour\nlines!".into());
+        let mut c = ChunkCache {
+            buf_size: remote_document.0.len(),
+            num_lines: remote_document.0.measure::<LinesMetric>() + 1,
+            ..Default::default()
+        };
+        assert_eq!(c.get_region(&remote_document, 5, 8).ok(), Some("has"));
+        assert_eq!(c.offset, 5);
+        // spans the end of the fetched chunk
+        assert_eq!(c.get_region(&remote_document, 9, 20).ok(), Some("four\nlines!"));
+        // before the current chunk
+        assert_eq!(c.get_region(&remote_document, 0, 4).ok(), Some("this"));
+        assert_eq!(c.offset, 0);
+        assert_eq!(c.get_region(&remote_document, 20, 20).ok(), Some(""));
+        match c.get_region(&remote_document, 10, 21) {
+            Err(Error::OutOfRange { end: 21, buf_size: 20 }) => (),
+            other => panic!("expected out of range error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn utf16_line_col() {
+        // 'a', a surrogate pair, 'b', CRLF, then 'c' with a combining accent
+        let source = MockDataSource("a\u{1F600}b\r\nc\u{301}d\nlast".into());
+        let mut c = ChunkCache {
+            buf_size: source.0.len(),
+            num_lines: source.0.measure::<LinesMetric>() + 1,
+            ..Default::default()
+        };
+        assert_eq!(c.buf_size, 17);
+
+        assert_eq!(c.offset_to_line_col(&source, 5).ok(), Some((0, 3)));
+        assert_eq!(c.offset_to_line_col(&source, 3).ok(), Some((0, 1)));
+        assert_eq!(c.offset_to_line_col(&source, 7).ok(), Some((0, 5)));
+        assert_eq!(c.offset_to_line_col(&source, 11).ok(), Some((1, 2)));
+        assert_eq!(c.offset_to_line_col(&source, 13).ok(), Some((2, 0)));
+        assert_eq!(c.offset_to_line_col(&source, 100).ok(), Some((2, 4)));
+
+        assert_eq!(c.line_col_to_offset(&source, 0, 3).ok(), Some(5));
+        assert_eq!(c.line_col_to_offset(&source, 0, 2).ok(), Some(1));
+        assert_eq!(c.line_col_to_offset(&source, 0, 99).ok(), Some(6));
+        assert_eq!(c.line_col_to_offset(&source, 1, 2).ok(), Some(11));
+        assert_eq!(c.line_col_to_offset(&source, 1, 99).ok(), Some(12));
+        assert_eq!(c.line_col_to_offset(&source, 2, 4).ok(), Some(17));
+        assert_eq!(c.line_col_to_offset(&source, 5, 0).ok(), Some(17));
+    }
+
     #[test]
     fn reset_chunk() {
         let data = GetDataResponse {
@@ -484,6 +1060,100 @@ mod tests {
         assert_eq!(c.line_offsets, vec![14]);
     }
 
+    #[test]
+    fn evict_over_budget() {
+        let text: String = (0..20).map(|i| format!("line {}\n", i)).collect();
+        let source = MockDataSource(text.as_str().into());
+        let mut c = ChunkCache {
+            buf_size: text.len(),
+            num_lines: 21,
+            max_bytes: Some(24),
+            ..Default::default()
+        };
+        // reading forward keeps the chunk within budget
+        for i in 0..20 {
+            let expected = format!("line {}\n", i);
+            assert_eq!(c.get_line(&source, i).ok(), Some(expected.as_str()));
+            assert!(c.contents.len() <= 24, "{} bytes cached", c.contents.len());
+        }
+        // a region larger than the budget is still read whole
+        assert_eq!(c.get_region(&source, 7, 60).ok(), Some(&text[7..60]));
+        assert_eq!(c.contents.len(), 53);
+        // evicted text is fetched again
+        assert_eq!(c.get_line(&source, 0).ok(), Some("line 0\n"));
+        assert_eq!(c.offset_of_line(&source, 15).ok(), Some(text.find("line 15").unwrap()));
+        assert_eq!(c.line_of_offset(&source, 100).ok(), Some(text[..100].matches('\n').count()));
+        assert_eq!((c.buf_size, c.num_lines), (text.len(), 21));
+
+        c.set_contents(text.clone());
+        assert_eq!(c.contents, &text[..24]);
+        assert!(!c.is_fully_cached());
+        assert_eq!(c.get_line(&source, 3).ok(), Some("line 3\n"));
+        assert_eq!(c.get_line(&source, 4).ok(), Some("line 4\n"));
+        c.set_max_bytes(None);
+        assert_eq!(c.get_region(&source, 0, text.len()).ok(), Some(text.as_str()));
+        assert!(c.is_fully_cached());
+    }
+
+    #[test]
+    fn verify() {
+        let text = "this\nhas\nfour\nlines, and a long last line";
+        let source = MockDataSource(text.into());
+        let mut c = ChunkCache {
+            buf_size: text.len(),
+            num_lines: 4,
+            ..Default::default()
+        };
+        assert_eq!(c.verify(&source).ok(), Some(true));
+        assert_eq!(c.get_region(&source, 7, text.len()).ok(), Some(&text[7..]));
+        assert_eq!(c.verify(&source).ok(), Some(true));
+
+        let mut wrong_text = c.clone();
+        wrong_text.contents.replace_range(20..21, "!");
+        assert_eq!(wrong_text.verify(&source).ok(), Some(false));
+        let mut wrong_offset = c.clone();
+        wrong_offset.first_line_offset += 1;
+        assert_eq!(wrong_offset.verify(&source).ok(), Some(false));
+        let mut wrong_lines = c.clone();
+        wrong_lines.num_lines = 5;
+        assert_eq!(wrong_lines.verify(&source).ok(), Some(false));
+        let mut too_short = c.clone();
+        too_short.buf_size -= 1;
+        assert_eq!(too_short.verify(&source).ok(), Some(false));
+        // the document in core is shorter than the cache thinks
+        c.buf_size += 1;
+        assert!(c.verify(&source).is_err());
+    }
+
+    #[test]
+    fn metadata_cache() {
+        let text = "this\nhas\nfour\nlines, and a last line longer than a chunk";
+        let source = MockDataSource(text.into());
+        let mut c = MetadataCache::default();
+        c.update(text.len(), 4, 0, None);
+        assert_eq!(c.get_line(&source, 1).ok(), Some("has\n"));
+        assert_eq!(c.get_line(&source, 3).ok(), Some(&text[14..]));
+        assert_eq!(c.get_line(&source, 4).ok(), Some(""));
+        assert!(c.get_line(&source, 5).is_err());
+        assert_eq!(c.get_region(&source, 2, 40).ok(), Some(&text[2..40]));
+        assert!(c.get_region(&source, 2, text.len() + 1).is_err());
+        assert_eq!(c.offset_of_line(&source, 2).ok(), Some(9));
+        assert_eq!(c.line_of_offset(&source, 9).ok(), Some(2));
+        assert_eq!(c.line_of_offset(&source, 8).ok(), Some(1));
+        assert_eq!(c.offset_to_line_col(&source, 11).ok(), Some((2, 2)));
+        assert_eq!(c.line_col_to_offset(&source, 1, 99).ok(), Some(8));
+        assert_eq!(c.verify(&source).ok(), Some(true));
+        assert_eq!(c.cached_region(0, 4), None);
+
+        // no text is kept across updates, so there's nothing to patch
+        let d = Delta::simple_edit(Interval::new_closed_open(0, 0), "so ".into(), text.len());
+        c.update(d.new_document_len(), 4, 1, Some(&d));
+        assert_eq!((c.buf_size(), c.num_lines(), c.rev()), (text.len() + 3, 4, 1));
+        assert_eq!(c.cached_bytes(), 0);
+        assert_eq!(c.get_line(&source, 1).ok(), Some("has\n"));
+        assert_eq!(c.cached_bytes(), 4);
+    }
+
     #[test]
     fn simple_insert() {
         let mut c = ChunkCache::default();
@@ -601,4 +1271,31 @@ mod tests {
         assert_eq!(c.get_line(&source, 3).unwrap(), "yay!");
         assert_eq!(c.first_line, 3);
     }
+
+    #[test]
+    fn line_endings() {
+        let mut c = ChunkCache::default();
+        c.set_contents("one\r\ntwo\r\nthree".into());
+        assert_eq!(c.line_ending(), Some(LineEnding::CrLf));
+        c.set_contents("one\ntwo\nthree\n".into());
+        assert_eq!(c.line_ending(), Some(LineEnding::Lf));
+        c.set_contents("one\r\ntwo\nthree".into());
+        assert_eq!(c.line_ending(), Some(LineEnding::Mixed));
+        assert_eq!(LineEnding::Mixed.as_str(), "\n");
+        c.set_contents("one".into());
+        assert_eq!(c.line_ending(), None);
+
+        // the '\r' of a break at the start of a chunk is unknown
+        c.set_contents("\ntwo\r\n".into());
+        c.offset = 4;
+        assert_eq!(c.line_ending(), Some(LineEnding::CrLf));
+    }
+
+    #[test]
+    fn normalize_line_endings() {
+        let text = "a\nb\r\nc";
+        assert_eq!(LineEnding::Lf.normalize(text), "a\nb\nc");
+        assert_eq!(LineEnding::CrLf.normalize(text), "a\r\nb\r\nc");
+        assert_eq!(LineEnding::Mixed.normalize(text), "a\nb\nc");
+    }
 }
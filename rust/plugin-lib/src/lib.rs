@@ -25,7 +25,14 @@ extern crate serde;
 extern crate bytecount;
 extern crate rand;
 extern crate memchr;
+extern crate libc;
+extern crate fnv;
 
 pub mod plugin_base;
 pub mod state_cache;
 pub mod base_cache;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod threaded;
+pub mod multi;
+pub mod store;
@@ -0,0 +1,387 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Several plugins in one executable.
+//!
+//! Core starts a plugin process for each buffer a plugin is active in, and
+//! assigns it a `PluginPid` when it does so; a process therefore serves one
+//! plugin, for one buffer, and the pid says nothing about which plugin that
+//! is. A `MultiPlugin` lets one executable, built and installed once, bundle
+//! several plugins, and decide which of them a process runs based on the
+//! view it is started for.
+//!
+//! When the process starts, each plugin's `new_view` is called in the order
+//! the plugins were added, and the first plugin to accept the view claims
+//! it. Every later RPC, including updates, is routed to that plugin alone;
+//! plugins added after it are never asked, even if they would also have
+//! claimed the view. If no plugin claims the view, it is rejected.
+
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use serde_json::Value;
+
+use xi_core::{ConfigTable, SyntaxDefinition};
+use xi_core::plugin_rpc::PluginBufferInfo;
+use xi_rope::rope::RopeDelta;
+use xi_rpc::RemoteError;
+
+use base_cache::{Cache, ChunkCache};
+use state_cache::{CompletionItem, Error, Hover, Idle, Plugin, PluginCtx, PluginError,
+                  PluginInfo, UpdateContext, ViewState, WorkKind};
+
+/// A plugin which runs whichever of its plugins claims the view.
+pub struct MultiPlugin<S> {
+    plugins: Vec<Box<dyn Plugin<State = S>>>,
+    active: Option<usize>,
+}
+
+impl<S: Default + Clone> MultiPlugin<S> {
+    pub fn new() -> Self {
+        MultiPlugin { plugins: Vec::new(), active: None }
+    }
+
+    /// Adds `plugin`, which is offered the view after those already added.
+    pub fn add<P: Plugin<State = S> + 'static>(&mut self, plugin: P) {
+        self.plugins.push(Box::new(plugin));
+    }
+
+    /// Returns the index, in the order they were added, of the plugin which
+    /// claimed the view, if any.
+    pub fn active_index(&self) -> Option<usize> {
+        self.active
+    }
+
+    fn active(&self) -> Option<&dyn Plugin<State = S>> {
+        self.active.map(|ix| &*self.plugins[ix])
+    }
+
+    fn active_mut(&mut self) -> Option<&mut (dyn Plugin<State = S> + 'static)> {
+        match self.active {
+            Some(ix) => Some(&mut *self.plugins[ix]),
+            None => None,
+        }
+    }
+}
+
+impl<S: Default + Clone> Default for MultiPlugin<S> {
+    fn default() -> Self {
+        MultiPlugin::new()
+    }
+}
+
+impl<S: Default + Clone> Plugin for MultiPlugin<S> {
+    type State = S;
+
+    fn new_view(&mut self, view: &ViewState) -> bool {
+        self.active = self.plugins.iter_mut().position(|plugin| plugin.new_view(view));
+        self.active.is_some()
+    }
+
+    fn initialize(&mut self, ctx: PluginCtx<S>, buf_size: usize) {
+        if let Some(plugin) = self.active_mut() {
+            plugin.initialize(ctx, buf_size);
+        }
+    }
+
+    fn should_defer_init(&self, info: &PluginBufferInfo) -> bool {
+        self.active().is_some_and(|plugin| plugin.should_defer_init(info))
+    }
+
+    fn update(&mut self, ctx: PluginCtx<S>, info: UpdateContext, delta: Option<RopeDelta>)
+              -> Result<Option<Value>, RemoteError> {
+        match self.active_mut() {
+            Some(plugin) => plugin.update(ctx, info, delta),
+            None => Ok(None),
+        }
+    }
+
+    fn will_save(&mut self, ctx: PluginCtx<S>, path: &Path) -> Result<(), RemoteError> {
+        match self.active_mut() {
+            Some(plugin) => plugin.will_save(ctx, path),
+            None => Ok(()),
+        }
+    }
+
+    fn did_save(&mut self, ctx: PluginCtx<S>) -> Result<(), String> {
+        match self.active_mut() {
+            Some(plugin) => plugin.did_save(ctx),
+            None => Ok(()),
+        }
+    }
+
+    fn idle(&mut self, ctx: PluginCtx<S>, token: usize) -> Idle {
+        match self.active_mut() {
+            Some(plugin) => plugin.idle(ctx, token),
+            None => Idle::Done,
+        }
+    }
+
+    fn idle_with_deadline(&mut self, ctx: PluginCtx<S>, token: usize, deadline: Instant) -> Idle {
+        match self.active_mut() {
+            Some(plugin) => plugin.idle_with_deadline(ctx, token, deadline),
+            None => Idle::Done,
+        }
+    }
+
+    fn do_work(&mut self, ctx: PluginCtx<S>, kind: WorkKind) -> Idle {
+        match self.active_mut() {
+            Some(plugin) => plugin.do_work(ctx, kind),
+            None => Idle::Done,
+        }
+    }
+
+    fn work_priority(&self, kind: WorkKind) -> i32 {
+        self.active().map_or(0, |plugin| plugin.work_priority(kind))
+    }
+
+    fn validate_config(&self, changes: &ConfigTable) -> Result<(), String> {
+        match self.active() {
+            Some(plugin) => plugin.validate_config(changes),
+            None => Ok(()),
+        }
+    }
+
+    fn config_changed(&mut self, ctx: PluginCtx<S>, changes: &ConfigTable) {
+        if let Some(plugin) = self.active_mut() {
+            plugin.config_changed(ctx, changes);
+        }
+    }
+
+    fn config_is_immediate(&self, key: &str) -> bool {
+        self.active().is_some_and(|plugin| plugin.config_is_immediate(key))
+    }
+
+    fn wants_batched_updates(&self) -> bool {
+        self.active().is_some_and(|plugin| plugin.wants_batched_updates())
+    }
+
+    fn wants_delta(&self) -> bool {
+        self.active().map_or(true, |plugin| plugin.wants_delta())
+    }
+
+    fn wants_own_edits(&self) -> bool {
+        self.active().is_some_and(|plugin| plugin.wants_own_edits())
+    }
+
+    fn wants_progressive_load(&self) -> bool {
+        self.active().is_some_and(|plugin| plugin.wants_progressive_load())
+    }
+
+    fn new_cache(&self) -> Box<dyn Cache> {
+        match self.active() {
+            Some(plugin) => plugin.new_cache(),
+            None => Box::new(ChunkCache::default()),
+        }
+    }
+
+    fn transform_positions(&mut self, delta: &RopeDelta) {
+        if let Some(plugin) = self.active_mut() {
+            plugin.transform_positions(delta);
+        }
+    }
+
+    fn region_changed(&mut self, ctx: PluginCtx<S>, token: usize, range: Range<usize>) {
+        if let Some(plugin) = self.active_mut() {
+            plugin.region_changed(ctx, token, range);
+        }
+    }
+
+    fn did_reset(&mut self, ctx: PluginCtx<S>) {
+        if let Some(plugin) = self.active_mut() {
+            plugin.did_reset(ctx);
+        }
+    }
+
+    fn buffer_chunk_loaded(&mut self, ctx: PluginCtx<S>, range: Range<usize>) {
+        if let Some(plugin) = self.active_mut() {
+            plugin.buffer_chunk_loaded(ctx, range);
+        }
+    }
+
+    fn buffer_loaded(&mut self, ctx: PluginCtx<S>) {
+        if let Some(plugin) = self.active_mut() {
+            plugin.buffer_loaded(ctx);
+        }
+    }
+
+    fn selection_changed(&mut self, ctx: PluginCtx<S>, selections: &[Range<usize>]) {
+        if let Some(plugin) = self.active_mut() {
+            plugin.selection_changed(ctx, selections);
+        }
+    }
+
+    fn focus_changed(&mut self, ctx: PluginCtx<S>, focused: bool) {
+        if let Some(plugin) = self.active_mut() {
+            plugin.focus_changed(ctx, focused);
+        }
+    }
+
+    fn viewport_changed(&mut self, ctx: PluginCtx<S>, first_line: usize, last_line: usize) {
+        if let Some(plugin) = self.active_mut() {
+            plugin.viewport_changed(ctx, first_line, last_line);
+        }
+    }
+
+    fn edit_failed(&mut self, ctx: PluginCtx<S>, delta: RopeDelta, err: Error) {
+        if let Some(plugin) = self.active_mut() {
+            plugin.edit_failed(ctx, delta, err);
+        }
+    }
+
+    fn syntax_changed(&mut self, ctx: PluginCtx<S>, syntax: SyntaxDefinition) {
+        if let Some(plugin) = self.active_mut() {
+            plugin.syntax_changed(ctx, syntax);
+        }
+    }
+
+    fn did_close(&mut self, ctx: PluginCtx<S>) {
+        if let Some(plugin) = self.active_mut() {
+            plugin.did_close(ctx);
+        }
+    }
+
+    fn custom_command(&mut self, ctx: PluginCtx<S>, method: &str, params: Value)
+                      -> Result<Value, RemoteError> {
+        match self.active_mut() {
+            Some(plugin) => plugin.custom_command(ctx, method, params),
+            None => Err(PluginError::MethodNotSupported(format!("unknown command {}", method))
+                .into()),
+        }
+    }
+
+    fn complete(&mut self, ctx: PluginCtx<S>, offset: usize)
+                -> Result<Vec<CompletionItem>, RemoteError> {
+        match self.active_mut() {
+            Some(plugin) => plugin.complete(ctx, offset),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn hover(&mut self, ctx: PluginCtx<S>, offset: usize) -> Result<Option<Hover>, RemoteError> {
+        match self.active_mut() {
+            Some(plugin) => plugin.hover(ctx, offset),
+            None => Ok(None),
+        }
+    }
+
+    fn cancel(&mut self, ctx: PluginCtx<S>, token: usize) {
+        if let Some(plugin) = self.active_mut() {
+            plugin.cancel(ctx, token);
+        }
+    }
+
+    fn shutdown(&mut self, ctx: PluginCtx<S>) {
+        if let Some(plugin) = self.active_mut() {
+            plugin.shutdown(ctx);
+        }
+    }
+
+    fn serialize_state(&self) -> Option<Value> {
+        self.active().and_then(|plugin| plugin.serialize_state())
+    }
+
+    fn restore_state(&mut self, state: Value) {
+        if let Some(plugin) = self.active_mut() {
+            plugin.restore_state(state);
+        }
+    }
+
+    fn state_path(&self, view: &ViewState) -> Option<PathBuf> {
+        self.active().and_then(|plugin| plugin.state_path(view))
+    }
+
+    fn store_dir(&self) -> Option<PathBuf> {
+        self.active().and_then(|plugin| plugin.store_dir())
+    }
+
+    fn info(&self) -> PluginInfo {
+        match self.active().or_else(|| self.plugins.first().map(|plugin| &**plugin)) {
+            Some(plugin) => plugin.info(),
+            None => PluginInfo {
+                name: "multi".to_owned(),
+                version: "unknown".to_owned(),
+                features: Vec::new(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use plugin_base::tests::{init_rpc, update_rpc};
+    use state_cache::tests::run_script;
+
+    /// A plugin which claims views with the given syntax, or every view,
+    /// and records the callbacks it receives.
+    struct NamedPlugin {
+        name: &'static str,
+        syntax: Option<SyntaxDefinition>,
+        calls: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Plugin for NamedPlugin {
+        type State = ();
+
+        fn new_view(&mut self, view: &ViewState) -> bool {
+            self.calls.borrow_mut().push(format!("{} new_view", self.name));
+            self.syntax.map_or(true, |syntax| syntax == view.syntax)
+        }
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {
+            self.calls.borrow_mut().push(format!("{} initialize", self.name));
+        }
+        fn update(&mut self, _ctx: PluginCtx<()>, info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            self.calls.borrow_mut().push(format!("{} update {}", self.name, info.rev));
+            Ok(None)
+        }
+    }
+
+    fn run(syntax: &str) -> (Option<usize>, Vec<String>) {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut plugin = MultiPlugin::new();
+        for &(name, syntax) in &[("rust", Some(SyntaxDefinition::Rust)), ("any", None)] {
+            plugin.add(NamedPlugin { name, syntax, calls: calls.clone() });
+        }
+        let mut init = init_rpc(42);
+        init["params"]["buffer_info"][0]["syntax"] = json!(syntax);
+        let rpcs = [init, update_rpc(0, 2)];
+        run_script(&mut plugin, &rpcs);
+        let calls = calls.borrow().clone();
+        (plugin.active_index(), calls)
+    }
+
+    #[test]
+    fn route_to_first_claiming_plugin() {
+        let (active, calls) = run("rust");
+        assert_eq!(active, Some(0));
+        assert_eq!(calls, vec!["rust new_view", "rust initialize", "rust update 2"]);
+
+        let (active, calls) = run("plaintext");
+        assert_eq!(active, Some(1));
+        assert_eq!(calls, vec!["rust new_view", "any new_view", "any initialize",
+                               "any update 2"]);
+    }
+
+    #[test]
+    fn no_claiming_plugin() {
+        let mut plugin = MultiPlugin::<()>::new();
+        run_script(&mut plugin, &[init_rpc(42)]);
+        assert_eq!(plugin.active_index(), None);
+    }
+}
@@ -14,24 +14,140 @@
 
 //! A base for xi plugins. Will be split out into its own crate once it's a bit more stable.
 
-use std::io;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::ffi::OsString;
+use std::panic::{self, PanicHookInfo};
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, Write};
 use std::path::{PathBuf, Path};
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex, Once};
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use serde_json::{self, Value};
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde::de::DeserializeOwned;
 
-use xi_core::{ViewIdentifier, PluginPid, SyntaxDefinition,
+use xi_core::{ViewIdentifier, BufferIdentifier, PluginPid, SyntaxDefinition,
 ConfigTable, BufferConfig};
 use xi_core::plugin_rpc::{TextUnit, PluginBufferInfo, HostRequest, HostNotification,
-GetDataResponse, ScopeSpan};
-use xi_rpc::{self, RpcLoop, RpcPeer, RpcCtx, RemoteError, ReadError};
+GetDataResponse, ScopeSpan, PluginEdit, Diagnostic, FoldRange, STALE_REVISION_ERROR};
+use xi_rpc::{self, Framing, RpcLoop, RpcPeer, RpcCtx, RemoteError, ReadError};
 use xi_trace;
 
+/// The environment variable which, if set, enables verbose RPC logging.
+pub const VERBOSE_ENV_VAR: &str = "XI_PLUGIN_VERBOSE";
+
+/// The environment variable naming a file to log to, if no `--log-file` is
+/// given; see `PluginOptions::log_file`.
+pub const LOG_FILE_ENV_VAR: &str = "XI_PLUGIN_LOG_FILE";
+
+/// The config key for how long, in milliseconds, the plugin may take to
+/// handle an RPC or an idle callback before a warning is logged. Zero, the
+/// default, turns the timing off.
+pub const CALLBACK_BUDGET_KEY: &str = "plugin_callback_budget_ms";
+
+/// The error code returned for requests which need a view the plugin does
+/// not have.
+pub const MISSING_VIEW_ERROR: i64 = 404;
+
+/// The error code returned for requests received before the plugin has
+/// been initialized, which usually means core sent them out of order.
+pub const NOT_INITIALIZED_ERROR: i64 = 425;
+
+/// The error code returned for requests and commands the plugin does not
+/// support.
+pub const METHOD_NOT_SUPPORTED_ERROR: i64 = 501;
+
+/// The error code returned when the plugin fails to handle a request.
+pub const INTERNAL_ERROR: i64 = 500;
+
+/// An error returned to core. Each kind of error has a fixed code, which
+/// core and other clients can rely on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PluginError {
+    /// The request needed a view which the plugin does not have.
+    MissingView(String),
+    /// The request arrived before the plugin was initialized.
+    NotInitialized(String),
+    /// The request or command is not supported by the plugin.
+    MethodNotSupported(String),
+    /// The request was made against a revision which is no longer current.
+    StaleRevision(String),
+    /// The plugin failed to handle the request.
+    Internal(String),
+}
+
+impl PluginError {
+    pub fn code(&self) -> i64 {
+        match *self {
+            PluginError::MissingView(_) => MISSING_VIEW_ERROR,
+            PluginError::NotInitialized(_) => NOT_INITIALIZED_ERROR,
+            PluginError::MethodNotSupported(_) => METHOD_NOT_SUPPORTED_ERROR,
+            PluginError::StaleRevision(_) => STALE_REVISION_ERROR,
+            PluginError::Internal(_) => INTERNAL_ERROR,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match *self {
+            PluginError::MissingView(ref msg)
+                | PluginError::NotInitialized(ref msg)
+                | PluginError::MethodNotSupported(ref msg)
+                | PluginError::StaleRevision(ref msg)
+                | PluginError::Internal(ref msg) => msg,
+        }
+    }
+}
+
+impl From<PluginError> for RemoteError {
+    fn from(err: PluginError) -> RemoteError {
+        let code = err.code();
+        let message = match err {
+            PluginError::MissingView(msg)
+                | PluginError::NotInitialized(msg)
+                | PluginError::MethodNotSupported(msg)
+                | PluginError::StaleRevision(msg)
+                | PluginError::Internal(msg) => msg,
+        };
+        RemoteError::custom(code, message, None)
+    }
+}
+
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+static NOTIFY_ON_PANIC: AtomicBool = AtomicBool::new(true);
+
+static INSTALL_PANIC_HOOK: Once = Once::new();
+
+thread_local! {
+    /// Where to report a panic on this thread: the peer and the ids of the
+    /// plugin whose main loop is running on it, once it has been initialized.
+    static PANIC_TARGET: RefCell<Option<(RpcPeer, PluginPid, ViewIdentifier)>> =
+        RefCell::new(None);
+    /// Set while a panic is being reported, in case reporting it panics.
+    static REPORTING_PANIC: Cell<bool> = const { Cell::new(false) };
+}
+
 #[derive(Debug)]
 pub enum Error {
     RpcError(xi_rpc::Error),
     WrongReturnType,
     BadRequest,
+    /// A requested range ends past the end of the document.
+    OutOfRange { end: usize, buf_size: usize },
+    /// An edit was rejected because the document has changed since the
+    /// revision it was made against. `head_rev` is core's current revision,
+    /// if it said what that is.
+    StaleRevision { head_rev: Option<u64> },
+    /// An edit couldn't be moved past the edits made since its revision,
+    /// because they overlap it, or are no longer in the delta history.
+    EditConflict,
+    /// Core did not respond to a request within the request timeout.
+    TimedOut,
     // Just used in tests
     Other(String),
 }
@@ -42,22 +158,67 @@ pub trait Handler {
                       -> Result<Value, RemoteError>;
     #[allow(unused_variables)]
     fn idle(&mut self, ctx: PluginCtx, token: usize) {}
+    /// Called before config changes are applied. If this returns an error,
+    /// the changes are logged and discarded.
+    #[allow(unused_variables)]
+    fn validate_config(&self, changes: &ConfigTable) -> Result<(), String> { Ok(()) }
+    /// Returns `true` if the handler has outstanding work, such as scheduled
+    /// idle callbacks. This is reported in response to `ping` requests.
+    fn is_busy(&self) -> bool { false }
+    /// Called before a `ping` request is answered, once the plugin has been
+    /// initialized. Core pings a plugin to wait until it has handled the
+    /// RPCs sent before, so any of those the handler held back should be
+    /// handled now.
+    #[allow(unused_variables)]
+    fn catch_up(&mut self, ctx: PluginCtx) {}
 }
 
 /// A container for general view information, shared between all plugin layers.
+///
+/// A plugin process is started once per buffer, so every view of that buffer
+/// shares this state (and the document cache built on top of it). All handler
+/// calls happen on the runloop's thread, so no synchronization is required.
 pub struct ViewState {
+    /// The view that initialized this plugin.
     pub view_id: ViewIdentifier,
+    /// The buffer backing this plugin's views.
+    pub buffer_id: BufferIdentifier,
+    /// All views of this buffer, including `view_id`.
+    pub views: Vec<ViewIdentifier>,
     pub syntax: SyntaxDefinition,
     config_table: ConfigTable,
     pub config: Option<BufferConfig>,
     pub path: Option<PathBuf>,
+    span_seq: SpanSequence,
 }
 
+/// Numbers the span updates and diagnostics sent for a view, so that core
+/// can drop one which arrives after a later one. Clones share the count, so
+/// those sent from other threads are numbered in order with those sent by
+/// the runloop.
+#[derive(Debug, Clone, Default)]
+pub struct SpanSequence(Arc<Mutex<u64>>);
+
+impl SpanSequence {
+    /// Calls `send` with the next sequence number, which is greater than any
+    /// before, on any thread. No other number is drawn until `send` returns,
+    /// so the messages it sends go out in the order they are numbered.
+    pub fn send_next<F: FnOnce(u64)>(&self, send: F) {
+        let mut seq = self.0.lock().unwrap();
+        *seq += 1;
+        send(*seq);
+    }
+}
+
+#[derive(Clone)]
 pub struct PluginCtx<'a> {
     inner: &'a RpcCtx,
     /// Information about the view initiating this RPC.
     pub view: &'a ViewState,
     pub plugin_id: PluginPid,
+    /// How long to wait for core to respond to requests; `None` to wait
+    /// indefinitely.
+    timeout: Option<Duration>,
 }
 
 /// The handler that does low level plugin setup, and then forwards RPC calls
@@ -66,6 +227,174 @@ struct BaseHandler<'a, H: 'a> {
     inner: &'a mut H,
     plugin_id: Option<PluginPid>,
     state: Option<ViewState>,
+    metrics: Metrics,
+    log_limiter: RateLimiter,
+}
+
+/// How often the same diagnostic message may be logged.
+const LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The most keys a `RateLimiter` keeps track of. Unknown methods each get
+/// a key, so without a bound, a core sending garbage could grow the map
+/// without end.
+const MAX_LOG_KEYS: usize = 64;
+
+/// Identifies a kind of diagnostic message: the method of the RPC it is
+/// about, and the view the RPC was for, if any.
+type LogKey = (String, Option<ViewIdentifier>);
+
+/// Limits how often messages are logged for each key, so that a flood of
+/// bad RPCs can't flood stderr as well.
+#[derive(Debug)]
+struct RateLimiter {
+    interval: Duration,
+    /// When a message for each key was last logged, and how many messages
+    /// have been suppressed since.
+    entries: HashMap<LogKey, (Instant, usize)>,
+}
+
+impl RateLimiter {
+    fn new(interval: Duration) -> Self {
+        RateLimiter { interval, entries: HashMap::new() }
+    }
+
+    /// Returns the number of messages suppressed since the last, if a
+    /// message for `key` should be logged at `now`. Otherwise, the message
+    /// is counted as suppressed.
+    fn check(&mut self, key: LogKey, now: Instant) -> Option<usize> {
+        if let Some(entry) = self.entries.get_mut(&key) {
+            if now.duration_since(entry.0) < self.interval {
+                entry.1 += 1;
+                return None;
+            }
+            let suppressed = entry.1;
+            *entry = (now, 0);
+            return Some(suppressed);
+        }
+        if self.entries.len() >= MAX_LOG_KEYS {
+            self.evict(now);
+        }
+        self.entries.insert(key, (now, 0));
+        Some(0)
+    }
+
+    /// Makes room for a new key, dropping every key whose interval has
+    /// passed, or failing that, the key logged longest ago. The counts of
+    /// messages suppressed for dropped keys are lost.
+    fn evict(&mut self, now: Instant) {
+        let interval = self.interval;
+        self.entries.retain(|_, entry| now.duration_since(entry.0) < interval);
+        if self.entries.len() < MAX_LOG_KEYS {
+            return;
+        }
+        let oldest = self.entries.iter()
+            .min_by_key(|&(_, entry)| entry.0)
+            .map(|(key, _)| key.clone());
+        if let Some(oldest) = oldest {
+            self.entries.remove(&oldest);
+        }
+    }
+
+    /// Logs `msg`, unless a message about `method` for `view_id` was logged
+    /// recently.
+    fn log(&mut self, method: &str, view_id: Option<ViewIdentifier>, msg: &str) {
+        match self.check((method.to_owned(), view_id), Instant::now()) {
+            Some(0) => eprintln!("{}", msg),
+            Some(n) => eprintln!("{} ({} similar messages suppressed)", msg, n),
+            None => (),
+        }
+    }
+}
+
+/// Counts of the RPCs handled by a plugin, reported in response to `ping`.
+#[derive(Debug, Default)]
+struct Metrics {
+    notifications: u64,
+    requests: u64,
+    /// The number of RPCs handled, by method.
+    methods: BTreeMap<&'static str, u64>,
+    /// When the plugin was initialized.
+    initialized: Option<Instant>,
+    /// The number of callbacks which ran over budget, by method; see
+    /// `CALLBACK_BUDGET_KEY`.
+    slow_callbacks: BTreeMap<&'static str, u64>,
+}
+
+impl Metrics {
+    fn record(&mut self, method: &'static str, is_request: bool) {
+        if is_request {
+            self.requests += 1;
+        } else {
+            self.notifications += 1;
+        }
+        *self.methods.entry(method).or_insert(0) += 1;
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "notifications": self.notifications,
+            "requests": self.requests,
+            "methods": self.methods,
+            "uptime_ms": self.initialized.map(|t| t.elapsed().as_millis() as u64),
+            "slow_callbacks": self.slow_callbacks,
+        })
+    }
+}
+
+/// Typed access to the values in a `ConfigTable`, such as the plugin's
+/// own settings, which are not part of `BufferConfig`.
+#[derive(Debug, Clone, Copy)]
+pub struct ConfigAccess<'a> {
+    table: &'a ConfigTable,
+}
+
+impl<'a> ConfigAccess<'a> {
+    pub fn new(table: &'a ConfigTable) -> Self {
+        ConfigAccess { table }
+    }
+
+    /// Returns the value for `key`, if it is present and a bool.
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        self.table.get(key).and_then(Value::as_bool)
+    }
+
+    /// Returns the value for `key`, if it is present and an unsigned integer.
+    pub fn get_u64(&self, key: &str) -> Option<u64> {
+        self.table.get(key).and_then(Value::as_u64)
+    }
+
+    /// Returns the value for `key`, if it is present and a string.
+    pub fn get_string(&self, key: &str) -> Option<&'a str> {
+        self.table.get(key).and_then(Value::as_str)
+    }
+
+    /// Returns the value for `key`, deserialized as a `T`, or `default` if
+    /// the key is missing or its value is not a valid `T`.
+    pub fn get_or<T: DeserializeOwned>(&self, key: &str, default: T) -> T {
+        self.table.get(key)
+            .and_then(|v| T::deserialize(v).ok())
+            .unwrap_or(default)
+    }
+}
+
+/// A notification from core. Notifications which can't be parsed, such as
+/// those with methods added in a newer version of the protocol, are
+/// deserialized as `Unknown`, so that the plugin keeps running.
+enum IncomingNotification {
+    Known(HostNotification),
+    Unknown(Value),
+}
+
+impl<'de> Deserialize<'de> for IncomingNotification {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'de>
+    {
+        let value = Value::deserialize(deserializer)?;
+        match HostNotification::deserialize(&value) {
+            Ok(rpc) => Ok(IncomingNotification::Known(rpc)),
+            Err(_) => Ok(IncomingNotification::Unknown(value)),
+        }
+    }
 }
 
 /// Abstracts getting data from the peer. This only exists so we can mock it in tests.
@@ -75,19 +404,27 @@ pub trait DataSource {
 }
 
 impl ViewState {
-    fn new(init_info: &PluginBufferInfo) -> Self {
+    /// Creates the state for the first view in `init_info`, returning an
+    /// error if it has no views, or if its config is invalid.
+    pub(crate) fn new(init_info: &PluginBufferInfo) -> Result<Self, String> {
 
         let &PluginBufferInfo {
-            ref views, ref path, ref syntax, ref config, ..
+            ref buffer_id, ref views, ref path, ref syntax, ref config, ..
         } = init_info;
 
-        ViewState {
-            view_id: *views.first().unwrap(),
+        let view_id = *views.first().ok_or("buffer has no views")?;
+        let buffer_config = serde_json::from_value(Value::Object(config.clone()))
+            .map_err(|err| format!("invalid config: {}", err))?;
+        Ok(ViewState {
+            view_id,
+            buffer_id: *buffer_id,
+            views: views.clone(),
             syntax: *syntax,
             config_table: config.clone(),
-            config: serde_json::from_value(Value::Object(config.clone())).unwrap(),
-            path: path.as_ref().map(PathBuf::from)
-        }
+            config: buffer_config,
+            path: path.as_ref().map(PathBuf::from),
+            span_seq: SpanSequence::default(),
+        })
     }
 
     fn update_config(&mut self, changes: &ConfigTable) {
@@ -98,14 +435,63 @@ impl ViewState {
         self.config = conf.unwrap();
     }
 
+    /// Returns the raw config table, including any keys which are not part
+    /// of `BufferConfig`.
+    pub fn config_table(&self) -> &ConfigTable {
+        &self.config_table
+    }
+
+    /// Returns typed access to the view's config.
+    pub fn config_access(&self) -> ConfigAccess<'_> {
+        ConfigAccess::new(&self.config_table)
+    }
+
+    /// Returns the extension of the buffer's file, without the leading dot.
+    /// This is `None` for files without an extension, including dotfiles
+    /// such as `.gitignore`, for unsaved buffers, and for paths which are
+    /// not valid UTF-8.
+    pub fn extension(&self) -> Option<&str> {
+        self.path.as_ref()?.extension()?.to_str()
+    }
+
+    /// Returns the final component of the buffer's path, if it has one and
+    /// it is valid UTF-8.
+    pub fn file_name(&self) -> Option<&str> {
+        self.path.as_ref()?.file_name()?.to_str()
+    }
+
+    /// Returns the sequence which numbers the view's span updates, for
+    /// sending spans from outside of the runloop.
+    pub fn span_sequence(&self) -> SpanSequence {
+        self.span_seq.clone()
+    }
+
     fn update_path(&mut self, path: &Path) {
         self.path = Some(path.to_owned())
     }
+
+    fn update_syntax(&mut self, syntax: SyntaxDefinition) {
+        self.syntax = syntax
+    }
+
+    /// Returns the views belonging to `buffer_id`. This is empty unless
+    /// `buffer_id` is the buffer this plugin was started for.
+    pub fn views_for_buffer(&self, buffer_id: BufferIdentifier) -> &[ViewIdentifier] {
+        if buffer_id == self.buffer_id { &self.views } else { &[] }
+    }
+
+    /// Returns the open views this plugin knows of, with their paths. As
+    /// a plugin process is started for a single buffer, these are the
+    /// buffer's views, which share its path. Paths are updated when the
+    /// buffer is saved.
+    pub fn open_paths(&self) -> Vec<(ViewIdentifier, Option<PathBuf>)> {
+        self.views.iter().map(|view_id| (*view_id, self.path.clone())).collect()
+    }
 }
 
 impl<'a> PluginCtx<'a> {
     fn new(inner: &'a RpcCtx, view: &'a ViewState, plugin_id: PluginPid) -> Self {
-        PluginCtx { inner, view, plugin_id }
+        PluginCtx { inner, view, plugin_id, timeout: None }
     }
 
     pub fn add_scopes(&self, scopes: &Vec<Vec<String>>) {
@@ -118,23 +504,118 @@ impl<'a> PluginCtx<'a> {
     }
 
     pub fn update_spans(&self, start: usize, len: usize, rev: u64, spans: &[ScopeSpan]) {
+        self.update_spans_chunked(start, len, rev, spans, None);
+    }
+
+    /// Sends `spans` as `update_spans` does, split across several messages
+    /// if they would take up more than `max_bytes`; see `chunk_payload`.
+    /// The messages share a sequence number, and all but the last have
+    /// `more` set, so that core applies the spans together.
+    pub fn update_spans_chunked(&self, start: usize, len: usize, rev: u64, spans: &[ScopeSpan],
+                                max_bytes: Option<usize>) {
+        let chunks = chunk_payload(spans, max_bytes);
+        let last = chunks.len() - 1;
+        self.view.span_seq.send_next(|seq| {
+            for (ix, chunk) in chunks.into_iter().enumerate() {
+                let mut params = json!({
+                    "plugin_id": self.plugin_id,
+                    "view_id": self.view.view_id,
+                    "start": start,
+                    "len": len,
+                    "rev": rev,
+                    "seq": seq,
+                    "spans": chunk,
+                });
+                if ix < last {
+                    params["more"] = json!(true);
+                }
+                self.send_rpc_notification("update_spans", &params);
+            }
+        });
+    }
+
+    pub fn publish_diagnostics(&self, diagnostics: &[Diagnostic]) {
+        self.publish_diagnostics_chunked(diagnostics, None);
+    }
+
+    /// Sends `diagnostics` as `publish_diagnostics` does, split across
+    /// several messages as `update_spans_chunked` splits spans.
+    pub fn publish_diagnostics_chunked(&self, diagnostics: &[Diagnostic],
+                                       max_bytes: Option<usize>) {
+        let chunks = chunk_payload(diagnostics, max_bytes);
+        let last = chunks.len() - 1;
+        self.view.span_seq.send_next(|seq| {
+            for (ix, chunk) in chunks.into_iter().enumerate() {
+                let mut params = json!({
+                    "plugin_id": self.plugin_id,
+                    "view_id": self.view.view_id,
+                    "seq": seq,
+                    "diagnostics": chunk,
+                });
+                if ix < last {
+                    params["more"] = json!(true);
+                }
+                self.send_rpc_notification("diagnostics", &params);
+            }
+        });
+    }
+
+    pub fn send_folding_ranges(&self, ranges: &[FoldRange]) {
         let params = json!({
             "plugin_id": self.plugin_id,
             "view_id": self.view.view_id,
-            "start": start,
-            "len": len,
-            "rev": rev,
-            "spans": spans,
+            "ranges": ranges,
         });
-        self.send_rpc_notification("update_spans", &params);
+        self.send_rpc_notification("folding_ranges", &params);
+    }
+
+    /// Asks core to apply `edit`. This blocks until core responds, and fails
+    /// with `Error::StaleRevision` if the document has changed since
+    /// `edit.rev`; the error includes the revision core has reached.
+    pub fn edit(&self, edit: &PluginEdit) -> Result<(), Error> {
+        let params = json!({
+            "plugin_id": self.plugin_id,
+            "view_id": self.view.view_id,
+            "edit": edit,
+        });
+        match self.send_rpc_request("edit", &params) {
+            Ok(_) => Ok(()),
+            Err(Error::RpcError(xi_rpc::Error::RemoteError(RemoteError::Custom { code, data, .. })))
+                if code == STALE_REVISION_ERROR => {
+                let head_rev = data.as_ref().and_then(|data| data["head_rev"].as_u64());
+                Err(Error::StaleRevision { head_rev })
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Sets how long requests to core, such as for data or edits, wait for
+    /// a response before failing with `Error::TimedOut`. By default, they
+    /// wait indefinitely.
+    pub fn set_request_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
     }
 
     fn send_rpc_notification(&self, method: &str, params: &Value) {
         self.inner.get_peer().send_rpc_notification(method, params)
     }
 
-    fn send_rpc_request(&self, method: &str, params: &Value) -> Result<Value, xi_rpc::Error> {
-        self.inner.get_peer().send_rpc_request(method, params)
+    fn send_rpc_request(&self, method: &str, params: &Value) -> Result<Value, Error> {
+        let peer = self.inner.get_peer();
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return peer.send_rpc_request(method, params).map_err(Error::RpcError),
+        };
+        let (tx, rx) = mpsc::channel();
+        peer.send_rpc_request_async(method, params, Box::new(move |result| {
+            let _ = tx.send(result);
+        }));
+        match rx.recv_timeout(timeout) {
+            Ok(result) => result.map_err(Error::RpcError),
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(Error::TimedOut),
+            Err(mpsc::RecvTimeoutError::Disconnected) =>
+                Err(Error::RpcError(xi_rpc::Error::PeerDisconnect)),
+        }
     }
 
     /// Determines whether an incoming request (or notification) is pending. This
@@ -164,67 +645,167 @@ impl<'a> DataSource for PluginCtx<'a> {
             "max_size": max_size,
             "rev": rev,
         });
-        let result = self.send_rpc_request("get_data", &params)
-            .map_err(|e| Error::RpcError(e))?;
+        let result = self.send_rpc_request("get_data", &params)?;
         GetDataResponse::deserialize(result)
             .map_err(|_| Error::WrongReturnType)
     }
 }
 
-impl<'a, H: 'a> BaseHandler<'a, H> {
+impl<'a, H: Handler + 'a> BaseHandler<'a, H> {
     fn new(inner: &'a mut H) -> Self {
         BaseHandler {
             inner: inner,
             plugin_id: None,
             state: None,
+            metrics: Metrics::default(),
+            log_limiter: RateLimiter::new(LOG_INTERVAL),
         }
     }
 
-    fn expect_state_mut(&mut self) -> &mut ViewState {
-        self.state.as_mut()
-            .expect("missing state; was plugin init RPC sent?")
+    /// Reports that the plugin is alive, along with the number of views
+    /// it is tracking, whether it has outstanding work, and how many RPCs
+    /// it has handled since it was started.
+    fn do_ping(&self) -> Value {
+        json!({
+            "alive": true,
+            "views": self.state.as_ref().map(|s| s.views.len()).unwrap_or(0),
+            "busy": self.inner.is_busy(),
+            "metrics": self.metrics.to_json(),
+        })
+    }
+
+    /// The view's callback budget, if it sets one.
+    fn callback_budget(&self) -> Option<Duration> {
+        let budget = self.state.as_ref()?.config_access().get_or(CALLBACK_BUDGET_KEY, 0);
+        if budget == 0 { None } else { Some(Duration::from_millis(budget)) }
     }
 
-    fn collect_trace(&self) -> Result<Value, RemoteError> {
+    /// Warns if handling `method` took longer than `budget`, counting it in
+    /// the metrics and, if tracing is enabled, recording it in the trace.
+    fn check_latency(&mut self, method: &'static str, elapsed: Duration, budget: Duration) {
+        if elapsed <= budget {
+            return;
+        }
+        *self.metrics.slow_callbacks.entry(method).or_insert(0) += 1;
+        let msg = format!("plugin {:?} took {}ms to handle {} for view {:?}, \
+                           over its budget of {}ms",
+                          self.plugin_id, elapsed.as_millis(), method,
+                          self.state.as_ref().map(|s| s.view_id), budget.as_millis());
+        if xi_trace::is_enabled() {
+            xi_trace::trace_payload("slow callback", &["plugin"], msg.clone());
+        }
+        let view_id = self.state.as_ref().map(|s| s.view_id);
+        self.log_limiter.log(&format!("slow {}", method), view_id, &msg);
+    }
+
+    /// Serializes all collected trace samples, in the chrome trace format.
+    fn do_collect_trace(&self) -> Result<Value, RemoteError> {
         use xi_trace_dump::*;
-        let traces = xi_trace::samples_cloned_unsorted();
-        let mut out = Vec::new();
-        chrome_trace::serialize(traces.iter(),
-                                chrome_trace::OutputFormat::JsonArray,
-                                &mut out).unwrap();
-        let traces = serde_json::from_reader(out.as_slice());
-        Ok(traces?)
+        let samples = xi_trace::samples_cloned_unsorted();
+        chrome_trace::to_value(&samples, chrome_trace::OutputFormat::JsonArray)
+            .map_err(|e| PluginError::Internal(format!("{:?}", e)).into())
     }
 }
 
 impl<'a, H: Handler> xi_rpc::Handler for BaseHandler<'a, H> {
-    type Notification = HostNotification;
+    type Notification = IncomingNotification;
     type Request = HostRequest;
     fn handle_notification(&mut self, ctx: &RpcCtx, rpc: Self::Notification) {
         use self::HostNotification::*;
+        let rpc = match rpc {
+            IncomingNotification::Known(rpc) => rpc,
+            IncomingNotification::Unknown(rpc) => {
+                let method = rpc.get("method").unwrap_or(&Value::Null);
+                let view_id = rpc.get("params").and_then(|params| params.get("view_id"))
+                    .and_then(|view_id| serde_json::from_value(view_id.clone()).ok());
+                let msg = format!("plugin {:?} ignoring unknown notification {}",
+                                  self.plugin_id, method);
+                self.log_limiter.log(&method.to_string(), view_id, &msg);
+                return
+            }
+        };
+        if VERBOSE.load(Ordering::Relaxed) {
+            log_rpc(notification_method(&rpc), self.plugin_id,
+                    self.state.as_ref().map(|s| s.view_id));
+        }
+        self.metrics.record(notification_method(&rpc), false);
         // we handle a few RPCs here, updating basic view information
         // before forwarding to the actual handler.
         match rpc {
             // don't forward ping before we're initialized
             Ping( .. ) => { if self.state.is_none() { return } }
+            // the runloop exits once this notification has been handled,
+            // giving the inner handler a chance to clean up.
+            Shutdown( .. ) => {
+                ctx.request_exit();
+                self.metrics = Metrics::default();
+                if self.state.is_none() { return }
+            }
             Initialize { ref plugin_id, ref buffer_info } => {
-                assert!(self.state.is_none());
-                self.state = Some(ViewState::new(buffer_info.first().as_ref().expect("missing buffer info?")));
+                if self.state.is_some() {
+                    eprintln!("ignoring duplicate initialize for plugin {:?} \
+                              (already initialized as {:?})",
+                              plugin_id, self.plugin_id);
+                    return
+                }
+                let info = match buffer_info.first() {
+                    Some(info) => info,
+                    None => {
+                        eprintln!("ignoring initialize for plugin {:?} without buffer info",
+                                  plugin_id);
+                        return
+                    }
+                };
+                let state = match ViewState::new(info) {
+                    Ok(state) => state,
+                    Err(msg) => {
+                        eprintln!("ignoring initialize for plugin {:?}: {}", plugin_id, msg);
+                        return
+                    }
+                };
+                self.state = Some(state);
                 self.plugin_id = Some(*plugin_id);
+                self.metrics.initialized = Some(Instant::now());
+                let view_id = self.state.as_ref().unwrap().view_id;
+                PANIC_TARGET.with(|target| {
+                    *target.borrow_mut() = Some((ctx.get_peer().clone(), *plugin_id, view_id));
+                });
             }
 
-            ConfigChanged { ref changes, .. } =>
-                self.expect_state_mut().update_config(changes),
+            ConfigChanged { ref changes, .. } => {
+                if let Err(msg) = self.inner.validate_config(changes) {
+                    eprintln!("rejecting config changes for plugin {:?}: {}\n{:?}",
+                              self.plugin_id, msg, changes);
+                    return
+                }
+                if let Some(state) = self.state.as_mut() {
+                    state.update_config(changes)
+                }
+            }
 
-            DidSave { ref path, .. } =>
-                self.expect_state_mut().update_path(path),
+            DidSave { ref path, .. } => {
+                if let Some(state) = self.state.as_mut() {
+                    state.update_path(path)
+                }
+            }
 
-            TracingConfig {enabled} => {
+            SyntaxChanged { syntax, .. } => {
+                if let Some(state) = self.state.as_mut() {
+                    state.update_syntax(syntax)
+                }
+            }
+
+            TracingConfig { enabled, ref categories, max_samples } => {
                 use xi_trace;
 
                 if enabled {
                     eprintln!("Enabling tracing in {:?}", self.plugin_id);
-                    xi_trace::enable_tracing();
+                    let config = match max_samples {
+                        Some(limit) => xi_trace::Config::with_limit_count(limit),
+                        None => xi_trace::Config::default(),
+                    };
+                    xi_trace::enable_tracing_with_config(config);
+                    xi_trace::set_category_filter(categories.clone());
                 } else {
                     eprintln!("Disabling tracing in {:?}",  self.plugin_id);
                     xi_trace::disable_tracing();
@@ -233,34 +814,826 @@ impl<'a, H: Handler> xi_rpc::Handler for BaseHandler<'a, H> {
             _ => (),
         }
 
-        let plugin_ctx = PluginCtx::new(
-            ctx, self.state.as_ref().unwrap(), self.plugin_id.unwrap());
-        self.inner.handle_notification(plugin_ctx, rpc)
+        let state = match self.state.as_ref() {
+            Some(state) => state,
+            None => {
+                let method = notification_method(&rpc);
+                self.log_limiter.log(method, notification_view(&rpc),
+                                     &not_initialized_message(method));
+                return
+            }
+        };
+        let method = notification_method(&rpc);
+        let budget = self.callback_budget();
+        let start = budget.map(|_| Instant::now());
+        let plugin_ctx = PluginCtx::new(ctx, state, self.plugin_id.unwrap());
+        self.inner.handle_notification(plugin_ctx, rpc);
+        if let (Some(budget), Some(start)) = (budget, start) {
+            self.check_latency(method, start.elapsed(), budget);
+        }
     }
 
     fn handle_request(&mut self, ctx: &RpcCtx, rpc: Self::Request)
                       -> Result<Value, RemoteError> {
-        assert!(self.state.is_some(), "request received before init: {:?}", &rpc);
-        let plugin_ctx = PluginCtx::new(
-            ctx, self.state.as_ref().unwrap(), self.plugin_id.unwrap());
+        if VERBOSE.load(Ordering::Relaxed) {
+            log_rpc(request_method(&rpc), self.plugin_id,
+                    self.state.as_ref().map(|s| s.view_id));
+        }
+        self.metrics.record(request_method(&rpc), true);
+        // ping is answered even before init, so that core can check on
+        // plugins which are slow to start.
+        if let HostRequest::Ping(..) = rpc {
+            if let Some(state) = self.state.as_ref() {
+                self.inner.catch_up(PluginCtx::new(ctx, state, self.plugin_id.unwrap()));
+            }
+            return Ok(self.do_ping());
+        }
+        // as with the notification, the runloop exits once this has been
+        // handled; the response is sent first.
+        if let HostRequest::Shutdown(..) = rpc {
+            ctx.request_exit();
+            self.metrics = Metrics::default();
+            if self.state.is_none() {
+                return Ok(json!({}))
+            }
+        }
+        let state = match self.state.as_ref() {
+            Some(state) => state,
+            None => {
+                let method = request_method(&rpc);
+                let msg = not_initialized_message(method);
+                self.log_limiter.log(method, request_view(&rpc), &msg);
+                return Err(PluginError::NotInitialized(msg).into())
+            }
+        };
+        let plugin_ctx = PluginCtx::new(ctx, state, self.plugin_id.unwrap());
         if let &HostRequest::CollectTrace(..) = &rpc {
-            return self.collect_trace();
+            return self.do_collect_trace();
+        }
+        let method = request_method(&rpc);
+        let budget = self.callback_budget();
+        let start = budget.map(|_| Instant::now());
+        let result = self.inner.handle_request(plugin_ctx, rpc);
+        if let (Some(budget), Some(start)) = (budget, start) {
+            self.check_latency(method, start.elapsed(), budget);
         }
-        self.inner.handle_request(plugin_ctx, rpc)
+        result
     }
 
     fn idle(&mut self, ctx: &RpcCtx, token: usize) {
-        let plugin_ctx = PluginCtx::new(
-            ctx, self.state.as_ref().unwrap(), self.plugin_id.unwrap());
+        let state = match self.state.as_ref() {
+            Some(state) => state,
+            None => return self.log_limiter.log("idle", None, &not_initialized_message("idle")),
+        };
+        let budget = self.callback_budget();
+        let start = budget.map(|_| Instant::now());
+        let plugin_ctx = PluginCtx::new(ctx, state, self.plugin_id.unwrap());
         self.inner.idle(plugin_ctx, token);
+        if let (Some(budget), Some(start)) = (budget, start) {
+            self.check_latency("idle", start.elapsed(), budget);
+        }
+    }
+}
+
+/// Enables or disables logging of every RPC received by the plugin.
+///
+/// Verbose logging is also enabled when the main loop starts if the
+/// `XI_PLUGIN_VERBOSE` environment variable is set.
+pub fn set_verbose(enabled: bool) {
+    VERBOSE.store(enabled, Ordering::Relaxed);
+}
+
+/// Enables or disables notifying core when the plugin panics. This is
+/// enabled by default.
+pub fn set_notify_on_panic(enabled: bool) {
+    NOTIFY_ON_PANIC.store(enabled, Ordering::Relaxed);
+}
+
+/// Installs a panic hook which, if a plugin's main loop panics, sends core
+/// a `panic` notification with the panic message, before deferring to the
+/// previously installed hook. Nothing is sent before the plugin has been
+/// initialized.
+fn install_panic_hook() {
+    INSTALL_PANIC_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            notify_panic(info);
+            previous(info);
+        }));
+    });
+}
+
+fn notify_panic(info: &PanicHookInfo) {
+    if !NOTIFY_ON_PANIC.load(Ordering::Relaxed)
+        || REPORTING_PANIC.with(|reporting| reporting.replace(true)) {
+        return
+    }
+    let msg = match info.payload().downcast_ref::<&str>() {
+        Some(msg) => msg.to_string(),
+        None => info.payload().downcast_ref::<String>().cloned()
+            .unwrap_or_else(|| "Box<Any>".to_owned()),
+    };
+    let msg = match info.location() {
+        Some(location) => format!("{} at {}", msg, location),
+        None => msg,
+    };
+    let _ = PANIC_TARGET.try_with(|target| {
+        if let Ok(target) = target.try_borrow() {
+            if let Some((ref peer, plugin_id, view_id)) = *target {
+                peer.send_rpc_notification("panic", &json!({
+                    "plugin_id": plugin_id,
+                    "view_id": view_id,
+                    "msg": msg,
+                }));
+            }
+        }
+    });
+    REPORTING_PANIC.with(|reporting| reporting.set(false));
+}
+
+/// Logs a received RPC to stderr.
+fn log_rpc(method: &str, pid: Option<PluginPid>, view_id: Option<ViewIdentifier>) {
+    match (pid, view_id) {
+        (Some(pid), Some(view_id)) =>
+            eprintln!("[plugin {:?}] rpc {} view {}", pid, method, view_id),
+        _ => eprintln!("[plugin (uninitialized)] rpc {}", method),
+    }
+}
+
+/// Describes an RPC received before the plugin was initialized, and so
+/// before it has a view to handle it with.
+fn not_initialized_message(method: &str) -> String {
+    format!("plugin received {} before initialize: not initialized", method)
+}
+
+fn request_method(rpc: &HostRequest) -> &'static str {
+    match *rpc {
+        HostRequest::Update(..) => "update",
+        HostRequest::CollectTrace(..) => "collect_trace",
+        HostRequest::Ping(..) => "ping",
+        HostRequest::CustomCommand { .. } => "custom_command",
+        HostRequest::Shutdown(..) => "shutdown",
+        HostRequest::WillSave { .. } => "will_save",
+        HostRequest::Completion { .. } => "completion",
+        HostRequest::Hover { .. } => "hover",
+        HostRequest::DebugState(..) => "debug_state",
+    }
+}
+
+/// The view `rpc` is for, if it is for one.
+fn request_view(rpc: &HostRequest) -> Option<ViewIdentifier> {
+    match *rpc {
+        HostRequest::Update(ref update) => Some(update.view_id),
+        HostRequest::CustomCommand { view_id, .. } |
+        HostRequest::WillSave { view_id, .. } |
+        HostRequest::Completion { view_id, .. } |
+        HostRequest::Hover { view_id, .. } => Some(view_id),
+        HostRequest::CollectTrace(..) |
+        HostRequest::Ping(..) |
+        HostRequest::Shutdown(..) |
+        HostRequest::DebugState(..) => None,
+    }
+}
+
+fn notification_method(rpc: &HostNotification) -> &'static str {
+    use self::HostNotification::*;
+    match *rpc {
+        Ping(..) => "ping",
+        Initialize { .. } => "initialize",
+        DidSave { .. } => "did_save",
+        ConfigChanged { .. } => "config_changed",
+        NewBuffer { .. } => "new_buffer",
+        DidClose { .. } => "did_close",
+        Shutdown(..) => "shutdown",
+        TracingConfig { .. } => "tracing_config",
+        Cancel { .. } => "cancel",
+        CustomCommand { .. } => "custom_command",
+        SyntaxChanged { .. } => "syntax_changed",
+        SelectionChanged { .. } => "selection_changed",
+        FocusChanged { .. } => "focus_changed",
+        ViewportChanged { .. } => "viewport_changed",
+    }
+}
+
+/// The view `rpc` is for, if it is for one.
+fn notification_view(rpc: &HostNotification) -> Option<ViewIdentifier> {
+    use self::HostNotification::*;
+    match *rpc {
+        DidSave { view_id, .. } |
+        ConfigChanged { view_id, .. } |
+        DidClose { view_id } |
+        Cancel { view_id, .. } |
+        CustomCommand { view_id, .. } |
+        SyntaxChanged { view_id, .. } |
+        SelectionChanged { view_id, .. } |
+        FocusChanged { view_id, .. } |
+        ViewportChanged { view_id, .. } => Some(view_id),
+        Ping(..) | Initialize { .. } | NewBuffer { .. } | Shutdown(..) |
+        TracingConfig { .. } => None,
+    }
+}
+
+/// Splits `items` into runs, in order, each taking up at most `max_bytes`
+/// when serialized as JSON, along with a separator for each item. An item
+/// larger than that is put in a run of its own. There is always at least
+/// one run, which is empty if `items` is; without a limit, all of `items`
+/// is a single run.
+pub fn chunk_payload<T: Serialize>(items: &[T], max_bytes: Option<usize>) -> Vec<&[T]> {
+    let max_bytes = match max_bytes {
+        Some(max_bytes) => max_bytes,
+        None => return vec![items],
+    };
+    let mut chunks = Vec::new();
+    let (mut start, mut size) = (0, 0);
+    for (ix, item) in items.iter().enumerate() {
+        let item_size = serde_json::to_vec(item).map(|json| json.len()).unwrap_or(0) + 1;
+        if ix > start && size + item_size > max_bytes {
+            chunks.push(&items[start..ix]);
+            start = ix;
+            size = 0;
+        }
+        size += item_size;
+    }
+    chunks.push(&items[start..]);
+    chunks
+}
+
+/// Options for the plugin process, usually parsed from its command line.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PluginOptions {
+    /// Enables tracing from startup, rather than once core requests it.
+    pub enable_trace: bool,
+    /// Logs every RPC received; see `set_verbose`.
+    pub verbose: bool,
+    /// A file to which the plugin's stderr, where it logs, is redirected.
+    /// If this is `None`, the file named by `LOG_FILE_ENV_VAR` is used, if
+    /// that is set; otherwise logs go to stderr, which core shares with
+    /// every plugin.
+    ///
+    /// Because stderr itself is redirected, everything written to it ends
+    /// up in the file, including the library's own logs and panic messages.
+    pub log_file: Option<PathBuf>,
+    /// How RPCs are framed on stdin and stdout. Core passes `--msgpack`
+    /// to plugins whose manifest asks for `Framing::MessagePack`.
+    pub framing: Framing,
+    /// Any arguments which were not recognized, for the plugin's own use.
+    pub args: Vec<String>,
+}
+
+impl PluginOptions {
+    /// Parses the options from the process's command line arguments.
+    pub fn from_args() -> Self {
+        PluginOptions::parse(env::args().skip(1))
+    }
+
+    /// Parses the options from `args`, which should not include the
+    /// program name. The recognized flags are `--enable-trace`, `--verbose`,
+    /// `--msgpack` and `--log-file PATH`; anything else is kept in `args`.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Self {
+        let mut options = PluginOptions::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--enable-trace" => options.enable_trace = true,
+                "--verbose" => options.verbose = true,
+                "--msgpack" => options.framing = Framing::MessagePack,
+                "--log-file" => match args.next() {
+                    Some(path) => options.log_file = Some(path.into()),
+                    None => eprintln!("ignoring --log-file without a path"),
+                },
+                _ => options.args.push(arg),
+            }
+        }
+        options
+    }
+
+    /// Returns the file to log to: `log_file`, or else `env_value`, the
+    /// value of `LOG_FILE_ENV_VAR`, unless it is empty.
+    fn log_target(&self, env_value: Option<OsString>) -> Option<PathBuf> {
+        self.log_file.clone()
+            .or_else(|| env_value.filter(|value| !value.is_empty()).map(PathBuf::from))
+    }
+
+    /// Applies the options which affect the whole process.
+    fn apply(&self) {
+        if self.enable_trace {
+            xi_trace::enable_tracing();
+        }
+        if self.verbose {
+            set_verbose(true);
+        }
+        if let Some(ref path) = self.log_target(env::var_os(LOG_FILE_ENV_VAR)) {
+            if let Err(err) = redirect_stderr(path) {
+                eprintln!("unable to log to {:?}: {}", path, err);
+            }
+        }
     }
 }
 
+#[cfg(unix)]
+fn redirect_stderr(path: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    // the duplicated descriptor stays open once `file` is dropped
+    if unsafe { libc::dup2(file.as_raw_fd(), libc::STDERR_FILENO) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn redirect_stderr(_path: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "log files are only supported on unix"))
+}
+
+/// Runs the plugin's main loop over stdin and stdout.
 pub fn mainloop<H: Handler>(handler: &mut H) -> Result<(), ReadError> {
-    let stdin = io::stdin();
+    mainloop_with_options(handler, &PluginOptions::default())
+}
+
+/// Runs the plugin's main loop over stdin and stdout, after applying
+/// `options`, which are usually parsed with `PluginOptions::from_args`.
+pub fn mainloop_with_options<H: Handler>(handler: &mut H, options: &PluginOptions)
+                                         -> Result<(), ReadError> {
+    options.apply();
     let stdout = io::stdout();
-    let mut rpc_looper = RpcLoop::new(stdout);
+    run_mainloop(handler, || io::stdin().lock(), stdout, options.framing)
+}
+
+/// Runs the plugin's main loop, reading RPCs from the reader returned by
+/// `rf` and writing to `writer`. This allows a plugin to be driven over
+/// some transport other than stdio, such as a socket or an in-memory buffer.
+///
+/// As with `RpcLoop::mainloop`, the reader is supplied via a closure, which
+/// is called on the loop's I/O thread.
+pub fn mainloop_with<H, R, RF, W>(handler: &mut H, rf: RF, writer: W)
+                                  -> Result<(), ReadError>
+    where H: Handler,
+          R: BufRead + 'static,
+          RF: Send + FnOnce() -> R + 'static,
+          W: Write + Send + 'static,
+{
+    run_mainloop(handler, rf, writer, Framing::Json)
+}
+
+fn run_mainloop<H, R, RF, W>(handler: &mut H, rf: RF, writer: W, framing: Framing)
+                             -> Result<(), ReadError>
+    where H: Handler,
+          R: BufRead + 'static,
+          RF: Send + FnOnce() -> R + 'static,
+          W: Write + Send + 'static,
+{
+    if env::var_os(VERBOSE_ENV_VAR).is_some() {
+        set_verbose(true);
+    }
+    install_panic_hook();
+    let _panic_target = PanicTargetGuard;
+    let mut rpc_looper = RpcLoop::with_framing(writer, framing);
     let mut my_handler = BaseHandler::new(handler);
 
-    rpc_looper.mainloop(|| stdin.lock(), &mut my_handler)
+    rpc_looper.mainloop(rf, &mut my_handler)
+}
+
+/// Clears the thread's panic target when the main loop exits.
+struct PanicTargetGuard;
+
+impl Drop for PanicTargetGuard {
+    fn drop(&mut self) {
+        let _ = PANIC_TARGET.try_with(|target| target.borrow_mut().take());
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::thread;
+    use xi_rpc::test_utils::{test_channel, make_reader, DummyReader};
+
+    /// Held by tests which enable or disable tracing, which is global.
+    pub static TRACING: Mutex<()> = Mutex::new(());
+
+    /// Responds to all requests with the plugin's id.
+    struct PidHandler;
+
+    impl Handler for PidHandler {
+        fn handle_notification(&mut self, _ctx: PluginCtx, _rpc: HostNotification) {}
+        fn handle_request(&mut self, ctx: PluginCtx, _rpc: HostRequest)
+                          -> Result<Value, RemoteError> {
+            Ok(json!(ctx.plugin_id))
+        }
+    }
+
+    pub fn init_rpc(plugin_id: usize) -> Value {
+        json!({"method": "initialize", "params": {
+            "plugin_id": plugin_id,
+            "buffer_info": [{
+                "buffer_id": 7,
+                "views": ["view-id-1"],
+                "rev": 1,
+                "buf_size": 0,
+                "nb_lines": 1,
+                "syntax": "plaintext",
+                "config": {
+                    "line_ending": "\n",
+                    "tab_size": 4,
+                    "translate_tabs_to_spaces": true,
+                    "use_tab_stops": true,
+                    "font_face": "Monaco",
+                    "font_size": 12.0,
+                    "auto_indent": true,
+                    "scroll_past_end": false,
+                    "wrap_width": 0,
+                },
+            }],
+        }})
+    }
+
+    pub fn update_rpc(id: u64, rev: u64) -> Value {
+        json!({"id": id, "method": "update", "params": {
+            "view_id": "view-id-1",
+            "delta": null,
+            "new_len": 0,
+            "new_line_count": 1,
+            "rev": rev,
+            "edit_type": "insert",
+            "author": "core",
+        }})
+    }
+
+    /// Joins `rpcs` into the input core would send.
+    pub fn script(rpcs: &[Value]) -> String {
+        rpcs.iter().map(|v| v.to_string()).collect::<Vec<_>>().join("\n")
+    }
+
+    /// Runs `handler`'s main loop over `rpcs`, as if sent by core, returning
+    /// what the plugin sent back. A `Plugin` is run with
+    /// `state_cache::tests::run_script`.
+    pub fn run_script<H: Handler>(handler: &mut H, rpcs: &[Value]) -> DummyReader {
+        let input = script(rpcs);
+        let (tx, rx) = test_channel();
+        assert!(mainloop_with(handler, || make_reader(input), tx).is_ok());
+        rx
+    }
+
+    /// Panics on every request.
+    struct PanicHandler;
+
+    impl Handler for PanicHandler {
+        fn handle_notification(&mut self, _ctx: PluginCtx, _rpc: HostNotification) {}
+        fn handle_request(&mut self, _ctx: PluginCtx, _rpc: HostRequest)
+                          -> Result<Value, RemoteError> {
+            panic!("bad request")
+        }
+    }
+
+    #[test]
+    fn notify_on_panic() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let rpcs = [init_rpc(42), update_rpc(0, 1)];
+        let reader = make_reader(script(&rpcs));
+        let (tx, mut rx) = test_channel();
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            mainloop_with(&mut PanicHandler, move || reader, tx)
+        }));
+        assert!(result.is_err());
+        let msg = rx.expect_rpc("panic");
+        assert_eq!(msg.0["params"]["plugin_id"], json!(42));
+        assert_eq!(msg.0["params"]["view_id"], json!("view-id-1"));
+        assert!(msg.0["params"]["msg"].as_str().unwrap().starts_with("bad request at "));
+    }
+
+    #[test]
+    fn collect_trace() {
+        let _tracing = TRACING.lock().unwrap_or_else(|err| err.into_inner());
+        let rpcs = [
+            init_rpc(42),
+            json!({"method": "tracing_config", "params": {"enabled": true}}),
+            update_rpc(0, 2),
+            json!({"id": 1, "method": "collect_trace", "params": {}}),
+        ];
+        let mut rx = run_script(&mut PidHandler, &rpcs);
+
+        assert_eq!(rx.expect_response(), Ok(json!(42)));
+        let samples = rx.expect_response().unwrap();
+        assert!(!samples.as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn unknown_notification() {
+        let rpcs = [
+            init_rpc(42),
+            json!({"method": "from_the_future", "params": {"view_id": "view-id-1"}}),
+            json!({"id": 0, "method": "collect_trace", "params": {}}),
+        ];
+        let mut rx = run_script(&mut PidHandler, &rpcs);
+        assert!(rx.expect_response().is_ok());
+    }
+
+    #[test]
+    fn config_access() {
+        let table = json!({
+            "flag": true,
+            "count": 4,
+            "name": "xi",
+            "sizes": [1, 2],
+        });
+        let table = table.as_object().unwrap();
+        let config = ConfigAccess::new(table);
+
+        assert_eq!(config.get_bool("flag"), Some(true));
+        assert_eq!(config.get_u64("count"), Some(4));
+        assert_eq!(config.get_string("name"), Some("xi"));
+        assert_eq!(config.get_or("sizes", Vec::<u32>::new()), vec![1, 2]);
+
+        // missing keys
+        assert_eq!(config.get_bool("missing"), None);
+        assert_eq!(config.get_or("missing", 7u32), 7);
+
+        // wrong types
+        assert_eq!(config.get_bool("count"), None);
+        assert_eq!(config.get_u64("name"), None);
+        assert_eq!(config.get_string("flag"), None);
+        assert_eq!(config.get_or("name", 7u32), 7);
+    }
+
+    #[test]
+    fn parse_options() {
+        let args = ["--enable-trace", "--log-file", "/tmp/plugin.log", "--fast", "--msgpack", "x"];
+        let options = PluginOptions::parse(args.iter().map(|s| s.to_string()));
+        assert_eq!(options, PluginOptions {
+            enable_trace: true,
+            verbose: false,
+            log_file: Some("/tmp/plugin.log".into()),
+            framing: Framing::MessagePack,
+            args: vec!["--fast".to_owned(), "x".to_owned()],
+        });
+
+        let options = PluginOptions::parse(vec!["--verbose".to_owned(), "--log-file".to_owned()]);
+        assert!(options.verbose);
+        assert_eq!(options.log_file, None);
+        assert_eq!(PluginOptions::parse(vec![]), PluginOptions::default());
+    }
+
+    #[test]
+    fn path_accessors() {
+        use testing::PluginBufferInfoBuilder;
+
+        let view = |path: Option<&str>| {
+            let mut builder = PluginBufferInfoBuilder::new();
+            if let Some(path) = path {
+                builder = builder.path(path);
+            }
+            ViewState::new(&builder.build()).unwrap()
+        };
+        let main = view(Some("/src/main.rs"));
+        assert_eq!(main.extension(), Some("rs"));
+        assert_eq!(main.file_name(), Some("main.rs"));
+        let archive = view(Some("/tmp/backup.tar.gz"));
+        assert_eq!(archive.extension(), Some("gz"));
+        let dotfile = view(Some("/home/.gitignore"));
+        assert_eq!(dotfile.extension(), None);
+        assert_eq!(dotfile.file_name(), Some(".gitignore"));
+        let makefile = view(Some("Makefile"));
+        assert_eq!(makefile.extension(), None);
+        assert_eq!(makefile.file_name(), Some("Makefile"));
+        let unsaved = view(None);
+        assert_eq!(unsaved.extension(), None);
+        assert_eq!(unsaved.file_name(), None);
+    }
+
+    #[test]
+    fn log_target() {
+        let flag = PluginOptions { log_file: Some("/tmp/flag.log".into()), ..Default::default() };
+        let env_value = || Some(OsString::from("/tmp/env.log"));
+        assert_eq!(flag.log_target(env_value()), Some("/tmp/flag.log".into()));
+        assert_eq!(flag.log_target(None), Some("/tmp/flag.log".into()));
+
+        let none = PluginOptions::default();
+        assert_eq!(none.log_target(env_value()), Some("/tmp/env.log".into()));
+        assert_eq!(none.log_target(Some(OsString::new())), None);
+        assert_eq!(none.log_target(None), None);
+    }
+
+    #[test]
+    fn rate_limit() {
+        let mut limiter = RateLimiter::new(Duration::from_secs(1));
+        let key = |method: &str, view: Option<&str>| (method.to_owned(), view.map(Into::into));
+        let start = Instant::now();
+        assert_eq!(limiter.check(key("a", None), start), Some(0));
+        assert_eq!(limiter.check(key("a", None), start + Duration::from_millis(10)), None);
+        assert_eq!(limiter.check(key("b", None), start + Duration::from_millis(20)), Some(0));
+        // the same method for another view is limited separately
+        assert_eq!(limiter.check(key("a", Some("view-id-2")), start), Some(0));
+        assert_eq!(limiter.check(key("a", None), start + Duration::from_millis(900)), None);
+        assert_eq!(limiter.check(key("a", None), start + Duration::from_millis(1000)), Some(2));
+        assert_eq!(limiter.check(key("a", None), start + Duration::from_millis(1500)), None);
+        assert_eq!(limiter.check(key("b", None), start + Duration::from_millis(1500)), Some(0));
+    }
+
+    #[test]
+    fn rate_limit_bounded() {
+        let mut limiter = RateLimiter::new(Duration::from_secs(1));
+        let start = Instant::now();
+        for i in 0..MAX_LOG_KEYS * 2 {
+            let now = start + Duration::from_millis(i as u64);
+            assert_eq!(limiter.check((format!("unknown_{}", i), None), now), Some(0));
+            assert!(limiter.entries.len() <= MAX_LOG_KEYS);
+        }
+        // the most recent keys are kept
+        let last = (format!("unknown_{}", MAX_LOG_KEYS * 2 - 1), None);
+        assert_eq!(limiter.check(last, start + Duration::from_millis(500)), None);
+        // once their interval has passed, all of the old keys are dropped
+        let later = start + Duration::from_secs(10);
+        limiter.check(("another".to_owned(), None), later);
+        assert_eq!(limiter.entries.len(), 1);
+    }
+
+    #[test]
+    fn rate_limit_before_init() {
+        let rpcs = (0..1000)
+            .map(|i| json!({"id": i, "method": "completion",
+                            "params": {"view_id": format!("view-id-{}", i % 2), "offset": 0}}))
+            .collect::<Vec<_>>();
+        let (tx, mut rx) = test_channel();
+        let mut handler = PidHandler;
+        let mut base = BaseHandler::new(&mut handler);
+        // so that nothing is logged twice, however slowly the test runs
+        base.log_limiter = RateLimiter::new(Duration::from_secs(3600));
+        let reader = make_reader(script(&rpcs));
+        assert!(RpcLoop::new(tx).mainloop(move || reader, &mut base).is_ok());
+        for _ in 0..1000 {
+            assert!(rx.expect_response().is_err());
+        }
+        // one message is logged for each view, and the rest suppressed
+        let entries = &base.log_limiter.entries;
+        assert_eq!(entries.len(), 2);
+        for view in &["view-id-0", "view-id-1"] {
+            let key = ("completion".to_owned(), Some((*view).into()));
+            assert_eq!(entries[&key].1, 499);
+        }
+    }
+
+    #[test]
+    fn plugin_error_codes() {
+        // these are relied on by core, and must not change
+        let errors = [
+            (PluginError::MissingView("a".into()), 404),
+            (PluginError::NotInitialized("e".into()), 425),
+            (PluginError::MethodNotSupported("b".into()), 501),
+            (PluginError::StaleRevision("c".into()), 409),
+            (PluginError::Internal("d".into()), 500),
+        ];
+        for (err, code) in errors.iter().cloned() {
+            assert_eq!(err.code(), code);
+            let message = err.message().to_owned();
+            match RemoteError::from(err) {
+                RemoteError::Custom { code: c, message: m, data: None } => {
+                    assert_eq!((c, m), (code, message))
+                }
+                other => panic!("unexpected error {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn ping_request() {
+        let rpcs = [
+            json!({"id": 0, "method": "ping", "params": {}}),
+            init_rpc(42),
+            json!({"id": 1, "method": "ping", "params": {}}),
+        ];
+        let mut rx = run_script(&mut PidHandler, &rpcs);
+        let resp = rx.expect_response().unwrap();
+        assert_eq!(resp["alive"], json!(true));
+        assert_eq!(resp["views"], json!(0));
+        assert_eq!(resp["busy"], json!(false));
+        assert_eq!(resp["metrics"]["uptime_ms"], Value::Null);
+        let resp = rx.expect_response().unwrap();
+        assert_eq!(resp["alive"], json!(true));
+        assert_eq!(resp["views"], json!(1));
+        assert_eq!(resp["busy"], json!(false));
+        assert!(resp["metrics"]["uptime_ms"].is_u64());
+    }
+
+    #[test]
+    fn rpc_metrics() {
+        let rpcs = [
+            init_rpc(42),
+            update_rpc(0, 2),
+            json!({"method": "ping", "params": {}}),
+            update_rpc(1, 3),
+            json!({"id": 2, "method": "ping", "params": {}}),
+        ];
+        let mut rx = run_script(&mut PidHandler, &rpcs);
+        rx.expect_response().unwrap();
+        rx.expect_response().unwrap();
+        let metrics = rx.expect_response().unwrap()["metrics"].clone();
+        assert_eq!(metrics["notifications"], json!(2));
+        assert_eq!(metrics["requests"], json!(3));
+        assert_eq!(metrics["methods"], json!({"initialize": 1, "ping": 2, "update": 2}));
+    }
+
+    /// Takes 20ms to handle each update.
+    struct SlowHandler;
+
+    impl Handler for SlowHandler {
+        fn handle_notification(&mut self, _ctx: PluginCtx, _rpc: HostNotification) {}
+        fn handle_request(&mut self, _ctx: PluginCtx, _rpc: HostRequest)
+                          -> Result<Value, RemoteError> {
+            thread::sleep(Duration::from_millis(20));
+            Ok(json!(0))
+        }
+    }
+
+    #[test]
+    fn slow_callbacks() {
+        let rpcs = [
+            init_rpc(42),
+            // not timed without a budget
+            update_rpc(0, 2),
+            json!({"method": "config_changed", "params": {
+                "view_id": "view-id-1",
+                "changes": {CALLBACK_BUDGET_KEY: 5},
+            }}),
+            update_rpc(1, 3),
+            update_rpc(2, 4),
+            json!({"id": 3, "method": "ping", "params": {}}),
+        ];
+        let mut rx = run_script(&mut SlowHandler, &rpcs);
+        for _ in 0..3 {
+            rx.expect_response().unwrap();
+        }
+        let metrics = rx.expect_response().unwrap()["metrics"].clone();
+        assert_eq!(metrics["slow_callbacks"], json!({"update": 2}));
+    }
+
+    #[test]
+    fn rpcs_before_init() {
+        let rpcs = [
+            json!({"method": "did_save", "params": {"view_id": "view-id-1", "path": "a.txt"}}),
+            json!({"id": 0, "method": "collect_trace", "params": {}}),
+            init_rpc(42),
+            json!({"id": 1, "method": "collect_trace", "params": {}}),
+        ];
+        let mut rx = run_script(&mut PidHandler, &rpcs);
+        match rx.expect_response() {
+            Err(RemoteError::Custom { code, message, .. }) => {
+                assert_eq!(code, NOT_INITIALIZED_ERROR);
+                assert_eq!(message, not_initialized_message("collect_trace"));
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+        assert!(rx.expect_response().is_ok());
+    }
+
+    #[test]
+    fn duplicate_initialize() {
+        let rpcs = [init_rpc(42), init_rpc(7), update_rpc(0, 2)];
+        let mut rx = run_script(&mut PidHandler, &rpcs);
+        assert_eq!(rx.expect_response(), Ok(json!(42)));
+    }
+
+    /// Records the tab size after each config change, and rejects changes
+    /// to the `forbidden` key.
+    #[derive(Default)]
+    struct ConfigHandler {
+        tab_sizes: Vec<usize>,
+    }
+
+    impl Handler for ConfigHandler {
+        fn handle_notification(&mut self, ctx: PluginCtx, rpc: HostNotification) {
+            if let HostNotification::ConfigChanged { .. } = rpc {
+                self.tab_sizes.push(ctx.view.config.as_ref().unwrap().tab_size);
+            }
+        }
+        fn handle_request(&mut self, _ctx: PluginCtx, _rpc: HostRequest)
+                          -> Result<Value, RemoteError> {
+            Ok(json!(0))
+        }
+        fn validate_config(&self, changes: &ConfigTable) -> Result<(), String> {
+            if changes.contains_key("forbidden") {
+                Err("forbidden is not a valid setting".into())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn validate_config() {
+        let config_rpc = |changes: Value| json!({"method": "config_changed", "params": {
+            "view_id": "view-id-1",
+            "changes": changes,
+        }});
+        let rpcs = [
+            init_rpc(42),
+            config_rpc(json!({"tab_size": 2, "forbidden": true})),
+            config_rpc(json!({"tab_size": 8})),
+        ];
+        let mut handler = ConfigHandler::default();
+        run_script(&mut handler, &rpcs);
+        assert_eq!(handler.tab_sizes, vec![8]);
+    }
 }
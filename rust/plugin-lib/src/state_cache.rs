@@ -14,32 +14,679 @@
 
 //! A more sophisticated cache that manages user state.
 
-use serde_json::Value;
+use std::any::Any;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::env;
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::ops::Range;
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{self, Value};
 use bytecount;
 use rand::{thread_rng, Rng};
+use fnv::FnvHasher;
 
-use xi_core::{plugin_rpc, BufferConfig};
-use xi_rpc::{RemoteError, ReadError};
-use xi_rope::rope::{RopeDelta, LinesMetric};
+use xi_core::{plugin_rpc, BufferConfig, ConfigTable, PluginPid, SyntaxDefinition,
+              ViewIdentifier};
+use xi_rpc::{ReadError, RpcPeer};
+use xi_rope::delta::{Builder as DeltaBuilder, DeltaElement, Transformer};
+use xi_rope::interval::Interval;
+use xi_rope::rope::{Rope, RopeDelta, LinesMetric};
+use xi_trace;
 
-use base_cache::ChunkCache;
-pub use plugin_base::{self, Error, ViewState};
+use base_cache::{Cache, ChunkCache};
+pub use base_cache::LineEnding;
+use store::PluginStore;
+pub use plugin_base::{self, ConfigAccess, Error, PluginError, PluginOptions, ViewState};
+pub use xi_core::plugin_rpc::{CompletionItem, CompletionKind, Diagnostic, FoldKind, FoldRange,
+                              Hover, PluginInfo, Severity};
+pub use xi_rpc::RemoteError;
 
 const CACHE_SIZE: usize = 1024;
 
+/// The most times an edit made with `PluginCtx::rebase_and_retry` is
+/// resubmitted after core rejects it as stale.
+pub const MAX_EDIT_RETRIES: usize = 3;
+
+/// The config key for the least number of bytes read per idle callback
+/// when the document is loaded progressively; see
+/// `Plugin::wants_progressive_load`.
+pub const LOAD_CHUNK_SIZE_KEY: &str = "plugin_load_chunk_size";
+
+/// The load chunk size, if not set in the config.
+const DEFAULT_LOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The most times in a row `run_supervised` tries to connect to core.
+pub const MAX_CONNECT_ATTEMPTS: usize = 5;
+
+/// How long `run_supervised` waits before its first attempt to reconnect;
+/// the wait doubles with each failed attempt.
+#[cfg(not(test))]
+const RECONNECT_DELAY: Duration = Duration::from_millis(100);
+
+#[cfg(test)]
+const RECONNECT_DELAY: Duration = Duration::from_millis(1);
+
+/// How long a connection to core must stay up, once the plugin has been
+/// initialized, for `run_supervised` to stop counting failed attempts.
+const STABLE_CONNECTION_TIME: Duration = Duration::from_secs(10);
+
 /// Number of probes for eviction logic.
 const NUM_PROBES: usize = 5;
 
+/// The config key for the number of recent deltas retained per view.
+pub const DELTA_HISTORY_KEY: &str = "plugin_delta_history";
+
+/// The number of recent deltas retained per view, if not set in the config.
+const DEFAULT_DELTA_HISTORY: usize = 8;
+
+/// The config key for the number of consecutive updates which may arrive
+/// while other RPCs are pending before updates are collapsed, even for
+/// plugins which do not want batched updates. Zero disables collapsing.
+pub const UPDATE_BACKLOG_KEY: &str = "plugin_update_backlog";
+
+/// The update backlog threshold, if not set in the config.
+const DEFAULT_UPDATE_BACKLOG: usize = 16;
+
+/// The config key for the most bytes of document text cached per view.
+/// Text read longest ago is evicted when the cache grows past this size.
+/// Zero leaves the cache unbounded.
+pub const CACHE_MAX_BYTES_KEY: &str = "plugin_cache_max_bytes";
+
+/// The cache budget, if not set in the config.
+const DEFAULT_CACHE_MAX_BYTES: usize = 16 * 1024 * 1024;
+
+/// The config key for the most bytes of spans or diagnostics sent to core
+/// in one message. Larger sets are split across several messages, which
+/// core puts back together; see `plugin_base::chunk_payload`. Zero sends
+/// each set in a single message, however large.
+pub const MAX_PAYLOAD_BYTES_KEY: &str = "plugin_max_payload_bytes";
+
+/// The payload limit, if not set in the config.
+const DEFAULT_MAX_PAYLOAD_BYTES: usize = 1024 * 1024;
+
+/// The config key which, if `true`, has the cache checked against core once
+/// idle after each update; see `PluginCtx::verify_cache`. This costs
+/// requests to core, and is meant for debugging.
+pub const VERIFY_CACHE_KEY: &str = "plugin_verify_cache";
+
+/// The config key for how long, in milliseconds, each idle callback may
+/// run; see `Plugin::idle_with_deadline`.
+pub const IDLE_SLICE_KEY: &str = "plugin_idle_slice_ms";
+
+/// The idle slice, if not set in the config.
+const DEFAULT_IDLE_SLICE_MS: u64 = 10;
+
+/// If more than this fraction of the spans passed to `set_spans` differ
+/// from those last published, all spans are resent, rather than only the
+/// region containing the changes.
+const SPAN_RESEND_FRACTION: f64 = 0.5;
+
+/// The lowest of the idle tokens reserved by this library, which plugins
+/// should not schedule themselves; all lower idle tokens are free for
+/// plugins to use as they see fit. New reserved tokens are numbered down
+/// from `BATCH_IDLE_TOKEN`, and this is lowered to match.
+pub const FIRST_RESERVED_IDLE_TOKEN: usize = WORK_IDLE_TOKEN;
+
+/// The idle token used to deliver batched updates.
+pub const BATCH_IDLE_TOKEN: usize = usize::MAX;
+
+/// The idle token used to deliver debounced config changes.
+pub const CONFIG_IDLE_TOKEN: usize = usize::MAX - 1;
+
+/// The idle token used to run cleanup deferred with `PluginCtx::defer`.
+pub const CLEANUP_IDLE_TOKEN: usize = usize::MAX - 2;
+
+/// The idle token used to check the cache when `VERIFY_CACHE_KEY` is set.
+pub const VERIFY_IDLE_TOKEN: usize = usize::MAX - 3;
+
+/// The idle token used to call `Plugin::did_reset` after the cache is reset.
+pub const RESET_IDLE_TOKEN: usize = usize::MAX - 4;
+
+/// The idle token used to load the document progressively.
+pub const LOAD_IDLE_TOKEN: usize = usize::MAX - 5;
+
+/// The idle token used to run a deferred `Plugin::initialize`; see
+/// `PluginCtx::request_init`.
+pub const INIT_IDLE_TOKEN: usize = usize::MAX - 6;
+
+/// The idle token used to deliver notifications held back while other RPCs
+/// were pending; see `coalesce_key`.
+pub const NOTIFY_IDLE_TOKEN: usize = usize::MAX - 7;
+
+/// The idle token used to run work scheduled with `PluginCtx::schedule_work`.
+pub const WORK_IDLE_TOKEN: usize = usize::MAX - 8;
+
+/// Returned by `Plugin::idle` to indicate whether there is more work to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Idle {
+    /// The work for this token is finished.
+    Done,
+    /// There is more work to do; `idle` will be called again with the same
+    /// token once no RPCs are pending.
+    Continue,
+}
+
+/// Names a kind of background work, such as reparsing or relinting, for
+/// `PluginCtx::schedule_work`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct WorkKind(pub &'static str);
+
+/// Describes the change passed to `Plugin::update`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdateContext {
+    /// The revision of the document after the change.
+    pub rev: u64,
+    /// The kind of edit, such as "insert", "delete", "undo" or "redo".
+    /// For a batch of updates, this is the kind of the last edit in it.
+    pub edit_type: String,
+    /// Who made the edit: "core" for the user's own edits, or the name of
+    /// the plugin which made it.
+    pub author: String,
+    /// The regions the change replaced, or `None` if the update has no
+    /// delta, in which case the whole document should be assumed changed.
+    pub summary: Option<DeltaSummary>,
+}
+
+/// A region of the document replaced by an edit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplacedRange {
+    /// The region replaced, in the document before the edit.
+    pub old: Range<usize>,
+    /// The length of the text which replaced it.
+    pub new_len: usize,
+}
+
+/// The regions of the document changed by a delta, from `summarize_delta`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeltaSummary {
+    /// The replaced regions, in document order. They don't overlap, and
+    /// adjacent changes are merged into a single region.
+    pub ranges: Vec<ReplacedRange>,
+}
+
+impl DeltaSummary {
+    /// Returns `true` if the delta changed nothing.
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Returns the regions of the document after the edit which hold the
+    /// replacement text, in the same order as `ranges`.
+    pub fn new_ranges(&self) -> Vec<Range<usize>> {
+        let mut shift = 0isize;
+        self.ranges.iter().map(|range| {
+            let start = (range.old.start as isize + shift) as usize;
+            shift += range.new_len as isize - range.old.len() as isize;
+            start..start + range.new_len
+        }).collect()
+    }
+}
+
+/// Returns the regions of the document which `delta` replaces, with the
+/// length of the text replacing each.
+pub fn summarize_delta(delta: &RopeDelta) -> DeltaSummary {
+    let mut ranges = Vec::new();
+    let mut pos = 0;
+    let mut inserted: Option<usize> = None;
+    for el in &delta.els {
+        match *el {
+            DeltaElement::Copy(start, end) => {
+                if start > pos || inserted.is_some() {
+                    let new_len = inserted.take().unwrap_or(0);
+                    ranges.push(ReplacedRange { old: pos..start, new_len });
+                }
+                pos = end;
+            }
+            DeltaElement::Insert(ref text) => *inserted.get_or_insert(0) += text.len(),
+        }
+    }
+    if delta.base_len > pos || inserted.is_some() {
+        ranges.push(ReplacedRange { old: pos..delta.base_len, new_len: inserted.unwrap_or(0) });
+    }
+    DeltaSummary { ranges }
+}
+
+/// Feedback about an update, such as a status message or progress, which a
+/// plugin can return from `Plugin::update` for core to show the user:
+///
+/// ```ignore
+/// Ok(UpdateResponse::status("indexing").with_progress(0.4).into())
+/// ```
+///
+/// It converts to `None` if it is empty, as is `UpdateResponse::none()`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UpdateResponse {
+    /// A short message, such as "indexing".
+    pub status: Option<String>,
+    /// How far along the plugin's work is, from 0.0 to 1.0.
+    pub progress: Option<f64>,
+    /// The names of commands the plugin offers, such as "fix all".
+    pub actions: Vec<String>,
+}
+
+impl UpdateResponse {
+    /// A response with nothing to say, which leaves core's display as it is.
+    pub fn none() -> Self {
+        UpdateResponse::default()
+    }
+
+    /// A response with the status message `status`.
+    pub fn status<S: Into<String>>(status: S) -> Self {
+        UpdateResponse { status: Some(status.into()), ..UpdateResponse::default() }
+    }
+
+    /// Sets the progress, which is clamped to 0.0..=1.0.
+    pub fn with_progress(mut self, progress: f64) -> Self {
+        self.progress = Some(progress.clamp(0.0, 1.0));
+        self
+    }
+
+    /// Adds `action` to the commands offered.
+    pub fn with_action<S: Into<String>>(mut self, action: S) -> Self {
+        self.actions.push(action.into());
+        self
+    }
+
+    /// Returns `true` if the response has nothing to say.
+    pub fn is_empty(&self) -> bool {
+        self.status.is_none() && self.progress.is_none() && self.actions.is_empty()
+    }
+}
+
+impl From<UpdateResponse> for Option<Value> {
+    fn from(resp: UpdateResponse) -> Option<Value> {
+        if resp.is_empty() {
+            return None;
+        }
+        Some(json!({
+            "status": resp.status,
+            "progress": resp.progress,
+            "actions": resp.actions,
+        }))
+    }
+}
+
 /// A handler that the plugin needs to instantiate.
+///
+/// Only `initialize` and `update` must be implemented. Every other method
+/// has a default which does nothing, or which goes along with what core
+/// asks, so that plugins only implement the callbacks they use. Methods
+/// added later must have defaults too, so that existing plugins keep
+/// building.
 pub trait Plugin {
     type State: Default + Clone;
 
+    /// Called with the view when the plugin is started, before `initialize`.
+    /// A plugin which is not interested in this view (for instance, based on
+    /// its syntax or path) can return `false`; the plugin is then not
+    /// initialized, and all further RPCs for the view are acknowledged
+    /// without being passed to the plugin.
+    #[allow(unused_variables)]
+    fn new_view(&mut self, view: &ViewState) -> bool { true }
+    /// Called when the plugin is started for the view, with the length of
+    /// the document, unless it is put off; see `should_defer_init`.
     fn initialize(&mut self, ctx: PluginCtx<Self::State>, buf_size: usize);
-    fn update(&mut self, ctx: PluginCtx<Self::State>, rev: usize,
-              delta: Option<RopeDelta>) -> Option<Value>;
-    fn did_save(&mut self, ctx: PluginCtx<Self::State>);
+    /// If this returns `true` for the view described by `info`, `initialize`
+    /// is deferred until the first update arrives, or the plugin calls
+    /// `PluginCtx::request_init`. Other RPCs for the view are still passed
+    /// to the plugin in the meantime; `PluginCtx::is_initialized` tells
+    /// them apart. This saves work for views which are never edited, such
+    /// as the many background tabs of a large project.
+    #[allow(unused_variables)]
+    fn should_defer_init(&self, info: &plugin_rpc::PluginBufferInfo) -> bool { false }
+    /// Called when the document changes. The plugin may return an edit to
+    /// be applied, an `UpdateResponse` with feedback for the user, or an
+    /// error (see `update_error`) if it was unable to handle the change; the
+    /// view id and revision are added to any custom error's data.
+    ///
+    /// `info` describes the change. Plugins which took a `rev: usize` here
+    /// before should use `info.rev` instead.
+    fn update(&mut self, ctx: PluginCtx<Self::State>, info: UpdateContext,
+              delta: Option<RopeDelta>) -> Result<Option<Value>, RemoteError>;
+    /// Called before the buffer is written to `path`, which may differ from
+    /// the view's current path if the buffer is being saved under a new
+    /// name. The plugin can make last edits to the document with
+    /// `PluginCtx::edit`; these are applied before the save goes ahead.
+    /// Returning an error asks core to cancel the save, in which case
+    /// `did_save` is not called; otherwise `did_save` follows once the file
+    /// has been written. By default, every save is allowed.
+    #[allow(unused_variables)]
+    fn will_save(&mut self, ctx: PluginCtx<Self::State>, path: &Path) -> Result<(), RemoteError> {
+        Ok(())
+    }
+    /// Called after the buffer is saved. If the plugin is unable to handle
+    /// the saved file, it can return an error message, which is logged and
+    /// shown to the user.
+    #[allow(unused_variables)]
+    fn did_save(&mut self, ctx: PluginCtx<Self::State>) -> Result<(), String> { Ok(()) }
+    /// Called with a token passed to `schedule_idle`, once no RPCs are
+    /// pending. Long running work should be done in chunks, returning
+    /// `Idle::Continue` after each chunk so that incoming RPCs are handled
+    /// in between.
+    ///
+    /// Idle callbacks run in the order they were scheduled, one at a time,
+    /// and a continued token is rescheduled behind any work which is
+    /// already waiting, so no single token can starve the others.
+    #[allow(unused_variables)]
+    fn idle(&mut self, ctx: PluginCtx<Self::State>, token: usize) -> Idle { Idle::Done }
+    /// Called in place of `idle`, with a `deadline` by which the callback
+    /// should return, so that work can be sized to fit: a plugin might
+    /// parse until the deadline passes, then return `Idle::Continue`. The
+    /// deadline is `IDLE_SLICE_KEY` from now, or now if an RPC is already
+    /// waiting. By default, this ignores the deadline and calls `idle`.
+    #[allow(unused_variables)]
+    fn idle_with_deadline(&mut self, ctx: PluginCtx<Self::State>, token: usize,
+                          deadline: Instant) -> Idle {
+        self.idle(ctx, token)
+    }
+    /// Called once no RPCs are pending, to do background work of a `kind`
+    /// scheduled with `PluginCtx::schedule_work`. As with `idle`, long
+    /// running work should be done in chunks; returning `Idle::Continue`
+    /// schedules `kind` again, behind any other pending work.
+    #[allow(unused_variables)]
+    fn do_work(&mut self, ctx: PluginCtx<Self::State>, kind: WorkKind) -> Idle { Idle::Done }
+    /// Returns the priority of `kind`. Of the pending kinds of work, the
+    /// one with the highest priority is run first, and those with equal
+    /// priority in the order they were scheduled. By default, all have
+    /// priority zero.
+    #[allow(unused_variables)]
+    fn work_priority(&self, kind: WorkKind) -> i32 { 0 }
+    /// Called before config changes are applied. Returning an error rejects
+    /// the changes, which are then neither applied nor passed to
+    /// `config_changed`.
+    #[allow(unused_variables)]
+    fn validate_config(&self, changes: &ConfigTable) -> Result<(), String> { Ok(()) }
+    /// Called after config changes have been applied to the view.
+    ///
+    /// Changes are debounced: rather than being passed on as they arrive,
+    /// they are merged, and passed on once no other RPCs are pending. This
+    /// avoids redundant work when many changes arrive in quick succession,
+    /// at the cost of some latency; in the meantime, the view's config
+    /// already reflects the changes.
+    #[allow(unused_variables)]
+    fn config_changed(&mut self, ctx: PluginCtx<Self::State>, changes: &ConfigTable) {}
+    /// Returns `true` if changes to the config `key` should be passed to
+    /// `config_changed` immediately, along with any pending changes, rather
+    /// than being debounced.
+    #[allow(unused_variables)]
+    fn config_is_immediate(&self, key: &str) -> bool { false }
+    /// If this returns `true`, updates which arrive while other RPCs are
+    /// pending are applied to the cache but not passed to `update`. Once no
+    /// RPCs are pending, `update` is called once, with a delta composed from
+    /// all of the updates in the batch.
+    ///
+    /// Regardless of this, updates are batched once the plugin falls
+    /// behind; see `UPDATE_BACKLOG_KEY`.
+    fn wants_batched_updates(&self) -> bool { false }
+    /// If this returns `false`, `update` is always passed `None` for the
+    /// delta, which is then dropped once the cache has been updated. This
+    /// suits plugins which only need to know that the document changed,
+    /// and saves composing deltas when updates are batched.
+    fn wants_delta(&self) -> bool { true }
+    /// If this returns `true`, updates for edits made by the plugin itself,
+    /// that is, edits whose author is `PluginCtx::author`, are passed to
+    /// `update`. By default they are applied to the cache but not passed
+    /// on, which avoids feedback loops.
+    fn wants_own_edits(&self) -> bool { false }
+    /// If this returns `true`, the document is read from core in chunks
+    /// once idle after `initialize`, a line at a time and in order, with
+    /// `buffer_chunk_loaded` called for each chunk and `buffer_loaded` once
+    /// the end is reached. This lets a plugin work on a large document as it
+    /// arrives, rather than reading all of it up front. The size of the
+    /// chunks is set by `LOAD_CHUNK_SIZE_KEY`.
+    fn wants_progressive_load(&self) -> bool { false }
+    /// Returns the cache to hold the document's text in, which is called
+    /// once the view has been accepted by `new_view`. The default is a
+    /// `ChunkCache`; plugins which rarely read the document can use a
+    /// `MetadataCache`, which fetches text as it is read instead.
+    fn new_cache(&self) -> Box<dyn Cache> { Box::new(ChunkCache::default()) }
+    /// Called with every delta, before `update`, so that positions stored
+    /// by the plugin can be moved to match; see `transform_offsets`.
+    ///
+    /// Unlike `update`, this is called for all deltas, including batched
+    /// updates and the plugin's own edits. It is not called for updates
+    /// without a delta, after which stored positions should be recomputed.
+    #[allow(unused_variables)]
+    fn transform_positions(&mut self, delta: &RopeDelta) {}
+    /// Called, before `update`, when an edit touches a region watched with
+    /// `PluginCtx::watch_region`. `range` is where the region is after the
+    /// edit. The plugin's own edits are skipped unless it `wants_own_edits`.
+    ///
+    /// An update without a delta is reported as changing every watched
+    /// region, which is then no longer watched, as its position is unknown.
+    #[allow(unused_variables)]
+    fn region_changed(&mut self, ctx: PluginCtx<Self::State>, token: usize,
+                      range: Range<usize>) {}
+    /// Called once idle after the view's caches are reset, either with
+    /// `PluginCtx::request_full_reset` or because `PluginCtx::verify_cache`
+    /// found them to be inconsistent. Any state derived from the document,
+    /// and any watched regions, should be rebuilt.
+    #[allow(unused_variables)]
+    fn did_reset(&mut self, ctx: PluginCtx<Self::State>) {}
+    /// Called, if the plugin `wants_progressive_load`, with each range of
+    /// the document read, which is cached by the time this is called.
+    /// Ranges are in the current revision, follow one another, and start
+    /// and end at line boundaries, except where an edit has split a line.
+    ///
+    /// Text edited after it was loaded is passed to `update` as usual, and
+    /// is not loaded again; text inserted at or past the point loading has
+    /// reached is loaded when it is reached. An update without a delta
+    /// restarts loading from the beginning of the document.
+    #[allow(unused_variables)]
+    fn buffer_chunk_loaded(&mut self, ctx: PluginCtx<Self::State>, range: Range<usize>) {}
+    /// Called, if the plugin `wants_progressive_load`, once the whole
+    /// document has been loaded.
+    #[allow(unused_variables)]
+    fn buffer_loaded(&mut self, ctx: PluginCtx<Self::State>) {}
+    /// Called when the syntax of the view's buffer changes, for instance
+    /// because it was saved with a new extension. `PluginCtx::syntax`
+    /// already returns the new syntax.
+    #[allow(unused_variables)]
+    fn syntax_changed(&mut self, ctx: PluginCtx<Self::State>, syntax: SyntaxDefinition) {}
+    /// Called when the view's selections change, with the new selections,
+    /// which `PluginCtx::selections` also returns from now on. A caret is
+    /// an empty selection.
+    #[allow(unused_variables)]
+    fn selection_changed(&mut self, ctx: PluginCtx<Self::State>,
+                         selections: &[Range<usize>]) {}
+    /// Called when the view gains or loses focus, which `PluginCtx::is_focused`
+    /// also returns from now on. Plugins can use this to put off work on
+    /// views the user isn't looking at, for instance by doing less in `idle`.
+    #[allow(unused_variables)]
+    fn focus_changed(&mut self, ctx: PluginCtx<Self::State>, focused: bool) {}
+    /// Called when the lines visible in the view change, with the first
+    /// and last of them, which `PluginCtx::viewport` also returns from now
+    /// on. Those lines have already been fetched into the cache, so
+    /// plugins which only highlight visible text can read them at once.
+    #[allow(unused_variables)]
+    fn viewport_changed(&mut self, ctx: PluginCtx<Self::State>,
+                        first_line: usize, last_line: usize) {}
+    /// Called when an edit made with `PluginCtx::rebase_and_retry` is given
+    /// up on after being rejected as stale, either because it conflicts with
+    /// the edits made since, or because it was still stale after
+    /// `MAX_EDIT_RETRIES` attempts. `delta` is the edit as last attempted.
+    #[allow(unused_variables)]
+    fn edit_failed(&mut self, ctx: PluginCtx<Self::State>, delta: RopeDelta, err: Error) {}
+    /// Called when core closes the view. The plugin can take what it needs
+    /// from the view, such as its `user_data`, which is then dropped; slow
+    /// work, such as writing to disk, can be passed to `PluginCtx::defer`.
+    #[allow(unused_variables)]
+    fn did_close(&mut self, ctx: PluginCtx<Self::State>) {}
+    /// Called with plugin-defined commands, such as "format document",
+    /// which are not part of the fixed protocol.
+    ///
+    /// Core sends these as `custom_command` RPCs; a client triggers them
+    /// with a `plugin_rpc` edit command naming this plugin as its
+    /// `receiver`, which core forwards as a notification. When the command
+    /// arrives as a notification, the result is discarded, and an error is
+    /// logged. By default, every command is rejected as unknown.
+    #[allow(unused_variables)]
+    fn custom_command(&mut self, ctx: PluginCtx<Self::State>, method: &str, params: Value)
+                      -> Result<Value, RemoteError> {
+        Err(PluginError::MethodNotSupported(format!("unknown command {}", method)).into())
+    }
+    /// Called when core asks for completions at `offset`, which is clamped
+    /// to the length of the document. By default there are none.
+    #[allow(unused_variables)]
+    fn complete(&mut self, ctx: PluginCtx<Self::State>, offset: usize)
+                -> Result<Vec<CompletionItem>, RemoteError> {
+        Ok(Vec::new())
+    }
+    /// Called when core asks for information about the text at `offset`,
+    /// which is clamped to the length of the document. Returning `None`
+    /// shows nothing, as it does by default. A returned range is clamped to
+    /// the document, and dropped if it is inverted.
+    #[allow(unused_variables)]
+    fn hover(&mut self, ctx: PluginCtx<Self::State>, offset: usize)
+             -> Result<Option<Hover>, RemoteError> {
+        Ok(None)
+    }
+    /// Called when core cancels idle work scheduled with `token`, and for
+    /// each token with idle callbacks still pending when the view is closed
+    /// or the plugin is shut down. Any pending idle callbacks for `token`
+    /// will be dropped, and `PluginCtx::is_cancelled` returns `true` until
+    /// they would have run. Core cannot cancel the tokens reserved by this
+    /// library, from `FIRST_RESERVED_IDLE_TOKEN` up.
+    #[allow(unused_variables)]
+    fn cancel(&mut self, ctx: PluginCtx<Self::State>, token: usize) {}
+    /// Called when core asks the plugin to shut down, before the runloop
+    /// exits. This is the last chance to flush or persist any state.
     #[allow(unused_variables)]
-    fn idle(&mut self, ctx: PluginCtx<Self::State>, token: usize) {}
+    fn shutdown(&mut self, ctx: PluginCtx<Self::State>) {}
+    /// Returns state to be saved when the plugin shuts down, and passed to
+    /// `restore_state` the next time it is started for the same file.
+    fn serialize_state(&self) -> Option<Value> { None }
+    /// Called with previously saved state, before `initialize`. This is not
+    /// called if there is no saved state, or if it could not be read.
+    #[allow(unused_variables)]
+    fn restore_state(&mut self, state: Value) {}
+    /// Returns the path where state for `view` is saved. By default, this is
+    /// derived from the plugin's executable and the view's file path, in the
+    /// system's temporary directory; views without a path have no state.
+    fn state_path(&self, view: &ViewState) -> Option<PathBuf> {
+        default_state_path(view)
+    }
+    /// Returns the directory under which the plugin's `PluginStore` is
+    /// kept, in a directory named for the plugin (see `info`), or `None`
+    /// for the plugin to have no store, which is the default. Plugins which
+    /// want a store will usually return `store::default_store_dir()`.
+    fn store_dir(&self) -> Option<PathBuf> {
+        None
+    }
+    /// Describes the plugin to core; this is sent after `initialize`.
+    ///
+    /// By default, the name is that of the plugin's executable, and no
+    /// version or features are reported. Plugins should generally override
+    /// this, using `env!("CARGO_PKG_VERSION")` for the version.
+    fn info(&self) -> PluginInfo {
+        let name = env::current_exe().ok()
+            .and_then(|exe| exe.file_stem().map(|s| s.to_string_lossy().into_owned()))
+            .unwrap_or_else(|| "unknown".to_owned());
+        PluginInfo { name, version: "unknown".to_owned(), features: Vec::new() }
+    }
+}
+
+/// The whole document at one revision, from `PluginCtx::snapshot`. It can
+/// be sent to other threads, and is unaffected by later edits. Clones share
+/// the text, so are cheap.
+#[derive(Debug, Clone)]
+pub struct DocumentSnapshot {
+    text: Arc<str>,
+    rev: u64,
+}
+
+impl DocumentSnapshot {
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The revision of the document the snapshot was taken at.
+    pub fn rev(&self) -> u64 {
+        self.rev
+    }
+}
+
+/// Basic statistics about the document, from `PluginCtx::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DocumentStats {
+    /// The length of the document in bytes.
+    pub bytes: usize,
+    /// The number of characters (Unicode scalar values). If `chars_exact`
+    /// is `false`, the text was not at hand, and this is the number of
+    /// bytes, which is an upper bound.
+    pub chars: usize,
+    pub chars_exact: bool,
+    /// The number of lines, as counted by core: one more than the number of
+    /// line breaks.
+    pub lines: usize,
+}
+
+/// Tells work running on other threads that the document has been edited,
+/// from `PluginCtx::cancellation_token`. Clones share the flag.
+///
+/// When an update arrives, the view's token is cancelled and replaced with
+/// a fresh one, so work started for an older revision sees the edit, while
+/// work started afterwards is unaffected.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Returns `true` once the document has been edited since the token was
+    /// taken. Long running work should check this, and stop early if it is
+    /// set.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+/// Collects spans to be sent with `PluginCtx::flush_spans`. Flushing clears
+/// the builder but keeps its allocation, so a plugin which highlights in a
+/// loop can keep a single builder rather than allocating spans each time.
+#[derive(Debug, Clone, Default)]
+pub struct SpanBuilder {
+    spans: Vec<plugin_rpc::ScopeSpan>,
+}
+
+impl SpanBuilder {
+    pub fn new() -> Self {
+        SpanBuilder::default()
+    }
+
+    /// Adds a span for `start..end`, relative to the region it will be
+    /// flushed with.
+    pub fn add(&mut self, start: usize, end: usize, scope_id: u32) {
+        self.spans.push(plugin_rpc::ScopeSpan { start, end, scope_id });
+    }
+
+    /// The spans added since the last flush.
+    pub fn spans(&self) -> &[plugin_rpc::ScopeSpan] {
+        &self.spans
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+
+    /// Discards the spans added since the last flush.
+    pub fn clear(&mut self) {
+        self.spans.clear();
+    }
 }
 
 struct CacheEntry<S> {
@@ -51,10 +698,182 @@ struct CacheEntry<S> {
 /// The caching state
 #[derive(Default)]
 struct CacheState<S> {
-    buf_cache: ChunkCache,
+    buf_cache: Box<dyn Cache>,
     state_cache: Vec<CacheEntry<S>>,
     /// The frontier, represented as a sorted list of line numbers.
     frontier: Vec<usize>,
+    /// When batching updates, the composition of the deltas which have been
+    /// applied to the cache but not yet passed to the plugin. The inner value
+    /// is `None` if any update in the batch was missing its delta.
+    batched_delta: Option<Option<RopeDelta>>,
+    /// The edit type and author of the last update in the batch.
+    batched_info: Option<(String, String)>,
+    /// The most recent deltas and the revisions they produced, oldest first.
+    recent_deltas: Vec<(u64, RopeDelta)>,
+    /// The revision the oldest of `recent_deltas` applies to.
+    deltas_base_rev: u64,
+    /// Edits rejected as stale, waiting for the updates which made them
+    /// stale; see `PluginCtx::rebase_and_retry`.
+    pending_edits: Vec<PendingEdit>,
+    /// The number of consecutive updates which have arrived while other
+    /// RPCs were pending.
+    backlog: usize,
+    /// Config changes which have not yet been passed to the plugin.
+    pending_config: Option<ConfigTable>,
+    /// The spans last published with `set_spans`, adjusted for later edits;
+    /// spans touched by an edit are `None`, as they must be resent. `None`
+    /// if they are unknown, in which case the next spans are sent in full.
+    published_spans: Option<Vec<Option<plugin_rpc::ScopeSpan>>>,
+    /// The timeout for requests to core; see `PluginCtx::set_request_timeout`.
+    request_timeout: Option<Duration>,
+    /// The options the plugin was started with.
+    options: PluginOptions,
+    /// The line ending style detected in the cached text, if any.
+    line_ending: Option<LineEnding>,
+    /// Work deferred with `PluginCtx::defer`, in the order it was deferred.
+    cleanup: Vec<Box<dyn FnOnce()>>,
+    /// Regions watched with `PluginCtx::watch_region`, and their tokens.
+    watched_regions: Vec<(usize, Range<usize>)>,
+    /// The view's selections, as last reported by core and moved through
+    /// later edits.
+    selections: Vec<Range<usize>>,
+    /// Whether the view has focus, as last reported by core.
+    focused: bool,
+    /// The lines visible in the view, as last reported by core and moved
+    /// through later edits.
+    viewport: Option<TrackedLines>,
+    /// The fold ranges last set by the plugin, moved through later edits.
+    folds: Vec<(FoldKind, TrackedLines)>,
+    /// The kinds of work scheduled with `schedule_work` and not yet run,
+    /// in the order they were scheduled.
+    pending_work: Vec<WorkKind>,
+    /// The plugin's persistent store, opened on init.
+    store: Option<PluginStore>,
+    /// Notifications to core which have not yet been sent; see
+    /// `PluginCtx::flush`.
+    outbound: RefCell<Vec<Outbound>>,
+    /// Coalescible notifications which arrived while other RPCs were
+    /// pending, oldest first, with at most one for each key.
+    pending_notifications: Vec<(NotificationKey, plugin_rpc::HostNotification)>,
+    /// The offset up to which the document has been loaded, while it is
+    /// being loaded progressively.
+    load_progress: Option<usize>,
+    /// Set while `Plugin::initialize` is deferred.
+    init_deferred: bool,
+    /// The last snapshot taken, reused until the revision changes.
+    snapshot: Option<DocumentSnapshot>,
+    /// The plugin's own data for the view; see `PluginCtx::user_data`.
+    user_data: Option<Box<dyn Any>>,
+    /// The number of scheduled but not yet run idle callbacks, by token.
+    idle_tokens: BTreeMap<usize, usize>,
+    /// The number of scheduled idle callbacks to drop, by token.
+    cancelled_tokens: BTreeMap<usize, usize>,
+    /// Cancelled when the next update arrives.
+    cancellation: CancellationToken,
+    /// Measures the delays of `PluginCtx::schedule_idle_after`, once it has
+    /// been called.
+    idle_timer: Option<IdleTimer>,
+}
+
+/// A notification to core held back by `PluginCtx` until it is flushed.
+enum Outbound {
+    Scopes(Vec<Vec<String>>),
+    Spans { start: usize, len: usize, rev: u64, spans: Vec<plugin_rpc::ScopeSpan> },
+    Diagnostics(Vec<Diagnostic>),
+    FoldingRanges(Vec<FoldRange>),
+}
+
+/// Schedules idle callbacks once their delays have elapsed, on a helper
+/// thread, which exits when the timer is dropped.
+struct IdleTimer {
+    deadlines: mpsc::Sender<(Instant, usize)>,
+}
+
+impl IdleTimer {
+    fn start(peer: RpcPeer) -> Self {
+        let (deadlines, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut pending = BinaryHeap::new();
+            loop {
+                let received = match pending.peek() {
+                    Some(&Reverse((deadline, token))) => {
+                        let now = Instant::now();
+                        if deadline <= now {
+                            pending.pop();
+                            peer.schedule_idle(token);
+                            continue;
+                        }
+                        receiver.recv_timeout(deadline - now)
+                    }
+                    None => receiver.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok(entry) => pending.push(Reverse(entry)),
+                    Err(mpsc::RecvTimeoutError::Timeout) => (),
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        });
+        IdleTimer { deadlines }
+    }
+
+    fn schedule(&self, token: usize, delay: Duration) {
+        // the thread only exits once this is dropped
+        self.deadlines.send((Instant::now() + delay, token)).unwrap();
+    }
+}
+
+/// A run of lines, such as those visible in the view, kept in step with
+/// edits.
+struct TrackedLines {
+    /// The first and last lines.
+    lines: (usize, usize),
+    /// The byte range the lines cover, if it is known.
+    range: Option<Range<usize>>,
+}
+
+impl TrackedLines {
+    /// Moves the lines through `delta`, which took the document from
+    /// `old_line_count` lines to `new_line_count`: lines added or removed
+    /// before them shift them, and those added or removed among them move
+    /// the last line. Without a delta, or if the byte range is unknown, the
+    /// lines are only clamped to the new document.
+    fn update(&mut self, delta: Option<&RopeDelta>, old_line_count: usize,
+              new_line_count: usize) {
+        let (mut first, mut last) = self.lines;
+        self.range = match (delta, self.range.take()) {
+            (Some(delta), Some(range)) => {
+                let (changed, _) = delta.summary();
+                let shift = |line: usize| (line + new_line_count).saturating_sub(old_line_count);
+                if changed.end() <= range.start {
+                    first = shift(first);
+                    last = shift(last);
+                } else if changed.start() < range.end {
+                    last = shift(last).max(first);
+                }
+                let mut transformer = Transformer::new(delta);
+                let start = transformer.transform(range.start, true);
+                let end = transformer.transform(range.end, false).max(start);
+                Some(start..end)
+            }
+            _ => None,
+        };
+        let max_line = new_line_count.saturating_sub(1);
+        self.lines = (first.min(max_line), last.min(max_line));
+    }
+}
+
+/// An edit to be rebased and resubmitted once core's revision `head_rev`
+/// reaches the plugin, or with the next update if that is unknown.
+struct PendingEdit {
+    delta: RopeDelta,
+    /// The revision `delta` applies to.
+    rev: u64,
+    head_rev: Option<u64>,
+    priority: u64,
+    after_cursor: bool,
+    author: String,
+    attempts: usize,
 }
 
 pub struct PluginCtx<'a, S: 'a> {
@@ -65,408 +884,5635 @@ pub struct PluginCtx<'a, S: 'a> {
 struct CacheHandler<'a, P: Plugin + 'a> {
     handler: &'a mut P,
     state: CacheState<P::State>,
+    /// Set if the plugin rejected its view in `new_view`.
+    rejected: bool,
+    /// Set once core has sent `initialize`.
+    initialized: bool,
+    /// Set once core has shut the plugin down.
+    shut_down: bool,
 }
 
-impl<'a, P: Plugin> plugin_base::Handler for CacheHandler<'a, P> {
-    fn handle_notification(&mut self, ctx: plugin_base::PluginCtx,
-                           rpc: plugin_rpc::HostNotification) {
+impl<'a, P: Plugin> CacheHandler<'a, P> {
+    fn new(handler: &'a mut P) -> Self {
+        CacheHandler {
+            handler,
+            state: CacheState::default(),
+            rejected: false,
+            initialized: false,
+            shut_down: false,
+        }
+    }
+
+    /// Shuts the plugin down, running any deferred cleanup, and saving its
+    /// state. Returns a summary, which is the response to a `shutdown`
+    /// request: the number of views closed and of deferred cleanups run,
+    /// and whether the plugin's state was saved.
+    fn do_shutdown(&mut self, peer: plugin_base::PluginCtx) -> Value {
+        self.shut_down = true;
+        // the notifications held back only describe a view which is going
+        // away, so they are dropped
+        self.state.pending_notifications.clear();
+        let mut ctx = PluginCtx::new(&mut self.state, peer);
+        let path = self.handler.state_path(ctx.get_view());
+        let views_closed = ctx.get_view().views.len();
+        ctx.cancel_idle_work(self.handler);
+        self.handler.shutdown(ctx);
+        let cleanups_run = self.state.cleanup.len();
+        for cleanup in self.state.cleanup.drain(..) {
+            cleanup();
+        }
+        let mut state_saved = false;
+        if let (Some(path), Some(state)) = (path, self.handler.serialize_state()) {
+            match save_state(&path, &state) {
+                Ok(()) => state_saved = true,
+                Err(err) => eprintln!("failed to save plugin state to {:?}: {}", path, err),
+            }
+        }
+        json!({
+            "views_closed": views_closed,
+            "cleanups_run": cleanups_run,
+            "state_saved": state_saved,
+        })
+    }
+}
+
+impl<'a, P: Plugin> CacheHandler<'a, P> {
+    /// Returns a snapshot of the plugin's state, for debugging: the view,
+    /// the size of the document and how much of it is cached, and the
+    /// work outstanding. The document's text is left out.
+    fn do_debug_state(&self, peer: &plugin_base::PluginCtx) -> Value {
+        let state = &self.state;
+        let view = peer.view;
+        let idle_tokens = state.idle_tokens.iter()
+            .map(|(&token, &count)| (idle_token_name(token), count))
+            .collect::<BTreeMap<_, _>>();
+        json!({
+            "plugin_id": peer.plugin_id,
+            "name": self.handler.info().name,
+            "rejected": self.rejected,
+            "initialized": !state.init_deferred,
+            "views": [{
+                "view_id": view.view_id,
+                "buffer_id": view.buffer_id,
+                "path": view.path,
+                "syntax": view.syntax,
+                "rev": state.buf_cache.rev(),
+                "buf_size": state.buf_cache.buf_size(),
+                "num_lines": state.buf_cache.num_lines(),
+                "cache_bytes": state.buf_cache.cached_bytes(),
+                "focused": state.focused,
+                "viewport": state.viewport.as_ref().map(|viewport| viewport.lines),
+                "load_progress": state.load_progress,
+            }],
+            "idle_tokens": idle_tokens,
+            "cancelled_idle_tokens": state.cancelled_tokens.values().sum::<usize>(),
+            "pending_work": state.pending_work.iter().map(|kind| kind.0).collect::<Vec<_>>(),
+            "pending_edits": state.pending_edits.len(),
+            "pending_notifications": state.pending_notifications.len(),
+            "unflushed": state.outbound.borrow().len(),
+        })
+    }
+
+    /// Passes on the notifications held back by `handle_notification`, in
+    /// the order they arrived.
+    fn flush_notifications(&mut self, ctx: &plugin_base::PluginCtx) {
+        let pending = mem::take(&mut self.state.pending_notifications);
+        for (_, rpc) in pending {
+            self.dispatch_notification(ctx.clone(), rpc);
+        }
+    }
+
+    fn dispatch_notification(&mut self, ctx: plugin_base::PluginCtx,
+                             rpc: plugin_rpc::HostNotification) {
         use self::plugin_rpc::HostNotification::*;
-        let ctx = PluginCtx {
-            state: &mut self.state,
-            peer: ctx,
-        };
+        let ctx = PluginCtx::new(&mut self.state, ctx);
         match rpc {
             Ping( .. ) => (),
             Initialize { mut buffer_info, .. } => {
+                self.initialized = true;
+                if !self.handler.new_view(ctx.get_view()) {
+                    self.rejected = true;
+                    return;
+                }
                 let info = buffer_info.remove(0);
                 ctx.do_initialize(info, self.handler);
             }
-            // TODO: add this to handler
-            ConfigChanged { .. } => (),
+            ConfigChanged { changes, .. } => ctx.do_config_changed(changes, self.handler),
             DidSave { .. } => ctx.do_did_save(self.handler),
-            NewBuffer { .. } | DidClose { .. } => eprintln!("Rust plugin lib \
+            SyntaxChanged { syntax, .. } => self.handler.syntax_changed(ctx, syntax),
+            SelectionChanged { selections, .. } => {
+                let buf_size = ctx.get_buf_size();
+                let selections = selections.iter()
+                    .map(|&(start, end)| (start.min(end), start.max(end)))
+                    .map(|(start, end)| start.min(buf_size)..end.min(buf_size))
+                    .collect::<Vec<_>>();
+                ctx.state.selections = selections.clone();
+                self.handler.selection_changed(ctx, &selections);
+            }
+            FocusChanged { focused, .. } => {
+                ctx.state.focused = focused;
+                self.handler.focus_changed(ctx, focused);
+            }
+            // fetching the lines while shutting down could block on core,
+            // which no longer answers
+            ViewportChanged { first_line, last_line, .. } =>
+                ctx.do_viewport_changed(first_line, last_line, !self.shut_down, self.handler),
+            DidClose { view_id } => ctx.do_did_close(view_id, self.handler),
+            NewBuffer { .. } => eprintln!("Rust plugin lib \
             does not support global plugins"),
-            //TODO: figure out shutdown
-            Shutdown( .. ) | TracingConfig{ .. } => (),
+            // handled above
+            Shutdown( .. ) => unreachable!(),
+            TracingConfig{ .. } => (),
+            Cancel { token, .. } => ctx.do_cancel(token, self.handler),
+            CustomCommand { method, params, .. } => {
+                if let Err(err) = self.handler.custom_command(ctx, &method, params) {
+                    eprintln!("custom command {} failed: {:?}", method, err);
+                }
+            }
+        }
+    }
+}
+
+impl<'a, P: Plugin> plugin_base::Handler for CacheHandler<'a, P> {
+    /// Notifications which only report the latest state of the view are
+    /// held back while other RPCs are pending, and only the last of each
+    /// kind is passed on, once the plugin has caught up. Every other RPC
+    /// but shutdown first flushes those held back, so the plugin sees them
+    /// in order.
+    fn handle_notification(&mut self, ctx: plugin_base::PluginCtx,
+                           rpc: plugin_rpc::HostNotification) {
+        use self::plugin_rpc::HostNotification::*;
+        if self.rejected { return }
+        if let Some(key) = coalesce_key(&rpc) {
+            if ctx.request_is_pending() {
+                PluginCtx::new(&mut self.state, ctx).queue_notification(key, rpc);
+                return
+            }
         }
+        if let Shutdown( .. ) = rpc {
+            self.do_shutdown(ctx.clone());
+        } else {
+            self.flush_notifications(&ctx);
+            self.dispatch_notification(ctx.clone(), rpc);
+        }
+        PluginCtx::new(&mut self.state, ctx).flush();
     }
 
     fn handle_request(&mut self, ctx: plugin_base::PluginCtx,
                       rpc: plugin_rpc::HostRequest)
                       -> Result<Value, RemoteError> {
         use self::plugin_rpc::HostRequest::*;
-        let ctx = PluginCtx {
-            state: &mut self.state,
-            peer: ctx,
+        // answered before anything else, so that asking doesn't change the
+        // state being asked about
+        if let DebugState( .. ) = rpc {
+            return Ok(self.do_debug_state(&ctx));
+        }
+        if self.rejected {
+            return match rpc {
+                Shutdown( .. ) => Ok(json!({})),
+                _ => to_rpc_result(0),
+            }
+        }
+        if let Shutdown( .. ) = rpc {
+            let summary = self.do_shutdown(ctx.clone());
+            PluginCtx::new(&mut self.state, ctx).flush();
+            return Ok(summary);
+        }
+        self.flush_notifications(&ctx);
+        let peer = ctx.clone();
+        let ctx = PluginCtx::new(&mut self.state, ctx);
+        let result = match rpc {
+            Update(params) => ctx.do_update(params, self.handler),
+            WillSave { path, .. } =>
+                self.handler.will_save(ctx, &path).and_then(|()| to_rpc_result(0)),
+            Completion { offset, .. } => {
+                let offset = offset.min(ctx.get_buf_size());
+                self.handler.complete(ctx, offset).and_then(to_rpc_result)
+            }
+            Hover { offset, .. } => {
+                let buf_size = ctx.get_buf_size();
+                let hover = self.handler.hover(ctx, offset.min(buf_size))?
+                    .map(|hover| clip_hover(hover, buf_size));
+                to_rpc_result(hover)
+            }
+            // handled above
+            Shutdown( .. ) | DebugState( .. ) => unreachable!(),
+            CustomCommand { method, params, .. } =>
+                self.handler.custom_command(ctx, &method, params),
+            // handled by `plugin_base`
+            CollectTrace( .. ) | Ping( .. ) => unreachable!(),
         };
-        match rpc {
-            Update(params) => Ok(ctx.do_update(params, self.handler)),
-            CollectTrace( .. ) => {
-                use xi_trace;
-                use xi_trace_dump::*;
+        PluginCtx::new(&mut self.state, peer).flush();
+        result
+    }
+
+    fn validate_config(&self, changes: &ConfigTable) -> Result<(), String> {
+        self.handler.validate_config(changes)
+    }
 
-                let samples = xi_trace::samples_cloned_unsorted();
-                let serialized_result = chrome_trace::to_value(
-                    &samples, chrome_trace::OutputFormat::JsonArray);
-                let serialized = serialized_result.map_err(|e| RemoteError::Custom {
-                    code: 0,
-                    message: format!("{:?}", e),
-                    data: None
-                })?;
-                Ok(serialized)
+    fn is_busy(&self) -> bool {
+        !self.state.idle_tokens.is_empty()
+    }
+
+    fn catch_up(&mut self, ctx: plugin_base::PluginCtx) {
+        if self.rejected { return }
+        self.flush_notifications(&ctx);
+        PluginCtx::new(&mut self.state, ctx).flush();
+    }
+
+    fn idle(&mut self, peer: plugin_base::PluginCtx, token: usize) {
+        if self.rejected { return }
+        let mut ctx = PluginCtx::new(&mut self.state, peer.clone());
+        if !ctx.take_idle_token(token) {
+            return;
+        }
+        match token {
+            BATCH_IDLE_TOKEN => ctx.do_batched_update(self.handler),
+            CONFIG_IDLE_TOKEN => ctx.do_pending_config_changed(self.handler),
+            CLEANUP_IDLE_TOKEN => for cleanup in self.state.cleanup.drain(..) {
+                cleanup();
+            },
+            VERIFY_IDLE_TOKEN => { ctx.verify_cache(); }
+            RESET_IDLE_TOKEN => self.handler.did_reset(ctx),
+            LOAD_IDLE_TOKEN => ctx.do_load_chunk(self.handler),
+            INIT_IDLE_TOKEN => ctx.ensure_initialized(self.handler),
+            NOTIFY_IDLE_TOKEN => self.flush_notifications(&peer),
+            WORK_IDLE_TOKEN => ctx.do_work(self.handler),
+            _ => {
+                let peer = ctx.peer.get_peer().clone();
+                let deadline = ctx.idle_deadline();
+                if self.handler.idle_with_deadline(ctx, token, deadline) == Idle::Continue {
+                    *self.state.idle_tokens.entry(token).or_insert(0) += 1;
+                    peer.schedule_idle(token);
+                }
             }
         }
+        PluginCtx::new(&mut self.state, peer).flush();
     }
+}
 
-    fn idle(&mut self, peer: plugin_base::PluginCtx, token: usize) {
-        let ctx = PluginCtx {
-            state: &mut self.state,
-            peer: peer,
+pub fn mainloop<P: Plugin>(handler: &mut P) -> Result<(), ReadError>  {
+    mainloop_with_options(handler, PluginOptions::default())
+}
+
+/// Runs the plugin's main loop over stdin and stdout with `options`, which
+/// are then available through `PluginCtx::options`. See
+/// `plugin_base::mainloop_with_options` for details.
+pub fn mainloop_with_options<P: Plugin>(handler: &mut P, options: PluginOptions)
+                                        -> Result<(), ReadError> {
+    let mut my_handler = CacheHandler::new(handler);
+    my_handler.state.options = options.clone();
+    plugin_base::mainloop_with_options(&mut my_handler, &options)
+}
+
+/// Runs the plugin's main loop over the provided transport. See
+/// `plugin_base::mainloop_with` for details.
+pub fn mainloop_with<P, R, RF, W>(handler: &mut P, rf: RF, writer: W)
+                                  -> Result<(), ReadError>
+    where P: Plugin,
+          R: BufRead + 'static,
+          RF: Send + FnOnce() -> R + 'static,
+          W: Write + Send + 'static,
+{
+    let mut my_handler = CacheHandler::new(handler);
+    plugin_base::mainloop_with(&mut my_handler, rf, writer)
+}
+
+/// Runs the plugin's main loop over connections to core returned by
+/// `connect`, such as to a socket, connecting again if the connection is
+/// lost, for instance because core restarted. Returns once core shuts the
+/// plugin down.
+///
+/// Each connection starts afresh: core initializes the plugin again, and
+/// the view's caches, and any idle work or deferred cleanup, are dropped.
+/// The plugin itself is kept, so it should expect `initialize` more than
+/// once.
+///
+/// A connection which is closed, or fails with an I/O error, such as a
+/// broken pipe, is reconnected; other errors, such as invalid messages,
+/// are returned. A connection which is lost before core initializes the
+/// plugin, or soon after, counts as a failed attempt, as does an error
+/// from `connect`: after `MAX_CONNECT_ATTEMPTS` failed attempts in a row,
+/// backing off between them, the last error is returned. Even after a
+/// connection which stayed up, there is a short wait before reconnecting.
+///
+/// ```ignore
+/// run_supervised(&mut plugin, || {
+///     let stream = UnixStream::connect(&socket_path)?;
+///     Ok((BufReader::new(stream.try_clone()?), stream))
+/// })
+/// ```
+pub fn run_supervised<P, R, W, C>(handler: &mut P, mut connect: C) -> Result<(), ReadError>
+    where P: Plugin,
+          R: BufRead + Send + 'static,
+          W: Write + Send + 'static,
+          C: FnMut() -> io::Result<(R, W)>,
+{
+    let mut attempts = 0;
+    loop {
+        let err = match connect() {
+            Ok((reader, writer)) => {
+                let connected = Instant::now();
+                let mut my_handler = CacheHandler::new(&mut *handler);
+                let result = plugin_base::mainloop_with(&mut my_handler, move || reader, writer);
+                let err = match result {
+                    Ok(()) if my_handler.shut_down => return Ok(()),
+                    Ok(()) => io::Error::new(io::ErrorKind::ConnectionAborted,
+                                             "connection to core closed"),
+                    Err(ReadError::Io(err)) => err,
+                    Err(err) => return Err(err),
+                };
+                // only a connection which stays up ends a run of failures,
+                // so that a core which keeps dropping it is not retried forever
+                if my_handler.initialized && connected.elapsed() >= STABLE_CONNECTION_TIME {
+                    attempts = 0;
+                }
+                err
+            }
+            Err(err) => err,
         };
-        self.handler.idle(ctx, token);
+        attempts += 1;
+        if attempts >= MAX_CONNECT_ATTEMPTS {
+            return Err(err.into());
+        }
+        eprintln!("connection to core failed (attempt {}): {}; reconnecting", attempts, err);
+        thread::sleep(RECONNECT_DELAY * 2u32.pow(attempts as u32 - 1));
     }
 }
 
-pub fn mainloop<P: Plugin>(handler: &mut P) -> Result<(), ReadError>  {
-    let mut my_handler = CacheHandler {
-        handler: handler,
-        state: CacheState::default(),
+/// Runs the plugin's main loop over a script of RPCs read from the file at
+/// `script`, one per line, as core would send them; for instance, a recorded
+/// session. Once the script has been processed, returns the responses to
+/// the requests it contains, in order.
+///
+/// Requests made by the plugin, such as for data, are answered with the
+/// response in the script which has the same id, wherever it appears;
+/// requests without one fail straight away, as if core had returned an
+/// error.
+pub fn replay<P, Pa>(handler: &mut P, script: Pa) -> Result<Vec<Value>, ReadError>
+    where P: Plugin,
+          Pa: AsRef<Path>,
+{
+    let mut messages = Vec::new();
+    let mut answers = BTreeMap::new();
+    for line in fs::read_to_string(script)?.lines() {
+        match serde_json::from_str::<Value>(line) {
+            Ok(ref msg) if msg.get("method").is_none() && msg.get("id").is_some() => {
+                answers.insert(msg["id"].to_string(), line.to_owned());
+            }
+            // anything else, even if invalid, is for the plugin to handle
+            _ => messages.push(line.to_owned()),
+        }
+    }
+    let (injected, received) = mpsc::channel();
+    let responses = Arc::new(Mutex::new(Vec::new()));
+    let writer = ReplayWriter {
+        answers,
+        injected,
+        responses: responses.clone(),
+        partial: Vec::new(),
+    };
+    let reader = ReplayReader {
+        messages: messages.into_iter(),
+        received,
+        finishing: false,
+        line: io::Cursor::new(Vec::new()),
     };
-    plugin_base::mainloop(&mut my_handler)
+    mainloop_with(handler, || BufReader::new(reader), writer)?;
+    let responses = responses.lock().unwrap().clone();
+    Ok(responses)
 }
 
-impl<'a, S: Default + Clone> PluginCtx<'a, S> {
-    fn do_initialize<P>(mut self, init_info: plugin_rpc::PluginBufferInfo, handler: &mut P)
-        where P: Plugin<State = S>
-    {
+/// The id of the request `replay` makes once the script has been sent, to
+/// learn when the plugin has handled all of it.
+const REPLAY_END_ID: u64 = u64::MAX;
 
-        self.state.buf_cache.buf_size = init_info.buf_size;
-        self.state.buf_cache.rev = init_info.rev;
-        self.state.buf_cache.num_lines = init_info.nb_lines;
-        self.truncate_frontier(0);
-        handler.initialize(self, init_info.buf_size);
-    }
+/// Sends `replay`'s script to the plugin, followed by the answers to its
+/// requests as `ReplayWriter` finds them. Once the script has been sent, it
+/// asks for the plugin's debug state, and ends when that is answered, as
+/// the plugin has then handled every message before it.
+struct ReplayReader {
+    messages: ::std::vec::IntoIter<String>,
+    /// Answers to the plugin's requests, or `None` once the plugin has
+    /// answered the final request.
+    received: mpsc::Receiver<Option<String>>,
+    /// Set once the final request has been sent.
+    finishing: bool,
+    /// The rest of the message being read.
+    line: io::Cursor<Vec<u8>>,
+}
 
-    fn do_did_save<P: Plugin<State = S>>(self, handler: &mut P) {
-        handler.did_save(self);
+impl io::Read for ReplayReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.line.position() as usize == self.line.get_ref().len() {
+            let next = match self.received.try_recv() {
+                Ok(answer) => answer,
+                Err(_) => match self.messages.next() {
+                    Some(msg) => Some(msg),
+                    None if !self.finishing => {
+                        self.finishing = true;
+                        Some(json!({"id": REPLAY_END_ID, "method": "debug_state",
+                                    "params": {}}).to_string())
+                    }
+                    None => self.received.recv().unwrap_or(None),
+                },
+            };
+            match next {
+                Some(line) => self.line = io::Cursor::new((line + "\n").into_bytes()),
+                None => return Ok(0),
+            }
+        }
+        self.line.read(buf)
     }
+}
 
-    fn do_update<P>(mut self, update: plugin_rpc::PluginUpdate, handler: &mut P) -> Value
-        where P: Plugin<State = S>
-    {
-        let plugin_rpc::PluginUpdate { delta, new_len, rev, new_line_count, .. } = update;
-        // update our own state before updating buf_cache
-        if let Some(ref delta) = delta {
-            self.update_line_cache(delta);
+/// Receives what the plugin sends during `replay`: answers its requests
+/// from the script, and keeps its responses to those in the script.
+struct ReplayWriter {
+    /// The responses in the script, by id.
+    answers: BTreeMap<String, String>,
+    injected: mpsc::Sender<Option<String>>,
+    responses: Arc<Mutex<Vec<Value>>>,
+    /// A message which has only been partly written.
+    partial: Vec<u8>,
+}
+
+impl ReplayWriter {
+    fn handle(&mut self, msg: Value) {
+        let id = match msg.get("id") {
+            Some(id) => id.clone(),
+            None => return,
+        };
+        if msg.get("method").is_some() {
+            let answer = self.answers.remove(&id.to_string()).unwrap_or_else(|| {
+                json!({"id": id, "error": {
+                    "code": 0,
+                    "message": "no response to this request in the replayed script",
+                }}).to_string()
+            });
+            let _ = self.injected.send(Some(answer));
+        } else if id == json!(REPLAY_END_ID) {
+            let _ = self.injected.send(None);
         } else {
-            // if there's no delta (very large edit) we blow away everything
-            self.clear_to_start(0);
+            self.responses.lock().unwrap().push(msg);
         }
-
-        self.state.buf_cache.apply_update(new_len, new_line_count, rev, delta.as_ref());
-        handler.update(self, rev as usize, delta)
-            .unwrap_or(Value::from(0i32))
     }
+}
 
-    /// Provides access to the view state, which contains information about
-    /// config options, path, etc.
-    pub fn get_view(&self) -> &ViewState {
-        &self.peer.view
+impl Write for ReplayWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.partial.extend_from_slice(buf);
+        while let Some(end) = self.partial.iter().position(|&b| b == b'\n') {
+            let line = self.partial.drain(..=end).collect::<Vec<_>>();
+            if let Ok(msg) = serde_json::from_slice(&line) {
+                self.handle(msg);
+            }
+        }
+        Ok(buf.len())
     }
 
-    //FIXME: config should be accessed through the view, but can be nil.
-    // Why can it be nil? There should always be a default config.
-    pub fn get_config(&self) -> &BufferConfig {
-        self.peer.view.config.as_ref().unwrap()
-    }
-
-    pub fn get_buf_size(&self) -> usize {
-        self.state.buf_cache.buf_size
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
+}
 
-    pub fn add_scopes(&self, scopes: &Vec<Vec<String>>) {
-        self.peer.add_scopes(scopes)
+impl<'a, S: Default + Clone> PluginCtx<'a, S> {
+    fn new(state: &'a mut CacheState<S>, mut peer: plugin_base::PluginCtx<'a>) -> Self {
+        peer.set_request_timeout(state.request_timeout);
+        PluginCtx { state, peer }
     }
 
-    pub fn update_spans(&self, start: usize, len: usize,
-                        spans: &[plugin_rpc::ScopeSpan]) {
-        self.peer.update_spans(start, len, self.state.buf_cache.rev, spans)
+    /// Returns a context which borrows from this one, so that the plugin can
+    /// be called more than once while handling an RPC.
+    fn reborrow(&mut self) -> PluginCtx<'_, S> {
+        PluginCtx { state: &mut *self.state, peer: self.peer.clone() }
     }
 
-    /// Determines whether an incoming request (or notification) is pending. This
-    /// is intended to reduce latency for bulk operations done in the background.
-    pub fn request_is_pending(&self) -> bool {
-        self.peer.request_is_pending()
+    fn do_initialize<P>(mut self, init_info: plugin_rpc::PluginBufferInfo, handler: &mut P)
+        where P: Plugin<State = S>
+    {
+        let defer_init = handler.should_defer_init(&init_info);
+        self.state.buf_cache = handler.new_cache();
+        self.state.buf_cache.update(init_info.buf_size, init_info.nb_lines, init_info.rev, None);
+        self.update_cache_budget();
+        match init_info.text {
+            Some(text) if text.len() == init_info.buf_size =>
+                self.state.buf_cache.set_contents(text),
+            _ => (),
+        }
+        self.state.line_ending = self.state.buf_cache.line_ending();
+        self.state.recent_deltas.clear();
+        self.state.deltas_base_rev = init_info.rev;
+        self.state.pending_edits.clear();
+        self.state.published_spans = None;
+        self.state.snapshot = None;
+        self.state.watched_regions.clear();
+        self.state.user_data = None;
+        self.state.load_progress = None;
+        self.state.selections.clear();
+        self.state.viewport = None;
+        self.state.folds.clear();
+        self.truncate_frontier(0);
+        if let Some(state) = handler.state_path(self.get_view()).and_then(|p| load_state(&p)) {
+            handler.restore_state(state);
+        }
+        if self.state.store.is_none() {
+            self.state.store = handler.store_dir()
+                .map(|dir| PluginStore::for_plugin(&dir, &handler.info().name));
+        }
+        let peer = self.peer.get_peer().clone();
+        let plugin_id = self.peer.plugin_id;
+        let view_id = self.peer.view.view_id;
+        self.state.init_deferred = defer_init;
+        if !defer_init {
+            self.run_initialize(handler);
+        }
+        peer.send_rpc_notification("plugin_info", &json!({
+            "plugin_id": plugin_id,
+            "view_id": view_id,
+            "info": handler.info(),
+        }));
     }
 
-    /// Schedule the idle handler to be run when there are no requests pending.
-    pub fn schedule_idle(&mut self, token: usize) {
-        self.peer.schedule_idle(token);
+    /// Calls `Plugin::initialize`, starting the progressive load first if
+    /// the plugin wants one.
+    fn run_initialize<P: Plugin<State = S>>(&mut self, handler: &mut P) {
+        self.state.init_deferred = false;
+        if handler.wants_progressive_load() {
+            self.state.load_progress = Some(0);
+            if !self.state.idle_tokens.contains_key(&LOAD_IDLE_TOKEN) {
+                self.schedule_idle(LOAD_IDLE_TOKEN);
+            }
+        }
+        let buf_size = self.get_buf_size();
+        handler.initialize(self.reborrow(), buf_size);
     }
 
-    /// Find an entry in the cache by line num. On return `Ok(i)` means entry
-    /// at index `i` is an exact match, while `Err(i)` means the entry would be
-    /// inserted at `i`.
-    fn find_line(&self, line_num: usize) -> Result<usize, usize> {
-        self.state.state_cache.binary_search_by(|probe| probe.line_num.cmp(&line_num))
+    /// Runs a deferred `Plugin::initialize`, if it has not yet been run.
+    fn ensure_initialized<P: Plugin<State = S>>(&mut self, handler: &mut P) {
+        if self.state.init_deferred {
+            self.run_initialize(handler);
+        }
     }
 
-    /// Find an entry in the cache by offset. Similar to `find_line`.
-    pub fn find_offset(&self, offset: usize) -> Result<usize, usize> {
-        self.state.state_cache.binary_search_by(|probe| probe.offset.cmp(&offset))
+    /// Merges `changes` with any pending config changes, and either passes
+    /// them to the plugin or schedules them to be passed on when idle.
+    fn do_config_changed<P>(mut self, changes: ConfigTable, handler: &mut P)
+        where P: Plugin<State = S>
+    {
+        if changes.contains_key(CACHE_MAX_BYTES_KEY) {
+            self.update_cache_budget();
+        }
+        let immediate = changes.keys().any(|key| handler.config_is_immediate(key));
+        let was_pending = self.state.pending_config.is_some();
+        let mut pending = self.state.pending_config.take().unwrap_or_default();
+        pending.extend(changes);
+        if immediate {
+            handler.config_changed(self, &pending);
+        } else {
+            self.state.pending_config = Some(pending);
+            if !was_pending {
+                self.schedule_idle(CONFIG_IDLE_TOKEN);
+            }
+        }
     }
 
-    /// Get the state from the nearest cache entry at or before given line number.
-    /// Returns line number, offset, and user state.
-    pub fn get_prev(&self, line_num: usize) -> (usize, usize, S) {
-        if line_num > 0 {
-            let mut ix = match self.find_line(line_num) {
-                Ok(ix) => ix,
-                Err(0) => return (0, 0, S::default()),
-                Err(ix) => ix - 1,
-            };
-            loop {
-                let item = &self.state.state_cache[ix];
-                if let Some(ref s) = item.user_state {
-                    return (item.line_num, item.offset, s.clone());
-                }
-                if ix == 0 { break; }
-                ix -= 1;
-            }
+    /// Holds back `rpc` until no other RPCs are pending, replacing any held
+    /// back notification with the same key.
+    fn queue_notification(mut self, key: NotificationKey, rpc: plugin_rpc::HostNotification) {
+        self.state.pending_notifications.retain(|&(pending, _)| pending != key);
+        self.state.pending_notifications.push((key, rpc));
+        if !self.state.idle_tokens.contains_key(&NOTIFY_IDLE_TOKEN) {
+            self.schedule_idle(NOTIFY_IDLE_TOKEN);
         }
-        (0, 0, S::default())
     }
 
-    /// Get the state at the given line number, if it exists in the cache.
-    pub fn get(&self, line_num: usize) -> Option<&S> {
-        self.find_line(line_num).ok()
-            .and_then(|ix| self.state.state_cache[ix].user_state.as_ref())
+    fn do_pending_config_changed<P: Plugin<State = S>>(self, handler: &mut P) {
+        if let Some(changes) = self.state.pending_config.take() {
+            handler.config_changed(self, &changes);
+        }
     }
 
-    /// Set the state at the given line number. Note: has no effect if line_num
-    /// references the end of the partial line at EOF.
-    pub fn set(&mut self, line_num: usize, s: S) {
-        if let Some(entry) = self.get_entry(line_num) {
-            entry.user_state = Some(s);
+    fn do_cancel<P: Plugin<State = S>>(self, token: usize, handler: &mut P) {
+        // the library's own idle work is not the plugin's to cancel
+        if token >= FIRST_RESERVED_IDLE_TOKEN {
+            eprintln!("ignoring cancel of reserved idle token {}", token);
+            return;
+        }
+        let pending = self.state.idle_tokens.get(&token).cloned().unwrap_or(0);
+        if pending > 0 {
+            self.state.cancelled_tokens.insert(token, pending);
         }
+        handler.cancel(self, token);
     }
 
-    /// Get the cache entry at the given line number, creating it if necessary.
-    /// Returns None if line_num > number of newlines in doc (ie if it references
-    /// the end of the partial line at EOF).
-    fn get_entry(&mut self, line_num: usize) -> Option<&mut CacheEntry<S>> {
-        match self.find_line(line_num) {
-            Ok(ix) => Some(&mut self.state.state_cache[ix]),
-            Err(_ix) => {
-                if line_num == self.state.buf_cache.num_lines {
+    /// Records the lines visible in the view, and if `warm` is set, fetches
+    /// them into the cache.
+    fn do_viewport_changed<P>(mut self, first_line: usize, last_line: usize, warm: bool,
+                              handler: &mut P)
+        where P: Plugin<State = S>
+    {
+        let max_line = self.state.buf_cache.num_lines().saturating_sub(1);
+        let first_line = first_line.min(last_line).min(max_line);
+        let last_line = last_line.max(first_line).min(max_line);
+        let range = if !warm { None } else {
+            match self.warm_lines(first_line, last_line + 1) {
+                Ok(range) => Some(range),
+                Err(err) => {
+                    eprintln!("unable to fetch viewport lines {}-{}: {:?}",
+                              first_line, last_line, err);
                     None
-                } else {
-                    let offset = self.state.buf_cache.offset_of_line(&self.peer, line_num)
-                        .expect("get_entry should validate inputs");
-                    let new_ix = self.insert_entry(line_num, offset, None);
-                    Some(&mut self.state.state_cache[new_ix])
                 }
             }
+        };
+        self.state.viewport = Some(TrackedLines { lines: (first_line, last_line), range });
+        handler.viewport_changed(self, first_line, last_line);
+    }
+
+    /// Fetches the lines from `start_line` up to but not including
+    /// `end_line` into the cache, returning the byte range they cover.
+    fn warm_lines(&mut self, start_line: usize, end_line: usize) -> Result<Range<usize>, Error> {
+        let range = self.line_range(start_line, end_line)?;
+        self.state.buf_cache.get_region(&self.peer, range.start, range.end)?;
+        Ok(range)
+    }
+
+    /// Returns the byte range covered by the lines from `start_line` up to
+    /// but not including `end_line`.
+    fn line_range(&mut self, start_line: usize, end_line: usize) -> Result<Range<usize>, Error> {
+        let num_lines = self.state.buf_cache.num_lines();
+        let buf_size = self.state.buf_cache.buf_size();
+        let mut offset_of_line = |line: usize| match line {
+            line if line >= num_lines => Ok(buf_size),
+            line => self.state.buf_cache.offset_of_line(&self.peer, line),
+        };
+        let start = offset_of_line(start_line)?;
+        let end = offset_of_line(end_line)?.max(start);
+        Ok(start..end)
+    }
+
+    fn do_did_close<P: Plugin<State = S>>(mut self, view_id: ViewIdentifier, handler: &mut P) {
+        if view_id != self.get_view().view_id {
+            return;
         }
+        self.cancel_idle_work(handler);
+        handler.did_close(self.reborrow());
+        self.state.user_data = None;
     }
 
-    /// Insert a new entry into the cache, returning its index.
-    fn insert_entry(&mut self, line_num: usize, offset: usize, user_state: Option<S>) -> usize {
-        if self.state.state_cache.len() >= CACHE_SIZE {
-            self.evict();
+    /// Runs the next kind of pending work, rescheduling the idle callback
+    /// if there is more.
+    fn do_work<P: Plugin<State = S>>(mut self, handler: &mut P) {
+        let next = self.state.pending_work.iter().enumerate()
+            .max_by_key(|&(ix, &kind)| (handler.work_priority(kind), Reverse(ix)))
+            .map(|(ix, _)| ix);
+        if let Some(ix) = next {
+            let kind = self.state.pending_work.remove(ix);
+            if handler.do_work(self.reborrow(), kind) == Idle::Continue {
+                self.schedule_work(kind);
+            }
         }
-        match self.find_line(line_num) {
-            Ok(_ix) => panic!("entry already exists"),
-            Err(ix) => {
-                self.state.state_cache.insert(ix, CacheEntry {
-                    line_num, offset, user_state
-                });
-                ix
+        if !self.state.pending_work.is_empty()
+            && !self.state.idle_tokens.contains_key(&WORK_IDLE_TOKEN) {
+            self.schedule_idle(WORK_IDLE_TOKEN);
+        }
+    }
+
+    /// Cancels the idle work scheduled for the view, which is moot once it
+    /// is closed, telling the plugin of each of its own tokens. Cleanup
+    /// deferred before then still runs.
+    fn cancel_idle_work<P: Plugin<State = S>>(&mut self, handler: &mut P) {
+        let idle_tokens = self.state.idle_tokens.iter()
+            .filter(|&(&token, &pending)| {
+                token != CLEANUP_IDLE_TOKEN &&
+                    self.state.cancelled_tokens.get(&token).cloned().unwrap_or(0) < pending
+            })
+            .map(|(&token, &pending)| (token, pending))
+            .collect::<Vec<_>>();
+        self.state.cancelled_tokens.extend(idle_tokens.iter().cloned());
+        self.state.pending_work.clear();
+        for (token, _) in idle_tokens {
+            if token < FIRST_RESERVED_IDLE_TOKEN {
+                handler.cancel(self.reborrow(), token);
             }
         }
     }
 
-    /// Evict one cache entry.
-    fn evict(&mut self) {
-        let ix = self.choose_victim();
-        self.state.state_cache.remove(ix);
+    /// Records that the idle callback for `token` is being run. Returns
+    /// `false` if it has been cancelled, and should be dropped.
+    fn take_idle_token(&mut self, token: usize) -> bool {
+        decrement(&mut self.state.idle_tokens, token);
+        !decrement(&mut self.state.cancelled_tokens, token)
     }
 
-    fn choose_victim(&self) -> usize {
-        let mut best = None;
-        let mut rng = thread_rng();
-        for _ in 0..NUM_PROBES {
-            let ix = rng.gen_range(0, self.state.state_cache.len());
-            let gap = self.compute_gap(ix);
-            if best.map(|(last_gap, _)| gap < last_gap).unwrap_or(true) {
-                best = Some((gap, ix));
-            }
+    fn do_did_save<P: Plugin<State = S>>(self, handler: &mut P) {
+        if let Some(line_ending) = self.state.buf_cache.line_ending() {
+            self.state.line_ending = Some(line_ending);
+        }
+        let peer = self.peer.get_peer().clone();
+        let plugin_id = self.peer.plugin_id;
+        let view_id = self.peer.view.view_id;
+        if let Err(msg) = handler.did_save(self) {
+            eprintln!("did_save failed for plugin {:?}, view {}: {}",
+                      plugin_id, view_id, msg);
+            peer.send_rpc_notification("alert", &json!({
+                "plugin_id": plugin_id,
+                "view_id": view_id,
+                "msg": msg,
+            }));
         }
-        best.unwrap().1
     }
 
-    /// Compute the gap that would result after deleting the given entry.
-    fn compute_gap(&self, ix: usize) -> usize {
-        let before = if ix == 0 { 0 } else { self.state.state_cache[ix - 1].offset };
-        let after = if let Some(item) = self.state.state_cache.get(ix + 1) {
-            item.offset
+    fn do_update<P>(mut self, update: plugin_rpc::PluginUpdate, handler: &mut P)
+        -> Result<Value, RemoteError>
+        where P: Plugin<State = S>
+    {
+        let plugin_rpc::PluginUpdate {
+            delta, new_len, rev, new_line_count, edit_type, author, ..
+        } = update;
+        mem::take(&mut self.state.cancellation).cancel();
+        self.ensure_initialized(handler);
+        if let Some(ref delta) = delta {
+            if let Err(msg) = check_delta(delta, self.state.buf_cache.buf_size(), new_len) {
+                return Err(self.reject_update(msg, new_len, new_line_count, rev));
+            }
+        }
+        // update our own state before updating buf_cache
+        if let Some(ref delta) = delta {
+            self.update_line_cache(delta);
         } else {
-            self.state.buf_cache.buf_size
+            // if there's no delta (very large edit) we blow away everything
+            self.clear_to_start(0);
+        }
+
+        let old_line_count = self.state.buf_cache.num_lines();
+        self.state.buf_cache.update(new_len, new_line_count, rev, delta.as_ref());
+        self.record_delta(rev, delta.as_ref());
+        self.update_selections(delta.as_ref(), new_len);
+        if let Some(ref mut viewport) = self.state.viewport {
+            viewport.update(delta.as_ref(), old_line_count, new_line_count);
+        }
+        self.update_folds(delta.as_ref(), old_line_count, new_line_count);
+        // text inserted where loading has got to is yet to be loaded
+        self.state.load_progress = self.state.load_progress.map(|offset| match delta {
+            Some(ref delta) => Transformer::new(delta).transform(offset, false),
+            None => 0,
+        });
+        self.state.published_spans = match (self.state.published_spans.take(), delta.as_ref()) {
+            (Some(spans), Some(delta)) => Some(transform_spans(&spans, delta)),
+            _ => None,
         };
-        assert!(after >= before, "{} < {} ix: {}", after, before, ix);
-        after - before
-    }
+        if let Some(ref delta) = delta {
+            handler.transform_positions(delta);
+        }
+        // an own edit in the middle of a batch is passed on with the batch,
+        // so that the batched delta stays in step with the revision.
+        let own_edit = author == self.author() && !handler.wants_own_edits();
+        let changed = self.update_watched_regions(delta.as_ref(), new_len);
+        if !own_edit {
+            for (token, range) in changed {
+                handler.region_changed(self.reborrow(), token, range);
+            }
+        }
+        if !self.state.pending_edits.is_empty() {
+            self.retry_pending_edits(handler);
+        }
+        let verify = self.peer.view.config_access().get_or(VERIFY_CACHE_KEY, false);
+        if verify && !self.state.idle_tokens.contains_key(&VERIFY_IDLE_TOKEN) {
+            self.schedule_idle(VERIFY_IDLE_TOKEN);
+        }
 
-    /// Get the line at the given line. Returns empty string if at EOF.
-    pub fn get_line(&mut self, line_num: usize) -> Result<&str, Error> {
-        self.state.buf_cache.get_line(&self.peer, line_num)
-    }
+        if own_edit && self.state.batched_delta.is_none() {
+            return to_rpc_result(0);
+        }
 
-    /// Release all state _after_ the given offset.
-    fn truncate_cache(&mut self, offset: usize) {
-        let (line_num, ix) = match self.find_offset(offset) {
-            Ok(ix) => (self.state.state_cache[ix].line_num, ix + 1),
-            Err(ix) => (
-                if ix == 0 { 0 } else {
-                    self.state.state_cache[ix - 1].line_num
-                },
-                ix
-            ),
+        let delta = match self.state.batched_delta.take() {
+            _ if !handler.wants_delta() => None,
+            Some(Some(batched)) => delta.map(|d| batched.compose(&d)),
+            Some(None) => None,
+            None => delta,
         };
-        self.truncate_frontier(line_num);
-        self.state.state_cache.truncate(ix);
+        let pending = self.request_is_pending();
+        self.state.backlog = if pending { self.state.backlog + 1 } else { 0 };
+        if pending && (handler.wants_batched_updates() || self.is_backlogged()) {
+            self.state.batched_delta = Some(delta);
+            self.state.batched_info = Some((edit_type, author));
+            self.schedule_idle(BATCH_IDLE_TOKEN);
+            return to_rpc_result(0);
+        }
+        let view_id = self.peer.view.view_id;
+        let summary = delta.as_ref().map(summarize_delta);
+        let info = UpdateContext { rev, edit_type, author, summary };
+        match handler.update(self, info, delta) {
+            Ok(Some(resp)) => Ok(resp),
+            Ok(None) => to_rpc_result(0),
+            Err(err) => Err(with_update_info(err, view_id, rev)),
+        }
     }
 
-    fn truncate_frontier(&mut self, line_num: usize) {
-        match self.state.frontier.binary_search(&line_num) {
-            Ok(ix) => self.state.frontier.truncate(ix + 1),
-            Err(ix) => {
-                self.state.frontier.truncate(ix);
-                self.state.frontier.push(line_num);
+    /// Reads the next chunk of a progressive load, passing it to the plugin,
+    /// and either schedules the next chunk or reports that loading is done.
+    fn do_load_chunk<P: Plugin<State = S>>(mut self, handler: &mut P) {
+        let start = match self.state.load_progress {
+            Some(start) => start,
+            None => return,
+        };
+        let end = match self.load_chunk_end(start) {
+            Ok(end) => end,
+            Err(err) => {
+                eprintln!("unable to load view {}: {:?}", self.peer.view.view_id, err);
+                self.state.load_progress = None;
+                return;
             }
+        };
+        if start < end {
+            handler.buffer_chunk_loaded(self.reborrow(), start..end);
+        }
+        if end < self.state.buf_cache.buf_size() {
+            self.state.load_progress = Some(end);
+            self.schedule_idle(LOAD_IDLE_TOKEN);
+        } else {
+            self.state.load_progress = None;
+            handler.buffer_loaded(self);
         }
     }
 
-    /// Updates the line cache to reflect this delta.
-    fn update_line_cache(&mut self, delta: &RopeDelta) {
-        let (iv, new_len) = delta.summary();
-        if let Some(n) = delta.as_simple_insert() {
-            assert_eq!(iv.size(), 0);
-            assert_eq!(new_len, n.len());
+    /// Returns the end of the chunk to load from `start`: the end of the
+    /// line which takes the chunk to at least `LOAD_CHUNK_SIZE_KEY` bytes,
+    /// or the end of the document.
+    fn load_chunk_end(&mut self, start: usize) -> Result<usize, Error> {
+        let chunk_size = self.peer.view.config_access()
+            .get_or(LOAD_CHUNK_SIZE_KEY, DEFAULT_LOAD_CHUNK_SIZE);
+        let cache = &mut self.state.buf_cache;
+        let buf_size = cache.buf_size();
+        if start >= buf_size {
+            return Ok(buf_size);
+        }
+        let mut line_num = cache.line_of_offset(&self.peer, start)?;
+        let mut end = cache.offset_of_line(&self.peer, line_num)?;
+        while end <= start || (end - start < chunk_size && end < buf_size) {
+            let line_len = cache.get_line(&self.peer, line_num)?.len();
+            if line_len == 0 {
+                break;
+            }
+            end += line_len;
+            line_num += 1;
+        }
+        Ok(end.min(buf_size))
+    }
 
-            let newline_count = n.measure::<LinesMetric>();
-            self.line_cache_simple_insert(iv.start(), new_len, newline_count);
-        } else if delta.is_simple_delete() {
-            assert_eq!(new_len, 0);
-            self.line_cache_simple_delete(iv.start(), iv.end())
-        } else {
-            self.clear_to_start(iv.start());
+    /// Handles an update whose delta doesn't fit the cached document, which
+    /// means the cache is out of step with core. The update's length, line
+    /// count and revision are taken as correct, everything else is reset, as
+    /// with `request_full_reset`, and the returned error is sent to core.
+    fn reject_update(&mut self, msg: String, new_len: usize, new_line_count: usize, rev: u64)
+        -> RemoteError
+    {
+        let view_id = self.peer.view.view_id;
+        eprintln!("rejecting update to rev {} of view {}: {}", rev, view_id, msg);
+        self.state.buf_cache.update(new_len, new_line_count, rev, None);
+        self.record_delta(rev, None);
+        self.clear_caches();
+        if let Some(Some(_)) = self.state.batched_delta {
+            self.state.batched_delta = Some(None);
+        }
+        if self.state.load_progress.is_some() {
+            self.state.load_progress = Some(0);
         }
+        with_update_info(PluginError::StaleRevision(msg).into(), view_id, rev)
     }
 
-    fn line_cache_simple_insert(&mut self, start: usize, new_len: usize,
-                                newline_num: usize) {
-        let ix = match self.find_offset(start) {
-            Ok(ix) => ix + 1,
-            Err(ix) => ix,
-        };
+    /// Returns `true` if the plugin has fallen far enough behind on updates
+    /// that they should be collapsed.
+    fn is_backlogged(&self) -> bool {
+        let threshold = self.peer.view.config_access()
+            .get_or(UPDATE_BACKLOG_KEY, DEFAULT_UPDATE_BACKLOG);
+        if threshold == 0 || self.state.backlog < threshold {
+            return false;
+        }
+        if self.state.backlog == threshold {
+            eprintln!("plugin {:?} is {} updates behind on view {}, collapsing updates",
+                      self.peer.plugin_id, threshold, self.peer.view.view_id);
+        }
+        true
+    }
 
-        for entry in &mut self.state.state_cache[ix..] {
-            entry.line_num += newline_num;
-            entry.offset += new_len;
+    /// Returns the deadline for an idle callback starting now.
+    fn idle_deadline(&self) -> Instant {
+        let now = Instant::now();
+        if self.request_is_pending() {
+            return now;
         }
-        self.patchup_frontier(ix, newline_num as isize);
+        let slice = self.peer.view.config_access().get_or(IDLE_SLICE_KEY, DEFAULT_IDLE_SLICE_MS);
+        now + Duration::from_millis(slice)
     }
 
-    fn line_cache_simple_delete(&mut self, start: usize, end: usize) {
-        let off = self.state.buf_cache.offset;
-        let chunk_end = off + self.state.buf_cache.contents.len();
-        if start >= off && end <= chunk_end {
-            let del_newline_num = count_newlines(&self.state.buf_cache.contents[start - off..end - off]);
-            // delete all entries that overlap the deleted range
-            let ix = match self.find_offset(start) {
-                Ok(ix) => ix + 1,
-                Err(ix) => ix,
+    /// Rebases and resubmits the pending edits which were waiting for the
+    /// current revision, passing those which fail for good to the plugin.
+    fn retry_pending_edits<P: Plugin<State = S>>(&mut self, handler: &mut P) {
+        let rev = self.state.buf_cache.rev();
+        let (ready, waiting): (Vec<_>, Vec<_>) = self.state.pending_edits.drain(..)
+            .partition(|edit| edit.head_rev.map_or(true, |head_rev| head_rev == rev));
+        self.state.pending_edits = waiting;
+        for mut pending in ready {
+            let mut attempted = pending.delta.clone();
+            let result = match self.rebase(attempted.clone(), pending.rev) {
+                Ok(delta) => {
+                    attempted = delta.clone();
+                    pending.delta = delta;
+                    pending.rev = rev;
+                    self.submit_edit(pending)
+                }
+                Err(err) => Err(err),
             };
-            while ix < self.state.state_cache.len() &&
-                self.state.state_cache[ix].offset <= end {
-                    self.state.state_cache.remove(ix);
-            }
-            for entry in &mut self.state.state_cache[ix..] {
-                entry.line_num -= del_newline_num;
-                entry.offset -= end - start;
+            if let Err(err) = result {
+                eprintln!("giving up on edit to view {}: {:?}", self.peer.view.view_id, err);
+                handler.edit_failed(self.reborrow(), attempted, err);
             }
-            self.patchup_frontier(ix, -(del_newline_num as isize));
-        } else {
-            // if this region isn't in our chunk we can't correctly adjust newlines
-            self.clear_to_start(start);
         }
     }
 
-    fn patchup_frontier(&mut self, cache_idx: usize, nl_count_delta: isize) {
-        let line_num = match cache_idx {
-            0 => 0,
-            ix => self.state.state_cache[ix - 1].line_num,
-        };
-        let mut new_frontier = Vec::new();
-        let mut need_push = true;
-        for old_ln in &self.state.frontier {
-            if *old_ln < line_num {
-                new_frontier.push(*old_ln);
-            } else {
-                if need_push {
-                    new_frontier.push(line_num);
-                    need_push = false;
-                    if let Some(ref entry) = self.state.state_cache.get(cache_idx) {
-                        if *old_ln >= entry.line_num {
-                            new_frontier.push(old_ln.wrapping_add(nl_count_delta as usize));
-                        }
-                    }
-                }
+    /// Moves the selections through `delta`, as core moves them, and clamps
+    /// them to `new_len`, in case they were reported for a longer document.
+    fn update_selections(&mut self, delta: Option<&RopeDelta>, new_len: usize) {
+        if let Some(delta) = delta {
+            let mut transformer = Transformer::new(delta);
+            for range in self.state.selections.iter_mut() {
+                *range = transformer.transform(range.start, true)
+                    ..transformer.transform(range.end, true);
             }
         }
-        if need_push {
-            new_frontier.push(line_num);
+        for range in self.state.selections.iter_mut() {
+            *range = range.start.min(new_len)..range.end.min(new_len);
         }
-        self.state.frontier = new_frontier;
     }
 
-    /// Clears any cached text and anything in the state cache before `start`.
-    fn clear_to_start(&mut self, start: usize) {
-        self.truncate_cache(start);
+    /// Moves the fold ranges through `delta`, and sends core those which
+    /// moved, until the plugin sets new ones.
+    fn update_folds(&mut self, delta: Option<&RopeDelta>, old_line_count: usize,
+                    new_line_count: usize) {
+        let before = self.folding_ranges();
+        for &mut (_, ref mut lines) in self.state.folds.iter_mut() {
+            lines.update(delta, old_line_count, new_line_count);
+        }
+        let after = self.folding_ranges();
+        if after != before {
+            self.send_folding_ranges(after);
+        }
     }
 
-    /// Clear all state and reset frontier to start.
-    pub fn reset(&mut self) {
-        self.truncate_cache(0);
+    /// Moves the watched regions through `delta`, returning the regions it
+    /// touched, with their tokens. Without a delta, every region is returned,
+    /// clipped to `new_len`, and no longer watched.
+    fn update_watched_regions(&mut self, delta: Option<&RopeDelta>, new_len: usize)
+        -> Vec<(usize, Range<usize>)>
+    {
+        let delta = match delta {
+            Some(delta) => delta,
+            None => return self.state.watched_regions.drain(..)
+                .map(|(token, range)| (token, range.start.min(new_len)..range.end.min(new_len)))
+                .collect(),
+        };
+        let (changed, _) = delta.summary();
+        let mut transformer = Transformer::new(delta);
+        let mut touched = Vec::new();
+        for &mut (token, ref mut range) in self.state.watched_regions.iter_mut() {
+            // text inserted at either end of the region is added to it
+            let hit = if changed.is_empty() {
+                range.start <= changed.start() && changed.start() <= range.end
+            } else {
+                changed.start() < range.end && range.start < changed.end()
+            };
+            let start = transformer.transform(range.start, false);
+            let end = transformer.transform(range.end, true);
+            *range = start..end;
+            if hit {
+                touched.push((token, range.clone()));
+            }
+        }
+        touched
     }
 
-    /// The frontier keeps track of work needing to be done. A typical
-    /// user will call `get_frontier` to get a line number, do the work
-    /// on that line, insert state for the next line, and then call either
-    /// `update_frontier` or `close_frontier` depending on whether there
-    /// is more work to be done at that location.
-    pub fn get_frontier(&self) -> Option<usize> {
-        self.state.frontier.first().cloned()
+    /// Sets the cache's budget from the view's config.
+    fn update_cache_budget(&mut self) {
+        let max_bytes = self.peer.view.config_access()
+            .get_or(CACHE_MAX_BYTES_KEY, DEFAULT_CACHE_MAX_BYTES);
+        self.state.buf_cache.set_max_bytes(if max_bytes == 0 { None } else { Some(max_bytes) });
     }
 
-    /// Updates the frontier. This can go backward, but most typically
-    /// goes forward by 1 line (compared to the `get_frontier` result).
-    pub fn update_frontier(&mut self, new_frontier: usize) {
-        if self.state.frontier.get(1) == Some(&new_frontier) {
-            self.state.frontier.remove(0);
+    /// Adds `delta` to the recent delta history, dropping the oldest entries
+    /// if the history is full. Without a delta there is no way to relate
+    /// older revisions to this one, so the history is cleared.
+    fn record_delta(&mut self, rev: u64, delta: Option<&RopeDelta>) {
+        let max_len = self.peer.view.config_access()
+            .get_or(DELTA_HISTORY_KEY, DEFAULT_DELTA_HISTORY);
+        let history = &mut self.state.recent_deltas;
+        match delta {
+            Some(delta) if max_len > 0 => {
+                history.push((rev, delta.clone()));
+                if history.len() > max_len {
+                    let excess = history.len() - max_len;
+                    self.state.deltas_base_rev = history[excess - 1].0;
+                    history.drain(..excess);
+                }
+            }
+            _ => {
+                history.clear();
+                self.state.deltas_base_rev = rev;
+            }
+        }
+    }
+
+    /// Passes any batched update to the plugin. Because the update RPCs in
+    /// the batch have already been acknowledged, an edit returned by the
+    /// plugin is sent to core as a separate notification.
+    fn do_batched_update<P>(self, handler: &mut P)
+        where P: Plugin<State = S>
+    {
+        let delta = match self.state.batched_delta.take() {
+            Some(delta) => delta,
+            None => return,
+        };
+        let rev = self.state.buf_cache.rev();
+        let (edit_type, author) = self.state.batched_info.take()
+            .unwrap_or_else(|| ("other".into(), "core".into()));
+        let peer = self.peer.get_peer().clone();
+        let plugin_id = self.peer.plugin_id;
+        let view_id = self.peer.view.view_id;
+        let summary = delta.as_ref().map(summarize_delta);
+        let info = UpdateContext { rev, edit_type, author, summary };
+        match handler.update(self, info, delta) {
+            // the update requests have been answered, so there's nowhere
+            // to send a status
+            Ok(Some(ref resp)) if plugin_rpc::UpdateStatus::deserialize(resp).is_ok() => (),
+            Ok(Some(edit)) => peer.send_rpc_notification("edit", &json!({
+                "plugin_id": plugin_id,
+                "view_id": view_id,
+                "edit": edit,
+            })),
+            Ok(None) => (),
+            Err(err) => eprintln!("batched update failed for view {}: {:?}",
+                                  view_id, with_update_info(err, view_id, rev)),
+        }
+    }
+
+    /// Provides access to the view state, which contains information about
+    /// config options, path, etc.
+    pub fn get_view(&self) -> &ViewState {
+        &self.peer.view
+    }
+
+    /// Returns the syntax of the view's buffer.
+    pub fn syntax(&self) -> SyntaxDefinition {
+        self.peer.view.syntax
+    }
+
+    //FIXME: config should be accessed through the view, but can be nil.
+    // Why can it be nil? There should always be a default config.
+    pub fn get_config(&self) -> &BufferConfig {
+        self.peer.view.config.as_ref().unwrap()
+    }
+
+    /// The options the plugin was started with; see `mainloop_with_options`.
+    pub fn options(&self) -> &PluginOptions {
+        &self.state.options
+    }
+
+    /// Returns the most recent deltas, oldest first, along with the revision
+    /// each produced. These can be used to move positions computed against
+    /// an older revision forward to the current one. The number retained is
+    /// set by the `plugin_delta_history` config key.
+    pub fn recent_deltas(&self) -> &[(u64, RopeDelta)] {
+        &self.state.recent_deltas
+    }
+
+    /// Sets how long requests to core, such as those made to fetch text or
+    /// to make edits, wait for a response before failing with
+    /// `Error::TimedOut`. The timeout applies to all later callbacks. By
+    /// default, requests wait indefinitely.
+    pub fn set_request_timeout(&mut self, timeout: Option<Duration>) {
+        self.state.request_timeout = timeout;
+        self.peer.set_request_timeout(timeout);
+    }
+
+    /// Defers `cleanup` until no RPCs are pending, keeping it off the path
+    /// of the current callback; for instance, writing state to disk when
+    /// the view is closed. Deferred work runs in order, and any which is
+    /// still pending when the plugin shuts down runs after `shutdown`.
+    pub fn defer<F: FnOnce() + 'static>(&mut self, cleanup: F) {
+        self.state.cleanup.push(Box::new(cleanup));
+        if self.state.cleanup.len() == 1 {
+            self.schedule_idle(CLEANUP_IDLE_TOKEN);
+        }
+    }
+
+    /// Returns the plugin's data for this view, creating it with
+    /// `T::default()` on first use. This saves plugins from keeping their
+    /// own per-view state alongside the view.
+    ///
+    /// The data is dropped when the view is initialized or closed. A view
+    /// holds a single value: asking for a different type replaces it.
+    pub fn user_data<T: Any + Default>(&mut self) -> &mut T {
+        let is_t = self.state.user_data.as_ref().is_some_and(|data| data.is::<T>());
+        if !is_t {
+            self.state.user_data = Some(Box::new(T::default()));
+        }
+        self.state.user_data.as_mut().and_then(|data| data.downcast_mut()).unwrap()
+    }
+
+    /// Returns this plugin's id, as assigned by core.
+    pub fn get_plugin_id(&self) -> PluginPid {
+        self.peer.plugin_id
+    }
+
+    pub fn get_buf_size(&self) -> usize {
+        self.state.buf_cache.buf_size()
+    }
+
+    /// The revision of the document as of the current callback. This is
+    /// set on init, and by every update.
+    ///
+    /// Results such as spans should be stamped with the revision they were
+    /// computed against, so that core can adjust them for later edits.
+    pub fn revision(&self) -> u64 {
+        self.state.buf_cache.rev()
+    }
+
+    /// The peer for sending RPCs to core. It can be cloned and sent to
+    /// other threads.
+    pub fn get_peer(&self) -> &RpcPeer {
+        self.peer.get_peer()
+    }
+
+    /// Registers `scopes` with core, for use by spans. Like spans, this is
+    /// buffered until the next `flush`.
+    pub fn add_scopes(&self, scopes: &[Vec<String>]) {
+        self.state.outbound.borrow_mut().push(Outbound::Scopes(scopes.to_vec()));
+    }
+
+    /// Replaces the spans in the region of length `len` at `start`. Span
+    /// offsets are relative to `start`. An empty `spans` clears the region.
+    ///
+    /// The region is clipped to the end of the document, and spans to the
+    /// region; spans which are entirely outside of it are dropped.
+    pub fn update_spans(&self, start: usize, len: usize,
+                        spans: &[plugin_rpc::ScopeSpan]) {
+        let buf_size = self.state.buf_cache.buf_size();
+        let (len, spans) = clip_spans(start, len, buf_size, spans);
+        self.send_spans(start.min(buf_size), len, spans);
+    }
+
+    /// Sends the spans in `builder` as `update_spans` does, and clears it,
+    /// keeping its allocation for the next batch of spans.
+    pub fn flush_spans(&self, start: usize, len: usize, builder: &mut SpanBuilder) {
+        let buf_size = self.state.buf_cache.buf_size();
+        let len = clip_spans_in_place(start, len, buf_size, &mut builder.spans);
+        self.send_spans(start.min(buf_size), len, builder.spans.drain(..).collect());
+    }
+
+    /// Publishes `diagnostics` for the document, replacing any previously
+    /// published. Ranges are clamped to the end of the document, and
+    /// diagnostics whose range is inverted are dropped.
+    ///
+    /// Diagnostics are buffered until the next `flush`; if more are
+    /// published before then, only the last are sent.
+    pub fn publish_diagnostics(&self, diagnostics: Vec<Diagnostic>) {
+        let diagnostics = clip_diagnostics(diagnostics, self.state.buf_cache.buf_size());
+        let mut outbound = self.state.outbound.borrow_mut();
+        outbound.retain(|msg| !matches!(msg, Outbound::Diagnostics(_)));
+        outbound.push(Outbound::Diagnostics(diagnostics));
+    }
+
+    /// Sets the ranges of the document which can be folded, replacing any
+    /// set before. Ranges whose first line is after their last, or which
+    /// go past the end of the document, are dropped with a warning.
+    ///
+    /// The ranges are moved along with later edits, line by line, and sent
+    /// to core again when they move, until the plugin sets new ones. Like
+    /// diagnostics, they are buffered until the next `flush`.
+    pub fn set_folding_ranges(&mut self, ranges: Vec<FoldRange>) {
+        let num_lines = self.state.buf_cache.num_lines();
+        let mut folds = Vec::with_capacity(ranges.len());
+        for fold in ranges {
+            if fold.start_line > fold.end_line || fold.end_line >= num_lines {
+                eprintln!("dropping invalid fold range {:?}, in a document of {} lines",
+                          fold, num_lines);
+                continue;
+            }
+            let range = self.line_range(fold.start_line, fold.end_line + 1).ok();
+            let lines = TrackedLines { lines: (fold.start_line, fold.end_line), range };
+            folds.push((fold.kind, lines));
+        }
+        self.state.folds = folds;
+        let ranges = self.folding_ranges();
+        self.send_folding_ranges(ranges);
+    }
+
+    /// Returns the fold ranges last set with `set_folding_ranges`, moved
+    /// through the edits made since.
+    pub fn folding_ranges(&self) -> Vec<FoldRange> {
+        self.state.folds.iter()
+            .map(|&(kind, ref lines)| FoldRange {
+                start_line: lines.lines.0,
+                end_line: lines.lines.1,
+                kind,
+            })
+            .collect()
+    }
+
+    fn send_folding_ranges(&self, ranges: Vec<FoldRange>) {
+        let mut outbound = self.state.outbound.borrow_mut();
+        outbound.retain(|msg| !matches!(msg, Outbound::FoldingRanges(_)));
+        outbound.push(Outbound::FoldingRanges(ranges));
+    }
+
+    /// Sends any buffered spans, scopes, diagnostics and fold ranges to core.
+    ///
+    /// These are not sent as soon as they are set, but buffered, so that
+    /// diagnostics replaced in the same callback are never sent. The buffer
+    /// is flushed, in order, when the callback returns, and before an edit
+    /// is sent with `edit`, so they reach core no later than that; a
+    /// plugin which wants core to see them sooner, in the middle of long
+    /// running work, can call this. Spans sent from other threads, such as
+    /// by a `ThreadedPlugin`, are not buffered.
+    ///
+    /// Spans and diagnostics larger than the limit set by
+    /// `MAX_PAYLOAD_BYTES_KEY` are split across several messages.
+    pub fn flush(&self) {
+        let max_bytes = self.peer.view.config_access()
+            .get_or(MAX_PAYLOAD_BYTES_KEY, DEFAULT_MAX_PAYLOAD_BYTES);
+        let max_bytes = if max_bytes == 0 { None } else { Some(max_bytes) };
+        for msg in self.state.outbound.borrow_mut().drain(..) {
+            match msg {
+                Outbound::Scopes(scopes) => self.peer.add_scopes(&scopes),
+                Outbound::Spans { start, len, rev, spans } =>
+                    self.peer.update_spans_chunked(start, len, rev, &spans, max_bytes),
+                Outbound::Diagnostics(diagnostics) =>
+                    self.peer.publish_diagnostics_chunked(&diagnostics, max_bytes),
+                Outbound::FoldingRanges(ranges) => self.peer.send_folding_ranges(&ranges),
+            }
+        }
+    }
+
+    /// Returns the number of notifications waiting for the next `flush`.
+    pub fn unflushed(&self) -> usize {
+        self.state.outbound.borrow().len()
+    }
+
+    fn send_spans(&self, start: usize, len: usize, spans: Vec<plugin_rpc::ScopeSpan>) {
+        let rev = self.state.buf_cache.rev();
+        self.state.outbound.borrow_mut().push(Outbound::Spans { start, len, rev, spans });
+    }
+
+    /// Sets the spans for the whole document, sending core only those which
+    /// have changed since the spans were last set. Span offsets are absolute,
+    /// and spans must be sorted and must not overlap.
+    ///
+    /// Previously set spans are moved along with edits, and spans touched by
+    /// an edit are treated as changed. The changes are sent as a single
+    /// region; if most spans have changed, all spans are sent. Plugins which
+    /// use this should not also use `update_spans`.
+    pub fn set_spans(&mut self, spans: Vec<plugin_rpc::ScopeSpan>) {
+        let buf_size = self.state.buf_cache.buf_size();
+        let (_, spans) = clip_spans(0, buf_size, buf_size, &spans);
+        let region = match self.state.published_spans {
+            Some(ref published) => diff_spans(published, &spans, buf_size),
+            None => Some((0, buf_size, spans.clone())),
+        };
+        if let Some((start, len, changed)) = region {
+            self.send_spans(start, len, changed);
+        }
+        self.state.published_spans = Some(spans.into_iter().map(Some).collect());
+    }
+
+    /// Asks core to apply `delta` to the document. The edit is made against
+    /// the current revision, and is rejected with `Error::StaleRevision` if
+    /// core has a newer one; in that case the plugin will receive an update,
+    /// and can retry, or `rebase_and_retry` can do so for it.
+    ///
+    /// `priority` determines the resolution strategy when merging concurrent
+    /// edits, and `after_cursor` whether inserted text is placed after the
+    /// cursor. `author` identifies the plugin in the resulting update; pass
+    /// `author()` for the update not to be passed back to the plugin.
+    ///
+    /// Line breaks in inserted text are converted to the document's style;
+    /// see `line_ending`.
+    pub fn edit(&self, mut delta: RopeDelta, priority: u64, after_cursor: bool,
+                author: &str) -> Result<(), Error> {
+        let line_ending = self.line_ending();
+        for el in delta.els.iter_mut() {
+            if let DeltaElement::Insert(ref mut text) = *el {
+                let normalized = line_ending.normalize(&String::from(&*text));
+                *text = Rope::from(normalized);
+            }
+        }
+        let edit = plugin_rpc::PluginEdit {
+            rev: self.state.buf_cache.rev(),
+            delta,
+            priority,
+            after_cursor,
+            author: author.to_owned(),
+        };
+        self.flush();
+        self.peer.edit(&edit)
+    }
+
+    /// Moves `delta`, made against the revision `original_rev`, past the
+    /// edits made since, and asks core to apply it, as `edit` does. This
+    /// suits edits computed from an older revision, such as one which was
+    /// rejected with `Error::StaleRevision`.
+    ///
+    /// Fails with `Error::EditConflict` if an edit made since overlaps
+    /// `delta`, or if `original_rev` is older than `recent_deltas` go back.
+    /// If core rejects the edit as stale, because updates are still on their
+    /// way, it is kept, and rebased and retried when they arrive, up to
+    /// `MAX_EDIT_RETRIES` times; if it then fails, `Plugin::edit_failed` is
+    /// called.
+    pub fn rebase_and_retry(&mut self, delta: RopeDelta, original_rev: u64, priority: u64,
+                            after_cursor: bool, author: &str) -> Result<(), Error> {
+        let delta = self.rebase(delta, original_rev)?;
+        self.submit_edit(PendingEdit {
+            delta,
+            rev: self.state.buf_cache.rev(),
+            head_rev: None,
+            priority,
+            after_cursor,
+            author: author.to_owned(),
+            attempts: 0,
+        })
+    }
+
+    /// Moves `delta` from `rev` to the current revision.
+    fn rebase(&self, delta: RopeDelta, rev: u64) -> Result<RopeDelta, Error> {
+        let history = &self.state.recent_deltas;
+        let start = if rev == self.state.buf_cache.rev() {
+            history.len()
+        } else if rev == self.state.deltas_base_rev {
+            0
         } else {
-            self.state.frontier[0] = new_frontier;
+            match history.iter().position(|&(delta_rev, _)| delta_rev == rev) {
+                Some(ix) => ix + 1,
+                None => return Err(Error::EditConflict),
+            }
+        };
+        history[start..].iter()
+            .try_fold(delta, |delta, (_, over)| rebase_delta(&delta, over))
+            .ok_or(Error::EditConflict)
+    }
+
+    /// Asks core to apply `pending`, keeping it to retry if core has moved
+    /// on and it has retries left.
+    fn submit_edit(&mut self, mut pending: PendingEdit) -> Result<(), Error> {
+        let result = self.edit(pending.delta.clone(), pending.priority, pending.after_cursor,
+                               &pending.author);
+        match result {
+            Err(Error::StaleRevision { head_rev }) if pending.attempts < MAX_EDIT_RETRIES => {
+                pending.attempts += 1;
+                pending.head_rev = head_rev;
+                self.state.pending_edits.push(pending);
+                Ok(())
+            }
+            result => result,
         }
     }
 
-    /// Closes the current frontier. This is the correct choice to handle
-    /// EOF.
-    pub fn close_frontier(&mut self) {
-        self.state.frontier.remove(0);
+    /// The document's line ending style, as detected in the text cached on
+    /// init and on save. If no line breaks have been seen, this is the
+    /// style set by the `line_ending` config.
+    pub fn line_ending(&self) -> LineEnding {
+        self.state.line_ending.unwrap_or_else(|| {
+            match self.peer.view.config.as_ref().map(|c| c.line_ending.as_str()) {
+                Some("\r\n") => LineEnding::CrLf,
+                _ => LineEnding::Lf,
+            }
+        })
     }
-}
 
-fn count_newlines(s: &str) -> usize {
-    bytecount::count(s.as_bytes(), b'\n')
+    /// The author which identifies edits made by this plugin; see
+    /// `Plugin::wants_own_edits`.
+    pub fn author(&self) -> String {
+        json!(self.peer.plugin_id).to_string()
+    }
+
+    /// Returns `true` if the whole document is in the cache, so that reading
+    /// it does not require a round-trip to core. Core sends the text of
+    /// small documents on init; larger ones are fetched as needed.
+    pub fn is_fully_cached(&self) -> bool {
+        self.state.buf_cache.is_fully_cached()
+    }
+
+    /// Checks the cache against core, which costs at least two requests.
+    /// If it differs, or can't be checked, an error is logged, the view is
+    /// reset as with `request_full_reset`, and `false` is returned.
+    ///
+    /// This runs automatically after updates if `VERIFY_CACHE_KEY` is set.
+    pub fn verify_cache(&mut self) -> bool {
+        let problem = match self.state.buf_cache.verify(&self.peer) {
+            Ok(true) => return true,
+            Ok(false) => "is inconsistent with core".to_owned(),
+            Err(err) => format!("could not be verified: {:?}", err),
+        };
+        eprintln!("cache for plugin {:?}, view {} at rev {} {}; resetting it",
+                  self.peer.plugin_id, self.peer.view.view_id, self.state.buf_cache.rev(),
+                  problem);
+        self.clear_caches();
+        false
+    }
+
+    /// Clears everything cached for the view, for when the plugin finds
+    /// that it is out of step with core, or its saved state doesn't match
+    /// the document. The view itself is unaffected.
+    ///
+    /// The document's text and line states are cleared, and fetched again
+    /// as needed, as is any snapshot. The record of published spans is
+    /// cleared, so that the next spans are sent in full, and watched regions
+    /// are dropped. `Plugin::did_reset` is called once idle, where these can
+    /// be rebuilt.
+    ///
+    /// The document's size and line count are checked against core, and a
+    /// mismatch is logged; they are corrected by the next update.
+    pub fn request_full_reset(&mut self) {
+        self.clear_caches();
+        match self.state.buf_cache.verify(&self.peer) {
+            Ok(true) => (),
+            Ok(false) => eprintln!("size or line count of view {} at rev {} is \
+                                   inconsistent with core", self.peer.view.view_id,
+                                   self.state.buf_cache.rev()),
+            Err(err) => eprintln!("unable to check size of view {}: {:?}",
+                                  self.peer.view.view_id, err),
+        }
+    }
+
+    fn clear_caches(&mut self) {
+        self.state.buf_cache.clear();
+        self.state.snapshot = None;
+        self.state.published_spans = None;
+        self.state.watched_regions.clear();
+        self.reset();
+        if !self.state.idle_tokens.contains_key(&RESET_IDLE_TOKEN) {
+            self.schedule_idle(RESET_IDLE_TOKEN);
+        }
+    }
+
+    /// Returns the view's selections, as byte ranges. Each is empty for a
+    /// caret; there are several with multiple cursors. This is empty until
+    /// core first reports a change to the selections.
+    pub fn selections(&self) -> &[Range<usize>] {
+        &self.state.selections
+    }
+
+    /// Returns `true` if the view has focus. Views are unfocused until core
+    /// reports otherwise.
+    pub fn is_focused(&self) -> bool {
+        self.state.focused
+    }
+
+    /// Returns the first and last lines visible in the view, moved through
+    /// the edits made since core reported them. This is `None` until core
+    /// first reports the viewport.
+    pub fn viewport(&self) -> Option<(usize, usize)> {
+        self.state.viewport.as_ref().map(|viewport| viewport.lines)
+    }
+
+    /// Returns `false` while `Plugin::initialize` is deferred for the view;
+    /// see `Plugin::should_defer_init`.
+    pub fn is_initialized(&self) -> bool {
+        !self.state.init_deferred
+    }
+
+    /// Asks for a deferred `Plugin::initialize` to be run once idle; for
+    /// instance, when a command needs the plugin's analysis of the view.
+    /// Does nothing if the plugin has already been initialized.
+    pub fn request_init(&mut self) {
+        if self.state.init_deferred && !self.state.idle_tokens.contains_key(&INIT_IDLE_TOKEN) {
+            self.schedule_idle(INIT_IDLE_TOKEN);
+        }
+    }
+
+    /// Returns the offset up to which the document has been loaded, if it is
+    /// being loaded progressively; see `Plugin::wants_progressive_load`.
+    pub fn load_progress(&self) -> Option<usize> {
+        self.state.load_progress
+    }
+
+    /// Returns the plugin's persistent key-value store, which is shared by
+    /// all of its views, and outlives them; see `Plugin::store_dir`. This
+    /// is `None` before init, or if the plugin has no store.
+    pub fn store(&self) -> Option<&PluginStore> {
+        self.state.store.as_ref()
+    }
+
+    /// Returns the number of bytes of document text in the cache. This is
+    /// kept within the budget set by `CACHE_MAX_BYTES_KEY`, except while a
+    /// single larger line or region is being read.
+    pub fn cache_bytes(&self) -> usize {
+        self.state.buf_cache.cached_bytes()
+    }
+
+    /// Runs `f`, recording how long it takes as a trace sample named `name`,
+    /// in the "plugin" category, if tracing is enabled; samples are returned
+    /// to core by `collect_trace`. Otherwise `f` is simply called.
+    pub fn traced<T, F>(&self, name: &str, f: F) -> T
+        where F: FnOnce() -> T
+    {
+        if xi_trace::is_enabled() {
+            xi_trace::trace_closure(name.to_owned(), &["plugin"], f)
+        } else {
+            f()
+        }
+    }
+
+    /// Determines whether an incoming request (or notification) is pending. This
+    /// is intended to reduce latency for bulk operations done in the background.
+    pub fn request_is_pending(&self) -> bool {
+        self.peer.request_is_pending()
+    }
+
+    /// Schedules background work of `kind`, to be passed to
+    /// `Plugin::do_work` once no RPCs are pending. Scheduling a kind which
+    /// is already pending has no effect, so a plugin can schedule, say, a
+    /// relint after every update, and have it run once. Pending work is
+    /// dropped if the view is closed.
+    pub fn schedule_work(&mut self, kind: WorkKind) {
+        if !self.state.pending_work.contains(&kind) {
+            self.state.pending_work.push(kind);
+        }
+        if !self.state.idle_tokens.contains_key(&WORK_IDLE_TOKEN) {
+            self.schedule_idle(WORK_IDLE_TOKEN);
+        }
+    }
+
+    /// Returns the kinds of work scheduled with `schedule_work` which have
+    /// not yet run, in the order they were scheduled.
+    pub fn pending_work(&self) -> &[WorkKind] {
+        &self.state.pending_work
+    }
+
+    /// Schedule the idle handler to be run when there are no requests pending.
+    ///
+    /// This is how a view queues itself for background work. Core starts a
+    /// separate plugin process for each buffer, so each has its own queue,
+    /// and work on one file can't starve another. Within the process, tokens
+    /// are served in the order they were scheduled, one callback at a time,
+    /// and a token whose callback returns `Idle::Continue` goes to the back
+    /// of the queue, so that different kinds of work take turns.
+    pub fn schedule_idle(&mut self, token: usize) {
+        *self.state.idle_tokens.entry(token).or_insert(0) += 1;
+        self.peer.schedule_idle(token);
+    }
+
+    /// Schedule the idle handler to be run with `token` once `delay` has
+    /// elapsed, and there are no requests pending. This is useful for
+    /// debouncing work.
+    ///
+    /// Delays are measured on a single helper thread, and the runloop only
+    /// checks for idle work scheduled from other threads periodically, so
+    /// the callback may run up to ~100ms after the delay has elapsed.
+    pub fn schedule_idle_after(&mut self, token: usize, delay: Duration) {
+        *self.state.idle_tokens.entry(token).or_insert(0) += 1;
+        if self.state.idle_timer.is_none() {
+            self.state.idle_timer = Some(IdleTimer::start(self.peer.get_peer().clone()));
+        }
+        self.state.idle_timer.as_ref().unwrap().schedule(token, delay);
+    }
+
+    /// Watches the byte range `range` of the document, so that
+    /// `Plugin::region_changed` is called with `token` when an edit touches
+    /// it. The region is moved through later edits, so that it keeps
+    /// covering the same text, until `unwatch_region` is called with `token`;
+    /// text inserted at either end of the region becomes part of it. A token
+    /// may be used for several regions.
+    pub fn watch_region(&mut self, range: Range<usize>, token: usize) {
+        self.state.watched_regions.push((token, range));
+    }
+
+    /// Stops watching all regions watched with `token`.
+    pub fn unwatch_region(&mut self, token: usize) {
+        self.state.watched_regions.retain(|&(t, _)| t != token);
+    }
+
+    /// Returns the tokens, other than those reserved by this library, with
+    /// idle callbacks which are scheduled and have not been cancelled.
+    /// Callbacks are cancelled by core, or when the view is closed.
+    pub fn scheduled_idle_tokens(&self) -> Vec<usize> {
+        self.state.idle_tokens.iter()
+            .filter(|&(&token, &pending)| {
+                token < FIRST_RESERVED_IDLE_TOKEN &&
+                    self.state.cancelled_tokens.get(&token).map_or(true, |&n| n < pending)
+            })
+            .map(|(&token, _)| token)
+            .collect()
+    }
+
+    /// Returns `true` if core has cancelled idle work scheduled with `token`.
+    /// Long running work should check this, and stop early if it is set.
+    pub fn is_cancelled(&self, token: usize) -> bool {
+        self.state.cancelled_tokens.contains_key(&token)
+    }
+
+    /// Returns a token which is cancelled when the document is next edited,
+    /// for work running on other threads to check.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.state.cancellation.clone()
+    }
+
+    /// Find an entry in the cache by line num. On return `Ok(i)` means entry
+    /// at index `i` is an exact match, while `Err(i)` means the entry would be
+    /// inserted at `i`.
+    fn find_line(&self, line_num: usize) -> Result<usize, usize> {
+        self.state.state_cache.binary_search_by(|probe| probe.line_num.cmp(&line_num))
+    }
+
+    /// Find an entry in the cache by offset. Similar to `find_line`.
+    pub fn find_offset(&self, offset: usize) -> Result<usize, usize> {
+        self.state.state_cache.binary_search_by(|probe| probe.offset.cmp(&offset))
+    }
+
+    /// Get the state from the nearest cache entry at or before given line number.
+    /// Returns line number, offset, and user state.
+    pub fn get_prev(&self, line_num: usize) -> (usize, usize, S) {
+        if line_num > 0 {
+            let mut ix = match self.find_line(line_num) {
+                Ok(ix) => ix,
+                Err(0) => return (0, 0, S::default()),
+                Err(ix) => ix - 1,
+            };
+            loop {
+                let item = &self.state.state_cache[ix];
+                if let Some(ref s) = item.user_state {
+                    return (item.line_num, item.offset, s.clone());
+                }
+                if ix == 0 { break; }
+                ix -= 1;
+            }
+        }
+        (0, 0, S::default())
+    }
+
+    /// Get the state at the given line number, if it exists in the cache.
+    pub fn get(&self, line_num: usize) -> Option<&S> {
+        self.find_line(line_num).ok()
+            .and_then(|ix| self.state.state_cache[ix].user_state.as_ref())
+    }
+
+    /// Set the state at the given line number. Note: has no effect if line_num
+    /// references the end of the partial line at EOF.
+    pub fn set(&mut self, line_num: usize, s: S) {
+        if let Some(entry) = self.get_entry(line_num) {
+            entry.user_state = Some(s);
+        }
+    }
+
+    /// Get the cache entry at the given line number, creating it if necessary.
+    /// Returns None if line_num > number of newlines in doc (ie if it references
+    /// the end of the partial line at EOF).
+    fn get_entry(&mut self, line_num: usize) -> Option<&mut CacheEntry<S>> {
+        match self.find_line(line_num) {
+            Ok(ix) => Some(&mut self.state.state_cache[ix]),
+            Err(_ix) => {
+                if line_num == self.state.buf_cache.num_lines() {
+                    None
+                } else {
+                    let offset = self.state.buf_cache.offset_of_line(&self.peer, line_num)
+                        .expect("get_entry should validate inputs");
+                    let new_ix = self.insert_entry(line_num, offset, None);
+                    Some(&mut self.state.state_cache[new_ix])
+                }
+            }
+        }
+    }
+
+    /// Insert a new entry into the cache, returning its index.
+    fn insert_entry(&mut self, line_num: usize, offset: usize, user_state: Option<S>) -> usize {
+        if self.state.state_cache.len() >= CACHE_SIZE {
+            self.evict();
+        }
+        match self.find_line(line_num) {
+            Ok(_ix) => panic!("entry already exists"),
+            Err(ix) => {
+                self.state.state_cache.insert(ix, CacheEntry {
+                    line_num, offset, user_state
+                });
+                ix
+            }
+        }
+    }
+
+    /// Evict one cache entry.
+    fn evict(&mut self) {
+        let ix = self.choose_victim();
+        self.state.state_cache.remove(ix);
+    }
+
+    fn choose_victim(&self) -> usize {
+        let mut best = None;
+        let mut rng = thread_rng();
+        for _ in 0..NUM_PROBES {
+            let ix = rng.gen_range(0, self.state.state_cache.len());
+            let gap = self.compute_gap(ix);
+            if best.map(|(last_gap, _)| gap < last_gap).unwrap_or(true) {
+                best = Some((gap, ix));
+            }
+        }
+        best.unwrap().1
+    }
+
+    /// Compute the gap that would result after deleting the given entry.
+    fn compute_gap(&self, ix: usize) -> usize {
+        let before = if ix == 0 { 0 } else { self.state.state_cache[ix - 1].offset };
+        let after = if let Some(item) = self.state.state_cache.get(ix + 1) {
+            item.offset
+        } else {
+            self.state.buf_cache.buf_size()
+        };
+        assert!(after >= before, "{} < {} ix: {}", after, before, ix);
+        after - before
+    }
+
+    /// Get the line at the given line. Returns empty string if at EOF.
+    pub fn get_line(&mut self, line_num: usize) -> Result<&str, Error> {
+        self.state.buf_cache.get_line(&self.peer, line_num)
+    }
+
+    /// Returns an iterator over the document's lines, along with their line
+    /// numbers. Lines include their trailing newline; if the document ends
+    /// with a newline, there is no empty line after it. Text is fetched from
+    /// core as needed, so the whole document is never held in memory at once.
+    ///
+    /// Iteration stops early if text can't be fetched from core.
+    pub fn lines<'b>(&'b mut self) -> Lines<'b, 'a, S> {
+        Lines { ctx: self, line_num: 0 }
+    }
+
+    /// Returns the lines from `start_line` up to but not including
+    /// `end_line`, each with its trailing newline, as `lines` does. Line
+    /// numbers are translated to offsets with the cache's line index, and
+    /// only that region is fetched from core if it is not cached. Lines past
+    /// the end of the document are left out, so fewer lines than asked for
+    /// may be returned.
+    pub fn get_lines(&mut self, start_line: usize, end_line: usize)
+                     -> Result<Vec<String>, Error> {
+        let num_lines = self.state.buf_cache.num_lines();
+        let end_line = end_line.min(num_lines);
+        if start_line >= end_line {
+            return Ok(Vec::new());
+        }
+        let start = self.state.buf_cache.offset_of_line(&self.peer, start_line)?;
+        let end = match end_line {
+            line if line == num_lines => self.state.buf_cache.buf_size(),
+            line => self.state.buf_cache.offset_of_line(&self.peer, line)?,
+        };
+        let text = self.state.buf_cache.get_region(&self.peer, start, end)?;
+        Ok(text.split_inclusive('\n').map(String::from).collect())
+    }
+
+    /// Returns the text in the byte range `start..end`, fetching it from
+    /// core if it is not cached.
+    ///
+    /// Fetching blocks until core responds, so this should be used
+    /// sparingly; plugins which need the whole document should prefer
+    /// `get_line` and incremental processing.
+    pub fn get_region(&mut self, start: usize, end: usize) -> Result<String, Error> {
+        self.state.buf_cache.get_region(&self.peer, start, end).map(String::from)
+    }
+
+    /// Returns a snapshot of the whole document at the current revision,
+    /// fetching it from core if it is not cached. Later calls at the same
+    /// revision return the same snapshot.
+    pub fn snapshot(&mut self) -> Result<DocumentSnapshot, Error> {
+        let rev = self.state.buf_cache.rev();
+        if let Some(snapshot) = self.state.snapshot.as_ref().filter(|s| s.rev == rev) {
+            return Ok(snapshot.clone());
+        }
+        let len = self.state.buf_cache.buf_size();
+        let text = self.state.buf_cache.get_region(&self.peer, 0, len)?.into();
+        let snapshot = DocumentSnapshot { text, rev };
+        self.state.snapshot = Some(snapshot.clone());
+        Ok(snapshot)
+    }
+
+    /// Returns statistics about the document, without contacting core.
+    /// The byte and line counts are tracked with each update, so are
+    /// always exact. Characters are counted if the whole document is cached
+    /// or has been snapshotted at the current revision, which takes a scan
+    /// of the text; otherwise they are estimated. Use `exact_stats` if an
+    /// exact count is needed.
+    pub fn stats(&self) -> DocumentStats {
+        let bytes = self.state.buf_cache.buf_size();
+        let rev = self.state.buf_cache.rev();
+        let text = match self.state.snapshot.as_ref().filter(|s| s.rev == rev) {
+            Some(snapshot) => Some(snapshot.text()),
+            None => self.state.buf_cache.cached_region(0, bytes),
+        };
+        DocumentStats {
+            bytes,
+            chars: text.map(|text| text.chars().count()).unwrap_or(bytes),
+            chars_exact: text.is_some(),
+            lines: self.state.buf_cache.num_lines(),
+        }
+    }
+
+    /// Like `stats`, but always counts characters exactly, taking a
+    /// `snapshot` of the document to do so if needed, which fetches it from
+    /// core if it is not cached.
+    pub fn exact_stats(&mut self) -> Result<DocumentStats, Error> {
+        let stats = self.stats();
+        if stats.chars_exact {
+            return Ok(stats);
+        }
+        let chars = self.snapshot()?.text().chars().count();
+        Ok(DocumentStats { chars, chars_exact: true, ..stats })
+    }
+
+    /// Returns the words in the byte range `range` of the document, along
+    /// with their ranges, fetching the text from core if it is not cached.
+    /// Ranges are byte offsets into the document, so they can be used for
+    /// spans; words which straddle the ends of `range` are cut short.
+    ///
+    /// A word is a run of alphanumeric characters (in the Unicode sense)
+    /// and underscores, which may contain single apostrophes, as in "don't".
+    /// This is simpler than Unicode's word boundaries (UAX #29): scripts
+    /// written without spaces, such as Japanese, come out as one word per
+    /// run of text.
+    pub fn words(&mut self, range: Range<usize>) -> Result<Vec<(Range<usize>, String)>, Error> {
+        let offset = range.start;
+        let text = self.get_region(range.start, range.end)?;
+        Ok(word_ranges(&text)
+           .map(|word| (offset + word.start..offset + word.end, text[word].to_owned()))
+           .collect())
+    }
+
+    /// Returns the user-perceived characters in the byte range `range` of
+    /// the document, along with their ranges, in the same way as `words`.
+    ///
+    /// A character carries along the combining marks, variation selectors
+    /// and emoji modifiers which follow it, and characters joined by a
+    /// zero-width joiner, as in emoji sequences, stay together, as does
+    /// "\r\n". This covers common text, but is simpler than Unicode's
+    /// grapheme clusters (UAX #29); for instance, Hangul syllables written
+    /// as separate jamo come out as one character per jamo.
+    pub fn graphemes(&mut self, range: Range<usize>)
+                     -> Result<Vec<(Range<usize>, String)>, Error> {
+        let offset = range.start;
+        let text = self.get_region(range.start, range.end)?;
+        Ok(grapheme_ranges(&text)
+           .map(|g| (offset + g.start..offset + g.end, text[g].to_owned()))
+           .collect())
+    }
+
+    /// Converts a byte offset into a line number and a UTF-16 column, as used
+    /// by the language server protocol. See `Cache::offset_to_line_col`.
+    pub fn offset_to_line_col(&mut self, offset: usize) -> Result<(usize, usize), Error> {
+        self.state.buf_cache.offset_to_line_col(&self.peer, offset)
+    }
+
+    /// Converts a line number and a UTF-16 column into a byte offset.
+    /// See `Cache::line_col_to_offset`.
+    pub fn line_col_to_offset(&mut self, line_num: usize, col: usize) -> Result<usize, Error> {
+        self.state.buf_cache.line_col_to_offset(&self.peer, line_num, col)
+    }
+
+    /// Release all state _after_ the given offset.
+    fn truncate_cache(&mut self, offset: usize) {
+        let (line_num, ix) = match self.find_offset(offset) {
+            Ok(ix) => (self.state.state_cache[ix].line_num, ix + 1),
+            Err(ix) => (
+                if ix == 0 { 0 } else {
+                    self.state.state_cache[ix - 1].line_num
+                },
+                ix
+            ),
+        };
+        self.truncate_frontier(line_num);
+        self.state.state_cache.truncate(ix);
+    }
+
+    fn truncate_frontier(&mut self, line_num: usize) {
+        match self.state.frontier.binary_search(&line_num) {
+            Ok(ix) => self.state.frontier.truncate(ix + 1),
+            Err(ix) => {
+                self.state.frontier.truncate(ix);
+                self.state.frontier.push(line_num);
+            }
+        }
+    }
+
+    /// Updates the line cache to reflect this delta.
+    fn update_line_cache(&mut self, delta: &RopeDelta) {
+        let (iv, new_len) = delta.summary();
+        if let Some(n) = delta.as_simple_insert() {
+            assert_eq!(iv.size(), 0);
+            assert_eq!(new_len, n.len());
+
+            let newline_count = n.measure::<LinesMetric>();
+            self.line_cache_simple_insert(iv.start(), new_len, newline_count);
+        } else if delta.is_simple_delete() {
+            assert_eq!(new_len, 0);
+            self.line_cache_simple_delete(iv.start(), iv.end())
+        } else {
+            self.clear_to_start(iv.start());
+        }
+    }
+
+    fn line_cache_simple_insert(&mut self, start: usize, new_len: usize,
+                                newline_num: usize) {
+        let ix = match self.find_offset(start) {
+            Ok(ix) => ix + 1,
+            Err(ix) => ix,
+        };
+
+        for entry in &mut self.state.state_cache[ix..] {
+            entry.line_num += newline_num;
+            entry.offset += new_len;
+        }
+        self.patchup_frontier(ix, newline_num as isize);
+    }
+
+    fn line_cache_simple_delete(&mut self, start: usize, end: usize) {
+        if let Some(deleted) = self.state.buf_cache.cached_region(start, end) {
+            let del_newline_num = count_newlines(deleted);
+            // delete all entries that overlap the deleted range
+            let ix = match self.find_offset(start) {
+                Ok(ix) => ix + 1,
+                Err(ix) => ix,
+            };
+            while ix < self.state.state_cache.len() &&
+                self.state.state_cache[ix].offset <= end {
+                    self.state.state_cache.remove(ix);
+            }
+            for entry in &mut self.state.state_cache[ix..] {
+                entry.line_num -= del_newline_num;
+                entry.offset -= end - start;
+            }
+            self.patchup_frontier(ix, -(del_newline_num as isize));
+        } else {
+            // if this region isn't in our chunk we can't correctly adjust newlines
+            self.clear_to_start(start);
+        }
+    }
+
+    fn patchup_frontier(&mut self, cache_idx: usize, nl_count_delta: isize) {
+        let line_num = match cache_idx {
+            0 => 0,
+            ix => self.state.state_cache[ix - 1].line_num,
+        };
+        let mut new_frontier = Vec::new();
+        let mut need_push = true;
+        for old_ln in &self.state.frontier {
+            if *old_ln < line_num {
+                new_frontier.push(*old_ln);
+            } else {
+                if need_push {
+                    new_frontier.push(line_num);
+                    need_push = false;
+                    if let Some(ref entry) = self.state.state_cache.get(cache_idx) {
+                        if *old_ln >= entry.line_num {
+                            new_frontier.push(old_ln.wrapping_add(nl_count_delta as usize));
+                        }
+                    }
+                }
+            }
+        }
+        if need_push {
+            new_frontier.push(line_num);
+        }
+        self.state.frontier = new_frontier;
+    }
+
+    /// Clears any cached text and anything in the state cache before `start`.
+    fn clear_to_start(&mut self, start: usize) {
+        self.truncate_cache(start);
+    }
+
+    /// Clear all state and reset frontier to start.
+    pub fn reset(&mut self) {
+        self.truncate_cache(0);
+    }
+
+    /// The frontier keeps track of work needing to be done. A typical
+    /// user will call `get_frontier` to get a line number, do the work
+    /// on that line, insert state for the next line, and then call either
+    /// `update_frontier` or `close_frontier` depending on whether there
+    /// is more work to be done at that location.
+    pub fn get_frontier(&self) -> Option<usize> {
+        self.state.frontier.first().cloned()
+    }
+
+    /// Updates the frontier. This can go backward, but most typically
+    /// goes forward by 1 line (compared to the `get_frontier` result).
+    pub fn update_frontier(&mut self, new_frontier: usize) {
+        if self.state.frontier.get(1) == Some(&new_frontier) {
+            self.state.frontier.remove(0);
+        } else {
+            self.state.frontier[0] = new_frontier;
+        }
+    }
+
+    /// Closes the current frontier. This is the correct choice to handle
+    /// EOF.
+    pub fn close_frontier(&mut self) {
+        self.state.frontier.remove(0);
+    }
+}
+
+/// An iterator over the lines of the document. See `PluginCtx::lines`.
+pub struct Lines<'b, 'a: 'b, S: 'a> {
+    ctx: &'b mut PluginCtx<'a, S>,
+    line_num: usize,
+}
+
+impl<'b, 'a, S: Default + Clone> Iterator for Lines<'b, 'a, S> {
+    type Item = (usize, String);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line_num = self.line_num;
+        if line_num >= self.ctx.state.buf_cache.num_lines() {
+            return None;
+        }
+        let line = match self.ctx.get_line(line_num) {
+            Ok("") => return None,
+            Ok(line) => line.to_owned(),
+            Err(err) => {
+                eprintln!("failed to fetch line {}: {:?}", line_num, err);
+                return None;
+            }
+        };
+        self.line_num += 1;
+        Some((line_num, line))
+    }
+}
+
+/// The error code used by `update_error`, that of `PluginError::Internal`.
+pub const UPDATE_ERROR_CODE: i64 = plugin_base::INTERNAL_ERROR;
+
+/// Creates an error reporting that the plugin could not handle the update
+/// to revision `rev` of `view_id`. The view id and revision are included
+/// in the error's data, so that core can correlate the failure.
+pub fn update_error<S: AsRef<str>>(view_id: ViewIdentifier, rev: u64, msg: S)
+    -> RemoteError
+{
+    RemoteError::custom(UPDATE_ERROR_CODE, msg, json!({
+        "view_id": view_id,
+        "rev": rev,
+    }))
+}
+
+/// Checks that `delta` applies to a document of `base_len` bytes, and
+/// produces one of `new_len` bytes.
+fn check_delta(delta: &RopeDelta, base_len: usize, new_len: usize) -> Result<(), String> {
+    if delta.base_len != base_len {
+        return Err(format!("delta is for a document of {} bytes, not {}",
+                           delta.base_len, base_len));
+    }
+    for el in &delta.els {
+        if let DeltaElement::Copy(start, end) = *el {
+            if start > end || end > base_len {
+                return Err(format!("delta copies {}..{} of a document of {} bytes",
+                                   start, end, base_len));
+            }
+        }
+    }
+    if delta.new_document_len() != new_len {
+        return Err(format!("delta produces a document of {} bytes, not {}",
+                           delta.new_document_len(), new_len));
+    }
+    Ok(())
+}
+
+/// Identifies the notifications which supersede one another: the kind of
+/// notification and the view it is for.
+type NotificationKey = (&'static str, ViewIdentifier);
+
+/// Returns the key of a coalescible notification, one which only reports
+/// the latest state of the view, so that once a newer one of the same kind
+/// has arrived, an older one can be dropped unseen. These are
+/// `selection_changed`, `focus_changed` and `viewport_changed`.
+///
+/// Every other notification must be delivered: those which report events,
+/// such as `did_save` and `did_close`; those which carry changes, such as
+/// `config_changed`, whose changes are instead merged and debounced (see
+/// `Plugin::config_is_immediate`); and lifecycle ones such as `initialize`
+/// and `shutdown`. Requests, including `update`, are never held back.
+fn coalesce_key(rpc: &plugin_rpc::HostNotification) -> Option<NotificationKey> {
+    use self::plugin_rpc::HostNotification::*;
+    match *rpc {
+        SelectionChanged { view_id, .. } => Some(("selection_changed", view_id)),
+        FocusChanged { view_id, .. } => Some(("focus_changed", view_id)),
+        ViewportChanged { view_id, .. } => Some(("viewport_changed", view_id)),
+        _ => None,
+    }
+}
+
+/// Names an idle token for `debug_state`, after the work it runs if it is
+/// reserved, or else by its number.
+fn idle_token_name(token: usize) -> String {
+    match token {
+        BATCH_IDLE_TOKEN => "batch".into(),
+        CONFIG_IDLE_TOKEN => "config".into(),
+        CLEANUP_IDLE_TOKEN => "cleanup".into(),
+        VERIFY_IDLE_TOKEN => "verify".into(),
+        RESET_IDLE_TOKEN => "reset".into(),
+        LOAD_IDLE_TOKEN => "load".into(),
+        INIT_IDLE_TOKEN => "init".into(),
+        NOTIFY_IDLE_TOKEN => "notify".into(),
+        WORK_IDLE_TOKEN => "work".into(),
+        token => token.to_string(),
+    }
+}
+
+/// Clamps a hover's range to a document of `buf_size` bytes, dropping it if
+/// it is inverted.
+fn clip_hover(hover: Hover, buf_size: usize) -> Hover {
+    let range = hover.range
+        .filter(|&(start, end)| start <= end)
+        .map(|(start, end)| (start.min(buf_size), end.min(buf_size)));
+    Hover { range, ..hover }
+}
+
+/// Serializes `val` as the result of an RPC. If serialization fails, the
+/// error is reported to core with the same code as failed updates.
+pub fn to_rpc_result<T: Serialize>(val: T) -> Result<Value, RemoteError> {
+    serde_json::to_value(val).map_err(|err| {
+        PluginError::Internal(format!("failed to serialize result: {}", err)).into()
+    })
+}
+
+/// Adds the view id and revision of a failed update to a custom error's data.
+/// Existing data is preserved; if it is not an object, it is moved under
+/// the `"data"` key.
+fn with_update_info(err: RemoteError, view_id: ViewIdentifier, rev: u64) -> RemoteError {
+    match err {
+        RemoteError::Custom { code, message, data } => {
+            let data = match data {
+                Some(Value::Object(mut obj)) => {
+                    obj.insert("view_id".into(), json!(view_id));
+                    obj.insert("rev".into(), json!(rev));
+                    Value::Object(obj)
+                }
+                Some(other) => json!({"view_id": view_id, "rev": rev, "data": other}),
+                None => json!({"view_id": view_id, "rev": rev}),
+            };
+            RemoteError::custom(code, message, data)
+        }
+        other => other,
+    }
+}
+
+/// Clips the region of length `len` at `start` to `buf_size`, and the spans
+/// in that region to the clipped region. Returns the clipped length and spans.
+fn clip_spans(start: usize, len: usize, buf_size: usize,
+              spans: &[plugin_rpc::ScopeSpan]) -> (usize, Vec<plugin_rpc::ScopeSpan>) {
+    let mut spans = spans.to_vec();
+    let len = clip_spans_in_place(start, len, buf_size, &mut spans);
+    (len, spans)
+}
+
+/// As `clip_spans`, but clips `spans` in place, returning the clipped length.
+fn clip_spans_in_place(start: usize, len: usize, buf_size: usize,
+                       spans: &mut Vec<plugin_rpc::ScopeSpan>) -> usize {
+    let len = len.min(buf_size.saturating_sub(start));
+    spans.retain(|span| span.start < len && span.start < span.end);
+    for span in spans.iter_mut() {
+        span.end = span.end.min(len);
+    }
+    len
+}
+
+/// Clamps the ranges of `diagnostics` to `buf_size`, dropping (and logging)
+/// any whose range is inverted.
+fn clip_diagnostics(diagnostics: Vec<Diagnostic>, buf_size: usize) -> Vec<Diagnostic> {
+    diagnostics.into_iter()
+        .filter(|diag| {
+            let (start, end) = diag.range;
+            if start > end {
+                eprintln!("dropping diagnostic with inverted range {}..{}: {}",
+                          start, end, diag.message);
+            }
+            start <= end
+        })
+        .map(|diag| {
+            let (start, end) = diag.range;
+            Diagnostic { range: (start.min(buf_size), end.min(buf_size)), ..diag }
+        })
+        .collect()
+}
+
+/// Returns the ranges of the words in `text`; see `PluginCtx::words`.
+fn word_ranges(text: &str) -> impl Iterator<Item = Range<usize>> + '_ {
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+    let mut chars = text.char_indices().peekable();
+    ::std::iter::from_fn(move || {
+        let (start, _) = chars.by_ref().find(|&(_, c)| is_word_char(c))?;
+        let mut end = text.len();
+        while let Some((ix, c)) = chars.next() {
+            let continues = is_word_char(c) || (c == '\''
+                && chars.peek().is_some_and(|&(_, next)| is_word_char(next)));
+            if !continues {
+                end = ix;
+                break;
+            }
+        }
+        Some(start..end)
+    })
+}
+
+/// Returns the ranges of the user-perceived characters in `text`; see
+/// `PluginCtx::graphemes`.
+fn grapheme_ranges(text: &str) -> impl Iterator<Item = Range<usize>> + '_ {
+    let extends = |c: char| match c as u32 {
+        // combining marks
+        0x0300..=0x036F | 0x1AB0..=0x1AFF | 0x1DC0..=0x1DFF | 0x20D0..=0x20FF |
+        0xFE20..=0xFE2F => true,
+        // variation selectors and emoji modifiers
+        0xFE00..=0xFE0F | 0x1F3FB..=0x1F3FF | 0xE0100..=0xE01EF => true,
+        _ => false,
+    };
+    let mut chars = text.char_indices().peekable();
+    ::std::iter::from_fn(move || {
+        let (start, first) = chars.next()?;
+        let mut prev = first;
+        let mut end = start + first.len_utf8();
+        while let Some(&(ix, c)) = chars.peek() {
+            let joined = extends(c) || c == '\u{200D}' || prev == '\u{200D}'
+                || (prev == '\r' && c == '\n' && ix == start + 1);
+            if !joined {
+                break;
+            }
+            chars.next();
+            prev = c;
+            end = ix + c.len_utf8();
+        }
+        Some(start..end)
+    })
+}
+
+/// Moves `offset` in the document before `delta` to the matching offset in
+/// the document after it, in the same way the cache moves its positions.
+///
+/// An offset in a deleted region moves to the start of the deletion, and
+/// an offset at which text is inserted moves after the inserted text, as
+/// a cursor would.
+pub fn transform_offset(offset: usize, delta: &RopeDelta) -> usize {
+    Transformer::new(delta).transform(offset, true)
+}
+
+/// Moves each of `offsets` as `transform_offset` does.
+pub fn transform_offsets(offsets: &mut [usize], delta: &RopeDelta) {
+    let mut transformer = Transformer::new(delta);
+    for offset in offsets.iter_mut() {
+        *offset = transformer.transform(*offset, true);
+    }
+}
+
+/// Moves `delta` past `over`, another delta made against the same document,
+/// so that it applies to the document `over` produces. Returns `None` if
+/// the deltas don't apply to the same document, or if they conflict: that
+/// is, if they change overlapping regions, or one inserts inside a region
+/// the other changes. Text inserted by both at the same offset is placed
+/// after the text inserted by `over`.
+fn rebase_delta(delta: &RopeDelta, over: &RopeDelta) -> Option<RopeDelta> {
+    if delta.base_len != over.base_len {
+        return None;
+    }
+    let edits = delta_edits(delta);
+    let others = delta_edits(over);
+    let conflicts = |start: usize, end: usize, other_start: usize, other_end: usize| {
+        start.max(other_start) < end.min(other_end)
+            || (start == end && other_start < start && start < other_end)
+            || (other_start == other_end && start < other_start && other_start < end)
+    };
+    let mut transformer = Transformer::new(over);
+    let mut builder = DeltaBuilder::new(over.new_document_len());
+    for (start, end, text) in edits {
+        if others.iter().any(|&(s, e, _)| conflicts(start, end, s, e)) {
+            return None;
+        }
+        let new_start = transformer.transform(start, true);
+        let new_end = if start == end { new_start } else { transformer.transform(end, false) };
+        builder.replace(Interval::new_closed_open(new_start, new_end), text);
+    }
+    Some(builder.build())
+}
+
+/// Returns the regions of the document `delta` changes, in order, with the
+/// text each is replaced by.
+fn delta_edits(delta: &RopeDelta) -> Vec<(usize, usize, Rope)> {
+    let mut edits = Vec::new();
+    let mut pos = 0;
+    let mut inserted: Option<String> = None;
+    for el in &delta.els {
+        match *el {
+            DeltaElement::Copy(start, end) => {
+                if start > pos || inserted.is_some() {
+                    edits.push((pos, start, Rope::from(inserted.take().unwrap_or_default())));
+                }
+                pos = end;
+            }
+            DeltaElement::Insert(ref text) => {
+                inserted.get_or_insert_with(String::new).push_str(&String::from(text));
+            }
+        }
+    }
+    if delta.base_len > pos || inserted.is_some() {
+        edits.push((pos, delta.base_len, Rope::from(inserted.unwrap_or_default())));
+    }
+    edits
+}
+
+/// Moves published `spans` from the document before `delta` to the document
+/// after it. Spans touched by the delta become `None`.
+fn transform_spans(spans: &[Option<plugin_rpc::ScopeSpan>], delta: &RopeDelta)
+    -> Vec<Option<plugin_rpc::ScopeSpan>>
+{
+    let (changed, _) = delta.summary();
+    let mut transformer = Transformer::new(delta);
+    spans.iter()
+        .map(|span| match *span {
+            Some(span) if span.end < changed.start() || span.start > changed.end() =>
+                Some(plugin_rpc::ScopeSpan {
+                    start: transformer.transform(span.start, true),
+                    end: transformer.transform(span.end, false),
+                    ..span
+                }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Compares the spans `new` with the previously published `old`, returning
+/// the region of length `len` at `start` which must be replaced, and its
+/// spans relative to `start`, or `None` if nothing has changed. If most
+/// spans have changed, the region is the whole document.
+fn diff_spans(old: &[Option<plugin_rpc::ScopeSpan>], new: &[plugin_rpc::ScopeSpan],
+              buf_size: usize) -> Option<(usize, usize, Vec<plugin_rpc::ScopeSpan>)>
+{
+    let prefix = old.iter().zip(new).take_while(|&(a, b)| a.as_ref() == Some(b)).count();
+    if prefix == old.len() && prefix == new.len() {
+        return None;
+    }
+    let max_suffix = old.len().min(new.len()) - prefix;
+    let suffix = old.iter().rev().zip(new.iter().rev())
+        .take(max_suffix)
+        .take_while(|&(a, b)| a.as_ref() == Some(b))
+        .count();
+    let changed = &new[prefix..new.len() - suffix];
+    if changed.len() as f64 > new.len() as f64 * SPAN_RESEND_FRACTION {
+        return Some((0, buf_size, new.to_vec()));
+    }
+    let start = if prefix == 0 { 0 } else { new[prefix - 1].end };
+    let end = if suffix == 0 { buf_size } else { new[new.len() - suffix].start };
+    let changed = changed.iter()
+        .map(|span| plugin_rpc::ScopeSpan {
+            start: span.start - start,
+            end: span.end - start,
+            ..*span
+        })
+        .collect();
+    Some((start, end - start, changed))
+}
+
+/// The default location for a view's saved state; see `Plugin::state_path`.
+/// The file is named for a hash of the view's path, which must be the same
+/// from one run to the next, and from one build of the plugin to the next,
+/// so it is FNV rather than the standard library's hasher.
+fn default_state_path(view: &ViewState) -> Option<PathBuf> {
+    let path = view.path.as_ref()?;
+    let exe = env::current_exe().ok()?;
+    let mut hasher = FnvHasher::default();
+    hasher.write(path.to_string_lossy().as_bytes());
+    let file_name = format!("{}-{:016x}.json",
+                            exe.file_stem()?.to_string_lossy(), hasher.finish());
+    Some(env::temp_dir().join("xi-plugin-state").join(file_name))
+}
+
+/// Writes `state` to `path`. The file is replaced in one step, so that a
+/// plugin which is killed while saving leaves the previous state intact.
+fn save_state(path: &Path, state: &Value) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension(format!("tmp.{}", process::id()));
+    fs::write(&tmp, state.to_string())?;
+    fs::rename(&tmp, path)
+}
+
+/// Reads saved state from `path`. Returns `None` if there is no saved state,
+/// or if it is corrupt, in which case the plugin starts from scratch.
+fn load_state(path: &Path) -> Option<Value> {
+    let file = File::open(path).ok()?;
+    match serde_json::from_reader(file) {
+        Ok(state) => Some(state),
+        Err(err) => {
+            eprintln!("ignoring corrupt plugin state in {:?}: {}", path, err);
+            None
+        }
+    }
+}
+
+/// Decrements the count for `key`, removing it when it reaches zero.
+/// Returns `false` if there was no count for `key`.
+fn decrement(counts: &mut BTreeMap<usize, usize>, key: usize) -> bool {
+    match counts.get(&key).cloned() {
+        Some(1) => { counts.remove(&key); }
+        Some(n) => { counts.insert(key, n - 1); }
+        None => return false,
+    }
+    true
+}
+
+fn count_newlines(s: &str) -> usize {
+    bytecount::count(s.as_bytes(), b'\n')
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::mpsc;
+    use plugin_base;
+    use plugin_base::tests::{init_rpc, update_rpc, script};
+    use xi_rpc::test_utils::{test_channel, make_reader, DummyReader};
+    use base_cache::MetadataCache;
+
+    /// Runs `plugin` over `rpcs`, as if sent by core, returning what it sent
+    /// back.
+    pub fn run_script<P: Plugin>(plugin: &mut P, rpcs: &[Value]) -> DummyReader {
+        plugin_base::tests::run_script(&mut CacheHandler::new(plugin), rpcs)
+    }
+
+    /// A plugin which fails to handle every update.
+    struct FailingPlugin;
+
+    impl Plugin for FailingPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, ctx: PluginCtx<()>, info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            Err(update_error(ctx.get_view().view_id, info.rev, "failed"))
+        }
+    }
+
+    /// A plugin which rejects every view.
+    struct PickyPlugin;
+
+    impl Plugin for PickyPlugin {
+        type State = ();
+
+        fn new_view(&mut self, _view: &ViewState) -> bool { false }
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {
+            panic!("rejected view should not be initialized");
+        }
+        fn update(&mut self, _ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            panic!("rejected view should not be updated");
+        }
+    }
+
+    #[test]
+    fn rejected_view() {
+        let rpcs = [init_rpc(42), update_rpc(0, 5)];
+        let mut rx = run_script(&mut PickyPlugin, &rpcs);
+        assert_eq!(rx.expect_response(), Ok(json!(0)));
+    }
+
+    #[test]
+    fn clip_spans_to_document() {
+        use self::plugin_rpc::ScopeSpan;
+        let spans = [
+            ScopeSpan { start: 0, end: 4, scope_id: 1 },
+            ScopeSpan { start: 4, end: 12, scope_id: 2 },
+            ScopeSpan { start: 10, end: 12, scope_id: 3 },
+        ];
+        let (len, clipped) = clip_spans(5, 20, 15, &spans);
+        assert_eq!(len, 10);
+        let clipped = clipped.iter().map(|s| (s.start, s.end, s.scope_id)).collect::<Vec<_>>();
+        assert_eq!(clipped, vec![(0, 4, 1), (4, 10, 2)]);
+
+        let (len, clipped) = clip_spans(20, 5, 15, &spans);
+        assert_eq!(len, 0);
+        assert!(clipped.is_empty());
+    }
+
+    #[test]
+    fn find_words() {
+        let words = |text: &'static str| word_ranges(text)
+            .map(|range| &text[range])
+            .collect::<Vec<_>>();
+        assert_eq!(words("hello, world"), vec!["hello", "world"]);
+        assert_eq!(words("  don't 'quote' snake_case x2"),
+                   vec!["don't", "quote", "snake_case", "x2"]);
+        assert_eq!(words("größe über-straße"), vec!["größe", "über", "straße"]);
+        assert_eq!(words("日本語 テキスト"), vec!["日本語", "テキスト"]);
+        assert!(words(" ,. ").is_empty());
+
+        let text = "héllo wörld";
+        let ranges = word_ranges(text).collect::<Vec<_>>();
+        assert_eq!(ranges, vec![0..6, 7..13]);
+    }
+
+    #[test]
+    fn find_graphemes() {
+        let graphemes = |text: &'static str| grapheme_ranges(text)
+            .map(|range| &text[range])
+            .collect::<Vec<_>>();
+        assert_eq!(graphemes("ab"), vec!["a", "b"]);
+        // "e" followed by a combining acute accent
+        assert_eq!(graphemes("e\u{301}x"), vec!["e\u{301}", "x"]);
+        assert_eq!(graphemes("a\r\nb\n\r"), vec!["a", "\r\n", "b", "\n", "\r"]);
+        // a thumbs up with a skin tone, and a family joined by ZWJs
+        assert_eq!(graphemes("\u{1F44D}\u{1F3FD}!"), vec!["\u{1F44D}\u{1F3FD}", "!"]);
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        assert_eq!(graphemes(family), vec![family]);
+        assert!(graphemes("").is_empty());
+
+        let ranges = grapheme_ranges("é\u{301}a").collect::<Vec<_>>();
+        assert_eq!(ranges, vec![0..4, 4..5]);
+    }
+
+    /// A plugin which records the words and characters after the first
+    /// three bytes of the document whenever it changes.
+    #[derive(Default)]
+    struct WordsPlugin {
+        words: Vec<(Range<usize>, String)>,
+        graphemes: Vec<(Range<usize>, String)>,
+    }
+
+    impl Plugin for WordsPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, mut ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            let len = ctx.get_buf_size();
+            self.words = ctx.words(3..len).unwrap();
+            self.graphemes = ctx.graphemes(3..len).unwrap();
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn document_words() {
+        use testing::{MockCore, PluginBufferInfoBuilder};
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        // left for the plugin to fetch, which takes several chunks, some of
+        // which end within a word, or within a character
+        let text = "the quick brown fox jumps over the lazy dog; the café owner naïvely jumps too";
+        assert!(text.len() > 4 * ::base_cache::CHUNK_SIZE);
+        let mut core = MockCore::start(WordsPlugin::default());
+        core.new_view_unfetched(PluginBufferInfoBuilder::new().text(text).build());
+        let delta = Delta::simple_edit(Interval::new_closed_open(text.len(), text.len()),
+                                       "!".into(), text.len());
+        core.update(delta).unwrap();
+        let plugin = core.finish();
+        let words = plugin.words.iter().map(|(_, word)| word.as_str()).collect::<Vec<_>>();
+        assert_eq!(words, vec!["quick", "brown", "fox", "jumps", "over", "the", "lazy", "dog",
+                               "the", "café", "owner", "naïvely", "jumps", "too"]);
+        assert_eq!(plugin.words[0], (4..9, "quick".to_owned()));
+        assert_eq!(plugin.words[9], (49..54, "café".to_owned()));
+        assert_eq!(plugin.words[13], (76..79, "too".to_owned()));
+        assert_eq!(plugin.graphemes.len(), text[3..].chars().count() + 1);
+        assert_eq!(plugin.graphemes[49], (52..54, "é".to_owned()));
+    }
+
+    #[test]
+    fn move_spans_with_edits() {
+        use self::plugin_rpc::ScopeSpan;
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        let spans = [
+            Some(ScopeSpan { start: 0, end: 4, scope_id: 1 }),
+            Some(ScopeSpan { start: 5, end: 8, scope_id: 2 }),
+            None,
+            Some(ScopeSpan { start: 10, end: 12, scope_id: 3 }),
+        ];
+        // insert two bytes at 6, inside the second span
+        let delta = Delta::simple_edit(Interval::new_closed_open(6, 6), "ab".into(), 12);
+        let moved = transform_spans(&spans, &delta);
+        let moved = moved.iter()
+            .map(|s| s.map(|s| (s.start, s.end, s.scope_id)))
+            .collect::<Vec<_>>();
+        assert_eq!(moved, vec![Some((0, 4, 1)), None, None, Some((12, 14, 3))]);
+    }
+
+    /// A plugin which highlights every "x" in the document.
+    struct XPlugin;
+
+    impl XPlugin {
+        fn highlight(&self, ctx: &mut PluginCtx<()>) {
+            use self::plugin_rpc::ScopeSpan;
+            let len = ctx.get_buf_size();
+            let text = ctx.get_region(0, len).unwrap();
+            let spans = text.match_indices('x')
+                .map(|(ix, _)| ScopeSpan { start: ix, end: ix + 1, scope_id: 1 })
+                .collect();
+            ctx.set_spans(spans);
+        }
+    }
+
+    impl Plugin for XPlugin {
+        type State = ();
+
+        fn initialize(&mut self, mut ctx: PluginCtx<()>, _buf_size: usize) {
+            self.highlight(&mut ctx);
+        }
+        fn update(&mut self, mut ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            self.highlight(&mut ctx);
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn send_changed_spans() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        let mut core = MockCore::start(XPlugin);
+        core.new_view("x.x.x.x.x.x");
+        // replace the third x
+        let delta = Delta::simple_edit(Interval::new_closed_open(4, 5), "y".into(), 11);
+        core.update(delta).unwrap();
+        // append after the last x, which touches it
+        let delta = Delta::simple_edit(Interval::new_closed_open(11, 11), ".".into(), 11);
+        core.update(delta).unwrap();
+        let updates = core.notifications().iter()
+            .filter(|n| n["method"] == "update_spans")
+            .map(|n| {
+                let params = &n["params"];
+                (params["start"].clone(), params["len"].clone(), params["spans"].clone())
+            })
+            .collect::<Vec<_>>();
+        core.finish();
+
+        assert_eq!(updates.len(), 3);
+        assert_eq!(updates[0].0, json!(0));
+        assert_eq!(updates[0].1, json!(11));
+        assert_eq!(updates[0].2.as_array().unwrap().len(), 6);
+        assert_eq!(updates[1], (json!(3), json!(3), json!([])));
+        assert_eq!(updates[2], (json!(9), json!(3), json!([{"start": 1, "end": 2, "scope_id": 1}])));
+    }
+
+    #[test]
+    fn span_diffs() {
+        use self::plugin_rpc::ScopeSpan;
+
+        // one span per line of a 10k line document, with 10 byte lines
+        let spans = (0..10_000)
+            .map(|i| ScopeSpan { start: i * 10, end: i * 10 + 5, scope_id: 1 })
+            .collect::<Vec<_>>();
+        let buf_size = 100_000;
+        let published = spans.iter().cloned().map(Some).collect::<Vec<_>>();
+        assert_eq!(diff_spans(&published, &spans, buf_size), None);
+
+        let mut changed = spans.clone();
+        changed[5000].scope_id = 2;
+        let (start, len, diff) = diff_spans(&published, &changed, buf_size).unwrap();
+        assert_eq!((start, len), (49_995, 15));
+        assert_eq!(diff, vec![ScopeSpan { start: 5, end: 10, scope_id: 2 }]);
+        // compared to resending everything
+        let full = serde_json::to_string(&changed).unwrap().len();
+        let incremental = serde_json::to_string(&diff).unwrap().len();
+        assert!(incremental * 1000 < full);
+
+        // removing the first span
+        let (start, len, diff) = diff_spans(&published, &spans[1..], buf_size).unwrap();
+        assert_eq!((start, len), (0, 10));
+        assert!(diff.is_empty());
+
+        // a span touched by an edit is resent, even if it is unchanged
+        let mut touched = published.clone();
+        touched[5000] = None;
+        let (start, len, diff) = diff_spans(&touched, &spans, buf_size).unwrap();
+        assert_eq!((start, len), (49_995, 15));
+        assert_eq!(diff, vec![ScopeSpan { start: 5, end: 10, scope_id: 1 }]);
+
+        // mostly changed
+        let shifted = spans.iter()
+            .map(|s| ScopeSpan { start: s.start + 1, end: s.end + 1, scope_id: 1 })
+            .collect::<Vec<_>>();
+        let (start, len, diff) = diff_spans(&published, &shifted, buf_size).unwrap();
+        assert_eq!((start, len), (0, buf_size));
+        assert_eq!(diff, shifted);
+    }
+
+    #[test]
+    fn clip_diagnostics_to_document() {
+        let diag = |start, end, message: &str| Diagnostic {
+            range: (start, end),
+            severity: Severity::Warning,
+            message: message.to_owned(),
+            code: None,
+        };
+        let diags = vec![diag(0, 4, "a"), diag(6, 3, "b"), diag(8, 20, "c"), diag(16, 18, "d")];
+        let clipped = clip_diagnostics(diags, 15);
+        let clipped = clipped.iter()
+            .map(|d| (d.range, d.message.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(clipped, vec![((0, 4), "a"), ((8, 15), "c"), ((15, 15), "d")]);
+    }
+
+    #[test]
+    fn save_and_load_state() {
+        let dir = env::temp_dir().join(format!("xi-plugin-lib-test-{}", ::std::process::id()));
+        let path = dir.join("state.json");
+        assert_eq!(load_state(&path), None);
+
+        let state = json!({"lines": [1, 2, 3], "name": "test"});
+        save_state(&path, &state).unwrap();
+        assert_eq!(load_state(&path), Some(state));
+
+        fs::write(&path, "{ not json").unwrap();
+        assert_eq!(load_state(&path), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A plugin which counts the views it has been started for in its store.
+    struct StorePlugin {
+        dir: PathBuf,
+    }
+
+    impl Plugin for StorePlugin {
+        type State = ();
+
+        fn initialize(&mut self, ctx: PluginCtx<()>, _buf_size: usize) {
+            let store = ctx.store().unwrap();
+            let views = store.get("views").and_then(|v| v.as_u64()).unwrap_or(0);
+            store.set("views", json!(views + 1)).unwrap();
+        }
+        fn update(&mut self, _ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            Ok(None)
+        }
+        fn store_dir(&self) -> Option<PathBuf> { Some(self.dir.clone()) }
+        fn info(&self) -> PluginInfo {
+            PluginInfo { name: "counter".into(), version: "1".into(), features: Vec::new() }
+        }
+    }
+
+    #[test]
+    fn plugin_store() {
+        use testing::MockCore;
+
+        let dir = env::temp_dir().join(format!("xi-plugin-lib-store-{}", ::std::process::id()));
+        for _ in 0..2 {
+            let mut core = MockCore::start(StorePlugin { dir: dir.clone() });
+            core.new_view("");
+            core.finish();
+        }
+        let store = PluginStore::for_plugin(&dir, "counter");
+        assert_eq!(store.get("views"), Some(json!(2)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A plugin which saves a counter of how many times it has been started.
+    struct CountingPlugin {
+        path: PathBuf,
+        starts: u64,
+    }
+
+    impl Plugin for CountingPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {
+            self.starts += 1;
+        }
+        fn update(&mut self, _ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            Ok(None)
+        }
+        fn serialize_state(&self) -> Option<Value> { Some(json!(self.starts)) }
+        fn restore_state(&mut self, state: Value) {
+            self.starts = state.as_u64().unwrap();
+        }
+        fn state_path(&self, _view: &ViewState) -> Option<PathBuf> {
+            Some(self.path.clone())
+        }
+    }
+
+    #[test]
+    fn state_path_is_stable() {
+        use testing::PluginBufferInfoBuilder;
+
+        let info = PluginBufferInfoBuilder::new().path("/src/main.rs").build();
+        let path = default_state_path(&ViewState::new(&info).unwrap()).unwrap();
+        // the same from one build to the next
+        let file_name = path.file_name().unwrap().to_string_lossy();
+        assert!(file_name.ends_with("-cabe508a7fec3555.json"), "{}", file_name);
+        let unsaved = ViewState::new(&PluginBufferInfoBuilder::new().build()).unwrap();
+        assert_eq!(default_state_path(&unsaved), None);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let dir = env::temp_dir()
+            .join(format!("xi-plugin-lib-round-trip-{}", ::std::process::id()));
+        let path = dir.join("state.json");
+        let rpcs = [init_rpc(42), json!({"method": "shutdown", "params": {}})];
+
+        for expected_starts in 1..3 {
+            let mut plugin = CountingPlugin { path: path.clone(), starts: 0 };
+            run_script(&mut plugin, &rpcs);
+            assert_eq!(plugin.starts, expected_starts);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A plugin which records the config changes it is passed. If `busy`,
+    /// it asks core for the text on each update, so that RPCs pile up
+    /// meanwhile.
+    #[derive(Default)]
+    struct ConfigPlugin {
+        changes: Vec<ConfigTable>,
+        busy: bool,
+    }
+
+    impl Plugin for ConfigPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, mut ctx: PluginCtx<()>, _info: UpdateContext,
+                  _delta: Option<RopeDelta>) -> Result<Option<Value>, RemoteError> {
+            if self.busy {
+                assert!(ctx.verify_cache());
+            }
+            Ok(None)
+        }
+        fn config_changed(&mut self, _ctx: PluginCtx<()>, changes: &ConfigTable) {
+            self.changes.push(changes.clone());
+        }
+        fn config_is_immediate(&self, key: &str) -> bool { key == "now" }
+    }
+
+    fn config_rpc(changes: Value) -> Value {
+        json!({"method": "config_changed",
+               "params": {"view_id": "view-id-1", "changes": changes}})
+    }
+
+    #[test]
+    fn debounce_config_changes() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        let mut core = MockCore::start(ConfigPlugin { busy: true, ..Default::default() });
+        core.new_view("a");
+        // the changes all arrive while the plugin is busy with the update
+        core.hold_notifications();
+        let changes = [
+            json!({"tab_size": 2}),
+            json!({"tab_size": 3, "font_size": 10}),
+            json!({"tab_size": 4}),
+        ];
+        for change in &changes {
+            core.config_changed(change.as_object().unwrap().clone());
+        }
+        let delta = Delta::simple_edit(Interval::new_closed_open(0, 0), "b".into(), 1);
+        core.update(delta).unwrap();
+        core.wait_idle();
+        let plugin = core.finish();
+        assert_eq!(plugin.changes.len(), 1, "{:?}", plugin.changes);
+        assert_eq!(plugin.changes[0]["tab_size"], json!(4));
+        assert_eq!(plugin.changes[0]["font_size"], json!(10));
+    }
+
+    #[test]
+    fn immediate_config_changes() {
+        let rpcs = [
+            init_rpc(42),
+            config_rpc(json!({"now": true})),
+        ];
+        let mut plugin = ConfigPlugin::default();
+        run_script(&mut plugin, &rpcs);
+        assert_eq!(plugin.changes.len(), 1);
+        assert_eq!(plugin.changes[0]["now"], json!(true));
+    }
+
+    /// A plugin which records the document's lines whenever it changes.
+    #[derive(Default)]
+    struct LinesPlugin {
+        lines: Vec<Vec<(usize, String)>>,
+    }
+
+    impl Plugin for LinesPlugin {
+        type State = ();
+
+        fn initialize(&mut self, mut ctx: PluginCtx<()>, _buf_size: usize) {
+            self.lines.push(ctx.lines().collect());
+        }
+        fn update(&mut self, mut ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            self.lines.push(ctx.lines().collect());
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn iterate_lines() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        // long enough to need several chunks
+        let text = (0..20).map(|i| format!("line number {}\n", i)).collect::<String>();
+        let mut core = MockCore::start(LinesPlugin::default());
+        core.new_view(&text);
+        let len = text.len();
+        let delta = Delta::simple_edit(Interval::new_closed_open(len, len), "end".into(), len);
+        core.update(delta).unwrap();
+        let plugin = core.finish();
+
+        assert_eq!(plugin.lines[0].len(), 20);
+        assert_eq!(plugin.lines[0][19], (19, "line number 19\n".to_owned()));
+        assert_eq!(plugin.lines[1].len(), 21);
+        assert_eq!(plugin.lines[1][20], (20, "end".to_owned()));
+        let joined = plugin.lines[1].iter().map(|l| l.1.as_str()).collect::<String>();
+        assert_eq!(joined, format!("{}end", text));
+    }
+
+    struct InfoPlugin;
+
+    impl Plugin for InfoPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, _ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> { Ok(None) }
+        fn info(&self) -> PluginInfo {
+            PluginInfo {
+                name: "info".to_owned(),
+                version: env!("CARGO_PKG_VERSION").to_owned(),
+                features: vec!["spans".to_owned()],
+            }
+        }
+    }
+
+    #[test]
+    fn report_plugin_info() {
+        use testing::MockCore;
+
+        let mut core = MockCore::start(InfoPlugin);
+        core.new_view("text");
+        core.sync();
+        let info = core.notifications().iter()
+            .find(|n| n["method"] == "plugin_info")
+            .map(|n| n["params"].clone())
+            .expect("no plugin_info notification");
+        assert_eq!(info["view_id"], json!("view-id-1"));
+        assert_eq!(info["info"], json!({
+            "name": "info",
+            "version": env!("CARGO_PKG_VERSION"),
+            "features": ["spans"],
+        }));
+        core.finish();
+
+        let info = LinesPlugin::default().info();
+        assert!(!info.name.is_empty());
+        assert!(info.features.is_empty());
+    }
+
+    /// A plugin which records the revisions it is updated to. If `busy`, it
+    /// asks core for the text on each update, so that RPCs pile up meanwhile.
+    #[derive(Default)]
+    struct RevsPlugin {
+        revs: Vec<usize>,
+        own_edits: bool,
+        busy: bool,
+    }
+
+    impl Plugin for RevsPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, mut ctx: PluginCtx<()>, info: UpdateContext,
+                  _delta: Option<RopeDelta>) -> Result<Option<Value>, RemoteError> {
+            self.revs.push(info.rev as usize);
+            if self.busy {
+                assert!(ctx.verify_cache());
+            }
+            Ok(None)
+        }
+        fn wants_own_edits(&self) -> bool { self.own_edits }
+    }
+
+    #[test]
+    fn collapse_backlogged_updates() {
+        use testing::{MockCore, PluginBufferInfoBuilder};
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        let mut config = ConfigTable::new();
+        config.insert(UPDATE_BACKLOG_KEY.into(), json!(1));
+        let info = PluginBufferInfoBuilder::new().text("").config(config).build();
+        let mut core = MockCore::start(RevsPlugin { busy: true, ..Default::default() });
+        core.new_view_with_info(info);
+        let insert = |i| Delta::simple_edit(Interval::new_closed_open(i, i), "x".into(), i);
+        // the other nineteen updates arrive while the plugin is busy with
+        // the first
+        for i in 1..20 {
+            core.update_at_request(0, insert(i));
+        }
+        assert_eq!(core.update(insert(0)), Ok(json!(0)));
+        core.wait_idle();
+        assert_eq!(core.text(), "x".repeat(20));
+        // with a threshold of one, they are all collapsed into the last
+        let plugin = core.finish();
+        assert_eq!(plugin.revs, vec![2, 21]);
+    }
+
+    #[test]
+    fn ignore_cancel_of_batch() {
+        use testing::{MockCore, PluginBufferInfoBuilder};
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        let mut config = ConfigTable::new();
+        config.insert(UPDATE_BACKLOG_KEY.into(), json!(1));
+        let info = PluginBufferInfoBuilder::new().text("").config(config).build();
+        let mut core = MockCore::start(RevsPlugin { busy: true, ..Default::default() });
+        core.new_view_with_info(info);
+        let insert = |i| Delta::simple_edit(Interval::new_closed_open(i, i), "x".into(), i);
+        // the cancel arrives after the updates are batched, before the
+        // batch is delivered
+        for i in 1..20 {
+            core.update_at_request(0, insert(i));
+        }
+        core.hold_notifications();
+        core.cancel(BATCH_IDLE_TOKEN);
+        assert_eq!(core.update(insert(0)), Ok(json!(0)));
+        core.wait_idle();
+        assert_eq!(core.text(), "x".repeat(20));
+        let plugin = core.finish();
+        assert_eq!(plugin.revs, vec![2, 21]);
+    }
+
+    const REPARSE: WorkKind = WorkKind("reparse");
+    const RELINT: WorkKind = WorkKind("relint");
+    const REINDEX: WorkKind = WorkKind("reindex");
+
+    /// A plugin with several kinds of background work, which records the
+    /// order they run in. Reparsing takes two chunks, and reindexing comes
+    /// first.
+    #[derive(Default)]
+    struct WorkPlugin {
+        log: Vec<&'static str>,
+    }
+
+    impl Plugin for WorkPlugin {
+        type State = ();
+
+        fn initialize(&mut self, mut ctx: PluginCtx<()>, _buf_size: usize) {
+            for &kind in &[REPARSE, RELINT, REPARSE, REINDEX, RELINT] {
+                ctx.schedule_work(kind);
+            }
+            assert_eq!(ctx.pending_work(), &[REPARSE, RELINT, REINDEX]);
+        }
+        fn update(&mut self, _ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> { Ok(None) }
+        fn do_work(&mut self, _ctx: PluginCtx<()>, kind: WorkKind) -> Idle {
+            self.log.push(kind.0);
+            match kind {
+                REPARSE if self.log.len() < 3 => Idle::Continue,
+                _ => Idle::Done,
+            }
+        }
+        fn work_priority(&self, kind: WorkKind) -> i32 {
+            if kind == REINDEX { 1 } else { 0 }
+        }
+    }
+
+    #[test]
+    fn work_kinds() {
+        use testing::MockCore;
+
+        let mut core = MockCore::start(WorkPlugin::default());
+        core.new_view("");
+        core.wait_idle();
+        assert_eq!(core.finish().log, vec!["reindex", "reparse", "relint", "reparse"]);
+    }
+
+    /// A plugin which reads ranges of lines on every update.
+    #[derive(Default)]
+    struct LineRangePlugin {
+        ranges: Vec<(usize, usize)>,
+        lines: Vec<Vec<String>>,
+    }
+
+    impl Plugin for LineRangePlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, mut ctx: PluginCtx<()>, _info: UpdateContext,
+                  _delta: Option<RopeDelta>) -> Result<Option<Value>, RemoteError> {
+            assert!(!ctx.is_fully_cached());
+            for &(start, end) in &self.ranges {
+                self.lines.push(ctx.get_lines(start, end).unwrap());
+            }
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn line_ranges() {
+        use testing::{MockCore, PluginBufferInfoBuilder};
+        use xi_rope::delta::Delta;
+
+        // spread over several chunks, with a budget which keeps only a few
+        let text = (0..20).map(|i| format!("line number {}\n", i)).collect::<String>() + "end";
+        let mut config = ConfigTable::new();
+        config.insert(CACHE_MAX_BYTES_KEY.into(), json!(20));
+        let info = PluginBufferInfoBuilder::new().text(&text).config(config).build();
+        let ranges = vec![(18, 19), (2, 5), (19, 40), (30, 40), (3, 3)];
+        let mut core = MockCore::start(LineRangePlugin { ranges, ..Default::default() });
+        core.new_view_with_info(info);
+        let delta = Delta::simple_edit(Interval::new_closed_open(0, 0), "first ".into(), text.len());
+        core.update(delta).unwrap();
+        let plugin = core.finish();
+        let expected: Vec<Vec<&str>> = vec![
+            vec!["line number 18\n"],
+            vec!["line number 2\n", "line number 3\n", "line number 4\n"],
+            vec!["line number 19\n", "end"],
+            vec![],
+            vec![],
+        ];
+        assert_eq!(plugin.lines, expected);
+    }
+
+    /// A plugin which publishes diagnostics and spans, flushing them part way
+    /// through its update.
+    struct FlushPlugin;
+
+    impl Plugin for FlushPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            let diag = |message: &str| Diagnostic {
+                range: (0, 1),
+                severity: Severity::Info,
+                message: message.into(),
+                code: None,
+            };
+            ctx.publish_diagnostics(vec![diag("replaced")]);
+            ctx.update_spans(0, 1, &[plugin_rpc::ScopeSpan { start: 0, end: 1, scope_id: 0 }]);
+            ctx.publish_diagnostics(vec![diag("first")]);
+            assert_eq!(ctx.unflushed(), 2);
+            ctx.flush();
+            assert_eq!(ctx.unflushed(), 0);
+            ctx.publish_diagnostics(vec![diag("second")]);
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn flush_outbound() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+
+        let mut core = MockCore::start(FlushPlugin);
+        core.new_view("");
+        core.update(Delta::simple_edit(Interval::new_closed_open(0, 0), "x".into(), 0)).unwrap();
+        // the last diagnostics are sent when the update returns
+        let sent = core.notifications().iter()
+            .filter(|msg| msg["method"] != "plugin_info")
+            .map(|msg| match msg["method"].as_str().unwrap() {
+                "diagnostics" => msg["params"]["diagnostics"][0]["message"].as_str().unwrap(),
+                method => method,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sent, vec!["update_spans", "first", "second"]);
+        core.finish();
+    }
+
+    /// A plugin which, on init, highlights each of the first `count`
+    /// characters and reports a diagnostic for each.
+    struct LargePayloadPlugin {
+        count: usize,
+    }
+
+    impl Plugin for LargePayloadPlugin {
+        type State = ();
+
+        fn initialize(&mut self, ctx: PluginCtx<()>, _buf_size: usize) {
+            let spans = (0..self.count)
+                .map(|ix| plugin_rpc::ScopeSpan { start: ix, end: ix + 1, scope_id: 0 })
+                .collect::<Vec<_>>();
+            let diagnostics = (0..self.count)
+                .map(|ix| Diagnostic {
+                    range: (ix, ix + 1),
+                    severity: Severity::Info,
+                    message: format!("char {}", ix),
+                    code: None,
+                })
+                .collect();
+            ctx.update_spans(0, self.count, &spans);
+            ctx.publish_diagnostics(diagnostics);
+        }
+        fn update(&mut self, _ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn chunked_payloads() {
+        use testing::{MockCore, PluginBufferInfoBuilder};
+
+        // a limit which fits four spans with two-digit offsets, or four
+        // but not five of the smaller ones
+        let text = "x".repeat(24);
+        let span = plugin_rpc::ScopeSpan { start: 10, end: 11, scope_id: 0 };
+        let span_size = serde_json::to_string(&span).unwrap().len() + 1;
+        let mut config = ConfigTable::new();
+        config.insert(MAX_PAYLOAD_BYTES_KEY.into(), json!(span_size * 4));
+        let info = PluginBufferInfoBuilder::new().text(&text).config(config).build();
+        let mut core = MockCore::start(LargePayloadPlugin { count: 12 });
+        core.new_view_with_info(info);
+        // so the twelve spans take three messages
+        let messages = |method: &str| core.notifications().iter()
+            .filter(|msg| msg["method"] == method)
+            .map(|msg| msg["params"].clone())
+            .collect::<Vec<_>>();
+        let updates = messages("update_spans");
+        let more = updates.iter().map(|params| params["more"].as_bool()).collect::<Vec<_>>();
+        assert_eq!(more, vec![Some(true), Some(true), None]);
+        assert!(updates.iter().all(|params| params["seq"] == updates[0]["seq"]));
+        let starts = updates.iter()
+            .flat_map(|params| params["spans"].as_array().unwrap().clone())
+            .map(|span| span["start"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(starts, (0..12).collect::<Vec<_>>());
+        // diagnostics are larger, and take more messages
+        let diagnostics = messages("diagnostics");
+        assert!(diagnostics.len() > 3, "{:?}", diagnostics);
+        let (last, rest) = diagnostics.split_last().unwrap();
+        assert!(rest.iter().all(|params| params["more"] == json!(true)));
+        assert_eq!(last.get("more"), None);
+        assert!(diagnostics.iter().all(|params| params["seq"] == last["seq"]));
+        assert!(last["seq"].as_u64() > updates[0]["seq"].as_u64());
+        let count = diagnostics.iter()
+            .map(|params| params["diagnostics"].as_array().unwrap().len())
+            .sum::<usize>();
+        assert_eq!(count, 12);
+        core.finish();
+    }
+
+    #[test]
+    fn update_before_init() {
+        use plugin_base::NOT_INITIALIZED_ERROR;
+
+        let rpcs = [update_rpc(0, 2), init_rpc(42), update_rpc(1, 2)];
+        let mut plugin = RevsPlugin::default();
+        let mut rx = run_script(&mut plugin, &rpcs);
+        match rx.expect_response() {
+            Err(RemoteError::Custom { code, message, .. }) => {
+                assert_eq!(code, NOT_INITIALIZED_ERROR);
+                assert!(message.contains("update before initialize"), "{}", message);
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+        rx.expect_rpc("plugin_info");
+        assert_eq!(rx.expect_response(), Ok(json!(0)));
+        assert_eq!(plugin.revs, vec![2]);
+    }
+
+    /// A plugin which does its work in a fixed number of idle chunks.
+    #[derive(Default)]
+    struct ChunkedPlugin {
+        chunks: Vec<usize>,
+    }
+
+    impl Plugin for ChunkedPlugin {
+        type State = ();
+
+        fn initialize(&mut self, mut ctx: PluginCtx<()>, _buf_size: usize) {
+            ctx.schedule_idle(1);
+            ctx.schedule_idle(2);
+        }
+        fn update(&mut self, _ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> { Ok(None) }
+        fn idle(&mut self, _ctx: PluginCtx<()>, token: usize) -> Idle {
+            self.chunks.push(token);
+            if self.chunks.iter().filter(|&&t| t == token).count() < 3 {
+                Idle::Continue
+            } else {
+                Idle::Done
+            }
+        }
+    }
+
+    #[test]
+    fn continue_idle_work() {
+        use testing::MockCore;
+
+        let mut core = MockCore::start(ChunkedPlugin::default());
+        core.new_view("text");
+        core.wait_idle();
+        let plugin = core.finish();
+        // continued tokens take turns
+        assert_eq!(plugin.chunks, vec![1, 2, 1, 2, 1, 2]);
+    }
+
+    #[test]
+    fn out_of_order_buffer_rpcs() {
+        let buffer_info = init_rpc(42)["params"]["buffer_info"].clone();
+        let new_buffer = json!({"method": "new_buffer", "params": {"buffer_info": buffer_info}});
+        let mut empty_init = init_rpc(43);
+        empty_init["params"]["buffer_info"] = json!([]);
+        let mut no_views_init = init_rpc(44);
+        no_views_init["params"]["buffer_info"][0]["views"] = json!([]);
+        let mut bad_config_init = init_rpc(45);
+        bad_config_init["params"]["buffer_info"][0]["config"]["tab_size"] = json!("four");
+        let rpcs = [new_buffer.clone(), empty_init, no_views_init, bad_config_init,
+                    init_rpc(42), new_buffer, update_rpc(0, 2)];
+        let mut plugin = RevsPlugin::default();
+        let mut rx = run_script(&mut plugin, &rpcs);
+        assert_eq!(rx.expect_rpc("plugin_info").0["params"]["plugin_id"], json!(42));
+        assert_eq!(rx.expect_response(), Ok(json!(0)));
+        assert_eq!(plugin.revs, vec![2]);
+    }
+
+    #[test]
+    fn skip_own_edits() {
+        let mut own_update = update_rpc(1, 3);
+        own_update["params"]["author"] = json!("42");
+        let rpcs = [init_rpc(42), update_rpc(0, 2), own_update, update_rpc(2, 4)];
+
+        let mut plugin = RevsPlugin::default();
+        let mut rx = run_script(&mut plugin, &rpcs);
+        rx.expect_rpc("plugin_info");
+        for _ in 0..3 {
+            assert_eq!(rx.expect_response(), Ok(json!(0)));
+        }
+        assert_eq!(plugin.revs, vec![2, 4]);
+
+        let mut plugin = RevsPlugin { own_edits: true, ..RevsPlugin::default() };
+        run_script(&mut plugin, &rpcs);
+        assert_eq!(plugin.revs, vec![2, 3, 4]);
+    }
+
+    /// A plugin which records whether the document was cached on init, and
+    /// if so, reads it.
+    #[derive(Default)]
+    struct InitTextPlugin {
+        cached: Option<bool>,
+        lines: Vec<(usize, String)>,
+    }
+
+    impl Plugin for InitTextPlugin {
+        type State = ();
+
+        fn initialize(&mut self, mut ctx: PluginCtx<()>, _buf_size: usize) {
+            self.cached = Some(ctx.is_fully_cached());
+            if ctx.is_fully_cached() {
+                self.lines = ctx.lines().collect();
+            }
+        }
+        fn update(&mut self, _ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> { Ok(None) }
+    }
+
+    #[test]
+    fn initial_text() {
+        let mut init = init_rpc(42);
+        init["params"]["buffer_info"][0]["buf_size"] = json!(11);
+        init["params"]["buffer_info"][0]["nb_lines"] = json!(2);
+        init["params"]["buffer_info"][0]["text"] = json!("hello\nworld");
+
+        // nothing is there to answer get_data, so all reads must hit the cache
+        let mut plugin = InitTextPlugin::default();
+        run_script(&mut plugin, &[init.clone()]);
+        assert_eq!(plugin.cached, Some(true));
+        assert_eq!(plugin.lines, vec![(0, "hello\n".to_owned()), (1, "world".to_owned())]);
+
+        init["params"]["buffer_info"][0].as_object_mut().unwrap().remove("text");
+        let mut plugin = InitTextPlugin::default();
+        run_script(&mut plugin, &[init.clone()]);
+        assert_eq!(plugin.cached, Some(false));
+    }
+
+    /// A plugin which counts updates in its view data, recording the count
+    /// after each update.
+    #[derive(Default)]
+    struct UserDataPlugin {
+        counts: Vec<usize>,
+    }
+
+    impl Plugin for UserDataPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, mut ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            *ctx.user_data::<usize>() += 1;
+            self.counts.push(*ctx.user_data::<usize>());
+            if *ctx.user_data::<usize>() == 2 {
+                // a different type replaces the count
+                ctx.user_data::<String>().push_str("reset");
+            }
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn view_user_data() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        let mut core = MockCore::start(UserDataPlugin::default());
+        core.new_view("");
+        for i in 0..3 {
+            let delta = Delta::simple_edit(Interval::new_closed_open(i, i), "a".into(), i);
+            core.update(delta).unwrap();
+        }
+        let plugin = core.finish();
+        assert_eq!(plugin.counts, vec![1, 2, 1]);
+    }
+
+    #[test]
+    fn replay_script() {
+        let rpcs = [init_rpc(42), update_rpc(0, 2), update_rpc(1, 3)];
+        let path = env::temp_dir().join(format!("xi-plugin-lib-replay-{}", ::std::process::id()));
+        fs::write(&path, script(&rpcs)).unwrap();
+
+        let mut plugin = RevsPlugin::default();
+        let responses = replay(&mut plugin, &path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(responses, vec![json!({"id": 0, "result": 0}), json!({"id": 1, "result": 0})]);
+        assert_eq!(plugin.revs, vec![2, 3]);
+
+        assert!(replay(&mut plugin, &path).is_err());
+    }
+
+    /// A plugin which reads the first line on each update.
+    #[derive(Default)]
+    struct FirstLinePlugin {
+        lines: Vec<Result<String, String>>,
+    }
+
+    impl Plugin for FirstLinePlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, mut ctx: PluginCtx<()>, _info: UpdateContext,
+                  _delta: Option<RopeDelta>) -> Result<Option<Value>, RemoteError> {
+            let line = ctx.get_line(0).map(str::to_owned).map_err(|err| format!("{:?}", err));
+            self.lines.push(line);
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn replay_answers_requests() {
+        let update = |id, rev| {
+            let mut update = update_rpc(id, rev);
+            update["params"]["new_len"] = json!(5);
+            update
+        };
+        // the first request for data is answered, but not the second
+        let rpcs = [
+            init_rpc(42),
+            update(0, 2),
+            json!({"id": 0, "result": {
+                "chunk": "hello", "offset": 0, "first_line": 0, "first_line_offset": 0,
+            }}),
+            update(1, 3),
+        ];
+        let path = env::temp_dir()
+            .join(format!("xi-plugin-lib-replay-requests-{}", ::std::process::id()));
+        fs::write(&path, script(&rpcs)).unwrap();
+
+        let mut plugin = FirstLinePlugin::default();
+        let responses = replay(&mut plugin, &path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(responses, vec![json!({"id": 0, "result": 0}), json!({"id": 1, "result": 0})]);
+        assert_eq!(plugin.lines.len(), 2);
+        assert_eq!(plugin.lines[0], Ok("hello".to_owned()));
+        assert!(plugin.lines[1].is_err());
+    }
+
+    /// A plugin which records the open paths on init and on save.
+    #[derive(Default)]
+    struct PathsPlugin {
+        paths: Vec<Vec<(ViewIdentifier, Option<PathBuf>)>>,
+    }
+
+    impl Plugin for PathsPlugin {
+        type State = ();
+
+        fn initialize(&mut self, ctx: PluginCtx<()>, _buf_size: usize) {
+            self.paths.push(ctx.get_view().open_paths());
+        }
+        fn update(&mut self, _ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> { Ok(None) }
+        fn did_save(&mut self, ctx: PluginCtx<()>) -> Result<(), String> {
+            self.paths.push(ctx.get_view().open_paths());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn open_paths() {
+        use testing::MockCore;
+
+        let mut core = MockCore::start(PathsPlugin::default());
+        core.new_view("text");
+        core.did_save("/tmp/file.txt");
+        let plugin = core.finish();
+        let view_id = ViewIdentifier::from("view-id-1");
+        assert_eq!(plugin.paths, vec![
+            vec![(view_id, None)],
+            vec![(view_id, Some(PathBuf::from("/tmp/file.txt")))],
+        ]);
+    }
+
+    /// A plugin which sets a short request timeout, and records the result
+    /// of fetching the first line on update.
+    #[derive(Default)]
+    struct TimeoutPlugin {
+        result: Option<Result<String, Error>>,
+    }
+
+    impl Plugin for TimeoutPlugin {
+        type State = ();
+
+        fn initialize(&mut self, mut ctx: PluginCtx<()>, _buf_size: usize) {
+            ctx.set_request_timeout(Some(Duration::from_millis(10)));
+        }
+        fn update(&mut self, mut ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            self.result = Some(ctx.get_line(0).map(String::from));
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn request_timeout() {
+        let rpcs = [init_rpc(42), update_rpc(0, 2)];
+        // nothing answers the plugin's request for data
+        let mut plugin = TimeoutPlugin::default();
+        let mut rx = run_script(&mut plugin, &rpcs);
+        match plugin.result {
+            Some(Err(Error::TimedOut)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        rx.expect_rpc("plugin_info");
+        rx.expect_rpc("get_data");
+        assert_eq!(rx.expect_response(), Ok(json!(0)));
+    }
+
+    #[test]
+    fn rpc_result() {
+        assert_eq!(to_rpc_result(0), Ok(json!(0)));
+        assert_eq!(to_rpc_result(vec!["a", "b"]), Ok(json!(["a", "b"])));
+
+        // maps with non-string keys can't be represented in JSON
+        let mut map = BTreeMap::new();
+        map.insert((1, 2), 3);
+        match to_rpc_result(map) {
+            Err(RemoteError::Custom { code, .. }) => assert_eq!(code, UPDATE_ERROR_CODE),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn update_error_data() {
+        let rpcs = [init_rpc(42), update_rpc(0, 5)];
+        let mut rx = run_script(&mut FailingPlugin, &rpcs);
+        rx.expect_rpc("plugin_info");
+        match rx.expect_response() {
+            Err(RemoteError::Custom { code, data: Some(data), .. }) => {
+                assert_eq!(code, UPDATE_ERROR_CODE);
+                assert_eq!(data["rev"], json!(5));
+                assert_eq!(data["view_id"], json!("view-id-1"));
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    /// A plugin which records updates, and resets of its view.
+    #[derive(Default)]
+    struct MalformedPlugin {
+        log: Vec<String>,
+    }
+
+    impl Plugin for MalformedPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, ctx: PluginCtx<()>, info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            self.log.push(format!("update {} {}", info.rev, ctx.get_buf_size()));
+            Ok(None)
+        }
+        fn did_reset(&mut self, _ctx: PluginCtx<()>) {
+            self.log.push("reset".into());
+        }
+    }
+
+    #[test]
+    fn malformed_delta() {
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        let with_delta = |id, rev, delta: RopeDelta| {
+            let mut update = update_rpc(id, rev);
+            update["params"]["new_len"] = json!(delta.new_document_len());
+            update["params"]["delta"] = serde_json::to_value(delta).unwrap();
+            update
+        };
+        // the document is empty, but the delta is for one of 5 bytes
+        let bad = Delta::simple_edit(Interval::new_closed_open(3, 3), "x".into(), 5);
+        let good = Delta::simple_edit(Interval::new_closed_open(0, 0), "y".into(), 6);
+        let rpcs = [init_rpc(42), with_delta(0, 2, bad), with_delta(1, 3, good)];
+        let mut plugin = MalformedPlugin::default();
+        let mut rx = run_script(&mut plugin, &rpcs);
+        rx.expect_rpc("plugin_info");
+        match rx.expect_response() {
+            Err(RemoteError::Custom { code, data: Some(data), .. }) => {
+                assert_eq!(code, plugin_rpc::STALE_REVISION_ERROR);
+                assert_eq!(data["rev"], json!(2));
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+        // the length from the rejected update is taken as correct
+        assert_eq!(rx.expect_response(), Ok(json!(0)));
+        let mut log = plugin.log.clone();
+        log.sort();
+        assert_eq!(log, vec!["reset", "update 3 7"]);
+
+        assert!(check_delta(&Delta::simple_edit(Interval::new_closed_open(0, 1), "ab".into(), 3),
+                            3, 4).is_ok());
+        let mut copy_past_end = Delta::simple_edit(Interval::new_closed_open(0, 1), "".into(), 3);
+        copy_past_end.els = vec![DeltaElement::Copy(0, 4)];
+        assert!(check_delta(&copy_past_end, 3, 4).is_err());
+    }
+
+    /// A plugin which records the custom commands it receives, and echoes
+    /// the params of `echo` commands.
+    #[derive(Default)]
+    struct CommandPlugin {
+        commands: Vec<String>,
+    }
+
+    impl Plugin for CommandPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, _ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> { Ok(None) }
+        fn custom_command(&mut self, _ctx: PluginCtx<()>, method: &str, params: Value)
+                          -> Result<Value, RemoteError> {
+            self.commands.push(method.to_owned());
+            match method {
+                "echo" => Ok(params),
+                _ => Err(PluginError::MethodNotSupported("unknown".into()).into()),
+            }
+        }
+    }
+
+    /// A plugin which ends the document with a newline before it is saved,
+    /// and refuses saves to ".lock" files.
+    #[derive(Default)]
+    struct NewlineOnSavePlugin {
+        saved: usize,
+    }
+
+    impl Plugin for NewlineOnSavePlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, _ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            Ok(None)
+        }
+        fn will_save(&mut self, mut ctx: PluginCtx<()>, path: &Path) -> Result<(), RemoteError> {
+            if path.extension().is_some_and(|ext| ext == "lock") {
+                return Err(RemoteError::custom(403, "lock files are read-only", None));
+            }
+            let len = ctx.get_buf_size();
+            if !ctx.snapshot().unwrap().text().ends_with('\n') {
+                let delta = xi_rope::delta::Delta::simple_edit(
+                    Interval::new_closed_open(len, len), "\n".into(), len);
+                let author = ctx.author();
+                ctx.edit(delta, 0, false, &author).unwrap();
+            }
+            Ok(())
+        }
+        fn did_save(&mut self, _ctx: PluginCtx<()>) -> Result<(), String> {
+            self.saved += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn will_save() {
+        use testing::MockCore;
+
+        let mut core = MockCore::start(NewlineOnSavePlugin::default());
+        core.new_view("fn main() {}");
+        assert_eq!(core.will_save("main.rs"), Ok(json!(0)));
+        assert_eq!(core.text(), "fn main() {}\n");
+        core.did_save("main.rs");
+        assert!(core.will_save("Cargo.lock").is_err());
+        assert_eq!(core.finish().saved, 1);
+    }
+
+    /// Completes the word before the offset with other words in the document.
+    #[derive(Default)]
+    struct WordCompletionPlugin {
+        offsets: Vec<usize>,
+    }
+
+    impl Plugin for WordCompletionPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+
+        fn update(&mut self, _ctx: PluginCtx<()>, _info: UpdateContext,
+                  _delta: Option<RopeDelta>) -> Result<Option<Value>, RemoteError> {
+            Ok(None)
+        }
+
+        fn complete(&mut self, mut ctx: PluginCtx<()>, offset: usize)
+                    -> Result<Vec<CompletionItem>, RemoteError> {
+            self.offsets.push(offset);
+            let snapshot = ctx.snapshot().unwrap();
+            let text = snapshot.text();
+            let prefix = text[..offset].rsplit(' ').next().unwrap();
+            if prefix.is_empty() {
+                return Ok(Vec::new());
+            }
+            Ok(text.split(' ')
+                .filter(|word| word.starts_with(prefix) && *word != prefix)
+                .map(|word| CompletionItem {
+                    label: word.to_owned(),
+                    insert_text: word[prefix.len()..].to_owned(),
+                    kind: CompletionKind::Text,
+                    detail: None,
+                })
+                .collect())
+        }
+    }
+
+    #[test]
+    fn completion() {
+        use testing::MockCore;
+
+        let mut core = MockCore::start(WordCompletionPlugin::default());
+        core.new_view("length lime lemon le");
+        assert_eq!(core.completion(20), Ok(json!([
+            {"label": "length", "insert_text": "ngth", "kind": "text"},
+            {"label": "lemon", "insert_text": "mon", "kind": "text"},
+        ])));
+        // past the end of the document
+        assert_eq!(core.completion(500).unwrap().as_array().unwrap().len(), 2);
+        // nothing to suggest
+        assert_eq!(core.completion(7), Ok(json!([])));
+        assert_eq!(core.finish().offsets, vec![20, 20, 7]);
+    }
+
+    /// Describes the word at the offset, claiming a range which runs past
+    /// the end of the document.
+    struct HoverPlugin;
+
+    impl Plugin for HoverPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, _ctx: PluginCtx<()>, _info: UpdateContext,
+                  _delta: Option<RopeDelta>) -> Result<Option<Value>, RemoteError> {
+            Ok(None)
+        }
+        fn hover(&mut self, mut ctx: PluginCtx<()>, offset: usize)
+                 -> Result<Option<Hover>, RemoteError> {
+            let words = ctx.words(0..ctx.get_buf_size()).unwrap();
+            Ok(words.into_iter()
+                .find(|(range, _)| range.contains(&offset))
+                .map(|(range, word)| Hover {
+                    contents: format!("{}: word", word),
+                    range: Some((range.start, range.end + 100)),
+                }))
+        }
+    }
+
+    #[test]
+    fn hover() {
+        use testing::MockCore;
+
+        let mut core = MockCore::start(HoverPlugin);
+        core.new_view("let answer");
+        assert_eq!(core.hover(5), Ok(json!({"contents": "answer: word", "range": [4, 10]})));
+        assert_eq!(core.hover(3), Ok(Value::Null));
+        // past the end of the document
+        assert_eq!(core.hover(50), Ok(Value::Null));
+        core.finish();
+    }
+
+    /// A plugin which defers initialization, and records its callbacks.
+    #[derive(Default)]
+    struct LazyPlugin {
+        /// Whether the "analyze" command asks for initialization.
+        request_init: bool,
+        calls: Vec<String>,
+    }
+
+    impl Plugin for LazyPlugin {
+        type State = ();
+
+        fn initialize(&mut self, ctx: PluginCtx<()>, buf_size: usize) {
+            self.calls.push(format!("initialize {} {}", buf_size, ctx.is_initialized()));
+        }
+        fn should_defer_init(&self, _info: &plugin_rpc::PluginBufferInfo) -> bool { true }
+        fn update(&mut self, _ctx: PluginCtx<()>, info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            self.calls.push(format!("update {}", info.rev));
+            Ok(None)
+        }
+        fn custom_command(&mut self, mut ctx: PluginCtx<()>, method: &str, _params: Value)
+                          -> Result<Value, RemoteError> {
+            self.calls.push(format!("{} {}", method, ctx.is_initialized()));
+            if self.request_init {
+                ctx.request_init();
+            }
+            Ok(Value::Null)
+        }
+    }
+
+    #[test]
+    fn deferred_init() {
+        let run = |request_init: bool, rpcs: &[Value]| {
+            let mut plugin = LazyPlugin { request_init, ..LazyPlugin::default() };
+            let mut rx = run_script(&mut plugin, rpcs);
+            // core still learns of the plugin straight away
+            rx.expect_rpc("plugin_info");
+            plugin.calls
+        };
+        let analyze = json!({"method": "custom_command", "params": {"view_id": "view-id-1",
+                             "method": "analyze", "params": {}}});
+
+        let calls = run(false, &[init_rpc(42), analyze.clone(), update_rpc(0, 2)]);
+        assert_eq!(calls, vec!["analyze false", "initialize 0 true", "update 2"]);
+
+        let calls = run(true, &[init_rpc(42), analyze.clone()]);
+        assert_eq!(calls, vec!["analyze false", "initialize 0 true"]);
+
+        let calls = run(false, &[init_rpc(42), analyze]);
+        assert_eq!(calls, vec!["analyze false"]);
+    }
+
+    #[test]
+    fn custom_commands() {
+        let rpcs = [
+            init_rpc(42),
+            json!({"method": "custom_command", "params": {"view_id": "view-id-1",
+                   "method": "format", "params": {}}}),
+            json!({"id": 0, "method": "custom_command", "params": {"view_id": "view-id-1",
+                   "method": "echo", "params": {"hi": 1}}}),
+            json!({"id": 1, "method": "custom_command", "params": {"view_id": "view-id-1",
+                   "method": "nope", "params": {}}}),
+        ];
+        let mut plugin = CommandPlugin::default();
+        let mut rx = run_script(&mut plugin, &rpcs);
+        assert_eq!(plugin.commands, vec!["format", "echo", "nope"]);
+        rx.expect_rpc("plugin_info");
+        assert_eq!(rx.expect_response(), Ok(json!({"hi": 1})));
+        match rx.expect_response() {
+            Err(RemoteError::Custom { code, .. }) => {
+                assert_eq!(code, plugin_base::METHOD_NOT_SUPPORTED_ERROR)
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    /// A plugin which records the line ending on save, and inserts two
+    /// lines at the start of the document.
+    #[derive(Default)]
+    struct LineEndingPlugin {
+        line_ending: Option<LineEnding>,
+    }
+
+    impl Plugin for LineEndingPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, _ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> { Ok(None) }
+        fn did_save(&mut self, ctx: PluginCtx<()>) -> Result<(), String> {
+            use xi_rope::delta::Delta;
+            use xi_rope::interval::Interval;
+            self.line_ending = Some(ctx.line_ending());
+            let len = ctx.get_buf_size();
+            let delta = Delta::simple_edit(Interval::new_closed_open(0, 0), "a\nb\r\n".into(), len);
+            ctx.edit(delta, 0, false, "test").map_err(|e| format!("{:?}", e))
+        }
+    }
+
+    #[test]
+    fn line_endings() {
+        use testing::MockCore;
+
+        let cases = [
+            ("one\r\ntwo\r\n", LineEnding::CrLf, "a\r\nb\r\none\r\ntwo\r\n"),
+            ("one\ntwo\n", LineEnding::Lf, "a\nb\none\ntwo\n"),
+            ("one\r\ntwo\n", LineEnding::Mixed, "a\nb\none\r\ntwo\n"),
+        ];
+        for &(text, line_ending, expected) in cases.iter() {
+            let mut core = MockCore::start(LineEndingPlugin::default());
+            core.new_view(text);
+            core.did_save("a.txt");
+            core.sync();
+            assert_eq!(core.text(), expected);
+            assert_eq!(core.finish().line_ending, Some(line_ending));
+        }
+    }
+
+    #[test]
+    fn transform_offset_edges() {
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        // "0123456789" -> "012ab3456789"
+        let insert = Delta::simple_edit(Interval::new_closed_open(3, 3), "ab".into(), 10);
+        assert_eq!(transform_offset(0, &insert), 0);
+        assert_eq!(transform_offset(2, &insert), 2);
+        assert_eq!(transform_offset(3, &insert), 5);
+        assert_eq!(transform_offset(4, &insert), 6);
+        assert_eq!(transform_offset(10, &insert), 12);
+
+        // inserting at the start and the end
+        let insert = Delta::simple_edit(Interval::new_closed_open(0, 0), "ab".into(), 10);
+        assert_eq!(transform_offset(0, &insert), 2);
+        let insert = Delta::simple_edit(Interval::new_closed_open(10, 10), "ab".into(), 10);
+        assert_eq!(transform_offset(9, &insert), 9);
+        assert_eq!(transform_offset(10, &insert), 12);
+
+        // "0123456789" -> "0126789"
+        let delete = Delta::simple_edit(Interval::new_closed_open(3, 6), Rope::from(""), 10);
+        assert_eq!(transform_offset(2, &delete), 2);
+        assert_eq!(transform_offset(3, &delete), 3);
+        assert_eq!(transform_offset(4, &delete), 3);
+        assert_eq!(transform_offset(5, &delete), 3);
+        assert_eq!(transform_offset(6, &delete), 3);
+        assert_eq!(transform_offset(7, &delete), 4);
+        assert_eq!(transform_offset(10, &delete), 7);
+
+        // deleting everything
+        let delete = Delta::simple_edit(Interval::new_closed_open(0, 10), Rope::from(""), 10);
+        assert_eq!(transform_offset(0, &delete), 0);
+        assert_eq!(transform_offset(5, &delete), 0);
+        assert_eq!(transform_offset(10, &delete), 0);
+
+        // "0123456789" -> "012xy6789"
+        let replace = Delta::simple_edit(Interval::new_closed_open(3, 6), "xy".into(), 10);
+        assert_eq!(transform_offset(2, &replace), 2);
+        assert_eq!(transform_offset(3, &replace), 5);
+        assert_eq!(transform_offset(4, &replace), 5);
+        assert_eq!(transform_offset(6, &replace), 5);
+        assert_eq!(transform_offset(7, &replace), 6);
+
+        let mut offsets = [0, 3, 4, 6, 10];
+        transform_offsets(&mut offsets, &replace);
+        assert_eq!(offsets, [0, 5, 5, 5, 9]);
+    }
+
+    /// A plugin which keeps a bookmark at an offset.
+    struct BookmarkPlugin {
+        bookmark: usize,
+    }
+
+    impl Plugin for BookmarkPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, _ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> { Ok(None) }
+        fn transform_positions(&mut self, delta: &RopeDelta) {
+            self.bookmark = transform_offset(self.bookmark, delta);
+        }
+    }
+
+    #[test]
+    fn transform_plugin_positions() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        let mut core = MockCore::start(BookmarkPlugin { bookmark: 4 });
+        core.new_view("one two three");
+        let delta = Delta::simple_edit(Interval::new_closed_open(0, 3), "1".into(), 13);
+        core.update(delta).unwrap();
+        let delta = Delta::simple_edit(Interval::new_closed_open(2, 2), "and ".into(), 11);
+        core.update(delta).unwrap();
+        assert_eq!(core.text(), "1 and two three");
+        assert_eq!(core.finish().bookmark, 6);
+    }
+
+    /// A plugin which does not want deltas, and records the first two
+    /// lines of the document on update.
+    #[derive(Default)]
+    struct NoDeltaPlugin {
+        deltas: usize,
+        lines: Vec<String>,
+        buf_size: usize,
+    }
+
+    impl Plugin for NoDeltaPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, mut ctx: PluginCtx<()>, _info: UpdateContext, delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            self.deltas += delta.is_some() as usize;
+            self.buf_size = ctx.get_buf_size();
+            self.lines = ctx.lines().take(2).map(|(_, line)| line).collect();
+            Ok(None)
+        }
+        fn wants_delta(&self) -> bool { false }
+    }
+
+    #[test]
+    fn updates_without_deltas() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        let mut core = MockCore::start(NoDeltaPlugin::default());
+        core.new_view("one\ntwo");
+        let delta = Delta::simple_edit(Interval::new_closed_open(3, 3), "\nand a half".into(), 7);
+        core.update(delta).unwrap();
+        let delta = Delta::simple_edit(Interval::new_closed_open(0, 3), "1".into(), 18);
+        core.update(delta).unwrap();
+        assert_eq!(core.text(), "1\nand a half\ntwo");
+        let plugin = core.finish();
+        assert_eq!(plugin.deltas, 0);
+        assert_eq!(plugin.buf_size, 16);
+        assert_eq!(plugin.lines, vec!["1\n", "and a half\n"]);
+    }
+
+    /// A plugin which records the revision on init and on every update.
+    #[derive(Default)]
+    struct RevisionPlugin {
+        revs: Vec<u64>,
+    }
+
+    impl Plugin for RevisionPlugin {
+        type State = ();
+
+        fn initialize(&mut self, ctx: PluginCtx<()>, _buf_size: usize) {
+            self.revs.push(ctx.revision());
+        }
+        fn update(&mut self, ctx: PluginCtx<()>, info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            assert_eq!(ctx.revision(), info.rev);
+            self.revs.push(ctx.revision());
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn current_revision() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        let mut core = MockCore::start(RevisionPlugin::default());
+        core.new_view("abc");
+        let delta = Delta::simple_edit(Interval::new_closed_open(0, 1), "x".into(), 3);
+        core.update(delta).unwrap();
+        let delta = Delta::simple_edit(Interval::new_closed_open(3, 3), "y".into(), 3);
+        core.update(delta).unwrap();
+        assert_eq!(core.finish().revs, vec![1, 2, 3]);
+    }
+
+    /// A plugin which records the size of its cache, and reads the last line
+    /// of the document, on init and on config changes.
+    #[derive(Default)]
+    struct CacheSizePlugin {
+        sizes: Vec<usize>,
+        last_lines: Vec<String>,
+        metadata_only: bool,
+    }
+
+    impl CacheSizePlugin {
+        fn record(&mut self, mut ctx: PluginCtx<()>) {
+            self.sizes.push(ctx.cache_bytes());
+            self.last_lines.push(ctx.get_line(10).unwrap().to_owned());
+            self.sizes.push(ctx.cache_bytes());
+        }
+    }
+
+    impl Plugin for CacheSizePlugin {
+        type State = ();
+
+        fn initialize(&mut self, ctx: PluginCtx<()>, _buf_size: usize) {
+            self.record(ctx);
+        }
+        fn update(&mut self, _ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            Ok(None)
+        }
+        fn config_changed(&mut self, ctx: PluginCtx<()>, _changes: &ConfigTable) {
+            self.record(ctx);
+        }
+        fn new_cache(&self) -> Box<dyn Cache> {
+            match self.metadata_only {
+                true => Box::new(MetadataCache::default()),
+                false => Box::new(ChunkCache::default()),
+            }
+        }
+    }
+
+    #[test]
+    fn cache_budget() {
+        use testing::MockCore;
+
+        let text: String = (0..10).map(|i| format!("line {}\n", i)).collect::<String>() + "end";
+        let mut core = MockCore::start(CacheSizePlugin::default());
+        core.new_view(&text);
+        let mut changes = ConfigTable::new();
+        changes.insert(CACHE_MAX_BYTES_KEY.into(), json!(20));
+        core.config_changed(changes);
+        core.wait_idle();
+        let plugin = core.finish();
+        assert_eq!(plugin.last_lines, vec!["end", "end"]);
+        // the whole document fits the default budget; the lowered budget
+        // evicts it, and the last line is fetched again
+        assert_eq!(plugin.sizes[..2], [text.len(), text.len()]);
+        assert!(plugin.sizes[2] <= 20);
+        assert!(plugin.sizes[3] <= 20);
+    }
+
+    #[test]
+    fn metadata_cache() {
+        use testing::MockCore;
+
+        let text: String = (0..10).map(|i| format!("line {}\n", i)).collect::<String>() + "end";
+        let mut core = MockCore::start(CacheSizePlugin { metadata_only: true, ..Default::default() });
+        core.new_view(&text);
+        let delta = xi_rope::delta::Delta::simple_edit(
+            Interval::new_closed_open(0, 0), "first ".into(), text.len());
+        core.update(delta).unwrap();
+        core.config_changed(ConfigTable::new());
+        core.wait_idle();
+        let plugin = core.finish();
+        assert_eq!(plugin.last_lines, vec!["end", "end"]);
+        // the text core sent on init isn't kept, and only the line read is
+        assert_eq!(plugin.sizes, vec![0, 3, 0, 3]);
+    }
+
+    /// A plugin which records the document's stats on every update, with
+    /// a cache which keeps no text if `metadata_only` is set.
+    #[derive(Default)]
+    struct StatsPlugin {
+        metadata_only: bool,
+        stats: Vec<DocumentStats>,
+    }
+
+    impl Plugin for StatsPlugin {
+        type State = ();
+
+        fn new_cache(&self) -> Box<dyn Cache> {
+            match self.metadata_only {
+                true => Box::new(MetadataCache::default()),
+                false => Box::<ChunkCache>::default(),
+            }
+        }
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, mut ctx: PluginCtx<()>, _info: UpdateContext,
+                  _delta: Option<RopeDelta>) -> Result<Option<Value>, RemoteError> {
+            self.stats.push(ctx.stats());
+            self.stats.push(ctx.exact_stats().unwrap());
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn stats() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+
+        let edit = || Delta::simple_edit(Interval::new_closed_open(0, 0), "ü".into(), 11);
+        let exact = DocumentStats { bytes: 13, chars: 11, chars_exact: true, lines: 2 };
+
+        let mut core = MockCore::start(StatsPlugin::default());
+        core.new_view("héllo\nwrld");
+        core.update(edit()).unwrap();
+        assert_eq!(core.finish().stats, vec![exact, exact]);
+
+        let mut core = MockCore::start(StatsPlugin { metadata_only: true, ..Default::default() });
+        core.new_view("héllo\nwrld");
+        core.update(edit()).unwrap();
+        let estimate = DocumentStats { chars: 13, chars_exact: false, ..exact };
+        assert_eq!(core.finish().stats, vec![estimate, exact]);
+    }
+
+    /// A plugin which reads the start of the document, and checks the cache,
+    /// on every update.
+    #[derive(Default)]
+    struct VerifyPlugin {
+        verified: Vec<bool>,
+    }
+
+    impl Plugin for VerifyPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, mut ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            ctx.get_line(0).unwrap();
+            self.verified.push(ctx.verify_cache());
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn verify_cache() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        let mut core = MockCore::start(VerifyPlugin::default());
+        core.new_view("one\ntwo\nthree");
+        let mut changes = ConfigTable::new();
+        changes.insert(VERIFY_CACHE_KEY.into(), json!(true));
+        core.config_changed(changes);
+        let delta = Delta::simple_edit(Interval::new_closed_open(3, 3), "\nand a half".into(), 13);
+        core.update(delta).unwrap();
+        let delta = Delta::simple_edit(Interval::new_closed_open(0, 8), "".into(), 24);
+        core.update(delta).unwrap();
+        core.wait_idle();
+        assert_eq!(core.finish().verified, vec![true, true]);
+    }
+
+    /// A plugin which takes a snapshot on init and on every update.
+    #[derive(Default)]
+    struct SnapshotPlugin {
+        snapshots: Vec<DocumentSnapshot>,
+    }
+
+    impl Plugin for SnapshotPlugin {
+        type State = ();
+
+        fn initialize(&mut self, mut ctx: PluginCtx<()>, _buf_size: usize) {
+            self.snapshots.push(ctx.snapshot().unwrap());
+            self.snapshots.push(ctx.snapshot().unwrap());
+        }
+        fn update(&mut self, mut ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            self.snapshots.push(ctx.snapshot().unwrap());
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn document_snapshot() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        fn assert_send<T: Send + Sync>(_: &T) {}
+
+        let mut core = MockCore::start(SnapshotPlugin::default());
+        core.new_view("one\ntwo");
+        let delta = Delta::simple_edit(Interval::new_closed_open(0, 3), "1".into(), 7);
+        core.update(delta).unwrap();
+        let snapshots = core.finish().snapshots;
+        assert_send(&snapshots[0]);
+        let texts = snapshots.iter().map(|s| (s.text(), s.rev())).collect::<Vec<_>>();
+        assert_eq!(texts, vec![("one\ntwo", 1), ("one\ntwo", 1), ("1\ntwo", 2)]);
+        assert!(Arc::ptr_eq(&snapshots[0].text, &snapshots[1].text));
+    }
+
+    /// A plugin which watches two regions, recording updates and changes to
+    /// the regions, and stops watching the first once it changes.
+    #[derive(Default)]
+    struct RegionPlugin {
+        log: Vec<String>,
+    }
+
+    impl Plugin for RegionPlugin {
+        type State = ();
+
+        fn initialize(&mut self, mut ctx: PluginCtx<()>, _buf_size: usize) {
+            ctx.watch_region(4..7, 1);
+            ctx.watch_region(8..13, 2);
+        }
+        fn update(&mut self, _ctx: PluginCtx<()>, info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            self.log.push(format!("update {}", info.rev));
+            Ok(None)
+        }
+        fn region_changed(&mut self, mut ctx: PluginCtx<()>, token: usize, range: Range<usize>) {
+            self.log.push(format!("changed {} {:?}", token, range));
+            if token == 1 {
+                ctx.unwatch_region(1);
+            }
+        }
+    }
+
+    #[test]
+    fn watch_regions() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        let mut core = MockCore::start(RegionPlugin::default());
+        core.new_view("one\ntwo\nthree");
+        let edits = [(0..0, "X", 13), (8..8, "Y", 14), (5..6, "T", 15), (9..11, "", 15)];
+        for &(ref iv, text, len) in &edits {
+            let delta = Delta::simple_edit(Interval::new_closed_open(iv.start, iv.end),
+                                           text.into(), len);
+            core.update(delta).unwrap();
+        }
+        assert_eq!(core.text(), "Xone\nTwoYhree");
+        assert_eq!(core.finish().log, vec![
+            "update 2",
+            // an insert at the end of a region is added to it
+            "changed 1 5..9",
+            "update 3",
+            "update 4",
+            "changed 2 9..13",
+            "update 5",
+        ]);
+    }
+
+    /// A plugin which watches the whole document, and resets the view on the
+    /// first update, recording what it sees.
+    #[derive(Default)]
+    struct ResetPlugin {
+        log: Vec<String>,
+    }
+
+    impl Plugin for ResetPlugin {
+        type State = ();
+
+        fn initialize(&mut self, mut ctx: PluginCtx<()>, buf_size: usize) {
+            ctx.watch_region(0..buf_size, 1);
+        }
+        fn update(&mut self, mut ctx: PluginCtx<()>, info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            self.log.push(format!("update {}", info.rev));
+            if info.rev == 2 {
+                ctx.request_full_reset();
+                ctx.request_full_reset();
+                self.log.push(format!("cached {}", ctx.cache_bytes()));
+            }
+            Ok(None)
+        }
+        fn region_changed(&mut self, _ctx: PluginCtx<()>, token: usize, _range: Range<usize>) {
+            self.log.push(format!("changed {}", token));
+        }
+        fn did_reset(&mut self, mut ctx: PluginCtx<()>) {
+            let line = ctx.get_line(0).unwrap().to_owned();
+            self.log.push(format!("reset {:?}", line));
+        }
+    }
+
+    #[test]
+    fn full_reset() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        let mut core = MockCore::start(ResetPlugin::default());
+        core.new_view("one\ntwo");
+        let delta = Delta::simple_edit(Interval::new_closed_open(0, 1), "O".into(), 7);
+        core.update(delta).unwrap();
+        core.wait_idle();
+        let delta = Delta::simple_edit(Interval::new_closed_open(1, 2), "N".into(), 7);
+        core.update(delta).unwrap();
+        assert_eq!(core.finish().log, vec![
+            "changed 1", "update 2", "cached 0", "reset \"One\\n\"",
+            // the region is no longer watched
+            "update 3",
+        ]);
+    }
+
+    /// A plugin which records each syntax change, and the syntax then
+    /// seen in updates.
+    #[derive(Default)]
+    struct SyntaxPlugin {
+        log: Vec<String>,
+    }
+
+    impl Plugin for SyntaxPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            self.log.push(format!("update {:?}", ctx.syntax()));
+            Ok(None)
+        }
+        fn syntax_changed(&mut self, ctx: PluginCtx<()>, syntax: SyntaxDefinition) {
+            assert_eq!(ctx.syntax(), syntax);
+            self.log.push(format!("changed {:?}", syntax));
+        }
+    }
+
+    #[test]
+    fn syntax_changed() {
+        let rpcs = [
+            init_rpc(42),
+            update_rpc(0, 2),
+            json!({"method": "syntax_changed",
+                   "params": {"view_id": "view-id-1", "syntax": "rust"}}),
+            update_rpc(1, 3),
+        ];
+        let mut plugin = SyntaxPlugin::default();
+        run_script(&mut plugin, &rpcs);
+        assert_eq!(plugin.log, vec!["update Plaintext", "changed Rust", "update Rust"]);
+    }
+
+    /// A plugin which loads the document progressively, keeping the ranges
+    /// loaded so far up to date with edits.
+    #[derive(Default)]
+    struct LoadPlugin {
+        chunks: Vec<String>,
+        loaded: Vec<Range<usize>>,
+        done: bool,
+    }
+
+    impl Plugin for LoadPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, _ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            Ok(None)
+        }
+        fn wants_progressive_load(&self) -> bool { true }
+        fn transform_positions(&mut self, delta: &RopeDelta) {
+            for range in self.loaded.iter_mut() {
+                *range = transform_offset(range.start, delta)..transform_offset(range.end, delta);
+            }
+        }
+        fn buffer_chunk_loaded(&mut self, mut ctx: PluginCtx<()>, range: Range<usize>) {
+            assert!(!self.done);
+            assert_eq!(ctx.load_progress(), Some(range.start));
+            self.chunks.push(ctx.get_region(range.start, range.end).unwrap());
+            self.loaded.push(range);
+        }
+        fn buffer_loaded(&mut self, ctx: PluginCtx<()>) {
+            assert_eq!(ctx.load_progress(), None);
+            self.done = true;
+        }
+    }
+
+    /// A buffer info for `text`, loaded eight bytes at a time.
+    fn small_load_chunks(text: &str) -> plugin_rpc::PluginBufferInfo {
+        use testing::PluginBufferInfoBuilder;
+
+        let mut config = ConfigTable::new();
+        config.insert(LOAD_CHUNK_SIZE_KEY.into(), json!(8));
+        PluginBufferInfoBuilder::new().text(text).config(config).build()
+    }
+
+    #[test]
+    fn progressive_load() {
+        use testing::MockCore;
+
+        let text = "one\ntwo\nthree\nfour\nfive";
+        let mut core = MockCore::start(LoadPlugin::default());
+        core.new_view_with_info(small_load_chunks(text));
+        core.wait_idle();
+        let plugin = core.finish();
+        assert!(plugin.done);
+        assert_eq!(plugin.chunks, vec!["one\ntwo\n", "three\nfour\n", "five"]);
+
+        // by default, a small document is loaded in one go
+        let mut core = MockCore::start(LoadPlugin::default());
+        core.new_view(text);
+        core.wait_idle();
+        let plugin = core.finish();
+        assert!(plugin.done);
+        assert_eq!(plugin.chunks, vec![text]);
+
+        let mut core = MockCore::start(LoadPlugin::default());
+        core.new_view("");
+        core.wait_idle();
+        let plugin = core.finish();
+        assert!(plugin.done);
+        assert!(plugin.chunks.is_empty());
+    }
+
+    #[test]
+    fn progressive_load_with_edits() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        let text = "one\ntwo\nthree\nfour\nfive\nsix\nseven\neight\n";
+        // delete "two\n", at each point in the load at which the plugin
+        // asks core for text, or once loading is done
+        for at_request in (0..7).map(Some).chain(Some(None)) {
+            let delta = Delta::simple_edit(Interval::new_closed_open(4, 8), "".into(), text.len());
+            let mut core = MockCore::start(LoadPlugin::default());
+            match at_request {
+                Some(n) => {
+                    core.update_at_request(n, delta);
+                    core.new_view_unfetched(small_load_chunks(text));
+                    core.wait_idle();
+                }
+                None => {
+                    core.new_view_unfetched(small_load_chunks(text));
+                    core.wait_idle();
+                    core.update(delta).unwrap();
+                }
+            }
+            assert_eq!(core.text(), text.replacen("two\n", "", 1), "{:?}", at_request);
+            let len = core.text().len();
+            let mut plugin = core.finish();
+            assert!(plugin.done);
+            // the loaded ranges tile the document, however the load and the
+            // edit are interleaved
+            plugin.loaded.retain(|range| !range.is_empty());
+            let mut end = 0;
+            for range in &plugin.loaded {
+                assert_eq!(range.start, end, "{:?}: {:?}", at_request, plugin.loaded);
+                end = range.end;
+            }
+            assert_eq!(end, len);
+        }
+    }
+
+    /// A plugin which records the selections it is told about, and those it
+    /// sees in updates.
+    #[derive(Default)]
+    struct SelectionPlugin {
+        log: Vec<Vec<Range<usize>>>,
+    }
+
+    impl Plugin for SelectionPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            self.log.push(ctx.selections().to_vec());
+            Ok(None)
+        }
+        fn selection_changed(&mut self, ctx: PluginCtx<()>, selections: &[Range<usize>]) {
+            assert_eq!(ctx.selections(), selections);
+            self.log.push(selections.to_vec());
+        }
+    }
+
+    #[test]
+    fn selections() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        let mut core = MockCore::start(SelectionPlugin::default());
+        core.new_view("hello world");
+        core.selection_changed(&[(11, 6), (3, 3)]);
+        let delta = Delta::simple_edit(Interval::new_closed_open(0, 0), "oh, ".into(), 11);
+        core.update(delta).unwrap();
+        let delta = Delta::simple_edit(Interval::new_closed_open(8, 15), "".into(), 15);
+        core.update(delta).unwrap();
+        // reported for a longer document than the plugin has seen
+        core.selection_changed(&[(2, 40)]);
+        assert_eq!(core.finish().log, vec![
+            vec![6..11, 3..3],
+            vec![10..15, 7..7],
+            vec![8..8, 7..7],
+            vec![2..8],
+        ]);
+    }
+
+    /// Records the selections it is told of, after asking core for the
+    /// text on each update, so that notifications pile up meanwhile.
+    #[derive(Default)]
+    struct BusySelectionPlugin {
+        log: Vec<Vec<Range<usize>>>,
+    }
+
+    impl Plugin for BusySelectionPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, mut ctx: PluginCtx<()>, _info: UpdateContext,
+                  _delta: Option<RopeDelta>) -> Result<Option<Value>, RemoteError> {
+            assert!(ctx.verify_cache());
+            Ok(None)
+        }
+        fn selection_changed(&mut self, _ctx: PluginCtx<()>, selections: &[Range<usize>]) {
+            self.log.push(selections.to_vec());
+        }
+    }
+
+    #[test]
+    fn coalesce_selections() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        let mut core = MockCore::start(BusySelectionPlugin::default());
+        core.new_view(&"a".repeat(100));
+        core.hold_notifications();
+        for i in 0..20 {
+            core.selection_changed(&[(i, i + 1)]);
+        }
+        // anything which can't be coalesced flushes those held back
+        core.did_save("/tmp/file.txt");
+        let delta = Delta::simple_edit(Interval::new_closed_open(100, 100), "b".into(), 100);
+        core.update(delta).unwrap();
+        core.sync();
+        assert_eq!(core.finish().log, vec![vec![19..20]]);
+    }
+
+    /// A plugin which reports its progress on odd revisions.
+    struct StatusPlugin;
+
+    impl Plugin for StatusPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, _ctx: PluginCtx<()>, info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            match info.rev % 2 {
+                1 => Ok(UpdateResponse::status("indexing").with_progress(1.5)
+                        .with_action("cancel").into()),
+                _ => Ok(UpdateResponse::none().into()),
+            }
+        }
+    }
+
+    #[test]
+    fn update_response() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+
+        let mut core = MockCore::start(StatusPlugin);
+        core.new_view("");
+        let mut responses = Vec::new();
+        for i in 0..2 {
+            let delta = Delta::simple_edit(Interval::new_closed_open(i, i), "x".into(), i);
+            responses.push(core.update(delta).unwrap());
+        }
+        core.finish();
+        // the view starts at rev 1
+        assert_eq!(responses, vec![
+            json!(0),
+            json!({"status": "indexing", "progress": 1.0, "actions": ["cancel"]}),
+        ]);
+        let status: plugin_rpc::UpdateResponse = serde_json::from_value(responses[1].clone())
+            .unwrap();
+        match status {
+            plugin_rpc::UpdateResponse::Status(status) => assert_eq!(status.progress, Some(1.0)),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    /// A plugin which counts the words in the document on each update,
+    /// tracing the count.
+    #[derive(Default)]
+    struct TracedPlugin {
+        counts: Vec<usize>,
+    }
+
+    impl Plugin for TracedPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, mut ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            let text = ctx.snapshot().unwrap();
+            let count = ctx.traced("traced_plugin::count", || text.text().split(' ').count());
+            self.counts.push(count);
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn traced() {
+        use plugin_base::tests::TRACING;
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+
+        let _tracing = TRACING.lock().unwrap_or_else(|err| err.into_inner());
+        let count_samples = || xi_trace::samples_cloned_unsorted().iter()
+            .filter(|sample| sample.name == "traced_plugin::count")
+            .count();
+        let mut core = MockCore::start(TracedPlugin::default());
+        core.new_view("a b");
+        xi_trace::disable_tracing();
+        core.update(Delta::simple_edit(Interval::new_closed_open(3, 3), " c".into(), 3)).unwrap();
+        assert_eq!(count_samples(), 0);
+        xi_trace::enable_tracing();
+        core.update(Delta::simple_edit(Interval::new_closed_open(5, 5), " d".into(), 5)).unwrap();
+        assert_eq!(count_samples(), 1);
+        xi_trace::disable_tracing();
+        assert_eq!(core.finish().counts, vec![3, 4]);
+    }
+
+    /// A plugin which sends spans for each update, and again once idle.
+    struct MixedSpansPlugin;
+
+    impl Plugin for MixedSpansPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, mut ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            ctx.update_spans(0, 1, &[]);
+            ctx.schedule_idle(0);
+            Ok(None)
+        }
+        fn idle(&mut self, ctx: PluginCtx<()>, _token: usize) -> Idle {
+            let len = ctx.get_buf_size();
+            ctx.update_spans(0, len, &[plugin_rpc::ScopeSpan { start: 0, end: len, scope_id: 0 }]);
+            Idle::Done
+        }
+    }
+
+    #[test]
+    fn span_sequence() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+
+        let mut core = MockCore::start(MixedSpansPlugin);
+        core.new_view("");
+        for i in 0..3 {
+            core.update(Delta::simple_edit(Interval::new_closed_open(i, i), "x".into(), i))
+                .unwrap();
+            if i == 1 {
+                core.wait_idle();
+            }
+        }
+        core.wait_idle();
+        let seqs = core.notifications().iter()
+            .filter(|msg| msg["method"] == "update_spans")
+            .map(|msg| msg["params"]["seq"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+        assert!(seqs.len() >= 5, "{:?}", seqs);
+        assert!(seqs.windows(2).all(|w| w[0] < w[1]), "out of order: {:?}", seqs);
+        core.finish();
+    }
+
+    /// A plugin which records whether its view is focused, on init, on focus
+    /// changes and on updates.
+    #[derive(Default)]
+    struct FocusPlugin {
+        log: Vec<(&'static str, bool)>,
+    }
+
+    impl Plugin for FocusPlugin {
+        type State = ();
+
+        fn initialize(&mut self, ctx: PluginCtx<()>, _buf_size: usize) {
+            self.log.push(("initialize", ctx.is_focused()));
+        }
+        fn update(&mut self, ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            self.log.push(("update", ctx.is_focused()));
+            Ok(None)
+        }
+        fn focus_changed(&mut self, ctx: PluginCtx<()>, focused: bool) {
+            assert_eq!(ctx.is_focused(), focused);
+            self.log.push(("focus_changed", focused));
+        }
+    }
+
+    #[test]
+    fn focus() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+
+        let mut core = MockCore::start(FocusPlugin::default());
+        core.new_view("hello");
+        core.focus_changed(true);
+        core.update(Delta::simple_edit(Interval::new_closed_open(0, 0), "oh ".into(), 5)).unwrap();
+        core.focus_changed(false);
+        core.update(Delta::simple_edit(Interval::new_closed_open(0, 0), "oh ".into(), 8)).unwrap();
+        assert_eq!(core.finish().log, vec![
+            ("initialize", false),
+            ("focus_changed", true),
+            ("update", true),
+            ("focus_changed", false),
+            ("update", false),
+        ]);
+    }
+
+    /// A plugin which records the viewport on viewport changes and updates,
+    /// and whether the visible lines were in the cache when it changed.
+    #[derive(Default)]
+    struct ViewportPlugin {
+        log: Vec<(&'static str, Option<(usize, usize)>)>,
+        warmed: Vec<bool>,
+    }
+
+    impl Plugin for ViewportPlugin {
+        type State = ();
+
+        fn initialize(&mut self, ctx: PluginCtx<()>, _buf_size: usize) {
+            self.log.push(("initialize", ctx.viewport()));
+        }
+        fn update(&mut self, ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            self.log.push(("update", ctx.viewport()));
+            Ok(None)
+        }
+        fn viewport_changed(&mut self, ctx: PluginCtx<()>, first_line: usize, last_line: usize) {
+            assert_eq!(ctx.viewport(), Some((first_line, last_line)));
+            let range = ctx.state.viewport.as_ref().and_then(|v| v.range.clone()).unwrap();
+            self.warmed.push(ctx.state.buf_cache.cached_region(range.start, range.end).is_some());
+            self.log.push(("viewport_changed", ctx.viewport()));
+        }
+    }
+
+    #[test]
+    fn viewport() {
+        use testing::{MockCore, PluginBufferInfoBuilder};
+        use xi_rope::delta::Delta;
+
+        fn line_start(text: &str, line_num: usize) -> usize {
+            text.split_inclusive('\n').take(line_num).map(str::len).sum()
+        }
+
+        // a budget small enough that the visible lines must be fetched
+        let text = (0..20).map(|i| format!("line number {}\n", i)).collect::<String>();
+        let mut config = ConfigTable::new();
+        config.insert(CACHE_MAX_BYTES_KEY.into(), json!(40));
+        let info = PluginBufferInfoBuilder::new().text(&text).config(config).build();
+        let mut core = MockCore::start(ViewportPlugin::default());
+        core.new_view_with_info(info);
+        core.viewport_changed(12, 13);
+
+        let edit = |core: &mut MockCore<_>, start: usize, end: usize, new: &str| {
+            let len = core.text().len();
+            let delta = Delta::simple_edit(Interval::new_closed_open(start, end), new.into(), len);
+            core.update(delta).unwrap();
+        };
+        // a line added above the viewport moves it down
+        edit(&mut core, 0, 0, "new\n");
+        // an edit within a line leaves it where it is
+        let start = line_start(&core.text(), 14) + 2;
+        edit(&mut core, start, start, "x");
+        // joining two visible lines shortens it
+        let end = line_start(&core.text(), 14);
+        edit(&mut core, end - 1, end, "");
+        // lines added below it don't move it
+        let len = core.text().len();
+        edit(&mut core, len, len, "\n\n");
+        // a viewport past the end is clamped to the last line
+        core.viewport_changed(40, 30);
+        let plugin = core.finish();
+        assert_eq!(plugin.log, vec![
+            ("initialize", None),
+            ("viewport_changed", Some((12, 13))),
+            ("update", Some((13, 14))),
+            ("update", Some((13, 14))),
+            ("update", Some((13, 13))),
+            ("update", Some((13, 13))),
+            ("viewport_changed", Some((22, 22))),
+        ]);
+        assert_eq!(plugin.warmed, vec![true, true]);
+    }
+
+    /// A plugin which sets fold ranges on init, and records them on each
+    /// update.
+    #[derive(Default)]
+    struct FoldPlugin {
+        log: Vec<Vec<(usize, usize)>>,
+    }
+
+    impl FoldPlugin {
+        fn record(&mut self, ctx: &PluginCtx<()>) {
+            self.log.push(ctx.folding_ranges().iter()
+                          .map(|fold| (fold.start_line, fold.end_line))
+                          .collect());
+        }
+    }
+
+    impl Plugin for FoldPlugin {
+        type State = ();
+
+        fn initialize(&mut self, mut ctx: PluginCtx<()>, _buf_size: usize) {
+            let fold = |start_line, end_line, kind| FoldRange { start_line, end_line, kind };
+            ctx.set_folding_ranges(vec![
+                fold(1, 3, FoldKind::Region),
+                fold(5, 4, FoldKind::Region),
+                fold(6, 100, FoldKind::Comment),
+                fold(7, 8, FoldKind::Comment),
+            ]);
+            self.record(&ctx);
+        }
+        fn update(&mut self, ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            self.record(&ctx);
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn folding_ranges() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+
+        let text = (0..10).map(|i| format!("line {}\n", i)).collect::<String>();
+        let mut core = MockCore::start(FoldPlugin::default());
+        core.new_view(&text);
+        let sent = |core: &MockCore<_>| core.notifications().iter()
+            .filter(|msg| msg["method"] == "folding_ranges")
+            .map(|msg| msg["params"]["ranges"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(sent(&core), vec![json!([
+            {"start_line": 1, "end_line": 3, "kind": "region"},
+            {"start_line": 7, "end_line": 8, "kind": "comment"},
+        ])]);
+
+        let edit = |core: &mut MockCore<_>, offset: usize, new: &str| {
+            let len = core.text().len();
+            let delta = Delta::simple_edit(Interval::new_closed_open(offset, offset), new.into(), len);
+            core.update(delta).unwrap();
+        };
+        // a line added at the top moves both
+        edit(&mut core, 0, "first\n");
+        // a line added in the middle of the first grows it
+        let offset = core.text().find("line 2").unwrap();
+        edit(&mut core, offset, "more\n");
+        // an edit at the end moves neither, and they aren't sent again
+        let len = core.text().len();
+        edit(&mut core, len, "last");
+        let sent = sent(&core);
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[2][1], json!({"start_line": 9, "end_line": 10, "kind": "comment"}));
+        let plugin = core.finish();
+        assert_eq!(plugin.log, vec![
+            vec![(1, 3), (7, 8)],
+            vec![(2, 4), (8, 9)],
+            vec![(2, 5), (9, 10)],
+            vec![(2, 5), (9, 10)],
+        ]);
+    }
+
+    /// A plugin which, on init, schedules an idle callback far in the
+    /// future, and records the tokens cancelled.
+    #[derive(Default)]
+    struct WaitingPlugin {
+        cancelled: Vec<usize>,
+    }
+
+    impl Plugin for WaitingPlugin {
+        type State = ();
+
+        fn initialize(&mut self, mut ctx: PluginCtx<()>, _buf_size: usize) {
+            ctx.schedule_idle_after(7, Duration::from_secs(60));
+        }
+        fn update(&mut self, _ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            Ok(None)
+        }
+        fn cancel(&mut self, _ctx: PluginCtx<()>, token: usize) {
+            self.cancelled.push(token);
+        }
+    }
+
+    #[test]
+    fn shutdown_cancels_idle_work() {
+        let rpcs = [init_rpc(42), json!({"id": 0, "method": "shutdown", "params": {}})];
+        let mut plugin = WaitingPlugin::default();
+        run_script(&mut plugin, &rpcs);
+        assert_eq!(plugin.cancelled, vec![7]);
+    }
+
+    #[test]
+    fn debug_state() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+
+        let mut core = MockCore::start(WaitingPlugin::default());
+        core.new_view("secret\ntext\n");
+        core.update(Delta::simple_edit(Interval::new_closed_open(0, 0), "top ".into(), 12))
+            .unwrap();
+        let state = core.debug_state().unwrap();
+        assert_eq!(state["plugin_id"], json!(0));
+        assert_eq!(state["initialized"], json!(true));
+        let view = &state["views"][0];
+        assert_eq!(view["view_id"], json!("view-id-1"));
+        assert_eq!(view["buf_size"], json!(16));
+        assert_eq!(view["num_lines"], json!(3));
+        assert_eq!(view["cache_bytes"], json!(16));
+        assert_eq!(state["idle_tokens"], json!({"7": 1}));
+        assert_eq!(state["pending_edits"], json!(0));
+        // sizes only, never the text
+        assert!(!state.to_string().contains("secret"), "{}", state);
+        core.finish();
+    }
+
+    /// A plugin which, on init, schedules idle callbacks after each of
+    /// `delays`, with its index as the token, signalling `fired` with each
+    /// token as its callback runs.
+    struct DelayedPlugin {
+        delays: Vec<Duration>,
+        fired: mpsc::Sender<usize>,
+    }
+
+    impl Plugin for DelayedPlugin {
+        type State = ();
+
+        fn initialize(&mut self, mut ctx: PluginCtx<()>, _buf_size: usize) {
+            for (token, &delay) in self.delays.iter().enumerate() {
+                ctx.schedule_idle_after(token, delay);
+            }
+        }
+        fn update(&mut self, _ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            Ok(None)
+        }
+        fn idle(&mut self, _ctx: PluginCtx<()>, token: usize) -> Idle {
+            self.fired.send(token).unwrap();
+            Idle::Done
+        }
+    }
+
+    #[test]
+    fn idle_after_delays() {
+        use testing::MockCore;
+
+        let (tx, fired) = mpsc::channel();
+        let delays = [200, 0, 100].iter().map(|&ms| Duration::from_millis(ms)).collect();
+        let mut core = MockCore::start(DelayedPlugin { delays, fired: tx });
+        core.new_view("text");
+        // callbacks run in the order their delays elapse
+        let tokens = (0..3)
+            .map(|_| fired.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(tokens, vec![1, 2, 0]);
+        core.finish();
+    }
+
+    /// A plugin which implements only the methods `Plugin` requires.
+    #[derive(Default)]
+    struct MinimalPlugin {
+        updates: usize,
+    }
+
+    impl Plugin for MinimalPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, _ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            self.updates += 1;
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn minimal_plugin() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+
+        let mut core = MockCore::start(MinimalPlugin::default());
+        core.new_view("hello");
+        core.selection_changed(&[(1, 1)]);
+        core.focus_changed(true);
+        core.viewport_changed(0, 0);
+        core.update(Delta::simple_edit(Interval::new_closed_open(5, 5), "!".into(), 5)).unwrap();
+        core.will_save("hello.txt").unwrap();
+        core.did_save("hello.txt");
+        assert_eq!(core.completion(2).unwrap(), json!([]));
+        assert_eq!(core.hover(2).unwrap(), Value::Null);
+        core.wait_idle();
+        assert!(!core.notifications().iter().any(|msg| msg["method"] == "alert"));
+        assert_eq!(core.finish().updates, 1);
+    }
+
+    /// A plugin which, when the document is saved, replaces its last
+    /// character with `suffix` using `rebase_and_retry`, and records any
+    /// edits which fail.
+    #[derive(Default)]
+    struct RetryPlugin {
+        suffix: &'static str,
+        failures: Vec<String>,
+    }
+
+    impl Plugin for RetryPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, _ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            Ok(None)
+        }
+        fn did_save(&mut self, mut ctx: PluginCtx<()>) -> Result<(), String> {
+            use xi_rope::delta::Delta;
+            let len = ctx.get_buf_size();
+            let delta = Delta::simple_edit(Interval::new_closed_open(len - 1, len),
+                                           self.suffix.into(), len);
+            let rev = ctx.revision();
+            ctx.rebase_and_retry(delta, rev, 0, false, "test").map_err(|e| format!("{:?}", e))
+        }
+        fn edit_failed(&mut self, _ctx: PluginCtx<()>, _delta: RopeDelta, err: Error) {
+            self.failures.push(format!("{:?}", err));
+        }
+    }
+
+    #[test]
+    fn rebase_and_retry() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+
+        // core moves on before the plugin's edit arrives; once the plugin
+        // catches up, the edit is moved past the concurrent one.
+        let mut core = MockCore::start(RetryPlugin { suffix: "!", ..Default::default() });
+        core.new_view("hello world");
+        core.hold_update(Delta::simple_edit(Interval::new_closed_open(0, 0), "oh, ".into(), 11));
+        core.did_save("/tmp/file.txt");
+        assert_eq!(core.text(), "oh, hello world");
+        assert!(core.send_held_updates().iter().all(Result::is_ok));
+        assert_eq!(core.text(), "oh, hello worl!");
+        assert!(core.finish().failures.is_empty());
+
+        // the concurrent edit deletes the text the plugin's edit replaces
+        let mut core = MockCore::start(RetryPlugin { suffix: "!", ..Default::default() });
+        core.new_view("hello world");
+        core.hold_update(Delta::simple_edit(Interval::new_closed_open(5, 11), "".into(), 11));
+        core.did_save("/tmp/file.txt");
+        core.send_held_updates();
+        assert_eq!(core.text(), "hello");
+        assert_eq!(core.finish().failures, vec!["EditConflict"]);
+    }
+
+    #[test]
+    fn delta_summaries() {
+        use xi_rope::delta::Delta;
+
+        let summary = |delta: &RopeDelta| summarize_delta(delta).ranges.iter()
+            .map(|r| (r.old.start, r.old.end, r.new_len))
+            .collect::<Vec<_>>();
+        let text = Rope::from("0123456789");
+
+        // a no-op delta, and one which only copies
+        let identity = Delta::simple_edit(Interval::new_closed_open(4, 4), Rope::from(""), 10);
+        assert_eq!(summary(&identity), vec![]);
+        assert!(summarize_delta(&identity).is_empty());
+        let mut builder = DeltaBuilder::new(10);
+        builder.replace(Interval::new_closed_open(0, 0), Rope::from(""));
+        assert!(summarize_delta(&builder.build()).is_empty());
+
+        // insert, delete and replace
+        let insert = Delta::simple_edit(Interval::new_closed_open(3, 3), Rope::from("ab"), 10);
+        assert_eq!(summary(&insert), vec![(3, 3, 2)]);
+        let delete = Delta::simple_edit(Interval::new_closed_open(2, 5), Rope::from(""), 10);
+        assert_eq!(summary(&delete), vec![(2, 5, 0)]);
+        let replace = Delta::simple_edit(Interval::new_closed_open(8, 10), Rope::from("xyz"), 10);
+        assert_eq!(summary(&replace), vec![(8, 10, 3)]);
+
+        // several edits at once, including at both ends of the document
+        let mut builder = DeltaBuilder::new(10);
+        builder.replace(Interval::new_closed_open(0, 0), Rope::from(">"));
+        builder.replace(Interval::new_closed_open(2, 4), Rope::from(""));
+        builder.replace(Interval::new_closed_open(6, 7), Rope::from("six"));
+        builder.replace(Interval::new_closed_open(10, 10), Rope::from("<"));
+        let multi = builder.build();
+        assert_eq!(summary(&multi), vec![(0, 0, 1), (2, 4, 0), (6, 7, 3), (10, 10, 1)]);
+        let new_text = String::from(multi.apply(&text));
+        assert_eq!(new_text, ">0145six789<");
+        let new_ranges = summarize_delta(&multi).new_ranges();
+        assert_eq!(new_ranges, vec![0..1, 3..3, 5..8, 11..12]);
+        assert_eq!(&new_text[5..8], "six");
+
+        // replacing the whole document
+        let all = Delta::simple_edit(Interval::new_closed_open(0, 10), Rope::from("new"), 10);
+        assert_eq!(summary(&all), vec![(0, 10, 3)]);
+        assert_eq!(summarize_delta(&all).new_ranges(), vec![0..3]);
+
+        // summaries agree with the edits used for rebasing
+        for delta in &[insert, delete, replace, multi, all] {
+            let edits = delta_edits(delta).into_iter()
+                .map(|(start, end, text)| (start, end, text.len()))
+                .collect::<Vec<_>>();
+            assert_eq!(summary(delta), edits);
+        }
+    }
+
+    #[test]
+    fn rebase_deltas() {
+        use xi_rope::delta::Delta;
+
+        let edit = |start, end, text: &str, len| {
+            Delta::simple_edit(Interval::new_closed_open(start, end), text.into(), len)
+        };
+        let rebased = |delta: &RopeDelta, over: &RopeDelta| {
+            rebase_delta(delta, over).map(|d| String::from(d.apply(&over.apply(&"0123456789".into()))))
+        };
+        assert_eq!(rebased(&edit(8, 9, "x", 10), &edit(2, 4, "abc", 10)),
+                   Some("01abc4567x9".to_owned()));
+        assert_eq!(rebased(&edit(2, 4, "abc", 10), &edit(8, 9, "x", 10)),
+                   Some("01abc4567x9".to_owned()));
+        // both insert at 5: ours goes after theirs
+        assert_eq!(rebased(&edit(5, 5, "b", 10), &edit(5, 5, "a", 10)),
+                   Some("01234ab56789".to_owned()));
+        // adjacent, but not overlapping
+        assert_eq!(rebased(&edit(0, 5, "", 10), &edit(5, 10, "", 10)), Some("".to_owned()));
+        // overlapping changes, and inserting inside a deletion
+        assert_eq!(rebased(&edit(3, 6, "x", 10), &edit(5, 8, "y", 10)), None);
+        assert_eq!(rebased(&edit(4, 4, "x", 10), &edit(3, 6, "", 10)), None);
+        assert_eq!(rebased(&edit(3, 6, "", 10), &edit(4, 4, "x", 10)), None);
+        // different base documents
+        assert_eq!(rebased(&edit(0, 0, "x", 9), &edit(0, 0, "y", 10)), None);
+    }
+
+    /// A plugin which spends its idle time counting, until it has counted
+    /// to `target`, recording how long each callback was given, and
+    /// signalling `slices` as each starts.
+    #[derive(Default)]
+    struct DeadlinePlugin {
+        count: u64,
+        target: u64,
+        budgets: Vec<Duration>,
+        slices: Option<mpsc::Sender<()>>,
+    }
+
+    impl Plugin for DeadlinePlugin {
+        type State = ();
+
+        fn initialize(&mut self, mut ctx: PluginCtx<()>, _buf_size: usize) {
+            ctx.schedule_idle(0);
+        }
+        fn update(&mut self, _ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            Ok(None)
+        }
+        fn idle_with_deadline(&mut self, _ctx: PluginCtx<()>, _token: usize,
+                              deadline: Instant) -> Idle {
+            self.budgets.push(deadline.saturating_duration_since(Instant::now()));
+            if let Some(ref slices) = self.slices {
+                let _ = slices.send(());
+            }
+            while self.count < self.target && Instant::now() < deadline {
+                self.count += 1;
+            }
+            if self.count < self.target { Idle::Continue } else { Idle::Done }
+        }
+    }
+
+    #[test]
+    fn idle_deadline() {
+        use testing::{MockCore, PluginBufferInfoBuilder};
+
+        let (tx, slices) = mpsc::channel();
+        let plugin = DeadlinePlugin { target: 1 << 40, slices: Some(tx), ..Default::default() };
+        let mut core = MockCore::start(plugin);
+        let config = json!({IDLE_SLICE_KEY: 1}).as_object().unwrap().clone();
+        core.new_view_with_info(PluginBufferInfoBuilder::new().config(config).build());
+        // wait for the work to be split across a few callbacks
+        for _ in 0..3 {
+            slices.recv_timeout(Duration::from_secs(5)).expect("no idle callback");
+        }
+        // the plugin can't have finished, but still responds promptly
+        core.sync();
+        let plugin = core.finish();
+        assert!(plugin.count < plugin.target);
+        assert!(plugin.budgets.len() >= 3);
+        assert!(plugin.budgets.iter().all(|&budget| budget <= Duration::from_millis(1)));
+    }
+
+    /// A plugin which starts counting on another thread when idle, until
+    /// the document is edited.
+    #[derive(Default)]
+    struct BackgroundPlugin {
+        worker: Option<thread::JoinHandle<u64>>,
+        cancelled_in_update: Option<bool>,
+    }
+
+    impl Plugin for BackgroundPlugin {
+        type State = ();
+
+        fn initialize(&mut self, mut ctx: PluginCtx<()>, _buf_size: usize) {
+            ctx.schedule_idle(0);
+        }
+        fn update(&mut self, ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            self.cancelled_in_update = Some(ctx.cancellation_token().is_cancelled());
+            Ok(None)
+        }
+        fn idle(&mut self, ctx: PluginCtx<()>, _token: usize) -> Idle {
+            let token = ctx.cancellation_token();
+            self.worker = Some(thread::spawn(move || {
+                let mut count = 0u64;
+                while !token.is_cancelled() {
+                    count += 1;
+                }
+                count
+            }));
+            Idle::Done
+        }
+    }
+
+    #[test]
+    fn cancellation_token() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+
+        let mut core = MockCore::start(BackgroundPlugin::default());
+        core.new_view("hello");
+        core.wait_idle();
+        let delta = Delta::simple_edit(Interval::new_closed_open(5, 5), "!".into(), 5);
+        core.update(delta).unwrap();
+        let mut plugin = core.finish();
+        // the worker only returns once it sees the edit
+        assert!(plugin.worker.take().unwrap().join().is_ok());
+        // the view has a fresh token for work on the new revision
+        assert_eq!(plugin.cancelled_in_update, Some(false));
+
+        let token = CancellationToken::default();
+        let clone = token.clone();
+        assert!(!clone.is_cancelled());
+        token.cancel();
+        assert!(clone.is_cancelled());
+    }
+
+    /// A plugin which records the context of every update.
+    #[derive(Default)]
+    struct UpdateInfoPlugin {
+        infos: Vec<UpdateContext>,
+    }
+
+    impl Plugin for UpdateInfoPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, _ctx: PluginCtx<()>, info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            self.infos.push(info);
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn update_context() {
+        let mut undo = update_rpc(1, 3);
+        undo["params"]["edit_type"] = json!("undo");
+        undo["params"]["author"] = json!("7");
+        let rpcs = [init_rpc(42), update_rpc(0, 2), undo];
+        let mut plugin = UpdateInfoPlugin::default();
+        run_script(&mut plugin, &rpcs);
+        let info = |rev, edit_type: &str, author: &str| UpdateContext {
+            rev, edit_type: edit_type.into(), author: author.into(), summary: None,
+        };
+        assert_eq!(plugin.infos, vec![info(2, "insert", "core"), info(3, "undo", "7")]);
+
+        // updates with a delta carry its summary
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+        let mut core = MockCore::start(UpdateInfoPlugin::default());
+        core.new_view("hello");
+        let delta = Delta::simple_edit(Interval::new_closed_open(1, 4), "ipp".into(), 5);
+        core.update(delta).unwrap();
+        let infos = core.finish().infos;
+        let summary = DeltaSummary { ranges: vec![ReplacedRange { old: 1..4, new_len: 3 }] };
+        assert_eq!(infos[0].summary, Some(summary));
+    }
+
+    /// A plugin which counts the updates to its view, and on close, records
+    /// the count once idle.
+    #[derive(Default)]
+    struct CloseCountPlugin {
+        closed: Rc<RefCell<Vec<usize>>>,
+    }
+
+    impl Plugin for CloseCountPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, mut ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            *ctx.user_data::<usize>() += 1;
+            Ok(None)
+        }
+        fn did_close(&mut self, mut ctx: PluginCtx<()>) {
+            let count = *ctx.user_data::<usize>();
+            let closed = self.closed.clone();
+            ctx.defer(move || closed.borrow_mut().push(count));
+        }
+    }
+
+    /// A plugin which schedules idle work in each update, then records the
+    /// idle callbacks it receives, and those cancelled.
+    #[derive(Default)]
+    struct IdleOnUpdatePlugin {
+        scheduled: Vec<Vec<usize>>,
+        idle_calls: Vec<usize>,
+        cancelled: Vec<usize>,
+    }
+
+    impl Plugin for IdleOnUpdatePlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+
+        fn update(&mut self, mut ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            // reserved tokens aren't reported, however they were scheduled
+            for &token in &[1, INIT_IDLE_TOKEN, NOTIFY_IDLE_TOKEN, WORK_IDLE_TOKEN] {
+                ctx.schedule_idle(token);
+            }
+            self.scheduled.push(ctx.scheduled_idle_tokens());
+            // asks core for the text, so that the close arrives meanwhile,
+            // and is handled before the idle work
+            assert!(ctx.verify_cache());
+            Ok(None)
+        }
+        fn did_close(&mut self, ctx: PluginCtx<()>) {
+            self.scheduled.push(ctx.scheduled_idle_tokens());
+        }
+        fn idle(&mut self, _ctx: PluginCtx<()>, token: usize) -> Idle {
+            self.idle_calls.push(token);
+            Idle::Done
+        }
+        fn cancel(&mut self, _ctx: PluginCtx<()>, token: usize) {
+            self.cancelled.push(token);
+        }
+    }
+
+    #[test]
+    fn close_cancels_idle_work() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        let mut core = MockCore::start(IdleOnUpdatePlugin::default());
+        core.new_view("hello");
+        core.hold_notifications();
+        core.did_close();
+        let delta = Delta::simple_edit(Interval::new_closed_open(5, 5), "!".into(), 5);
+        core.update(delta).unwrap();
+        core.wait_idle();
+        let plugin = core.finish();
+        assert_eq!(plugin.scheduled, vec![vec![1], vec![]]);
+        // the plugin is told that its work won't run, but not of the
+        // library's own
+        assert_eq!(plugin.cancelled, vec![1]);
+        assert!(plugin.idle_calls.is_empty());
+    }
+
+    #[test]
+    fn defer_cleanup_on_close() {
+        let rpcs = [
+            init_rpc(42),
+            update_rpc(0, 2),
+            update_rpc(1, 3),
+            update_rpc(2, 4),
+            json!({"method": "did_close", "params": {"view_id": "view-id-2"}}),
+            json!({"method": "did_close", "params": {"view_id": "view-id-1"}}),
+            json!({"method": "shutdown", "params": {}}),
+        ];
+        let mut plugin = CloseCountPlugin::default();
+        run_script(&mut plugin, &rpcs);
+        assert_eq!(*plugin.closed.borrow(), vec![3]);
+    }
+
+    #[test]
+    fn shutdown_request() {
+        let rpcs = [
+            init_rpc(42),
+            update_rpc(0, 2),
+            json!({"id": 1, "method": "shutdown", "params": {}}),
+            update_rpc(2, 3),
+        ];
+        let mut plugin = CloseCountPlugin::default();
+        let mut rx = run_script(&mut plugin, &rpcs);
+        rx.expect_rpc("plugin_info");
+        assert_eq!(rx.expect_response(), Ok(json!(0)));
+        assert_eq!(rx.expect_response(),
+                   Ok(json!({"views_closed": 1, "cleanups_run": 0, "state_saved": false})));
+        // the update after shutdown is not handled
+        rx.expect_nothing();
+    }
+
+    #[test]
+    fn supervised_reconnect() {
+        let connect = |rpcs: &[Value]| Ok((make_reader(script(rpcs)), test_channel().0));
+        let mut connections = vec![
+            connect(&[init_rpc(42), update_rpc(0, 2)]),
+            Err(io::Error::new(io::ErrorKind::ConnectionRefused, "core is restarting")),
+            connect(&[init_rpc(43), update_rpc(0, 2), json!({"method": "shutdown", "params": {}})]),
+            connect(&[init_rpc(44)]),
+        ].into_iter();
+
+        let mut plugin = FocusPlugin::default();
+        assert!(run_supervised(&mut plugin, || connections.next().unwrap()).is_ok());
+        assert_eq!(plugin.log, vec![
+            ("initialize", false),
+            ("update", false),
+            ("initialize", false),
+            ("update", false),
+        ]);
+        // not reconnected after the shutdown
+        assert_eq!(connections.len(), 1);
+    }
+
+    #[test]
+    fn supervised_connect_fails() {
+        let mut attempts = 0;
+        let result = run_supervised(&mut FocusPlugin::default(), || {
+            attempts += 1;
+            Err::<(io::Cursor<Vec<u8>>, io::Sink), _>(
+                io::Error::new(io::ErrorKind::NotFound, "no socket"))
+        });
+        match result {
+            Err(ReadError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::NotFound),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(attempts, MAX_CONNECT_ATTEMPTS);
+    }
+
+    #[test]
+    fn supervised_closed_before_init() {
+        // core accepts each connection, but closes it straight away
+        let mut attempts = 0;
+        let result = run_supervised(&mut FocusPlugin::default(), || {
+            attempts += 1;
+            Ok((make_reader(String::new()), test_channel().0))
+        });
+        match result {
+            Err(ReadError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(attempts, MAX_CONNECT_ATTEMPTS);
+    }
+
+    #[test]
+    fn supervised_dropped_after_init() {
+        // core initializes the plugin on each connection, then closes it
+        let mut attempts = 0;
+        let start = Instant::now();
+        let result = run_supervised(&mut FocusPlugin::default(), || {
+            attempts += 1;
+            Ok((make_reader(script(&[init_rpc(42)])), test_channel().0))
+        });
+        match result {
+            Err(ReadError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(attempts, MAX_CONNECT_ATTEMPTS);
+        // backing off between attempts
+        let backoff = (0..MAX_CONNECT_ATTEMPTS as u32 - 1)
+            .map(|i| RECONNECT_DELAY * 2u32.pow(i))
+            .sum::<Duration>();
+        assert!(start.elapsed() >= backoff);
+    }
+
+    /// Highlights every "x" with a single, reused `SpanBuilder`.
+    #[derive(Default)]
+    struct BuilderPlugin {
+        builder: SpanBuilder,
+        capacities: Vec<usize>,
+    }
+
+    impl Plugin for BuilderPlugin {
+        type State = ();
+
+        fn initialize(&mut self, _ctx: PluginCtx<()>, _buf_size: usize) {}
+        fn update(&mut self, mut ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            let len = ctx.get_buf_size();
+            let text = ctx.get_region(0, len).unwrap();
+            for (ix, _) in text.match_indices('x') {
+                self.builder.add(ix, ix + 1, 1);
+            }
+            // a span past the end of the document, which is clipped
+            self.builder.add(len, len + 2, 2);
+            ctx.flush_spans(0, len, &mut self.builder);
+            assert!(self.builder.is_empty());
+            self.capacities.push(self.builder.spans.capacity());
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn reuse_span_builder() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        let mut core = MockCore::start(BuilderPlugin::default());
+        core.new_view("x.x.x.x");
+        let delta = Delta::simple_edit(Interval::new_closed_open(1, 2), "x".into(), 7);
+        core.update(delta).unwrap();
+        let delta = Delta::simple_edit(Interval::new_closed_open(0, 1), "y".into(), 7);
+        core.update(delta).unwrap();
+        core.sync();
+        let spans = core.notifications().iter()
+            .filter(|msg| msg["method"] == "update_spans")
+            .map(|msg| msg["params"]["spans"].as_array().unwrap().len())
+            .collect::<Vec<_>>();
+        assert_eq!(spans, vec![5, 4]);
+        let capacities = core.finish().capacities;
+        // the second update needs no more room than the first
+        assert_eq!(capacities[0], capacities[1]);
+    }
 }
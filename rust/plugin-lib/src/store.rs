@@ -0,0 +1,251 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Persistent key-value storage for a plugin.
+//!
+//! Unlike the state saved with `Plugin::serialize_state`, which belongs to a
+//! single file, a `PluginStore` belongs to the plugin, and is shared by
+//! every view it is started for; it suits data such as an index of recently
+//! used symbols. Values are JSON, and are written to disk as soon as they
+//! are set.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+
+use serde_json::{self, Map, Value};
+
+/// The environment variable which, if set, names the directory in which
+/// plugins keep their stores.
+pub const STORE_DIR_ENV_VAR: &str = "XI_PLUGIN_STORE_DIR";
+
+/// A plugin's persistent key-value store, kept in a JSON file.
+///
+/// Clones share the same store, so it can be used from several threads;
+/// every change is written through to the file. Core starts a process for
+/// each buffer, so several processes may share the file too: each change
+/// takes an advisory lock on a `.lock` file beside the store, and re-reads
+/// the store before writing it, so changes made by other processes are
+/// kept, although `get` only sees them after the next change. The lock is
+/// only taken on unix; elsewhere, of two processes changing the store at
+/// once, the last to write wins.
+#[derive(Debug, Clone)]
+pub struct PluginStore {
+    path: Arc<PathBuf>,
+    data: Arc<Mutex<Map<String, Value>>>,
+}
+
+impl PluginStore {
+    /// Opens the store kept in `path`, which is created when a value is
+    /// first set. If the file is corrupt, it is moved aside, with a
+    /// `.corrupt` extension, and the store starts out empty.
+    pub fn open<T: Into<PathBuf>>(path: T) -> Self {
+        let path = path.into();
+        let data = read_store(&path);
+        PluginStore { path: Arc::new(path), data: Arc::new(Mutex::new(data)) }
+    }
+
+    /// Opens the store for the plugin named `name`, in its own directory
+    /// under `base_dir`.
+    pub fn for_plugin(base_dir: &Path, name: &str) -> Self {
+        PluginStore::open(base_dir.join(name).join("store.json"))
+    }
+
+    /// The file the store is kept in.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the value for `key`, if there is one.
+    pub fn get(&self, key: &str) -> Option<Value> {
+        self.data.lock().unwrap().get(key).cloned()
+    }
+
+    /// Sets the value for `key`, and writes the store to disk.
+    pub fn set(&self, key: &str, value: Value) -> io::Result<()> {
+        self.modify(|data| { data.insert(key.to_owned(), value); })
+    }
+
+    /// Removes the value for `key`, returning it, and writes the store to
+    /// disk.
+    pub fn remove(&self, key: &str) -> io::Result<Option<Value>> {
+        let mut removed = None;
+        self.modify(|data| removed = data.remove(key))?;
+        Ok(removed)
+    }
+
+    /// Applies `change` to the latest contents of the file, and writes them
+    /// back, holding the lock file throughout. The file is replaced in one
+    /// step, so a reader never sees it half written.
+    fn modify<F: FnOnce(&mut Map<String, Value>)>(&self, change: F) -> io::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // released when the file is closed
+        let _lock = lock_file(&self.path.with_extension("lock"))?;
+        *data = read_store(&self.path);
+        change(&mut data);
+        let tmp = self.path.with_extension(format!("tmp.{}", process::id()));
+        fs::write(&tmp, Value::Object(data.clone()).to_string())?;
+        fs::rename(&tmp, &*self.path)
+    }
+}
+
+/// The default directory in which plugins keep their stores: the one named
+/// by `STORE_DIR_ENV_VAR`, if it is set, or else `.config/xi/plugin-store`
+/// in the user's home directory.
+pub fn default_store_dir() -> Option<PathBuf> {
+    match env::var_os(STORE_DIR_ENV_VAR) {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => env::var_os("HOME")
+            .map(|home| Path::new(&home).join(".config").join("xi").join("plugin-store")),
+    }
+}
+
+/// Opens the file at `path`, creating it if need be, and waits for an
+/// exclusive advisory lock on it.
+#[cfg(unix)]
+fn lock_file(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::io::AsRawFd;
+    let file = fs::OpenOptions::new().create(true).truncate(false).write(true).open(path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+fn lock_file(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new().create(true).truncate(false).write(true).open(path)
+}
+
+/// Reads the store in `path`, which is empty if the file doesn't exist.
+fn read_store(path: &Path) -> Map<String, Value> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Map::new(),
+        Err(err) => {
+            eprintln!("unable to read plugin store {:?}: {}", path, err);
+            return Map::new();
+        }
+    };
+    match serde_json::from_str(&contents) {
+        Ok(Value::Object(data)) => data,
+        _ => {
+            eprintln!("plugin store {:?} is corrupt, starting afresh", path);
+            if let Err(err) = fs::rename(path, path.with_extension("corrupt")) {
+                eprintln!("unable to move corrupt plugin store aside: {}", err);
+            }
+            Map::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn test_dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("xi-plugin-store-{}-{}", name, process::id()))
+    }
+
+    #[test]
+    fn get_set_remove() {
+        let dir = test_dir("basic");
+        let store = PluginStore::for_plugin(&dir, "symbols");
+        assert_eq!(store.path(), dir.join("symbols").join("store.json"));
+        assert_eq!(store.get("recent"), None);
+        store.set("recent", json!(["main", "run"])).unwrap();
+        store.set("count", json!(2)).unwrap();
+        assert_eq!(store.get("recent"), Some(json!(["main", "run"])));
+        assert_eq!(store.remove("count").unwrap(), Some(json!(2)));
+        assert_eq!(store.remove("count").unwrap(), None);
+
+        // the next session sees what was left
+        let store = PluginStore::for_plugin(&dir, "symbols");
+        assert_eq!(store.get("recent"), Some(json!(["main", "run"])));
+        assert_eq!(store.get("count"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_writes() {
+        let dir = test_dir("threads");
+        let store = PluginStore::open(dir.join("store.json"));
+        let threads = (0..8)
+            .map(|i| {
+                let store = store.clone();
+                thread::spawn(move || store.set(&format!("key{}", i), json!(i)).unwrap())
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        // another process, opening the store afresh, sees every write
+        let other = PluginStore::open(dir.join("store.json"));
+        for i in 0..8 {
+            assert_eq!(other.get(&format!("key{}", i)), Some(json!(i)));
+        }
+        other.set("other", json!(true)).unwrap();
+        store.set("mine", json!(true)).unwrap();
+        assert_eq!(store.get("other"), Some(json!(true)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_stores() {
+        // each store stands in for another process, with its own view of
+        // the file
+        let dir = test_dir("processes");
+        let threads = (0..8)
+            .map(|i| {
+                let store = PluginStore::open(dir.join("store.json"));
+                thread::spawn(move || {
+                    for j in 0..10 {
+                        store.set(&format!("key{}-{}", i, j), json!(j)).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let store = PluginStore::open(dir.join("store.json"));
+        for i in 0..8 {
+            for j in 0..10 {
+                assert_eq!(store.get(&format!("key{}-{}", i, j)), Some(json!(j)));
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_file() {
+        let dir = test_dir("corrupt");
+        let path = dir.join("store.json");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, "{ not json").unwrap();
+        let store = PluginStore::open(&path);
+        assert_eq!(store.get("anything"), None);
+        assert_eq!(fs::read_to_string(dir.join("store.corrupt")).unwrap(), "{ not json");
+        store.set("fresh", json!(1)).unwrap();
+        assert_eq!(PluginStore::open(&path).get("fresh"), Some(json!(1)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,707 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Utilities for testing plugins in-process, without a running core.
+//!
+//! `MockCore` runs a plugin's main loop on a background thread, connected
+//! by in-memory channels instead of stdio. It plays the part of core:
+//! it keeps a copy of the document, sends RPCs to the plugin, and answers
+//! the plugin's requests for document data.
+
+use std::cmp::min;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, BufReader, Read};
+use std::mem;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde_json::{self, Value};
+
+use xi_core::{BufferIdentifier, ConfigTable, SyntaxDefinition, ViewIdentifier};
+use xi_core::internal::config::ConfigManager;
+use xi_core::plugin_rpc::{GetDataResponse, PluginBufferInfo, PluginEdit, PluginUpdate, TextUnit,
+STALE_REVISION_ERROR};
+use xi_rope::rope::{LinesMetric, Rope, RopeDelta};
+use xi_rpc::{RemoteError, RpcObject};
+use xi_rpc::test_utils::{test_channel, DummyReader};
+
+use state_cache::{mainloop_with, Plugin};
+
+/// How long to wait for the plugin to respond before giving up.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Drives a `Plugin` in-process, standing in for core.
+///
+/// All methods panic if the plugin stops responding, or sends something
+/// which isn't valid RPC.
+pub struct MockCore<P: Plugin + Send + 'static> {
+    to_plugin: Option<Sender<String>>,
+    from_plugin: DummyReader,
+    thread: Option<JoinHandle<P>>,
+    view_id: ViewIdentifier,
+    text: Rope,
+    rev: u64,
+    /// The text at each revision, so that the plugin can fetch data for
+    /// revisions older than the current one.
+    history: BTreeMap<u64, Rope>,
+    next_id: u64,
+    notifications: Vec<Value>,
+    /// Updates held back by `hold_update`.
+    held_updates: Vec<PluginUpdate>,
+    /// Notifications held back by `hold_notifications`.
+    held_notifications: Option<Vec<Value>>,
+    /// Edits passed to `update_at_request`, with the number of requests
+    /// from the plugin to wait for before sending each.
+    deferred_updates: Vec<(usize, RopeDelta)>,
+    /// The ids of updates sent by `update_at_request` which the plugin has
+    /// yet to respond to.
+    awaited: BTreeSet<u64>,
+}
+
+impl<P: Plugin + Send + 'static> MockCore<P> {
+    /// Starts running `plugin`. The plugin is not initialized until
+    /// `new_view` is called.
+    pub fn start(plugin: P) -> Self {
+        let (to_plugin, plugin_rx) = channel();
+        let (writer, from_plugin) = test_channel();
+        let reader = ChannelReader { rx: plugin_rx, buf: Vec::new(), pos: 0 };
+        let thread = thread::spawn(move || {
+            let mut plugin = plugin;
+            if let Err(err) = mainloop_with(&mut plugin, move || BufReader::new(reader), writer) {
+                panic!("plugin mainloop exited with error: {:?}", err);
+            }
+            plugin
+        });
+
+        MockCore {
+            to_plugin: Some(to_plugin),
+            from_plugin,
+            thread: Some(thread),
+            view_id: "view-id-1".into(),
+            text: Rope::from(""),
+            rev: 1,
+            history: BTreeMap::new(),
+            next_id: 0,
+            notifications: Vec::new(),
+            held_updates: Vec::new(),
+            held_notifications: None,
+            deferred_updates: Vec::new(),
+            awaited: BTreeSet::new(),
+        }
+    }
+
+    /// Initializes the plugin with a view of a document containing `text`,
+    /// using the default config.
+    ///
+    /// This and the other methods which send notifications wait for the
+    /// plugin to handle them before returning; see `sync`.
+    pub fn new_view(&mut self, text: &str) {
+        let info = PluginBufferInfoBuilder::new()
+            .view_id(self.view_id)
+            .rev(self.rev)
+            .text(text)
+            .build();
+        self.new_view_with_info(info);
+    }
+
+    /// Initializes the plugin with the view described by `info`, usually
+    /// made with a `PluginBufferInfoBuilder`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `info` does not include the document's text, which is
+    /// needed to answer the plugin's requests for data.
+    pub fn new_view_with_info(&mut self, info: PluginBufferInfo) {
+        let text = info.text.clone().expect("MockCore needs the document's text");
+        self.view_id = info.views[0];
+        self.rev = info.rev;
+        self.set_text(Rope::from(text));
+        self.send_notification("initialize", json!({"plugin_id": 0, "buffer_info": [info]}));
+        self.sync();
+    }
+
+    /// Initializes the plugin as `new_view_with_info` does, but leaves the
+    /// text out of the buffer info sent to the plugin, as core does for
+    /// large documents, so that the plugin has to fetch it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `info` does not include the document's text.
+    pub fn new_view_unfetched(&mut self, mut info: PluginBufferInfo) {
+        let text = info.text.take().expect("MockCore needs the document's text");
+        self.view_id = info.views[0];
+        self.rev = info.rev;
+        self.set_text(Rope::from(text));
+        self.send_notification("initialize", json!({"plugin_id": 0, "buffer_info": [info]}));
+        self.sync();
+    }
+
+    /// Applies `delta` to the document, and sends the plugin the resulting
+    /// update, returning the plugin's response.
+    pub fn update(&mut self, delta: RopeDelta) -> Result<Value, RemoteError> {
+        let update = self.apply_delta(delta);
+        self.send_request("update", serde_json::to_value(update).unwrap())
+    }
+
+    /// Applies each of `deltas` to the document in turn, and sends the
+    /// resulting updates without waiting for the plugin to respond, as core
+    /// does while the user types quickly. Returns the plugin's responses,
+    /// in order.
+    pub fn update_burst(&mut self, deltas: Vec<RopeDelta>) -> Vec<Result<Value, RemoteError>> {
+        let ids = deltas.into_iter()
+            .map(|delta| {
+                let update = self.apply_delta(delta);
+                self.send_request_async("update", serde_json::to_value(update).unwrap())
+            })
+            .collect::<Vec<_>>();
+        ids.into_iter().map(|id| self.wait_response(id)).collect()
+    }
+
+    /// Applies `delta` to the document, as a concurrent edit by another
+    /// client would be, but holds back the update until `send_held_updates`
+    /// is called. Until then, edits from the plugin are rejected as stale.
+    pub fn hold_update(&mut self, delta: RopeDelta) {
+        let update = self.apply_delta(delta);
+        self.held_updates.push(update);
+    }
+
+    /// Sends the updates held back by `hold_update`, in order, returning the
+    /// plugin's responses.
+    pub fn send_held_updates(&mut self) -> Vec<Result<Value, RemoteError>> {
+        let updates = self.held_updates.drain(..).collect::<Vec<_>>();
+        updates.into_iter()
+            .map(|update| self.send_request("update", serde_json::to_value(update).unwrap()))
+            .collect()
+    }
+
+    /// Sends an update for `delta` just before answering the plugin's `n`th
+    /// request to core from now, counting from zero, so that it arrives in
+    /// the middle of whatever the plugin is doing. The delta is applied to
+    /// the document when the update is sent, and so can be deferred before
+    /// `new_view`. If the plugin makes fewer requests, it is never sent.
+    ///
+    /// The plugin's response is checked, and must not be an error, by the
+    /// next method which waits for the plugin, such as `sync`.
+    pub fn update_at_request(&mut self, n: usize, delta: RopeDelta) {
+        self.deferred_updates.push((n, delta));
+    }
+
+    /// Holds back the notifications sent from now on, such as those sent by
+    /// `selection_changed`, until the plugin next makes a request of core.
+    /// They are sent just before the request is answered, after any updates
+    /// deferred with `update_at_request`, so that they all arrive while the
+    /// plugin is busy, as they would in a burst from core.
+    pub fn hold_notifications(&mut self) {
+        self.held_notifications.get_or_insert_with(Vec::new);
+    }
+
+    /// Sends the plugin a change to its config.
+    pub fn config_changed(&mut self, changes: ConfigTable) {
+        let params = json!({"view_id": self.view_id, "changes": changes});
+        self.send_notification("config_changed", params);
+        self.sync();
+    }
+
+    /// Tells the plugin that the view's selections changed. Each selection
+    /// is a pair of offsets, the inactive edge first, as core sends them.
+    pub fn selection_changed(&mut self, selections: &[(usize, usize)]) {
+        let params = json!({"view_id": self.view_id, "selections": selections});
+        self.send_notification("selection_changed", params);
+        self.sync();
+    }
+
+    /// Tells the plugin that the view gained or lost focus.
+    pub fn focus_changed(&mut self, focused: bool) {
+        let params = json!({"view_id": self.view_id, "focused": focused});
+        self.send_notification("focus_changed", params);
+        self.sync();
+    }
+
+    /// Tells the plugin that the lines from `first_line` to `last_line`,
+    /// inclusive, are now visible.
+    pub fn viewport_changed(&mut self, first_line: usize, last_line: usize) {
+        let params = json!({"view_id": self.view_id, "first_line": first_line,
+                            "last_line": last_line});
+        self.send_notification("viewport_changed", params);
+        self.sync();
+    }
+
+    /// Asks the plugin to abandon the idle work scheduled with `token`.
+    pub fn cancel(&mut self, token: usize) {
+        let params = json!({"view_id": self.view_id, "token": token});
+        self.send_notification("cancel", params);
+        self.sync();
+    }
+
+    /// Tells the plugin that the view was closed.
+    pub fn did_close(&mut self) {
+        let params = json!({"view_id": self.view_id});
+        self.send_notification("did_close", params);
+        self.sync();
+    }
+
+    /// Tells the plugin that the document is about to be saved to `path`,
+    /// returning its response; an error means the plugin asks for the save
+    /// to be cancelled.
+    pub fn will_save<T: AsRef<Path>>(&mut self, path: T) -> Result<Value, RemoteError> {
+        let params = json!({"view_id": self.view_id, "path": path.as_ref()});
+        self.send_request("will_save", params)
+    }
+
+    /// Asks the plugin for completions at `offset`.
+    pub fn completion(&mut self, offset: usize) -> Result<Value, RemoteError> {
+        let params = json!({"view_id": self.view_id, "offset": offset});
+        self.send_request("completion", params)
+    }
+
+    /// Asks the plugin for information about the text at `offset`.
+    pub fn hover(&mut self, offset: usize) -> Result<Value, RemoteError> {
+        let params = json!({"view_id": self.view_id, "offset": offset});
+        self.send_request("hover", params)
+    }
+
+    /// Asks the plugin for a snapshot of its state.
+    pub fn debug_state(&mut self) -> Result<Value, RemoteError> {
+        self.send_request("debug_state", json!({}))
+    }
+
+    /// Tells the plugin that the document was saved to `path`.
+    pub fn did_save<T: AsRef<Path>>(&mut self, path: T) {
+        let params = json!({"view_id": self.view_id, "path": path.as_ref()});
+        self.send_notification("did_save", params);
+        self.sync();
+    }
+
+    /// Waits until the plugin has handled everything sent so far. Idle work,
+    /// such as debounced config changes, may still be pending.
+    pub fn sync(&mut self) {
+        self.send_request("ping", json!({})).expect("ping failed");
+    }
+
+    /// Waits until the plugin has handled everything sent so far, and has
+    /// finished any idle work.
+    pub fn wait_idle(&mut self) {
+        loop {
+            let resp = self.send_request("ping", json!({})).expect("ping failed");
+            if resp["busy"] != json!(true) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Waits until the plugin has sent a notification for which `pred`
+    /// returns `true`, such as one sent from another thread, handling
+    /// anything else the plugin sends meanwhile.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no such notification arrives in time.
+    pub fn wait_for_notification<F: Fn(&Value) -> bool>(&mut self, pred: F) {
+        let start = Instant::now();
+        while !self.notifications.iter().any(&pred) {
+            let remaining = TIMEOUT.checked_sub(start.elapsed())
+                .expect("timed out waiting for notification");
+            let msg = self.from_plugin.next_timeout(remaining)
+                .expect("timed out waiting for notification");
+            self.handle_message(msg.expect("plugin sent invalid message"));
+        }
+    }
+
+    /// The current text of the document, including any edits from the plugin.
+    pub fn text(&self) -> String {
+        String::from(&self.text)
+    }
+
+    /// The notifications received from the plugin so far. Call `sync` first
+    /// to make sure that the plugin has sent everything it is going to.
+    pub fn notifications(&self) -> &[Value] {
+        &self.notifications
+    }
+
+    /// Shuts the plugin down, returning it once its main loop has exited.
+    /// Requests the plugin makes while shutting down are still answered.
+    pub fn finish(mut self) -> P {
+        self.send(&json!({"method": "shutdown", "params": {}}));
+        self.to_plugin.take();
+        let thread = self.thread.take().unwrap();
+        let start = Instant::now();
+        while !thread.is_finished() {
+            assert!(start.elapsed() < TIMEOUT, "timed out waiting for plugin to exit");
+            if let Some(msg) = self.from_plugin.next_timeout(Duration::from_millis(10)) {
+                self.handle_message(msg.expect("plugin sent invalid message"));
+            }
+        }
+        thread.join().expect("plugin thread panicked")
+    }
+
+    fn send_notification(&mut self, method: &str, params: Value) {
+        let msg = json!({"method": method, "params": params});
+        match self.held_notifications.as_mut() {
+            Some(held) => held.push(msg),
+            None => self.send(&msg),
+        }
+    }
+
+    fn send_request(&mut self, method: &str, params: Value) -> Result<Value, RemoteError> {
+        let id = self.send_request_async(method, params);
+        self.wait_response(id)
+    }
+
+    /// Sends a request, returning its id.
+    fn send_request_async(&mut self, method: &str, params: Value) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.send(&json!({"id": id, "method": method, "params": params}));
+        id
+    }
+
+    /// Waits for the response to request `id`, handling anything the plugin
+    /// sends in the meantime. Responses must arrive in the order requested.
+    fn wait_response(&mut self, id: u64) -> Result<Value, RemoteError> {
+        loop {
+            let msg = self.next_message();
+            if msg.is_response() && msg.get_id() == Some(id) {
+                return msg.into_response().unwrap();
+            }
+            self.handle_message(msg);
+        }
+    }
+
+    /// Answers a request from the plugin, records a notification, or checks
+    /// the response to an update sent by `update_at_request`.
+    fn handle_message(&mut self, msg: RpcObject) {
+        if msg.is_response() {
+            let id = msg.get_id().unwrap();
+            assert!(self.awaited.remove(&id), "unexpected response");
+            if let Err(err) = msg.into_response().unwrap() {
+                panic!("update failed: {:?}", err);
+            }
+        } else if msg.get_id().is_some() {
+            self.handle_plugin_request(msg);
+        } else {
+            self.notifications.push(msg.0);
+        }
+    }
+
+    fn next_message(&mut self) -> RpcObject {
+        self.from_plugin.next_timeout(TIMEOUT)
+            .expect("timed out waiting for plugin")
+            .expect("plugin sent invalid message")
+    }
+
+    fn handle_plugin_request(&mut self, msg: RpcObject) {
+        for (n, delta) in mem::take(&mut self.deferred_updates) {
+            if n > 0 {
+                self.deferred_updates.push((n - 1, delta));
+                continue;
+            }
+            let update = self.apply_delta(delta);
+            let id = self.send_request_async("update", serde_json::to_value(update).unwrap());
+            self.awaited.insert(id);
+        }
+        for held in self.held_notifications.take().unwrap_or_default() {
+            self.send(&held);
+        }
+        let id = msg.get_id().unwrap();
+        let params = &msg.0["params"];
+        let result = match msg.get_method() {
+            Some("get_data") => self.get_data(params),
+            Some("edit") => self.edit(params),
+            other => Err(RemoteError::custom(404, format!("unsupported: {:?}", other), None)),
+        };
+        let resp = match result {
+            Ok(result) => json!({"id": id, "result": result}),
+            Err(err) => json!({"id": id, "error": err}),
+        };
+        self.send(&resp);
+    }
+
+    fn apply_delta(&mut self, delta: RopeDelta) -> PluginUpdate {
+        self.rev += 1;
+        let text = delta.apply(&self.text);
+        self.set_text(text);
+        PluginUpdate::new(self.view_id, self.rev, delta, self.text.len(),
+                          self.text.measure::<LinesMetric>() + 1,
+                          "insert".into(), "test".into())
+    }
+
+    fn set_text(&mut self, text: Rope) {
+        self.history.insert(self.rev, text.clone());
+        self.text = text;
+    }
+
+    /// Responds to `get_data`, as core does.
+    fn get_data(&self, params: &Value) -> Result<Value, RemoteError> {
+        let start = params["start"].as_u64().unwrap() as usize;
+        let unit: TextUnit = serde_json::from_value(params["unit"].clone()).unwrap();
+        let max_size = params["max_size"].as_u64().unwrap() as usize;
+        let text = params["rev"].as_u64()
+            .and_then(|rev| self.history.get(&rev))
+            .ok_or_else(|| RemoteError::custom(404, "unknown revision", None))?;
+        let offset = unit.resolve_offset(text, start)
+            .ok_or_else(|| RemoteError::custom(404, "offset out of range", None))?;
+        let mut end = min(offset.saturating_add(max_size), text.len());
+        if end < text.len() {
+            end = text.prev_codepoint_offset(end + 1).unwrap();
+        }
+        let first_line = text.line_of_offset(offset);
+        let resp = GetDataResponse {
+            chunk: text.slice_to_string(offset, end),
+            offset,
+            first_line,
+            first_line_offset: offset - text.offset_of_line(first_line),
+        };
+        Ok(serde_json::to_value(resp).unwrap())
+    }
+
+    /// Applies an edit from the plugin, rejecting stale edits as core does.
+    /// Unlike core, no update is sent back to the plugin.
+    fn edit(&mut self, params: &Value) -> Result<Value, RemoteError> {
+        let edit: PluginEdit = serde_json::from_value(params["edit"].clone()).unwrap();
+        if edit.rev != self.rev {
+            return Err(RemoteError::custom(STALE_REVISION_ERROR, "stale revision",
+                                           json!({"rev": edit.rev, "head_rev": self.rev})));
+        }
+        self.rev += 1;
+        let text = edit.delta.apply(&self.text);
+        self.set_text(text);
+        Ok(json!(0))
+    }
+
+    fn send(&self, msg: &Value) {
+        self.to_plugin.as_ref().unwrap()
+            .send(format!("{}\n", msg))
+            .expect("plugin thread exited");
+    }
+}
+
+/// Builds the `PluginBufferInfo` that core sends a plugin for its buffer.
+///
+/// By default this describes an empty, unsaved, plain text buffer at
+/// revision 1, with one view and the default config.
+#[derive(Debug, Clone)]
+pub struct PluginBufferInfoBuilder {
+    info: PluginBufferInfo,
+}
+
+impl PluginBufferInfoBuilder {
+    pub fn new() -> Self {
+        PluginBufferInfoBuilder {
+            info: PluginBufferInfo {
+                buffer_id: serde_json::from_value(json!(1)).unwrap(),
+                views: vec!["view-id-1".into()],
+                rev: 1,
+                buf_size: 0,
+                nb_lines: 1,
+                path: None,
+                syntax: SyntaxDefinition::Plaintext,
+                config: ConfigManager::default().default_buffer_config().to_table(),
+                text: Some(String::new()),
+            },
+        }
+    }
+
+    /// Sets the buffer's identifier, which is 1 by default.
+    pub fn buffer_id(mut self, buffer_id: BufferIdentifier) -> Self {
+        self.info.buffer_id = buffer_id;
+        self
+    }
+
+    /// Sets the buffer's only view.
+    pub fn view_id(mut self, view_id: ViewIdentifier) -> Self {
+        self.info.views = vec![view_id];
+        self
+    }
+
+    pub fn rev(mut self, rev: u64) -> Self {
+        self.info.rev = rev;
+        self
+    }
+
+    pub fn path<T: AsRef<Path>>(mut self, path: T) -> Self {
+        self.info.path = Some(path.as_ref().to_string_lossy().into_owned());
+        self
+    }
+
+    pub fn syntax(mut self, syntax: SyntaxDefinition) -> Self {
+        self.info.syntax = syntax;
+        self
+    }
+
+    /// Sets the buffer's text, along with its length and line count.
+    pub fn text(mut self, text: &str) -> Self {
+        let rope = Rope::from(text);
+        self.info.buf_size = rope.len();
+        self.info.nb_lines = rope.measure::<LinesMetric>() + 1;
+        self.info.text = Some(text.to_owned());
+        self
+    }
+
+    /// Sets the buffer's length, and leaves out its text, as core does for
+    /// large buffers.
+    pub fn len(mut self, len: usize) -> Self {
+        self.info.buf_size = len;
+        self.info.text = None;
+        self
+    }
+
+    pub fn line_count(mut self, line_count: usize) -> Self {
+        self.info.nb_lines = line_count;
+        self
+    }
+
+    /// Overrides entries of the default config with those in `changes`.
+    pub fn config(mut self, changes: ConfigTable) -> Self {
+        self.info.config.extend(changes);
+        self
+    }
+
+    pub fn build(self) -> PluginBufferInfo {
+        self.info
+    }
+}
+
+impl Default for PluginBufferInfoBuilder {
+    fn default() -> Self {
+        PluginBufferInfoBuilder::new()
+    }
+}
+
+/// Reads the strings sent over a channel, hitting EOF when the sender
+/// is dropped.
+struct ChannelReader {
+    rx: Receiver<String>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.len() {
+            match self.rx.recv() {
+                Ok(s) => {
+                    self.buf = s.into_bytes();
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = min(out.len(), self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xi_rope::delta::Delta;
+    use xi_rope::interval::Interval;
+    use state_cache::{PluginCtx, UpdateContext};
+
+    /// Records the first line of the document whenever it changes, and
+    /// appends "!" to the document when saved.
+    #[derive(Default)]
+    struct FirstLinePlugin {
+        first_lines: Vec<String>,
+        tab_sizes: Vec<u64>,
+    }
+
+    impl Plugin for FirstLinePlugin {
+        type State = ();
+
+        fn initialize(&mut self, mut ctx: PluginCtx<()>, _buf_size: usize) {
+            let line = ctx.get_line(0).unwrap().to_owned();
+            self.first_lines.push(line);
+        }
+
+        fn update(&mut self, mut ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+                  -> Result<Option<Value>, RemoteError> {
+            let line = ctx.get_line(0).unwrap().to_owned();
+            self.first_lines.push(line);
+            Ok(None)
+        }
+
+        fn did_save(&mut self, ctx: PluginCtx<()>) -> Result<(), String> {
+            let len = ctx.get_buf_size();
+            let delta = Delta::simple_edit(Interval::new_closed_open(len, len), "!".into(), len);
+            ctx.edit(delta, 0, false, "test").map_err(|e| format!("{:?}", e))
+        }
+
+        fn config_changed(&mut self, ctx: PluginCtx<()>, _changes: &ConfigTable) {
+            self.tab_sizes.push(ctx.get_config().tab_size as u64);
+        }
+
+        fn config_is_immediate(&self, _key: &str) -> bool { true }
+    }
+
+    #[test]
+    fn updates() {
+        let mut core = MockCore::start(FirstLinePlugin::default());
+        core.new_view("hello\nworld");
+        let delta = Delta::simple_edit(Interval::new_closed_open(0, 0), "oh, ".into(), 11);
+        assert_eq!(core.update(delta), Ok(json!(0)));
+        assert_eq!(core.text(), "oh, hello\nworld");
+
+        let plugin = core.finish();
+        assert_eq!(plugin.first_lines, vec!["hello\n", "oh, hello\n"]);
+    }
+
+    #[test]
+    fn buffer_info_builder() {
+        let info = PluginBufferInfoBuilder::new().build();
+        assert_eq!((info.buf_size, info.nb_lines), (0, 1));
+        assert_eq!(info.text.as_deref(), Some(""));
+        assert_eq!(info.path, None);
+
+        let info = PluginBufferInfoBuilder::new()
+            .text("one\ntwo")
+            .path("/tmp/file.rs")
+            .syntax(SyntaxDefinition::Rust)
+            .config(json!({"tab_size": 2}).as_object().unwrap().clone())
+            .build();
+        assert_eq!((info.buf_size, info.nb_lines), (7, 2));
+        assert_eq!(info.config["tab_size"], json!(2));
+        assert!(info.config.contains_key("font_face"));
+
+        let info = PluginBufferInfoBuilder::new().text("one").len(1 << 20).build();
+        assert_eq!((info.buf_size, info.text), (1 << 20, None));
+
+        let mut core = MockCore::start(FirstLinePlugin::default());
+        core.new_view_with_info(PluginBufferInfoBuilder::new().text("hello\nworld").rev(3).build());
+        let delta = Delta::simple_edit(Interval::new_closed_open(0, 0), "oh, ".into(), 11);
+        assert_eq!(core.update(delta), Ok(json!(0)));
+        assert_eq!(core.finish().first_lines, vec!["hello\n", "oh, hello\n"]);
+    }
+
+    #[test]
+    fn save_and_config() {
+        let mut core = MockCore::start(FirstLinePlugin::default());
+        core.new_view("text");
+        core.did_save("/tmp/file.txt");
+        core.config_changed(json!({"tab_size": 2}).as_object().unwrap().clone());
+        assert_eq!(core.text(), "text!");
+        let methods = core.notifications().iter()
+            .map(|n| n["method"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(methods, vec!["plugin_info"]);
+
+        let plugin = core.finish();
+        assert_eq!(plugin.tab_sizes, vec![2]);
+    }
+}
@@ -0,0 +1,264 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running plugin work on a pool of worker threads.
+//!
+//! `Plugin` callbacks run on the main loop's thread, so a slow `update`
+//! holds up every RPC behind it. A `ThreadedPlugin` instead hands a snapshot
+//! of the document to a `Worker` on one of its threads, and returns at once;
+//! the worker's spans and diagnostics are sent to core when they are ready.
+//!
+//! Results are sent in revision order. Once the results for a revision have
+//! been sent, those for older revisions are dropped, as is any work for them
+//! which has not started. Results for the most recent revision are always
+//! sent, and core moves spans computed against an older revision forward to
+//! the current one.
+
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use serde_json::Value;
+
+use xi_core::{PluginPid, ViewIdentifier};
+use xi_core::plugin_rpc::{Diagnostic, ScopeSpan};
+use xi_rope::rope::RopeDelta;
+use xi_rpc::{RemoteError, RpcPeer};
+
+use plugin_base::SpanSequence;
+use state_cache::{DocumentSnapshot, Plugin, PluginCtx, UpdateContext};
+
+/// Analysis of a document, done off the main loop's thread.
+///
+/// Each of the pool's threads has its own clone of the worker.
+pub trait Worker: Send + Clone + 'static {
+    /// Analyzes `text`, the whole document at revision `rev`.
+    fn analyze(&mut self, text: &str, rev: u64) -> Analysis;
+}
+
+/// The results of a `Worker`'s analysis.
+#[derive(Debug, Clone, Default)]
+pub struct Analysis {
+    /// If present, replaces the spans for the whole document.
+    pub spans: Option<Vec<ScopeSpan>>,
+    /// If present, replaces the plugin's diagnostics.
+    pub diagnostics: Option<Vec<Diagnostic>>,
+}
+
+/// Adapts a `Worker` to the `Plugin` interface, running it on a pool of
+/// threads.
+pub struct ThreadedPlugin<W: Worker> {
+    worker: W,
+    num_threads: usize,
+    pool: Option<Pool>,
+}
+
+struct Pool {
+    jobs: Sender<Job>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+struct Job {
+    snapshot: DocumentSnapshot,
+    plugin_id: PluginPid,
+    view_id: ViewIdentifier,
+    peer: RpcPeer,
+    seq: SpanSequence,
+}
+
+impl<W: Worker> ThreadedPlugin<W> {
+    /// Creates a plugin which runs `worker` on `num_threads` threads, which
+    /// are started when the plugin is initialized.
+    pub fn new(worker: W, num_threads: usize) -> Self {
+        assert!(num_threads > 0, "a pool needs at least one thread");
+        ThreadedPlugin { worker, num_threads, pool: None }
+    }
+
+    fn submit(&mut self, ctx: &mut PluginCtx<()>) {
+        let snapshot = match ctx.snapshot() {
+            Ok(snapshot) => snapshot,
+            Err(err) => return eprintln!("unable to read document for worker: {:?}", err),
+        };
+        let job = Job {
+            snapshot,
+            plugin_id: ctx.get_plugin_id(),
+            view_id: ctx.get_view().view_id,
+            peer: ctx.get_peer().clone(),
+            seq: ctx.get_view().span_sequence(),
+        };
+        let (worker, num_threads) = (&self.worker, self.num_threads);
+        let pool = self.pool.get_or_insert_with(|| Pool::start(worker, num_threads));
+        if pool.jobs.send(job).is_err() {
+            eprintln!("worker pool has stopped");
+        }
+    }
+}
+
+impl Pool {
+    fn start<W: Worker>(worker: &W, num_threads: usize) -> Self {
+        let (jobs, rx) = channel();
+        let rx = Arc::new(Mutex::new(rx));
+        // the most recent revision whose results have been sent
+        let last_sent = Arc::new(Mutex::new(None));
+        let threads = (0..num_threads)
+            .map(|_| {
+                let worker = worker.clone();
+                let rx = rx.clone();
+                let last_sent = last_sent.clone();
+                thread::spawn(move || run_worker(worker, &rx, &last_sent))
+            })
+            .collect();
+        Pool { jobs, threads }
+    }
+
+    /// Waits for the threads to finish the work already submitted.
+    fn stop(self) {
+        drop(self.jobs);
+        for thread in self.threads {
+            if thread.join().is_err() {
+                eprintln!("worker thread panicked");
+            }
+        }
+    }
+}
+
+fn run_worker<W: Worker>(mut worker: W, jobs: &Mutex<Receiver<Job>>,
+                         last_sent: &Mutex<Option<u64>>) {
+    let is_stale = |rev, last_sent: &Option<u64>| last_sent.is_some_and(|last| rev <= last);
+    loop {
+        let job = match jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        let rev = job.snapshot.rev();
+        if is_stale(rev, &last_sent.lock().unwrap()) {
+            continue;
+        }
+        let analysis = worker.analyze(job.snapshot.text(), rev);
+        // hold the lock while sending, so results can't be sent out of order
+        let mut last_sent = last_sent.lock().unwrap();
+        if is_stale(rev, &last_sent) {
+            continue;
+        }
+        *last_sent = Some(rev);
+        job.send(analysis);
+    }
+}
+
+impl Job {
+    fn send(&self, analysis: Analysis) {
+        if let Some(spans) = analysis.spans {
+            self.seq.send_next(|seq| {
+                self.peer.send_rpc_notification("update_spans", &json!({
+                    "plugin_id": self.plugin_id,
+                    "view_id": self.view_id,
+                    "start": 0,
+                    "len": self.snapshot.text().len(),
+                    "rev": self.snapshot.rev(),
+                    "seq": seq,
+                    "spans": spans,
+                }));
+            });
+        }
+        if let Some(diagnostics) = analysis.diagnostics {
+            self.seq.send_next(|seq| {
+                self.peer.send_rpc_notification("diagnostics", &json!({
+                    "plugin_id": self.plugin_id,
+                    "view_id": self.view_id,
+                    "seq": seq,
+                    "diagnostics": diagnostics,
+                }));
+            });
+        }
+    }
+}
+
+impl<W: Worker> Plugin for ThreadedPlugin<W> {
+    type State = ();
+
+    fn initialize(&mut self, mut ctx: PluginCtx<()>, _buf_size: usize) {
+        self.submit(&mut ctx);
+    }
+
+    fn update(&mut self, mut ctx: PluginCtx<()>, _info: UpdateContext, _delta: Option<RopeDelta>)
+              -> Result<Option<Value>, RemoteError> {
+        self.submit(&mut ctx);
+        Ok(None)
+    }
+
+    // only the latest text is needed, so there's no point in snapshotting
+    // the document for updates which are already out of date.
+    fn wants_batched_updates(&self) -> bool { true }
+
+    fn wants_delta(&self) -> bool { false }
+
+    fn shutdown(&mut self, _ctx: PluginCtx<()>) {
+        if let Some(pool) = self.pool.take() {
+            pool.stop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Highlights every "x", slowly.
+    #[derive(Clone)]
+    struct SlowX;
+
+    impl Worker for SlowX {
+        fn analyze(&mut self, text: &str, _rev: u64) -> Analysis {
+            thread::sleep(Duration::from_millis(10));
+            let spans = text.match_indices('x')
+                .map(|(ix, _)| ScopeSpan { start: ix, end: ix + 1, scope_id: 1 })
+                .collect();
+            Analysis { spans: Some(spans), diagnostics: None }
+        }
+    }
+
+    #[test]
+    fn results_in_revision_order() {
+        use testing::MockCore;
+        use xi_rope::delta::Delta;
+        use xi_rope::interval::Interval;
+
+        let mut core = MockCore::start(ThreadedPlugin::new(SlowX, 3));
+        core.new_view("");
+        for i in 0..8 {
+            let delta = Delta::simple_edit(Interval::new_closed_open(i, i), "x".into(), i);
+            core.update(delta).unwrap();
+        }
+        // results for the latest revision are always sent
+        core.wait_for_notification(|msg| {
+            msg["method"] == "update_spans" && msg["params"]["rev"] == 9
+        });
+        let revs = core.notifications().iter()
+            .filter(|msg| msg["method"] == "update_spans")
+            .map(|msg| msg["params"]["rev"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+        assert!(revs.windows(2).all(|w| w[0] < w[1]), "out of order: {:?}", revs);
+        let seqs = core.notifications().iter()
+            .filter(|msg| msg["method"] == "update_spans")
+            .map(|msg| msg["params"]["seq"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+        assert!(seqs.windows(2).all(|w| w[0] < w[1]), "out of order: {:?}", seqs);
+        assert_eq!(revs.last(), Some(&9));
+        let last = core.notifications().iter().rev()
+            .find(|msg| msg["method"] == "update_spans").unwrap();
+        assert_eq!(last["params"]["spans"].as_array().unwrap().len(), 8);
+        core.finish();
+    }
+}
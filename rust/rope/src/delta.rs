@@ -19,7 +19,7 @@
 use interval::Interval;
 use tree::{Node, NodeInfo, TreeBuilder};
 use multiset::{Subset, SubsetBuilder, CountMatcher};
-use std::cmp::min;
+use std::cmp::{min, max};
 use std::ops::Deref;
 use std::fmt;
 use std::slice;
@@ -130,6 +130,52 @@ impl<N: NodeInfo> Delta<N> {
         b.build()
     }
 
+    /// Composes this delta with `other`, which must apply to the document
+    /// produced by this delta. The result transforms this delta's base
+    /// document directly into the document produced by `other`:
+    ///
+    /// `d1.compose(&d2).apply(s) == d2.apply(&d1.apply(s))`
+    pub fn compose(&self, other: &Delta<N>) -> Delta<N> {
+        debug_assert_eq!(self.new_document_len(), other.base_len,
+                         "composed Delta must apply to this Delta's result");
+        let mut els = Vec::new();
+        for elem in &other.els {
+            match *elem {
+                DeltaElement::Copy(beg, end) => self.push_new_range(&mut els, beg, end),
+                DeltaElement::Insert(ref n) => push_element(&mut els, DeltaElement::Insert(n.clone())),
+            }
+        }
+        Delta { els, base_len: self.base_len }
+    }
+
+    /// Pushes the elements which produce the range `beg..end` of the new
+    /// document onto `els`.
+    fn push_new_range(&self, els: &mut Vec<DeltaElement<N>>, beg: usize, end: usize) {
+        let mut pos = 0;
+        for elem in &self.els {
+            if pos >= end {
+                break;
+            }
+            let elem_end = pos + match *elem {
+                DeltaElement::Copy(b, e) => e - b,
+                DeltaElement::Insert(ref n) => n.len(),
+            };
+            if elem_end > beg {
+                let start = max(beg, pos) - pos;
+                let stop = min(end, elem_end) - pos;
+                match *elem {
+                    DeltaElement::Copy(b, _) =>
+                        push_element(els, DeltaElement::Copy(b + start, b + stop)),
+                    DeltaElement::Insert(ref n) => {
+                        let iv = Interval::new_closed_open(start, stop);
+                        push_element(els, DeltaElement::Insert(n.subseq(iv)))
+                    }
+                }
+            }
+            pos = elem_end;
+        }
+    }
+
     /// Factor the delta into an insert-only delta and a subset representing deletions.
     /// Applying the insert then the delete yields the same result as the original delta:
     ///
@@ -330,6 +376,27 @@ impl<N: NodeInfo> Delta<N> {
     }
 }
 
+/// Pushes `elem` onto `els`, merging it with the last element where possible.
+fn push_element<N: NodeInfo>(els: &mut Vec<DeltaElement<N>>, elem: DeltaElement<N>) {
+    let merged = match (els.last_mut(), elem) {
+        (Some(&mut DeltaElement::Copy(_, ref mut last_end)), DeltaElement::Copy(beg, end))
+            if *last_end == beg => {
+                *last_end = end;
+                None
+            }
+        (Some(&mut DeltaElement::Insert(ref mut last)), DeltaElement::Insert(n)) => {
+            *last = Node::concat(last.clone(), n);
+            None
+        }
+        (_, DeltaElement::Copy(beg, end)) if beg == end => None,
+        (_, DeltaElement::Insert(ref n)) if n.len() == 0 => None,
+        (_, elem) => Some(elem),
+    };
+    if let Some(elem) = merged {
+        els.push(elem);
+    }
+}
+
 impl<N: NodeInfo> fmt::Debug for Delta<N> where Node<N>: fmt::Debug {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         if f.alternate() {
@@ -706,6 +773,31 @@ mod tests {
         assert_eq!(6, d.new_document_len());
     }
 
+    #[test]
+    fn compose() {
+        let d1 = Delta::simple_edit(Interval::new_closed_open(1, 9), Rope::from("era"), 11);
+        let d2 = Delta::simple_edit(Interval::new_closed_open(6, 6), Rope::from("!"), 6);
+        let composed = d1.compose(&d2);
+        assert_eq!("herald!", composed.apply_to_string("hello world"));
+        assert_eq!(11, composed.base_len);
+        assert_eq!(7, composed.new_document_len());
+
+        // deleting part of an earlier insert
+        let d3 = Delta::simple_edit(Interval::new_closed_open(2, 4), Rope::from(""), 7);
+        let composed = composed.compose(&d3);
+        assert_eq!("held!", composed.apply_to_string("hello world"));
+    }
+
+    #[test]
+    fn compose_simple_inserts() {
+        // two adjacent inserts compose into a single simple insert
+        let d1 = Delta::simple_edit(Interval::new_closed_open(5, 5), Rope::from("a"), 11);
+        let d2 = Delta::simple_edit(Interval::new_closed_open(6, 6), Rope::from("b"), 12);
+        let composed = d1.compose(&d2);
+        assert_eq!("helloab world", composed.apply_to_string("hello world"));
+        assert_eq!(String::from(composed.as_simple_insert().unwrap()), "ab");
+    }
+
     #[test]
     fn factor() {
         let d = Delta::simple_edit(Interval::new_closed_open(1, 9), Rope::from("era"), 11);
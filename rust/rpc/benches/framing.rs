@@ -0,0 +1,84 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![feature(test)]
+
+#[macro_use]
+extern crate serde_json;
+extern crate test;
+extern crate xi_rpc;
+
+use std::io;
+use std::sync::Arc;
+
+use serde_json::Value;
+use test::Bencher;
+use xi_rpc::{Framing, Handler, RemoteError, RpcCall, RpcCtx, RpcLoop};
+
+/// Responds to every request with an empty result.
+struct NullHandler;
+
+impl Handler for NullHandler {
+    type Notification = RpcCall;
+    type Request = RpcCall;
+    fn handle_notification(&mut self, _ctx: &RpcCtx, _rpc: RpcCall) {}
+    fn handle_request(&mut self, _ctx: &RpcCtx, _rpc: RpcCall)
+                      -> Result<Value, RemoteError> {
+        Ok(json!({}))
+    }
+}
+
+/// A burst of updates, as core sends to a plugin while the user types,
+/// each carrying a delta which inserts a line of text.
+fn update_burst(framing: Framing) -> Vec<u8> {
+    let line = "let x = some_function(argument, another_argument); // comment\n";
+    (0..1000).flat_map(|i| framing.encode(&json!({
+        "id": i,
+        "method": "update",
+        "params": {
+            "view_id": "view-id-1",
+            "plugin_id": 0,
+            "rev": i + 2,
+            "new_len": (i + 1) * line.len(),
+            "new_line_count": i + 2,
+            "edit_type": "insert",
+            "author": "core",
+            "delta": {
+                "base_len": i * line.len(),
+                "els": [{"copy": [0, i * line.len()]}, {"insert": line}],
+            },
+        },
+    })).unwrap()).collect()
+}
+
+fn run_burst(b: &mut Bencher, framing: Framing) {
+    let input: Arc<[u8]> = update_burst(framing).into();
+    b.bytes = input.len() as u64;
+    b.iter(|| {
+        let mut looper = RpcLoop::with_framing(io::sink(), framing);
+        let input = input.clone();
+        looper.mainloop(move || io::Cursor::new(input), &mut NullHandler).unwrap();
+    });
+}
+
+#[bench]
+fn bench_json_updates(b: &mut Bencher) {
+    run_burst(b, Framing::Json);
+}
+
+#[bench]
+#[cfg(feature = "msgpack")]
+fn bench_message_pack_updates(b: &mut Bencher) {
+    run_burst(b, Framing::MessagePack);
+}
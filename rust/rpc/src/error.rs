@@ -15,6 +15,8 @@
 use std::io;
 use std::fmt;
 
+#[cfg(feature = "msgpack")]
+use rmp_serde::decode::Error as MessagePackError;
 use serde_json::{Value, Error as JsonError};
 use serde::de::{Deserializer, Deserialize};
 use serde::ser::{Serializer, Serialize};
@@ -39,12 +41,18 @@ pub enum ReadError {
     Io(io::Error),
     /// The message was not valid JSON.
     Json(JsonError),
+    /// The message was not valid MessagePack.
+    #[cfg(feature = "msgpack")]
+    MessagePack(MessagePackError),
     /// The message was not a JSON object.
     NotObject,
     /// The the method and params were not recognized by the handler.
     UnknownRequest(JsonError),
     /// The peer closed the connection.
     Disconnect,
+    /// A MessagePack message was longer than `MAX_FRAME_SIZE`.
+    #[cfg(feature = "msgpack")]
+    FrameTooLarge(usize),
 }
 
 /// Errors that can be received from the other side of the RPC channel.
@@ -143,9 +151,14 @@ impl fmt::Display for ReadError {
         match *self {
             ReadError::Io(ref err) => write!(f, "I/O Error: {:?}", err),
             ReadError::Json(ref err) => write!(f, "JSON Error: {:?}", err),
+            #[cfg(feature = "msgpack")]
+            ReadError::MessagePack(ref err) => write!(f, "MessagePack Error: {:?}", err),
             ReadError::NotObject => write!(f, "JSON message was not an object."),
             ReadError::UnknownRequest(ref err) => write!(f, "Unknown request: {:?}", err),
             ReadError::Disconnect => write!(f, "Peer closed the connection."),
+            #[cfg(feature = "msgpack")]
+            ReadError::FrameTooLarge(len) =>
+                write!(f, "Message of {} bytes is over the maximum frame size.", len),
         }
     }
 }
@@ -156,6 +169,13 @@ impl From<JsonError> for ReadError {
     }
 }
 
+#[cfg(feature = "msgpack")]
+impl From<MessagePackError> for ReadError {
+    fn from(err: MessagePackError) -> ReadError {
+        ReadError::MessagePack(err)
+    }
+}
+
 impl From<io::Error> for ReadError {
     fn from(err: io::Error) -> ReadError {
         ReadError::Io(err)
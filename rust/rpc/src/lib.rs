@@ -27,7 +27,8 @@ extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
-extern crate crossbeam;
+#[cfg(feature = "msgpack")]
+extern crate rmp_serde;
 extern crate xi_trace;
 
 mod parse;
@@ -48,7 +49,10 @@ use serde::de::DeserializeOwned;
 
 use xi_trace::{trace, trace_block, trace_block_payload, trace_payload};
 
-use parse::{Call, Response, RpcObject, MessageReader};
+use parse::{Call, MessageReader};
+pub use parse::{Framing, Response, RpcObject};
+#[cfg(feature = "msgpack")]
+pub use parse::MAX_FRAME_SIZE;
 pub use error::{Error, ReadError, RemoteError};
 
 
@@ -93,6 +97,7 @@ pub type RpcPeer = Box<Peer>;
 
 pub struct RpcCtx {
     peer: RpcPeer,
+    exit_requested: AtomicBool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -177,11 +182,11 @@ struct RpcState<W: Write> {
     pending: Mutex<BTreeMap<usize, ResponseHandler>>,
     idle_queue: Mutex<VecDeque<usize>>,
     needs_exit: AtomicBool,
+    framing: Framing,
 }
 
 /// A structure holding the state of a main loop for handling RPC's.
 pub struct RpcLoop<W: Write + 'static> {
-    reader: MessageReader,
     peer: RawPeer<W>,
 }
 
@@ -189,6 +194,12 @@ impl<W: Write + Send> RpcLoop<W> {
     /// Creates a new `RpcLoop` with the given output stream (which is used for
     /// sending requests and notifications, as well as responses).
     pub fn new(writer: W) -> Self {
+        RpcLoop::with_framing(writer, Framing::Json)
+    }
+
+    /// Creates a new `RpcLoop` which reads and writes messages framed with
+    /// `framing`, rather than as lines of JSON.
+    pub fn with_framing(writer: W, framing: Framing) -> Self {
         let rpc_peer = RawPeer(Arc::new(RpcState {
             rx_queue: Mutex::new(VecDeque::new()),
             rx_cvar: Condvar::new(),
@@ -197,11 +208,9 @@ impl<W: Write + Send> RpcLoop<W> {
             pending: Mutex::new(BTreeMap::new()),
             idle_queue: Mutex::new(VecDeque::new()),
             needs_exit: AtomicBool::new(false),
+            framing,
         }));
-        RpcLoop {
-            reader: MessageReader::default(),
-            peer: rpc_peer,
-        }
+        RpcLoop { peer: rpc_peer }
     }
 
     /// Gets a reference to the peer.
@@ -219,110 +228,133 @@ impl<W: Write + Send> RpcLoop<W> {
     /// The reader is supplied via a closure, as basically a workaround
     /// so that the reader doesn't have to be `Send`. Internally, the
     /// main loop starts a separate thread for I/O, and at startup that
-    /// thread calls the given closure.
+    /// thread calls the given closure. That thread is not joined: if the
+    /// loop exits because of `RpcCtx::request_exit`, it may still be
+    /// blocked on a read, and it ends once that read returns.
     ///
     /// Calls to the handler happen on the caller's thread.
     ///
     /// Calls to the handler are guaranteed to preserve the order as
     /// they appear on on the channel. At the moment, there is no way
     /// for there to be more than one incoming request to be outstanding.
-    pub fn mainloop<'a, R, RF, H>(&mut self, rf: RF, handler: &mut H)
-                                  -> Result<(), ReadError>
-    where R: BufRead,
-          RF: Send + FnOnce() -> R,
+    pub fn mainloop<R, RF, H>(&mut self, rf: RF, handler: &mut H)
+                              -> Result<(), ReadError>
+    where R: BufRead + 'static,
+          RF: Send + FnOnce() -> R + 'static,
           H: Handler,
     {
+        let peer = self.get_raw_peer();
+        peer.reset_needs_exit();
+        // Set once this run of the loop has returned. The read thread is not
+        // joined, so it checks this after each read, to avoid handing
+        // messages to a later run of the loop.
+        let done = Arc::new(AtomicBool::new(false));
+
+        let ctx = RpcCtx {
+            peer: Box::new(peer.clone()),
+            exit_requested: AtomicBool::new(false),
+        };
+        let read_peer = peer.clone();
+        let read_done = done.clone();
+        let mut reader = MessageReader::new(self.peer.0.framing);
+        thread::spawn(move|| {
+            let mut stream = rf();
+            loop {
+                // The main thread does not wait for this thread; when the
+                // main thread wants to exit it sets these flags.
+                if read_done.load(Ordering::SeqCst) || read_peer.needs_exit() {
+                    trace("read loop exit", &["rpc"]);
+                    break
+                }
 
-        let exit = crossbeam::scope(|scope| {
-            let peer = self.get_raw_peer();
-            peer.reset_needs_exit();
-
-            let ctx = RpcCtx {
-                peer: Box::new(peer.clone()),
-            };
-            scope.spawn(move|| {
-                let mut stream = rf();
-                loop {
-                    // The main thread cannot return while this thread is active;
-                    // when the main thread wants to exit it sets this flag.
-                    if self.peer.needs_exit() {
-                        trace("read loop exit", &["rpc"]);
+                let json = match reader.next(&mut stream) {
+                    Ok(json) => json,
+                    Err(err) => {
+                        if !read_done.load(Ordering::SeqCst) {
+                            read_peer.put_rx(Err(err));
+                        }
                         break
                     }
-
-                    let json = match self.reader.next(&mut stream) {
-                        Ok(json) => json,
-                        Err(err) => {
-                            self.peer.put_rx(Err(err));
-                            break
+                };
+                if read_done.load(Ordering::SeqCst) {
+                    trace("read loop exit", &["rpc"]);
+                    break
+                }
+                if json.is_response() {
+                    let id = json.get_id().unwrap();
+                    let _resp = trace_block_payload("read loop response",
+                                                    &["rpc"],
+                                                    format!("{}", id));
+                    match json.into_response() {
+                        Ok(resp) => {
+                            let resp = resp.map_err(Error::from);
+                            read_peer.handle_response(id, resp);
                         }
-                    };
-                    if json.is_response() {
-                        let id = json.get_id().unwrap();
-                        let _resp = trace_block_payload("read loop response",
-                                                        &["rpc"],
-                                                        format!("{}", id));
-                        match json.into_response() {
-                            Ok(resp) => {
-                                let resp = resp.map_err(Error::from);
-                                self.peer.handle_response(id, resp);
-                            }
-                            Err(msg) => {
-                                eprintln!("failed to parse response: {}", msg);
-                                self.peer.handle_response(
-                                    id, Err(Error::InvalidResponse));
-                            }
+                        Err(msg) => {
+                            eprintln!("failed to parse response: {}", msg);
+                            read_peer.handle_response(
+                                id, Err(Error::InvalidResponse));
                         }
-                    } else {
-                        self.peer.put_rx(Ok(json));
                     }
+                } else {
+                    read_peer.put_rx(Ok(json));
                 }
-            });
-
-            loop {
-                let _guard = PanicGuard(&peer);
-                let read_result = next_read(&peer, handler, &ctx);
-                let _trace = trace_block("main got msg", &["rpc"]);
+            }
+        });
 
-                let json = match read_result {
-                    Ok(json) => json,
-                    Err(err) => {
-                        trace_payload("main loop err", &["rpc"],
-                                      err.to_string());
-                        // finish idle work before disconnecting;
-                        // this is mostly useful for integration tests.
-                        if let Some(idle_token) = peer.try_get_idle() {
-                            handler.idle(&ctx, idle_token);
-                        }
-                        peer.disconnect();
-                        return err
+        let exit = loop {
+            let _guard = PanicGuard(&peer);
+            let read_result = next_read(&peer, handler, &ctx);
+            let _trace = trace_block("main got msg", &["rpc"]);
+
+            let json = match read_result {
+                Ok(json) => json,
+                Err(err) => {
+                    trace_payload("main loop err", &["rpc"],
+                                  err.to_string());
+                    // finish idle work before disconnecting;
+                    // this is mostly useful for integration tests.
+                    if let Some(idle_token) = peer.try_get_idle() {
+                        handler.idle(&ctx, idle_token);
                     }
-                };
+                    peer.disconnect();
+                    break err
+                }
+            };
 
-                let method = json.get_method().map(String::from);
-                match json.into_rpc::<H::Notification, H::Request>() {
-                    Ok(Call::Request(id, cmd)) => {
-                        let _t = trace_block_payload("handle request", &["rpc"],
-                                                     method.unwrap());
-                        let result = handler.handle_request(&ctx, cmd);
-                        peer.respond(result, id);
-                    }
-                    Ok(Call::Notification(cmd)) => {
-                        let _t = trace_block_payload("handle notif", &["rpc"],
-                                                     method.unwrap());
-                        handler.handle_notification(&ctx, cmd);
+            let method = json.get_method().map(String::from);
+            match json.into_rpc::<H::Notification, H::Request>() {
+                Ok(Call::Request(id, cmd)) => {
+                    let _t = trace_block_payload("handle request", &["rpc"],
+                                                 method.unwrap());
+                    let result = handler.handle_request(&ctx, cmd);
+                    peer.respond(result, id);
+                }
+                Ok(Call::Notification(cmd)) => {
+                    let _t = trace_block_payload("handle notif", &["rpc"],
+                                                 method.unwrap());
+                    handler.handle_notification(&ctx, cmd);
 
-                    }
-                    Ok(Call::InvalidRequest(id, err)) => peer.respond(Err(err), id),
-                    Err(err) => {
-                        trace_payload("read loop exit", &["rpc"],
-                                      err.to_string());
-                        peer.disconnect();
-                        return ReadError::UnknownRequest(err)
-                    }
+                }
+                Ok(Call::InvalidRequest(id, err)) => peer.respond(Err(err), id),
+                Err(err) => {
+                    trace_payload("read loop exit", &["rpc"],
+                                  err.to_string());
+                    peer.disconnect();
+                    break ReadError::UnknownRequest(err)
                 }
             }
-        });
+
+            if ctx.exit_requested() {
+                trace("main loop exit requested", &["rpc"]);
+                // any outstanding idle work is dropped (see `request_exit`),
+                // so that it does not run if this loop is restarted.
+                peer.clear_idle();
+                peer.disconnect();
+                break ReadError::Disconnect
+            }
+        };
+        done.store(true, Ordering::SeqCst);
         if exit.is_disconnect() {
             Ok(())
         } else {
@@ -367,6 +399,22 @@ impl RpcCtx {
     pub fn schedule_idle(&self, token: usize) {
         self.peer.schedule_idle(token)
     }
+
+    /// Requests that the main loop exit after the current RPC has been
+    /// handled. `RpcLoop::mainloop` will then return `Ok(())`, as if the
+    /// peer had closed the connection, without waiting for the reader to
+    /// reach EOF.
+    ///
+    /// Any idle work that is still scheduled is dropped without calling
+    /// `Handler::idle`; a handler which needs to finish or cancel it should
+    /// do so before requesting the exit.
+    pub fn request_exit(&self) {
+        self.exit_requested.store(true, Ordering::SeqCst);
+    }
+
+    fn exit_requested(&self) -> bool {
+        self.exit_requested.load(Ordering::SeqCst)
+    }
 }
 
 impl<W: Write + Send + 'static> Peer for RawPeer<W> {
@@ -418,9 +466,8 @@ impl<W: Write + Send + 'static> Peer for RawPeer<W> {
 impl<W:Write> RawPeer<W> {
     fn send(&self, v: &Value) -> Result<(), io::Error> {
         let _trace = trace_block("send", &["rpc"]);
-        let mut s = serde_json::to_string(v).unwrap();
-        s.push('\n');
-        self.0.writer.lock().unwrap().write_all(s.as_bytes())
+        let bytes = self.0.framing.encode(v)?;
+        self.0.writer.lock().unwrap().write_all(&bytes)
         // Technically, maybe we should flush here, but doesn't seem to be required.
     }
 
@@ -494,6 +541,11 @@ impl<W:Write> RawPeer<W> {
         self.0.idle_queue.lock().unwrap().pop_front()
     }
 
+    /// Discards any scheduled idle tokens.
+    fn clear_idle(&self) {
+        self.0.idle_queue.lock().unwrap().clear();
+    }
+
     /// send disconnect error to pending requests.
     fn disconnect(&self) {
         let mut pending = self.0.pending.lock().unwrap();
@@ -578,4 +630,68 @@ mod tests {
             _ => panic!("parse failed"),
         }
     }
+
+    #[cfg(feature = "msgpack")]
+    /// A writer whose output can be read back once the loop has finished.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    #[cfg(feature = "msgpack")]
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "msgpack")]
+    struct EchoHandler;
+
+    #[cfg(feature = "msgpack")]
+    impl Handler for EchoHandler {
+        type Notification = RpcCall;
+        type Request = RpcCall;
+        fn handle_notification(&mut self, _ctx: &RpcCtx, _rpc: RpcCall) {}
+        fn handle_request(&mut self, _ctx: &RpcCtx, rpc: RpcCall)
+                          -> Result<Value, RemoteError> {
+            Ok(rpc.params)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "msgpack")]
+    fn test_message_pack_loop() {
+        let input = [
+            json!({"method": "hullo", "params": {}}),
+            json!({"id": 1, "method": "hullo", "params": {"words": "plz", "n": 42}}),
+        ].iter().flat_map(|msg| Framing::MessagePack.encode(msg).unwrap()).collect::<Vec<_>>();
+        let output = SharedBuf::default();
+        let mut rpc_looper = RpcLoop::with_framing(output.clone(), Framing::MessagePack);
+        assert!(rpc_looper.mainloop(move || io::Cursor::new(input), &mut EchoHandler).is_ok());
+
+        let output = output.0.lock().unwrap();
+        let mut stream = &output[..];
+        let mut reader = MessageReader::new(Framing::MessagePack);
+        let resp = reader.next(&mut stream).unwrap();
+        assert_eq!(resp.0, json!({"id": 1, "result": {"words": "plz", "n": 42}}));
+        assert!(reader.next(&mut stream).unwrap_err().is_disconnect());
+    }
+
+    #[test]
+    #[cfg(feature = "msgpack")]
+    fn test_message_pack_too_large() {
+        let output = SharedBuf::default();
+        let rpc_looper = RpcLoop::with_framing(output.clone(), Framing::MessagePack);
+        let peer = rpc_looper.get_raw_peer();
+        let params = json!({"text": "x".repeat(MAX_FRAME_SIZE)});
+        match peer.send_rpc_request("big", &params) {
+            Err(Error::Io(ref err)) => assert_eq!(err.kind(), io::ErrorKind::InvalidData),
+            other => panic!("{:?}", other),
+        }
+        // nothing is sent that the peer would reject
+        assert!(output.0.lock().unwrap().is_empty());
+    }
 }
@@ -17,7 +17,9 @@ extern crate serde_json;
 extern crate xi_rpc;
 
 use std::time::Duration;
-use std::io;
+use std::io::{self, Read};
+use std::sync::mpsc;
+use std::thread;
 
 use serde_json::Value;
 use xi_rpc::{Handler, RpcLoop, RpcCtx, RpcCall, RemoteError, ReadError};
@@ -93,3 +95,101 @@ fn test_bad_json_err() {
         Ok(()) => panic!("Expected an error"),
     }
 }
+
+/// Handler that asks the runloop to exit when it receives an `exit` notification.
+pub struct ExitHandler {
+    handled: usize,
+}
+
+#[allow(unused)]
+impl Handler for ExitHandler {
+    type Notification = RpcCall;
+    type Request = RpcCall;
+    fn handle_notification(&mut self, ctx: &RpcCtx, rpc: Self::Notification) {
+        self.handled += 1;
+        if rpc.method == "exit" {
+            ctx.schedule_idle(0);
+            ctx.request_exit();
+        }
+    }
+    fn handle_request(&mut self, ctx: &RpcCtx, rpc: Self::Request)
+                      -> Result<Value, RemoteError> {
+        Ok(rpc.params)
+    }
+    fn idle(&mut self, ctx: &RpcCtx, token: usize) {
+        panic!("idle work should be dropped on exit");
+    }
+}
+
+#[test]
+fn test_request_exit() {
+    // messages after an exit request should not be handled
+    let mut handler = ExitHandler { handled: 0 };
+    let (tx, mut rx) = test_channel();
+    let mut rpc_looper = RpcLoop::new(tx);
+    let r = make_reader(r#"{"method": "exit", "params": {}}
+{"id": 0, "method": "hullo", "params": {"words": "plz"}}"#);
+    assert!(rpc_looper.mainloop(|| r, &mut handler).is_ok());
+    assert_eq!(handler.handled, 1);
+    rx.expect_nothing();
+}
+
+/// A reader that yields its contents and then blocks, as stdin does when
+/// the peer keeps it open.
+struct NeverEof {
+    input: io::Cursor<Vec<u8>>,
+    blocked: mpsc::Sender<()>,
+}
+
+impl Read for NeverEof {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.input.read(buf)? {
+            0 => {
+                let _ = self.blocked.send(());
+                loop { thread::park() }
+            }
+            n => Ok(n),
+        }
+    }
+}
+
+/// Handler that requests an exit once the reader is blocked.
+pub struct BlockedExitHandler {
+    blocked: mpsc::Receiver<()>,
+}
+
+#[allow(unused)]
+impl Handler for BlockedExitHandler {
+    type Notification = RpcCall;
+    type Request = RpcCall;
+    fn handle_notification(&mut self, ctx: &RpcCtx, rpc: Self::Notification) {
+        self.blocked.recv().unwrap();
+        ctx.request_exit();
+    }
+    fn handle_request(&mut self, ctx: &RpcCtx, rpc: Self::Request)
+                      -> Result<Value, RemoteError> {
+        Ok(rpc.params)
+    }
+}
+
+#[test]
+fn test_request_exit_without_eof() {
+    // the loop should exit even if the reader never reaches EOF
+    let (blocked_tx, blocked_rx) = mpsc::channel();
+    let (done_tx, done_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut handler = BlockedExitHandler { blocked: blocked_rx };
+        let (tx, _rx) = test_channel();
+        let mut rpc_looper = RpcLoop::new(tx);
+        let input = br#"{"method": "exit", "params": {}}
+"#.to_vec();
+        let r = io::BufReader::new(NeverEof {
+            input: io::Cursor::new(input),
+            blocked: blocked_tx,
+        });
+        done_tx.send(rpc_looper.mainloop(move || r, &mut handler).is_ok()).unwrap();
+    });
+    let exited = done_rx.recv_timeout(Duration::from_secs(5))
+        .expect("mainloop did not return");
+    assert!(exited);
+}
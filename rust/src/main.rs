@@ -22,11 +22,10 @@ use xi_rpc::RpcLoop;
 
 fn main() {
     let mut state = MainState::new();
-    let stdin = io::stdin();
     let stdout = io::stdout();
     let mut rpc_looper = RpcLoop::new(stdout);
 
-    match rpc_looper.mainloop(|| stdin.lock(), &mut state) {
+    match rpc_looper.mainloop(|| io::stdin().lock(), &mut state) {
         Ok(_) => (),
         Err(err) => eprintln!("xi-core exited with error:\n{:?}", err),
     }
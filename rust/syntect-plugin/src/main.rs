@@ -29,8 +29,8 @@ use std::borrow::Cow;
 
 use serde_json::Value;
 
-use xi_plugin_lib::state_cache::{self, PluginCtx};
-use xi_core_lib::plugin_rpc::ScopeSpan;
+use xi_plugin_lib::state_cache::{self, Idle, PluginCtx, PluginInfo, RemoteError, SpanBuilder,
+                                 UpdateContext};
 use xi_rope::rope::RopeDelta;
 use xi_rope::interval::Interval;
 use xi_rope::delta::Builder as EditBuilder;
@@ -47,7 +47,7 @@ struct PluginState<'a> {
     initial_state: Option<(ParseState, ScopeStack)>,
     spans_start: usize,
     // unflushed spans
-    spans: Vec<ScopeSpan>,
+    spans: SpanBuilder,
     new_scopes: Vec<Vec<String>>,
     syntax_name: String,
 }
@@ -73,7 +73,7 @@ impl<'a> PluginState<'a> {
             offset: 0,
             initial_state: None,
             spans_start: 0,
-            spans: Vec::new(),
+            spans: SpanBuilder::new(),
             new_scopes: Vec::new(),
             syntax_name: String::from("None"),
         }
@@ -92,8 +92,7 @@ impl<'a> PluginState<'a> {
                 let start = self.offset - self.spans_start + prev_cursor;
                 let end = start + (cursor - prev_cursor);
                 if start != end {
-                    let span = ScopeSpan { start, end, scope_id };
-                    self.spans.push(span);
+                    self.spans.add(start, end, scope_id);
                 }
             }
             prev_cursor = cursor;
@@ -103,8 +102,7 @@ impl<'a> PluginState<'a> {
         let start = self.offset - self.spans_start + prev_cursor;
         let end = start + (line.len() - prev_cursor);
         let scope_id = self.identifier_for_stack(&scope_state, &repo);
-        let span = ScopeSpan { start, end, scope_id };
-        self.spans.push(span);
+        self.spans.add(start, end, scope_id);
         Some((parse_state, scope_state))
     }
 
@@ -171,9 +169,8 @@ impl<'a> PluginState<'a> {
             self.new_scopes.clear();
         }
         if self.spans_start != self.offset {
-            ctx.update_spans(self.spans_start, self.offset - self.spans_start,
-                             &self.spans);
-            self.spans.clear();
+            ctx.flush_spans(self.spans_start, self.offset - self.spans_start,
+                            &mut self.spans);
         }
         self.spans_start = self.offset;
     }
@@ -191,7 +188,7 @@ impl<'a> PluginState<'a> {
         }
 
         self.initial_state = Some((ParseState::new(syntax), ScopeStack::new()));
-        self.spans = Vec::new();
+        self.spans.clear();
         self.new_scopes = Vec::new();
         self.offset = 0;
         self.spans_start = 0;
@@ -280,8 +277,8 @@ impl<'a> state_cache::Plugin for PluginState<'a> {
         self.do_highlighting(ctx);
     }
 
-    fn update(&mut self, mut ctx: PluginCtx<State>, rev: usize,
-              delta: Option<RopeDelta>) -> Option<Value> {
+    fn update(&mut self, mut ctx: PluginCtx<State>, info: UpdateContext,
+              delta: Option<RopeDelta>) -> Result<Option<Value>, RemoteError> {
         ctx.schedule_idle(0);
         let should_auto_indent = ctx.get_config().auto_indent;
         if should_auto_indent {
@@ -289,24 +286,26 @@ impl<'a> state_cache::Plugin for PluginState<'a> {
                 let (iv, _) = delta.summary();
                 if let Some(s) = delta.as_simple_insert() {
                     let s: String = s.into();
-                    return self.do_indentation(&mut ctx, iv.start(), iv.end(), rev, &s)
+                    return Ok(self.do_indentation(&mut ctx, iv.start(), iv.end(),
+                                                   info.rev as usize, &s))
                 }
             }
         }
-        None
+        Ok(None)
     }
 
-    fn did_save(&mut self, ctx: PluginCtx<State>) {
+    fn did_save(&mut self, ctx: PluginCtx<State>) -> Result<(), String> {
         // TODO: use smarter logic to figure out whether we need to re-highlight the whole file
         self.do_highlighting(ctx);
+        Ok(())
     }
 
-    fn idle(&mut self, mut ctx: PluginCtx<State>, _token: usize) {
+    fn idle(&mut self, mut ctx: PluginCtx<State>, _token: usize) -> Idle {
         //eprintln!("idle task at offset {}", self.offset);
         for _ in 0..LINES_PER_RPC {
             if !self.highlight_one_line(&mut ctx) {
                 self.flush_spans(&mut ctx);
-                return;
+                return Idle::Done;
             }
             if ctx.request_is_pending() {
                 eprintln!("request pending at offset {}", self.offset);
@@ -314,7 +313,15 @@ impl<'a> state_cache::Plugin for PluginState<'a> {
             }
         }
         self.flush_spans(&mut ctx);
-        ctx.schedule_idle(0);
+        Idle::Continue
+    }
+
+    fn info(&self) -> PluginInfo {
+        PluginInfo {
+            name: "syntect".to_owned(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            features: vec!["spans".to_owned(), "edits".to_owned()],
+        }
     }
 }
 
@@ -158,6 +158,13 @@ impl CategoriesT {
             &CategoriesT::DynamicArray(ref vec) => vec.join(sep),
         }
     }
+
+    pub fn contains(&self, category: &str) -> bool {
+        match *self {
+            CategoriesT::StaticArray(arr) => arr.contains(&category),
+            CategoriesT::DynamicArray(ref vec) => vec.iter().any(|c| c == category),
+        }
+    }
 }
 
 macro_rules! categories_from_constant_array {
@@ -413,13 +420,20 @@ impl<'a> Drop for SampleGuard<'a> {
 pub struct Trace {
     enabled: AtomicBool,
     samples: Mutex<FixedLifoDeque<Sample>>,
+    /// If not empty, only samples in one of these categories are recorded.
+    categories: Mutex<Vec<String>>,
+    /// Whether `categories` is non-empty, so that recording needn't take
+    /// its lock when there is no filter.
+    filtered: AtomicBool,
 }
 
 impl Trace {
     pub fn disabled() -> Self {
         Self {
             enabled: AtomicBool::new(false),
-            samples: Mutex::new(FixedLifoDeque::new())
+            samples: Mutex::new(FixedLifoDeque::new()),
+            categories: Mutex::new(Vec::new()),
+            filtered: AtomicBool::new(false),
         }
     }
 
@@ -427,12 +441,16 @@ impl Trace {
         Self {
             enabled: AtomicBool::new(true),
             samples: Mutex::new(FixedLifoDeque::with_limit(config.max_samples())),
+            categories: Mutex::new(Vec::new()),
+            filtered: AtomicBool::new(false),
         }
     }
 
     pub fn disable(&self) {
         let mut all_samples = self.samples.lock().unwrap();
         all_samples.reset_limit(0);
+        self.categories.lock().unwrap().clear();
+        self.filtered.store(false, AtomicOrdering::Relaxed);
         self.enabled.store(false, AtomicOrdering::Relaxed);
     }
 
@@ -447,6 +465,14 @@ impl Trace {
         self.enabled.store(true, AtomicOrdering::Relaxed);
     }
 
+    /// Only record samples in at least one of `categories` from now on.  An
+    /// empty list records every sample, which is the default.
+    pub fn set_category_filter(&self, categories: Vec<String>) {
+        let mut filter = self.categories.lock().unwrap();
+        self.filtered.store(!categories.is_empty(), AtomicOrdering::Relaxed);
+        *filter = categories;
+    }
+
     /// Generally racy since the underlying storage might be mutated in a separate thread.
     /// Exposed for unit tests.
     pub fn get_samples_count(&self) -> usize {
@@ -460,6 +486,13 @@ impl Trace {
 
     #[inline]
     pub(crate) fn record(&self, sample: &Sample) {
+        if self.filtered.load(AtomicOrdering::Relaxed) {
+            let categories = self.categories.lock().unwrap();
+            if !categories.is_empty() &&
+                !categories.iter().any(|c| sample.categories.contains(c)) {
+                return;
+            }
+        }
         let mut all_samples = self.samples.lock().unwrap();
         all_samples.push_back(sample.clone());
     }
@@ -562,6 +595,13 @@ pub fn disable_tracing() {
     SAMPLE_COUNTER.store(0, AtomicOrdering::Relaxed);
 }
 
+/// Only record samples in at least one of `categories`.  An empty list, the
+/// default, records every sample.  Disabling tracing clears the filter.
+#[inline]
+pub fn set_category_filter(categories: Vec<String>) {
+    TRACE.set_category_filter(categories);
+}
+
 /// Is tracing enabled.  Technically doesn't guarantee any samples will be
 /// stored as tracing could still be enabled but set with a limit of 0.
 #[inline]
@@ -844,6 +884,24 @@ mod tests {
         assert_eq!(trace.get_samples_count(), 0);
     }
 
+    #[test]
+    fn test_category_filter() {
+        let trace = Trace::enabled(Config::with_limit_count(10));
+        trace.set_category_filter(vec!["rpc".to_owned(), "plugin".to_owned()]);
+        trace.instant("1", &["rpc"]);
+        trace.instant("2", &["test"]);
+        trace.instant("3", &["test", "plugin"]);
+        trace.instant("4", &[]);
+        let names = trace.samples_cloned_unsorted().into_iter()
+            .map(|s| s.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["1", "3"]);
+
+        trace.set_category_filter(Vec::new());
+        trace.instant("5", &["test"]);
+        assert_eq!(trace.get_samples_count(), 3);
+    }
+
     #[test]
     fn test_get_samples() {
         let trace = Trace::enabled(Config::with_limit_count(20));